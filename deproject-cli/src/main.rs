@@ -0,0 +1,637 @@
+//! Headless capture and processing, for scripting data collection and batch jobs on machines
+//! without a display
+
+use std::collections::BTreeMap;
+use std::fs::File;
+use std::io::{BufWriter, Write};
+use std::path::{Path, PathBuf};
+use std::sync::mpsc::{self, RecvTimeoutError};
+use std::thread;
+use std::time::{Duration, Instant};
+
+use anyhow::{bail, ensure, Context, Result};
+use clap::{Parser, Subcommand};
+use deproject_io::codec::{ColorCodec, DepthCodec, FrameCodecs};
+use deproject_io::extrinsics::{calibrate_rig, Extrinsics, PairCalibrationParams};
+use deproject_io::graycode::ProjectorMap;
+use deproject_io::metrics::Metrics;
+use deproject_io::profile::{DeviceProfile, RigProfile};
+use deproject_io::recording::{decode_capture, RecordingHeader, RecordingReader, RecordingWriter};
+use deproject_io::stream::{start_stream_client, StreamServer, DEFAULT_PORT};
+use deproject_io::{list_devices, ply, start_realsense, CaptureEvent, SensorOptions, StreamConfig};
+use glam::Mat4;
+
+/// Longest a device may go without sending a frame while recording, including while starting up
+const FRAME_TIMEOUT: Duration = Duration::from_secs(10);
+
+#[derive(Parser)]
+#[command(about = "Capture and process depth camera recordings without the viewer")]
+struct Cli {
+    #[command(subcommand)]
+    command: Command,
+}
+
+#[derive(Subcommand)]
+enum Command {
+    /// List the serial numbers of connected devices
+    Devices,
+    /// Record frames from connected devices into a recording directory
+    Record {
+        /// Directory to write the recording into
+        out: PathBuf,
+        /// Device to record, by serial number. Repeat for several; defaults to every device.
+        #[arg(long = "serial")]
+        serials: Vec<String>,
+        /// Number of frames to record from each device
+        #[arg(long, default_value_t = 100)]
+        frames: usize,
+        /// Width of the depth and color streams, with the height chosen by the device
+        #[arg(long, default_value_t = StreamConfig::default().depth_width)]
+        width: usize,
+        #[arg(long, default_value_t = StreamConfig::default().fps)]
+        fps: usize,
+        /// Store depth images compressed with this codec (raw, zstd or png) instead of positions
+        #[arg(long)]
+        depth_codec: Option<DepthCodec>,
+        /// Store color images compressed with this codec (raw, qoi or jpeg) instead of positions
+        #[arg(long)]
+        color_codec: Option<ColorCodec>,
+    },
+    /// Export the frames of a recording as PLY files, in meters in each depth camera's frame
+    ExportPly {
+        recording: PathBuf,
+        /// Directory to write the PLY files into
+        out: PathBuf,
+        /// Export only this frame, by index
+        #[arg(long)]
+        frame: Option<usize>,
+    },
+    /// Decode a saved structured light capture, writing the projector column and row seen by each
+    /// pixel as 16-bit PNGs. Undecoded pixels are 0, and decoded ones the coordinate plus 1.
+    Decode {
+        capture: PathBuf,
+        /// Directory to write the images into
+        out: PathBuf,
+        /// Minimum difference in luminance between a pattern and its inverse for a pixel to decode
+        #[arg(long, default_value_t = 0.05)]
+        min_contrast: f32,
+    },
+    /// Align every camera of a saved structured light capture to a reference camera
+    Calibrate {
+        capture: PathBuf,
+        /// Serial number of the camera the others are aligned to. Defaults to the first.
+        #[arg(long)]
+        reference: Option<String>,
+        #[arg(long, default_value_t = 0.05)]
+        min_contrast: f32,
+        /// Correspondences further than this multiple of the median residual are rejected
+        #[arg(long, default_value_t = PairCalibrationParams::default().outlier_factor)]
+        outlier_factor: f32,
+        #[arg(long, default_value_t = PairCalibrationParams::default().icp_iterations)]
+        icp_iterations: usize,
+        /// Use every n-th point of each camera's cloud for ICP
+        #[arg(long, default_value_t = PairCalibrationParams::default().icp_stride)]
+        icp_stride: usize,
+        /// Write the camera placements to a rig profile, which the viewer can import. The profile
+        /// holds nothing else, so importing it resets other rig settings to their defaults.
+        #[arg(long)]
+        out: Option<PathBuf>,
+    },
+    /// Stream frames to remote viewers over TCP, from connected devices or a looping recording
+    Serve {
+        /// Address to listen on
+        #[arg(long, default_value_t = format!("0.0.0.0:{DEFAULT_PORT}"))]
+        bind: String,
+        /// Play this recording in a loop instead of streaming from devices
+        #[arg(long)]
+        recording: Option<PathBuf>,
+        /// Device to stream, by serial number. Repeat for several; defaults to every device.
+        #[arg(long = "serial")]
+        serials: Vec<String>,
+        #[arg(long, default_value_t = StreamConfig::default().depth_width)]
+        width: usize,
+        #[arg(long, default_value_t = StreamConfig::default().fps)]
+        fps: usize,
+        /// Codec for depth images: raw, zstd or png
+        #[arg(long, default_value_t = DepthCodec::Zstd)]
+        depth_codec: DepthCodec,
+        /// Codec for color images: raw, qoi or jpeg
+        #[arg(long, default_value_t = ColorCodec::Raw)]
+        color_codec: ColorCodec,
+    },
+    /// Receive frames from a stream server into a recording directory
+    Receive {
+        /// Address of the server, as host:port
+        address: String,
+        out: PathBuf,
+        /// Number of frames to receive, counting every device
+        #[arg(long, default_value_t = 100)]
+        frames: usize,
+    },
+    /// Measure the compression ratio and speed of every codec on the frames of a recording
+    Bench {
+        recording: PathBuf,
+        /// Number of frames to measure, from the start of the recording
+        #[arg(long, default_value_t = 30)]
+        frames: usize,
+    },
+}
+
+fn main() -> Result<()> {
+    match Cli::parse().command {
+        Command::Devices => {
+            for serial in list_devices()? {
+                println!("{serial}");
+            }
+            Ok(())
+        }
+        Command::Record {
+            out,
+            serials,
+            frames,
+            width,
+            fps,
+            depth_codec,
+            color_codec,
+        } => {
+            let stream = StreamConfig {
+                color_width: width,
+                color_height: 0,
+                depth_width: width,
+                depth_height: 0,
+                fps,
+            };
+            let codecs = (depth_codec.is_some() || color_codec.is_some()).then(|| FrameCodecs {
+                depth: depth_codec.unwrap_or_default(),
+                color: color_codec.unwrap_or_default(),
+            });
+            record(&out, serials, frames, stream, codecs)
+        }
+        Command::ExportPly {
+            recording,
+            out,
+            frame,
+        } => export_ply(&recording, &out, frame),
+        Command::Decode {
+            capture,
+            out,
+            min_contrast,
+        } => decode(&capture, &out, min_contrast),
+        Command::Calibrate {
+            capture,
+            reference,
+            min_contrast,
+            outlier_factor,
+            icp_iterations,
+            icp_stride,
+            out,
+        } => {
+            let params = PairCalibrationParams {
+                outlier_factor,
+                icp_iterations,
+                icp_stride,
+            };
+            calibrate(&capture, reference, min_contrast, &params, out.as_deref())
+        }
+        Command::Serve {
+            bind,
+            recording,
+            serials,
+            width,
+            fps,
+            depth_codec,
+            color_codec,
+        } => {
+            let codecs = FrameCodecs {
+                depth: depth_codec,
+                color: color_codec,
+            };
+            let server = StreamServer::bind(&bind, codecs)?;
+            eprintln!("Serving on {}", server.local_addr());
+            match recording {
+                Some(recording) => serve_recording(&server, &recording),
+                None => {
+                    let stream = StreamConfig {
+                        color_width: width,
+                        color_height: 0,
+                        depth_width: width,
+                        depth_height: 0,
+                        fps,
+                    };
+                    serve_devices(&server, serials, stream)
+                }
+            }
+        }
+        Command::Receive {
+            address,
+            out,
+            frames,
+        } => receive(address, &out, frames),
+        Command::Bench { recording, frames } => bench(&recording, frames),
+    }
+}
+
+fn record(
+    out: &Path,
+    serials: Vec<String>,
+    frames: usize,
+    stream: StreamConfig,
+    codecs: Option<FrameCodecs>,
+) -> Result<()> {
+    let serials = match serials.is_empty() {
+        true => list_devices()?,
+        false => serials,
+    };
+    ensure!(!serials.is_empty(), "No devices connected");
+
+    let options = SensorOptions::default();
+    let mut header = RecordingHeader::new(serials.clone(), stream, options);
+    if let Some(codecs) = codecs {
+        header = header.with_codecs(codecs);
+    }
+    let mut writer = RecordingWriter::create(out, &header)?;
+
+    let (tx, rx) = mpsc::channel();
+    let metrics = Metrics::new();
+    let captures: Vec<_> = serials
+        .iter()
+        .map(|serial| {
+            let tx = tx.clone();
+            let handle = start_realsense(serial.clone(), stream, metrics.clone(), move |event| {
+                tx.send(event).is_ok()
+            });
+            handle.set_options(&options);
+            handle
+        })
+        .collect();
+    drop(tx);
+
+    // Frames recorded from each device, and when it last sent one
+    let start = Instant::now();
+    let mut counts: BTreeMap<String, (usize, Instant)> =
+        serials.iter().map(|s| (s.clone(), (0, start))).collect();
+    while counts.values().any(|(n, _)| *n < frames) {
+        let stalled = counts
+            .iter()
+            .find(|(_, (n, last))| *n < frames && last.elapsed() > FRAME_TIMEOUT);
+        if let Some((serial, (n, _))) = stalled {
+            bail!("{serial} sent no frames for {FRAME_TIMEOUT:?}, after {n} of {frames}");
+        }
+
+        let event = match rx.recv_timeout(Duration::from_secs(1)) {
+            Ok(event) => event,
+            Err(RecvTimeoutError::Timeout) => continue,
+            Err(RecvTimeoutError::Disconnected) => bail!("Every capture thread stopped"),
+        };
+        match event {
+            CaptureEvent::Frame(frame) => {
+                let Some((count, last)) = counts.get_mut(frame.serial()) else {
+                    continue;
+                };
+                if *count < frames {
+                    writer.write(&frame)?;
+                    *count += 1;
+                    *last = Instant::now();
+                }
+            }
+            CaptureEvent::Status { serial, status } => eprintln!("{serial}: {status:?}"),
+        }
+    }
+
+    for capture in captures {
+        capture.stop();
+    }
+    println!(
+        "Recorded {} frames to {}",
+        writer.frame_count(),
+        out.display()
+    );
+    Ok(())
+}
+
+fn export_ply(recording: &Path, out: &Path, frame: Option<usize>) -> Result<()> {
+    let reader = RecordingReader::open(recording)?;
+    std::fs::create_dir_all(out).with_context(|| format!("Creating {}", out.display()))?;
+
+    let indices = match frame {
+        Some(index) => index..index + 1,
+        None => 0..reader.len(),
+    };
+    for index in indices {
+        let frame = reader.read(index)?;
+        let path = out.join(format!("frame_{index:06}_{}.ply", frame.serial()));
+        let mut w = BufWriter::new(File::create(&path)?);
+        ply::write_ply(&mut w, &ply::frame_points(&frame))
+            .with_context(|| format!("Writing {}", path.display()))?;
+        w.flush()?;
+    }
+    Ok(())
+}
+
+fn decode(capture: &Path, out: &Path, min_contrast: f32) -> Result<()> {
+    let reader = RecordingReader::open(capture)?;
+    let captures = decode_capture(&reader, min_contrast)?;
+    std::fs::create_dir_all(out).with_context(|| format!("Creating {}", out.display()))?;
+
+    for (serial, capture) in &captures {
+        let map = &capture.map;
+        for (axis, name) in ["columns", "rows"].into_iter().enumerate() {
+            let path = out.join(format!("{serial}_{name}.png"));
+            write_coords_png(&path, map, axis)?;
+        }
+        let decoded = map.coords().iter().filter(|c| c.is_some()).count();
+        println!(
+            "{serial}: {decoded} of {} pixels decoded",
+            map.coords().len()
+        );
+    }
+    Ok(())
+}
+
+/// Write one axis of a projector map as a 16-bit grayscale PNG, offset by one so that 0 means
+/// undecoded
+fn write_coords_png(path: &Path, map: &ProjectorMap, axis: usize) -> Result<()> {
+    let file = File::create(path).with_context(|| format!("Creating {}", path.display()))?;
+    let mut encoder = png::Encoder::new(
+        BufWriter::new(file),
+        map.width() as u32,
+        map.height() as u32,
+    );
+    encoder.set_color(png::ColorType::Grayscale);
+    encoder.set_depth(png::BitDepth::Sixteen);
+
+    let pixels: Vec<u8> = map
+        .coords()
+        .iter()
+        .map(|c| c.map_or(0, |c| (c[axis] + 1).min(u16::MAX as u32) as u16))
+        .flat_map(u16::to_be_bytes)
+        .collect();
+    encoder
+        .write_header()?
+        .write_image_data(&pixels)
+        .context("Writing PNG")?;
+    Ok(())
+}
+
+fn calibrate(
+    capture: &Path,
+    reference: Option<String>,
+    min_contrast: f32,
+    params: &PairCalibrationParams,
+    out: Option<&Path>,
+) -> Result<()> {
+    let reader = RecordingReader::open(capture)?;
+    let captures = decode_capture(&reader, min_contrast)?;
+    let reference = match reference {
+        Some(reference) => reference,
+        None => captures
+            .keys()
+            .next()
+            .cloned()
+            .context("Capture is empty")?,
+    };
+    ensure!(
+        captures.contains_key(&reference),
+        "No capture from reference camera {reference}"
+    );
+
+    let results = calibrate_rig(&captures, &reference, params);
+    println!("Camera\tPairs\tInliers\tInitial RMS\tRMS\tMedian\tMax");
+    for (serial, result) in &results {
+        let r = &result.refined;
+        println!(
+            "{serial}\t{}\t{}\t{:.2}\t{:.2}\t{:.2}\t{:.2}",
+            r.count, r.inliers, result.initial.rms, r.rms, r.median, r.max
+        );
+    }
+    for serial in captures.keys() {
+        if *serial != reference && !results.contains_key(serial) {
+            eprintln!("{serial}: too few correspondences with {reference}");
+        }
+    }
+
+    let Some(out) = out else {
+        return Ok(());
+    };
+    // Place the reference camera at the origin, streaming as the capture was recorded
+    let header = reader.header();
+    let mut profile = RigProfile::<()> {
+        stream: header.stream,
+        options: header.options,
+        ..Default::default()
+    };
+    profile.devices.insert(reference, DeviceProfile::default());
+    for (serial, result) in &results {
+        let device = DeviceProfile {
+            extrinsics: Extrinsics::from_matrix(Mat4::from(result.transform)),
+            visible: true,
+        };
+        profile.devices.insert(serial.clone(), device);
+    }
+    profile.save(out)
+}
+
+/// Send frames from connected devices until every capture thread stops
+fn serve_devices(server: &StreamServer, serials: Vec<String>, stream: StreamConfig) -> Result<()> {
+    let serials = match serials.is_empty() {
+        true => list_devices()?,
+        false => serials,
+    };
+    ensure!(!serials.is_empty(), "No devices connected");
+
+    let (tx, rx) = mpsc::channel();
+    let metrics = Metrics::new();
+    let options = SensorOptions::default();
+    let _captures: Vec<_> = serials
+        .iter()
+        .map(|serial| {
+            let tx = tx.clone();
+            let handle = start_realsense(serial.clone(), stream, metrics.clone(), move |event| {
+                tx.send(event).is_ok()
+            });
+            handle.set_options(&options);
+            handle
+        })
+        .collect();
+    drop(tx);
+
+    for event in rx {
+        match event {
+            CaptureEvent::Frame(frame) => server.send(&frame)?,
+            CaptureEvent::Status { serial, status } => eprintln!("{serial}: {status:?}"),
+        }
+    }
+    Ok(())
+}
+
+/// Send the frames of a recording forever, at the framerate it was recorded at
+fn serve_recording(server: &StreamServer, recording: &Path) -> Result<()> {
+    let reader = RecordingReader::open(recording)?;
+    ensure!(!reader.is_empty(), "Recording is empty");
+    let header = reader.header();
+    // Every device's frame for one tick is sent within the tick
+    let per_tick = (header.stream.fps * header.devices.len()).max(1);
+    let interval = Duration::from_secs(1) / per_tick as u32;
+
+    let mut next = Instant::now();
+    loop {
+        for frame in reader.frames() {
+            server.send(&frame?)?;
+            next += interval;
+            thread::sleep(next.saturating_duration_since(Instant::now()));
+        }
+    }
+}
+
+/// Write the first "frames" frames from a stream server to a recording
+fn receive(address: String, out: &Path, frames: usize) -> Result<()> {
+    ensure!(frames > 0, "No frames to receive");
+    let (tx, rx) = mpsc::channel();
+    let client = start_stream_client(address, move |event| tx.send(event).is_ok());
+
+    let mut received = Vec::with_capacity(frames);
+    while received.len() < frames {
+        match rx.recv().context("Stream client stopped")? {
+            CaptureEvent::Frame(frame) => received.push(frame),
+            CaptureEvent::Status { serial, status } => eprintln!("{serial}: {status:?}"),
+        }
+    }
+    client.stop();
+
+    // The devices and stream are only known once their frames have arrived
+    let mut devices: Vec<String> = received.iter().map(|f| f.serial().to_string()).collect();
+    devices.sort();
+    devices.dedup();
+    let stream = StreamConfig {
+        color_width: received[0].width(),
+        color_height: received[0].height(),
+        depth_width: received[0].width(),
+        depth_height: received[0].height(),
+        ..StreamConfig::default()
+    };
+    let header = RecordingHeader::new(devices, stream, SensorOptions::default());
+    let mut writer = RecordingWriter::create(out, &header)?;
+    for frame in &received {
+        writer.write(frame)?;
+    }
+    println!(
+        "Received {} frames to {}",
+        writer.frame_count(),
+        out.display()
+    );
+    Ok(())
+}
+
+/// Print the compression ratio, encode and decode time per frame, and bandwidth at the recorded
+/// framerate of each codec, averaged over the first "frames" frames of a recording
+fn bench(recording: &Path, frames: usize) -> Result<()> {
+    let reader = RecordingReader::open(recording)?;
+    let frames: Vec<_> = reader.frames().take(frames).collect::<Result<_>>()?;
+    ensure!(!frames.is_empty(), "Recording is empty");
+    let fps = reader.header().stream.fps as f64;
+
+    println!("Image\tCodec\tRatio\tEncode (ms)\tDecode (ms)\tMB/s\tPSNR (dB)");
+    for codec in DepthCodec::ALL {
+        let images: Vec<_> = frames
+            .iter()
+            .map(|f| (f.depth_image(), f.width()))
+            .collect();
+        let result = measure(
+            &images,
+            |(depth, width)| codec.encode(depth, *width),
+            |bytes, (depth, width)| {
+                let decoded = codec.decode(bytes, *width, depth.len() / width)?;
+                ensure!(decoded == *depth, "{codec} depth is not lossless");
+                Ok(None)
+            },
+            |(depth, _)| depth.len() * 2,
+        )?;
+        result.print("depth", &codec.to_string(), fps);
+    }
+    for codec in ColorCodec::ALL {
+        let images: Vec<_> = frames.iter().map(|f| (f.color(), f.width())).collect();
+        let result = measure(
+            &images,
+            |(color, width)| codec.encode(color, *width),
+            |bytes, (color, width)| {
+                let decoded = codec.decode(bytes, *width, color.len() / width)?;
+                Ok(Some(psnr(color, &decoded)))
+            },
+            |(color, _)| color.len() * 3,
+        )?;
+        result.print("color", &codec.to_string(), fps);
+    }
+    Ok(())
+}
+
+/// Averages over the frames of a benchmark
+struct BenchResult {
+    raw_bytes: usize,
+    encoded_bytes: usize,
+    encode: Duration,
+    decode: Duration,
+    /// Mean PSNR, for lossy codecs
+    psnr: Option<f64>,
+    count: usize,
+}
+
+fn measure<T>(
+    images: &[T],
+    encode: impl Fn(&T) -> Result<Vec<u8>>,
+    decode: impl Fn(&[u8], &T) -> Result<Option<f64>>,
+    raw_size: impl Fn(&T) -> usize,
+) -> Result<BenchResult> {
+    let mut result = BenchResult {
+        raw_bytes: 0,
+        encoded_bytes: 0,
+        encode: Duration::ZERO,
+        decode: Duration::ZERO,
+        psnr: None,
+        count: images.len(),
+    };
+    let mut psnr_sum = 0.;
+    for image in images {
+        let start = Instant::now();
+        let bytes = encode(image)?;
+        result.encode += start.elapsed();
+
+        let start = Instant::now();
+        let psnr = decode(&bytes, image)?;
+        result.decode += start.elapsed();
+
+        result.raw_bytes += raw_size(image);
+        result.encoded_bytes += bytes.len();
+        psnr_sum += psnr.unwrap_or(f64::INFINITY);
+    }
+    result.psnr = Some(psnr_sum / images.len() as f64).filter(|p| p.is_finite());
+    Ok(result)
+}
+
+impl BenchResult {
+    fn print(&self, image: &str, codec: &str, fps: f64) {
+        let per_frame = |d: Duration| d.as_secs_f64() * 1e3 / self.count as f64;
+        let bytes_per_frame = self.encoded_bytes as f64 / self.count as f64;
+        let psnr = self
+            .psnr
+            .map_or("lossless".to_string(), |p| format!("{p:.1}"));
+        println!(
+            "{image}\t{codec}\t{:.2}\t{:.2}\t{:.2}\t{:.1}\t{psnr}",
+            self.raw_bytes as f64 / self.encoded_bytes.max(1) as f64,
+            per_frame(self.encode),
+            per_frame(self.decode),
+            bytes_per_frame * fps / 1e6,
+        );
+    }
+}
+
+/// Peak signal-to-noise ratio of a decoded color image, infinite if it is identical
+fn psnr(original: &[[u8; 3]], decoded: &[[u8; 3]]) -> f64 {
+    let squared_error: f64 = original
+        .iter()
+        .flatten()
+        .zip(decoded.iter().flatten())
+        .map(|(a, b)| (*a as f64 - *b as f64).powi(2))
+        .sum();
+    let mse = squared_error / (original.len() * 3).max(1) as f64;
+    10. * (255_f64.powi(2) / mse).log10()
+}
@@ -0,0 +1,311 @@
+//! Fusing several frames of a static scene into one with less depth noise, for structured light
+//! captures and high quality snapshots
+
+use glam::Vec3;
+use serde::{Deserialize, Serialize};
+
+use crate::{FrameMetadata, ImagePointCloud};
+
+/// Ratio of standard deviation to median absolute deviation for normally distributed noise
+const MAD_TO_SIGMA: f32 = 1.4826;
+
+/// Which statistic of each pixel's depth samples gives its fused depth
+#[derive(Copy, Clone, Debug, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub enum Reduction {
+    /// Mean, for the least noise once outliers are gone
+    Mean,
+    /// Median, for the most robustness to outliers left behind
+    #[default]
+    Median,
+}
+
+/// Parameters for `FrameAccumulator`
+#[derive(Copy, Clone, Debug, PartialEq, Serialize, Deserialize)]
+#[serde(default)]
+pub struct AccumulateParams {
+    /// Number of frames to fuse
+    pub frames: usize,
+    pub reduction: Reduction,
+    /// Samples further from their pixel's median than this many standard deviations are rejected.
+    /// The standard deviation is estimated from the median absolute deviation, and taken to be at
+    /// least one depth unit.
+    pub outlier_factor: f32,
+    /// Fraction of frames a pixel must keep a sample from to be valid in the fused frame
+    pub min_inlier_fraction: f32,
+}
+
+/// Per-pixel statistics of the depth samples kept after outlier rejection, in depth units. Pixels
+/// without any samples are zero.
+#[derive(Clone, Debug, Default)]
+pub struct DepthStats {
+    pub mean: Vec<f32>,
+    pub median: Vec<f32>,
+    /// In depth units squared
+    pub variance: Vec<f32>,
+    /// Number of samples kept
+    pub inliers: Vec<u32>,
+}
+
+/// Collects frames of a static scene from one device, to fuse them into one
+pub struct FrameAccumulator {
+    params: AccumulateParams,
+    width: usize,
+    /// Depth of each pixel in each frame, zero where invalid
+    depths: Vec<Vec<f32>>,
+    /// Direction of each pixel's ray, scaled to unit depth, from the first frame it was valid in
+    rays: Vec<Option<Vec3>>,
+    /// Sum of the aligned color of each pixel over every frame
+    color_sum: Vec<[u32; 3]>,
+    /// Metadata and unaligned color image of the most recent frame
+    metadata: FrameMetadata,
+    raw_color: Vec<[u8; 3]>,
+}
+
+impl FrameAccumulator {
+    pub fn new(params: AccumulateParams) -> Self {
+        Self {
+            params,
+            width: 0,
+            depths: vec![],
+            rays: vec![],
+            color_sum: vec![],
+            metadata: FrameMetadata::default(),
+            raw_color: vec![],
+        }
+    }
+
+    /// Add a frame, unless `is_full`. A frame of a different size to the earlier ones starts the
+    /// accumulation over.
+    pub fn add(&mut self, frame: &ImagePointCloud) {
+        let n_pixels = frame.position().len();
+        if frame.width() != self.width || n_pixels != self.rays.len() {
+            *self = Self::new(self.params);
+            self.width = frame.width();
+            self.rays = vec![None; n_pixels];
+            self.color_sum = vec![[0; 3]; n_pixels];
+        }
+        if self.is_full() {
+            return;
+        }
+
+        let mut depth = Vec::with_capacity(n_pixels);
+        for (ray, sample) in self.rays.iter_mut().zip(frame.iter_pixels()) {
+            match sample {
+                Some((pos, _)) if pos.z > 0. => {
+                    ray.get_or_insert(pos / pos.z);
+                    depth.push(pos.z);
+                }
+                _ => depth.push(0.),
+            }
+        }
+        self.depths.push(depth);
+
+        for (sum, color) in self.color_sum.iter_mut().zip(frame.color()) {
+            for (s, c) in sum.iter_mut().zip(color) {
+                *s += *c as u32;
+            }
+        }
+        self.metadata = frame.metadata().clone();
+        self.raw_color = frame.raw_color().to_vec();
+    }
+
+    /// Number of frames added
+    pub fn len(&self) -> usize {
+        self.depths.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.depths.is_empty()
+    }
+
+    /// Whether `AccumulateParams::frames` frames have been added
+    pub fn is_full(&self) -> bool {
+        self.len() >= self.params.frames.max(1)
+    }
+
+    /// Statistics of each pixel's depth over the frames added, after rejecting outliers
+    pub fn stats(&self) -> DepthStats {
+        let n_pixels = self.rays.len();
+        let mut stats = DepthStats {
+            mean: vec![0.; n_pixels],
+            median: vec![0.; n_pixels],
+            variance: vec![0.; n_pixels],
+            inliers: vec![0; n_pixels],
+        };
+
+        let mut samples = Vec::with_capacity(self.len());
+        let mut deviations = Vec::with_capacity(self.len());
+        for pixel in 0..n_pixels {
+            samples.clear();
+            samples.extend(self.depths.iter().map(|d| d[pixel]).filter(|d| *d > 0.));
+            if samples.is_empty() {
+                continue;
+            }
+            samples.sort_unstable_by(f32::total_cmp);
+            let median = sorted_median(&samples);
+
+            deviations.clear();
+            deviations.extend(samples.iter().map(|s| (s - median).abs()));
+            deviations.sort_unstable_by(f32::total_cmp);
+            let sigma = (sorted_median(&deviations) * MAD_TO_SIGMA).max(1.);
+            let tolerance = self.params.outlier_factor * sigma;
+            samples.retain(|s| (s - median).abs() <= tolerance);
+            if samples.is_empty() {
+                continue;
+            }
+
+            let count = samples.len() as f32;
+            let mean = samples.iter().sum::<f32>() / count;
+            stats.mean[pixel] = mean;
+            stats.median[pixel] = sorted_median(&samples);
+            stats.variance[pixel] = samples.iter().map(|s| (s - mean).powi(2)).sum::<f32>() / count;
+            stats.inliers[pixel] = samples.len() as u32;
+        }
+        stats
+    }
+
+    /// Fuse the frames added into one, placing each pixel at its mean or median depth along its
+    /// ray and averaging its color. Pixels with too few samples left after outlier rejection are
+    /// invalid. None if no frames have been added.
+    pub fn fuse(&self) -> Option<ImagePointCloud> {
+        if self.is_empty() {
+            return None;
+        }
+
+        let stats = self.stats();
+        let fused = match self.params.reduction {
+            Reduction::Mean => &stats.mean,
+            Reduction::Median => &stats.median,
+        };
+        let n_frames = self.len() as u32;
+        let min_inliers = (self.params.min_inlier_fraction * n_frames as f32).ceil() as u32;
+
+        let n_pixels = self.rays.len();
+        let mut valid = Vec::with_capacity(n_pixels);
+        let mut position = Vec::with_capacity(n_pixels);
+        let mut depth = Vec::with_capacity(n_pixels);
+        for ((ray, d), inliers) in self.rays.iter().zip(fused).zip(&stats.inliers) {
+            match ray {
+                Some(ray) if *inliers >= min_inliers.max(1) => {
+                    valid.push(true);
+                    position.push(*ray * *d);
+                    depth.push(d.round() as u16);
+                }
+                _ => {
+                    valid.push(false);
+                    position.push(Vec3::ZERO);
+                    depth.push(0);
+                }
+            }
+        }
+        let color = self
+            .color_sum
+            .iter()
+            .map(|sum| sum.map(|s| (s / n_frames) as u8))
+            .collect();
+
+        let cloud = ImagePointCloud::new(valid, position, color, self.width)
+            .with_raw(depth, self.raw_color.clone())
+            .with_metadata(self.metadata.clone());
+        Some(cloud)
+    }
+}
+
+/// Median of a sorted, non-empty slice
+fn sorted_median(sorted: &[f32]) -> f32 {
+    let mid = sorted.len() / 2;
+    match sorted.len() % 2 {
+        0 => (sorted[mid - 1] + sorted[mid]) / 2.,
+        _ => sorted[mid],
+    }
+}
+
+impl Default for AccumulateParams {
+    fn default() -> Self {
+        Self {
+            frames: 10,
+            reduction: Reduction::Median,
+            outlier_factor: 3.,
+            min_inlier_fraction: 0.5,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// One row of pixels at the given depths along the same ray, where zero is invalid
+    fn frame(depths: &[f32]) -> ImagePointCloud {
+        let valid = depths.iter().map(|d| *d > 0.).collect();
+        let position = depths
+            .iter()
+            .map(|d| Vec3::new(0.1, 0.2, 1.) * *d)
+            .collect();
+        ImagePointCloud::new(valid, position, vec![[0; 3]; depths.len()], depths.len())
+    }
+
+    fn accumulate(frames: &[&[f32]], params: AccumulateParams) -> FrameAccumulator {
+        let mut accumulator = FrameAccumulator::new(AccumulateParams {
+            frames: frames.len(),
+            ..params
+        });
+        for depths in frames {
+            accumulator.add(&frame(depths));
+        }
+        accumulator
+    }
+
+    #[test]
+    fn constant_pixel() {
+        for reduction in [Reduction::Mean, Reduction::Median] {
+            let params = AccumulateParams {
+                reduction,
+                ..Default::default()
+            };
+            let fused = accumulate(&[&[500.][..]; 5], params).fuse().unwrap();
+            assert_eq!(fused.valid(), [true]);
+            assert_eq!(fused.depth(), [500]);
+            let expected = Vec3::new(50., 100., 500.);
+            assert!(fused.position()[0].abs_diff_eq(expected, 1e-3));
+        }
+    }
+
+    #[test]
+    fn outlier_rejected() {
+        let params = AccumulateParams {
+            reduction: Reduction::Mean,
+            ..Default::default()
+        };
+        let frames: [&[f32]; 5] = [&[500.], &[501.], &[499.], &[500.], &[900.]];
+        let accumulator = accumulate(&frames, params);
+        assert_eq!(accumulator.stats().inliers, [4]);
+        let fused = accumulator.fuse().unwrap();
+        assert_eq!(fused.valid(), [true]);
+        assert_eq!(fused.depth(), [500]);
+    }
+
+    #[test]
+    fn too_few_inliers_invalid() {
+        // The first pixel is only seen in two of the five frames
+        let frames: [&[f32]; 5] = [
+            &[500., 700.],
+            &[500., 700.],
+            &[0., 700.],
+            &[0., 700.],
+            &[0., 700.],
+        ];
+        let fused = accumulate(&frames, AccumulateParams::default())
+            .fuse()
+            .unwrap();
+        assert_eq!(fused.valid(), [false, true]);
+        assert_eq!(fused.depth(), [0, 700]);
+
+        let params = AccumulateParams {
+            min_inlier_fraction: 0.3,
+            ..Default::default()
+        };
+        let fused = accumulate(&frames, params).fuse().unwrap();
+        assert_eq!(fused.valid(), [true, true]);
+    }
+}
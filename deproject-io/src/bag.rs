@@ -0,0 +1,620 @@
+//! Playback of RealSense Viewer recordings. These are ROS1 bag files (format 2.0), holding one
+//! device's streams under topics like `/device_0/sensor_1/Color_0/image/data`:
+//!
+//! | Topic                                         | Message                    |
+//! |-----------------------------------------------|----------------------------|
+//! | `/device_0/info`                              | `diagnostic_msgs/KeyValue` |
+//! | `/device_0/sensor_N/option/Depth Units/value` | `std_msgs/Float32`         |
+//! | `.../<Stream>_0/info/camera_info`             | `sensor_msgs/CameraInfo`   |
+//! | `.../<Stream>_0/tf/0`                         | `geometry_msgs/Transform`  |
+//! | `.../<Stream>_0/image/data`                   | `sensor_msgs/Image`        |
+//! | `.../<Stream>_0/image/metadata`               | `diagnostic_msgs/KeyValue` |
+//!
+//! Each depth image is paired with the latest color image before it. Other topics and streams
+//! are ignored.
+
+use std::collections::HashMap;
+use std::fs::File;
+use std::io::{BufReader, Cursor, ErrorKind, Read};
+use std::path::Path;
+use std::time::Duration;
+
+use anyhow::{bail, ensure, Context, Result};
+use glam::{DQuat, DVec3, Mat3, Quat, Vec3};
+
+use crate::capture::CaptureHandle;
+use crate::error::CaptureEvent;
+use crate::playback::{start_playback, RecordedFrames};
+use crate::{
+    FrameMetadata, ImagePointCloud, Rs2ExtrinsicsSerde, Rs2IntrinsicsSerde, StreamMetadata,
+    TimestampDomain,
+};
+
+const BAG_MAGIC: &[u8] = b"#ROSBAG V2.0\n";
+
+const OP_MESSAGE: u8 = 0x02;
+const OP_CHUNK: u8 = 0x05;
+const OP_CONNECTION: u8 = 0x07;
+
+/// Most that LZ4 can shrink data by
+const LZ4_MAX_RATIO: usize = 255;
+
+/// A message read from a bag
+pub struct BagMessage {
+    pub topic: String,
+    /// Time the message was recorded
+    pub time: Duration,
+    /// The message, serialized as ROS does
+    pub data: Vec<u8>,
+}
+
+/// Reads the messages of a bag file in the order they were written, one chunk at a time
+pub struct BagReader {
+    file: BufReader<File>,
+    /// Topic of each connection, by ID
+    topics: HashMap<u32, String>,
+    /// Records of the current chunk not yet read
+    chunk: Cursor<Vec<u8>>,
+}
+
+/// A record of a bag file: "name=value" header fields, then data
+struct Record {
+    fields: HashMap<String, Vec<u8>>,
+    data: Vec<u8>,
+}
+
+impl BagReader {
+    pub fn open(path: impl AsRef<Path>) -> Result<Self> {
+        let path = path.as_ref();
+        let file = File::open(path).with_context(|| format!("Opening {}", path.display()))?;
+        let mut file = BufReader::new(file);
+        let mut magic = [0; BAG_MAGIC.len()];
+        file.read_exact(&mut magic)?;
+        ensure!(magic == BAG_MAGIC, "{} is not a ROS bag", path.display());
+
+        Ok(Self {
+            file,
+            topics: HashMap::new(),
+            chunk: Cursor::default(),
+        })
+    }
+
+    /// The next message, or None at the end of the file
+    pub fn next_message(&mut self) -> Result<Option<BagMessage>> {
+        loop {
+            let record = match Record::read(&mut self.chunk)? {
+                Some(record) => record,
+                None => match Record::read(&mut self.file)? {
+                    Some(record) => record,
+                    None => return Ok(None),
+                },
+            };
+
+            match record.op()? {
+                OP_CHUNK => self.chunk = Cursor::new(record.decompress()?),
+                OP_CONNECTION => {
+                    let topic = String::from_utf8_lossy(record.field("topic")?).into_owned();
+                    self.topics.insert(record.u32_field("conn")?, topic);
+                }
+                OP_MESSAGE => {
+                    let conn = record.u32_field("conn")?;
+                    let topic = self
+                        .topics
+                        .get(&conn)
+                        .with_context(|| format!("Message on unknown connection {conn}"))?;
+                    let time = record.field("time")?;
+                    ensure!(time.len() == 8, "Bad message time");
+                    let secs = u32::from_le_bytes(time[..4].try_into().unwrap());
+                    let nanos = u32::from_le_bytes(time[4..].try_into().unwrap());
+                    return Ok(Some(BagMessage {
+                        topic: topic.clone(),
+                        time: Duration::new(secs as u64, nanos),
+                        data: record.data,
+                    }));
+                }
+                // Bag headers, indexes and chunk info are only needed for random access
+                _ => (),
+            }
+        }
+    }
+}
+
+impl Record {
+    /// Read the next record, or None at the end of "r"
+    fn read(r: &mut impl Read) -> Result<Option<Self>> {
+        let mut len = [0; 4];
+        match r.read_exact(&mut len) {
+            Ok(()) => (),
+            Err(e) if e.kind() == ErrorKind::UnexpectedEof => return Ok(None),
+            Err(e) => return Err(e.into()),
+        }
+        let header = read_bytes(r, u32::from_le_bytes(len))?;
+        r.read_exact(&mut len)?;
+        let data = read_bytes(r, u32::from_le_bytes(len))?;
+
+        let mut fields = HashMap::new();
+        let mut header = header.as_slice();
+        while !header.is_empty() {
+            ensure!(header.len() >= 4, "Truncated record header");
+            let (len, rest) = header.split_at(4);
+            let len = u32::from_le_bytes(len.try_into().unwrap()) as usize;
+            ensure!(rest.len() >= len, "Truncated record header");
+            let (field, rest) = rest.split_at(len);
+            let eq = field
+                .iter()
+                .position(|b| *b == b'=')
+                .context("Record header field has no name")?;
+            let name = String::from_utf8_lossy(&field[..eq]).into_owned();
+            fields.insert(name, field[eq + 1..].to_vec());
+            header = rest;
+        }
+        Ok(Some(Self { fields, data }))
+    }
+
+    fn field(&self, name: &str) -> Result<&[u8]> {
+        self.fields
+            .get(name)
+            .map(Vec::as_slice)
+            .with_context(|| format!("Record has no {name} field"))
+    }
+
+    fn u32_field(&self, name: &str) -> Result<u32> {
+        let bytes = self.field(name)?;
+        ensure!(bytes.len() == 4, "Bad {name} field");
+        Ok(u32::from_le_bytes(bytes.try_into().unwrap()))
+    }
+
+    fn op(&self) -> Result<u8> {
+        match self.field("op")? {
+            [op] => Ok(*op),
+            _ => bail!("Bad op field"),
+        }
+    }
+
+    /// The records inside a chunk
+    fn decompress(&self) -> Result<Vec<u8>> {
+        let size = self.u32_field("size")? as usize;
+        let records = match self.field("compression")? {
+            b"none" => self.data.clone(),
+            b"lz4" => {
+                // Stop just past the expected size, so a corrupt chunk can't grow without bound
+                let capacity = size.min(self.data.len().saturating_mul(LZ4_MAX_RATIO));
+                let mut records = Vec::with_capacity(capacity);
+                lz4_flex::frame::FrameDecoder::new(self.data.as_slice())
+                    .take(size as u64 + 1)
+                    .read_to_end(&mut records)
+                    .context("Decompressing chunk")?;
+                records
+            }
+            other => bail!(
+                "Unsupported chunk compression {}",
+                String::from_utf8_lossy(other)
+            ),
+        };
+        ensure!(records.len() == size, "Chunk has the wrong size");
+        Ok(records)
+    }
+}
+
+/// Read "len" bytes, allocating only as much as "r" actually holds so that a corrupt length can't
+/// exhaust memory
+fn read_bytes(r: &mut impl Read, len: u32) -> Result<Vec<u8>> {
+    let mut bytes = vec![];
+    r.take(len as u64).read_to_end(&mut bytes)?;
+    ensure!(bytes.len() == len as usize, "Record is truncated");
+    Ok(bytes)
+}
+
+/// Reads the fields of a ROS message in order
+struct MessageReader<'a> {
+    data: &'a [u8],
+}
+
+impl<'a> MessageReader<'a> {
+    fn new(data: &'a [u8]) -> Self {
+        Self { data }
+    }
+
+    fn take(&mut self, len: usize) -> Result<&'a [u8]> {
+        ensure!(self.data.len() >= len, "Message is truncated");
+        let (head, rest) = self.data.split_at(len);
+        self.data = rest;
+        Ok(head)
+    }
+
+    fn u8(&mut self) -> Result<u8> {
+        Ok(self.take(1)?[0])
+    }
+
+    fn u32(&mut self) -> Result<u32> {
+        Ok(u32::from_le_bytes(self.take(4)?.try_into().unwrap()))
+    }
+
+    fn f32(&mut self) -> Result<f32> {
+        Ok(f32::from_le_bytes(self.take(4)?.try_into().unwrap()))
+    }
+
+    fn f64(&mut self) -> Result<f64> {
+        Ok(f64::from_le_bytes(self.take(8)?.try_into().unwrap()))
+    }
+
+    /// A length-prefixed array of bytes
+    fn bytes(&mut self) -> Result<&'a [u8]> {
+        let len = self.u32()? as usize;
+        self.take(len)
+    }
+
+    fn string(&mut self) -> Result<String> {
+        Ok(String::from_utf8_lossy(self.bytes()?).into_owned())
+    }
+
+    fn f64s(&mut self, len: usize) -> Result<Vec<f64>> {
+        (0..len).map(|_| self.f64()).collect()
+    }
+
+    /// `std_msgs/Header`, returning the sequence number and stamp in milliseconds
+    fn header(&mut self) -> Result<(u32, f64)> {
+        let seq = self.u32()?;
+        let secs = self.u32()?;
+        let nanos = self.u32()?;
+        let _frame_id = self.bytes()?;
+        Ok((seq, secs as f64 * 1e3 + nanos as f64 * 1e-6))
+    }
+}
+
+/// `sensor_msgs/Image`
+struct Image {
+    frame_number: u32,
+    /// Capture time in milliseconds
+    timestamp: f64,
+    width: usize,
+    height: usize,
+    encoding: String,
+    big_endian: bool,
+    /// Bytes per row
+    step: usize,
+    data: Vec<u8>,
+}
+
+impl Image {
+    fn parse(data: &[u8]) -> Result<Self> {
+        let mut r = MessageReader::new(data);
+        let (frame_number, timestamp) = r.header()?;
+        let height = r.u32()? as usize;
+        let width = r.u32()? as usize;
+        let encoding = r.string()?;
+        let big_endian = r.u8()? != 0;
+        let step = r.u32()? as usize;
+        let data = r.bytes()?.to_vec();
+        let row_len = width.checked_mul(bytes_per_pixel(&encoding)?);
+        ensure!(
+            step > 0 && row_len.is_some_and(|row_len| step >= row_len),
+            "Image rows are shorter than its width"
+        );
+        ensure!(
+            step.checked_mul(height)
+                .is_some_and(|len| data.len() >= len),
+            "Image data is truncated"
+        );
+        Ok(Self {
+            frame_number,
+            timestamp,
+            width,
+            height,
+            encoding,
+            big_endian,
+            step,
+            data,
+        })
+    }
+
+    fn rows(&self) -> impl Iterator<Item = &[u8]> {
+        self.data.chunks(self.step).take(self.height)
+    }
+
+    fn depth(&self) -> Result<Vec<u16>> {
+        ensure!(
+            matches!(self.encoding.as_str(), "mono16" | "16UC1"),
+            "Unsupported depth encoding {}",
+            self.encoding
+        );
+        let to_u16 = match self.big_endian {
+            true => u16::from_be_bytes,
+            false => u16::from_le_bytes,
+        };
+        Ok(self
+            .rows()
+            .flat_map(|row| row[..self.width * 2].chunks_exact(2))
+            .map(|b| to_u16([b[0], b[1]]))
+            .collect())
+    }
+
+    fn color(&self) -> Result<Vec<[u8; 3]>> {
+        let (channels, bgr) = match self.encoding.as_str() {
+            "rgb8" => (3, false),
+            "bgr8" => (3, true),
+            "rgba8" => (4, false),
+            "bgra8" => (4, true),
+            other => bail!("Unsupported color encoding {other}"),
+        };
+        Ok(self
+            .rows()
+            .flat_map(|row| row[..self.width * channels].chunks_exact(channels))
+            .map(|p| match bgr {
+                true => [p[2], p[1], p[0]],
+                false => [p[0], p[1], p[2]],
+            })
+            .collect())
+    }
+}
+
+/// Size of a pixel in the image encodings librealsense records
+fn bytes_per_pixel(encoding: &str) -> Result<usize> {
+    Ok(match encoding {
+        "mono16" | "16UC1" => 2,
+        "rgb8" | "bgr8" => 3,
+        "rgba8" | "bgra8" => 4,
+        other => bail!("Unsupported image encoding {other}"),
+    })
+}
+
+/// Intrinsics from a `sensor_msgs/CameraInfo`
+fn parse_camera_info(data: &[u8]) -> Result<Rs2IntrinsicsSerde> {
+    let mut r = MessageReader::new(data);
+    r.header()?;
+    let height = r.u32()?;
+    let width = r.u32()?;
+    let model = match r.string()?.as_str() {
+        "None" => realsense_sys::rs2_distortion_RS2_DISTORTION_NONE,
+        "Modified Brown Conrady" => {
+            realsense_sys::rs2_distortion_RS2_DISTORTION_MODIFIED_BROWN_CONRADY
+        }
+        "Inverse Brown Conrady" => {
+            realsense_sys::rs2_distortion_RS2_DISTORTION_INVERSE_BROWN_CONRADY
+        }
+        "Ftheta" => realsense_sys::rs2_distortion_RS2_DISTORTION_FTHETA,
+        "Brown Conrady" => realsense_sys::rs2_distortion_RS2_DISTORTION_BROWN_CONRADY,
+        "Kannala Brandt4" => realsense_sys::rs2_distortion_RS2_DISTORTION_KANNALA_BRANDT4,
+        other => bail!("Unknown distortion model {other}"),
+    };
+    let n_coeffs = r.u32()? as usize;
+    let d = r.f64s(n_coeffs)?;
+    let k = r.f64s(9)?;
+
+    let mut coeffs = [0.; 5];
+    for (c, d) in coeffs.iter_mut().zip(d) {
+        *c = d as f32;
+    }
+    Ok(Rs2IntrinsicsSerde {
+        width: width as i32,
+        height: height as i32,
+        ppx: k[2] as f32,
+        ppy: k[5] as f32,
+        fx: k[0] as f32,
+        fy: k[4] as f32,
+        model,
+        coeffs,
+    })
+}
+
+/// Rotation and translation (in meters) of a `geometry_msgs/Transform`
+fn parse_transform(data: &[u8]) -> Result<(Quat, Vec3)> {
+    let mut r = MessageReader::new(data);
+    let t = r.f64s(3)?;
+    let q = r.f64s(4)?;
+    let translation = DVec3::new(t[0], t[1], t[2]).as_vec3();
+    let rotation = DQuat::from_xyzw(q[0], q[1], q[2], q[3]).normalize();
+    Ok((rotation.as_f32(), translation))
+}
+
+/// Key and value of a `diagnostic_msgs/KeyValue`
+fn parse_key_value(data: &[u8]) -> Result<(String, String)> {
+    let mut r = MessageReader::new(data);
+    Ok((r.string()?, r.string()?))
+}
+
+/// What is known so far about one stream of the recording
+#[derive(Default)]
+struct StreamState {
+    intrinsics: Option<Rs2IntrinsicsSerde>,
+    /// Transform from this stream's frame to the device's reference frame
+    to_reference: Option<(Quat, Vec3)>,
+    metadata: StreamMetadata,
+    /// Latest image, with its metadata still to come
+    image: Option<Image>,
+}
+
+/// Frames of a RealSense Viewer recording, aligned and deprojected as if streamed from the device
+pub struct BagFrames {
+    bag: BagReader,
+    serial: String,
+    depth_scale: f32,
+    depth: StreamState,
+    color: StreamState,
+    /// Recording time of the depth image in `depth.image`
+    depth_time: Duration,
+}
+
+impl BagFrames {
+    /// Open a recording, reading ahead to the first image to find which device recorded it
+    pub fn open(path: impl AsRef<Path>) -> Result<Self> {
+        let path = path.as_ref();
+        let mut frames = Self {
+            bag: BagReader::open(path)?,
+            serial: String::new(),
+            depth_scale: 0.001,
+            depth: StreamState::default(),
+            color: StreamState::default(),
+            depth_time: Duration::ZERO,
+        };
+        while frames.serial.is_empty() && frames.depth.image.is_none() {
+            let Some(message) = frames.bag.next_message()? else {
+                break;
+            };
+            frames.handle(message)?;
+        }
+        if frames.serial.is_empty() {
+            let stem = path.file_stem().unwrap_or_default().to_string_lossy();
+            frames.serial = stem.into_owned();
+        }
+        Ok(frames)
+    }
+
+    /// Serial number of the recorded device, or the file name if the recording doesn't say
+    pub fn serial(&self) -> &str {
+        &self.serial
+    }
+
+    /// The next frame and the time it was recorded, or None at the end of the recording
+    pub fn next_frame(&mut self) -> Result<Option<(ImagePointCloud, Duration)>> {
+        loop {
+            let Some(message) = self.bag.next_message()? else {
+                return Ok(self.finish_depth()?.map(|frame| (frame, self.depth_time)));
+            };
+            let time = self.depth_time;
+            if let Some(frame) = self.handle(message)? {
+                return Ok(Some((frame, time)));
+            }
+        }
+    }
+
+    /// Apply a message, returning a frame once the depth image before it is complete
+    fn handle(&mut self, message: BagMessage) -> Result<Option<ImagePointCloud>> {
+        let parts: Vec<&str> = message.topic.split('/').collect();
+        let data = message.data.as_slice();
+        match parts.as_slice() {
+            ["", "device_0", "info"] => {
+                let (key, value) = parse_key_value(data)?;
+                if key == "Serial Number" {
+                    self.serial = value;
+                }
+            }
+            ["", "device_0", _, "option", "Depth Units", "value"] => {
+                self.depth_scale = MessageReader::new(data).f32()?;
+            }
+            ["", "device_0", _, stream, rest @ ..] => {
+                let is_depth = stream.starts_with("Depth_");
+                if !is_depth && !stream.starts_with("Color_") {
+                    return Ok(None);
+                }
+                // A depth image's metadata follows it, so it's complete once the next one arrives
+                let mut frame = None;
+                if is_depth && rest == ["image", "data"] {
+                    frame = self.finish_depth()?;
+                    self.depth_time = message.time;
+                }
+
+                let state = match is_depth {
+                    true => &mut self.depth,
+                    false => &mut self.color,
+                };
+                match rest {
+                    ["info", "camera_info"] => state.intrinsics = Some(parse_camera_info(data)?),
+                    ["tf", "0"] => state.to_reference = Some(parse_transform(data)?),
+                    ["image", "metadata"] => {
+                        apply_metadata(&mut state.metadata, parse_key_value(data)?)
+                    }
+                    ["image", "data"] => {
+                        let image = Image::parse(data)?;
+                        state.metadata.frame_number = image.frame_number as u64;
+                        state.metadata.timestamp = image.timestamp;
+                        state.image = Some(image);
+                    }
+                    _ => (),
+                }
+                return Ok(frame);
+            }
+            _ => (),
+        }
+        Ok(None)
+    }
+
+    /// Build a frame from the pending depth image and the latest color image, if there are both
+    fn finish_depth(&mut self) -> Result<Option<ImagePointCloud>> {
+        let Some(depth_image) = self.depth.image.take() else {
+            return Ok(None);
+        };
+        let Some(color_image) = &self.color.image else {
+            return Ok(None);
+        };
+
+        let depth_intrinsics = self.depth.intrinsics.context("No depth camera_info")?;
+        let color_intrinsics = self.color.intrinsics.context("No color camera_info")?;
+        ensure!(
+            depth_image.width == depth_intrinsics.width as usize
+                && depth_image.height == depth_intrinsics.height as usize,
+            "Depth image doesn't match its camera_info"
+        );
+        ensure!(
+            color_image.width == color_intrinsics.width as usize
+                && color_image.height == color_intrinsics.height as usize,
+            "Color image doesn't match its camera_info"
+        );
+
+        let identity = (Quat::IDENTITY, Vec3::ZERO);
+        let (depth_rotation, depth_translation) = self.depth.to_reference.unwrap_or(identity);
+        let (color_rotation, color_translation) = self.color.to_reference.unwrap_or(identity);
+        let rotation = color_rotation.inverse() * depth_rotation;
+        let translation = color_rotation.inverse() * (depth_translation - color_translation);
+
+        let metadata = FrameMetadata {
+            serial: self.serial.clone(),
+            depth: StreamMetadata {
+                intrinsics: depth_intrinsics,
+                ..self.depth.metadata
+            },
+            color: StreamMetadata {
+                intrinsics: color_intrinsics,
+                ..self.color.metadata
+            },
+            depth_to_color: Rs2ExtrinsicsSerde {
+                rotation: Mat3::from_quat(rotation).to_cols_array(),
+                translation: translation.to_array(),
+            },
+            depth_scale: self.depth_scale,
+        };
+        let frame =
+            ImagePointCloud::from_images(depth_image.depth()?, color_image.color()?, metadata)?;
+        Ok(Some(frame))
+    }
+}
+
+/// Apply one of the frame metadata values librealsense records after each image
+fn apply_metadata(metadata: &mut StreamMetadata, (key, value): (String, String)) {
+    match key.as_str() {
+        "Time Of Arrival" => metadata.time_of_arrival = value.parse().ok(),
+        "Actual Exposure" => metadata.exposure = value.parse().ok(),
+        "timestamp_domain" => {
+            metadata.timestamp_domain = match value.as_str() {
+                "System Time" => TimestampDomain::SystemTime,
+                "Global Time" => TimestampDomain::GlobalTime,
+                _ => TimestampDomain::HardwareClock,
+            }
+        }
+        _ => (),
+    }
+}
+
+impl RecordedFrames for BagFrames {
+    fn next_frame(&mut self) -> Result<Option<(ImagePointCloud, Duration)>> {
+        BagFrames::next_frame(self)
+    }
+}
+
+/// Starts playing a RealSense Viewer recording on a new thread, in real time and in a loop. Like
+/// `start_realsense`, the thread calls "callback" with each frame and any change in status, under
+/// the serial number of the recorded device.
+pub fn start_bag_playback(
+    path: impl AsRef<Path>,
+    callback: impl FnMut(CaptureEvent) -> bool + Send + 'static,
+) -> Result<CaptureHandle> {
+    let path = path.as_ref().to_path_buf();
+    let frames = BagFrames::open(&path)?;
+    let serial = frames.serial().to_string();
+    Ok(start_playback(
+        serial,
+        frames,
+        move || BagFrames::open(&path),
+        callback,
+    ))
+}
@@ -0,0 +1,154 @@
+use std::sync::mpsc::{Receiver, RecvTimeoutError, Sender};
+use std::thread::JoinHandle;
+use std::time::{Duration, Instant};
+
+pub use realsense_rust::kind::Rs2Option;
+use serde::{Deserialize, Serialize};
+
+use crate::options::{SensorKind, SensorOptions};
+
+/// Resolution and framerate requested from a device. A height of zero lets the device choose the
+/// height matching the given width.
+#[derive(Copy, Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub struct StreamConfig {
+    pub color_width: usize,
+    pub color_height: usize,
+    pub depth_width: usize,
+    pub depth_height: usize,
+    pub fps: usize,
+}
+
+/// Instructions sent to a running capture thread
+#[derive(Clone, Debug)]
+pub enum CaptureCommand {
+    /// Close the device and end the thread
+    Stop,
+    /// Close the device (true) or reopen it (false), keeping the thread alive
+    Pause(bool),
+    /// Restart streaming with a different resolution and framerate
+    Reconfigure(StreamConfig),
+    /// Set an option on one of the device's sensors. Options are reapplied after reconnecting.
+    SetOption(SensorKind, Rs2Option, f32),
+}
+
+/// Controls a capture thread. The thread is stopped when this is dropped.
+pub struct CaptureHandle {
+    serial: String,
+    tx: Sender<CaptureCommand>,
+    thread: Option<JoinHandle<()>>,
+}
+
+impl CaptureHandle {
+    pub(crate) fn new(serial: String, tx: Sender<CaptureCommand>, thread: JoinHandle<()>) -> Self {
+        Self {
+            serial,
+            tx,
+            thread: Some(thread),
+        }
+    }
+
+    /// Serial number of the device being captured from
+    pub fn serial(&self) -> &str {
+        &self.serial
+    }
+
+    /// Stop capturing and wait for the thread to finish
+    pub fn stop(mut self) {
+        self.shutdown();
+    }
+
+    pub fn pause(&self, paused: bool) {
+        self.send(CaptureCommand::Pause(paused));
+    }
+
+    pub fn reconfigure(&self, config: StreamConfig) {
+        self.send(CaptureCommand::Reconfigure(config));
+    }
+
+    pub fn set_option(&self, sensor: SensorKind, option: Rs2Option, value: f32) {
+        self.send(CaptureCommand::SetOption(sensor, option, value));
+    }
+
+    /// Apply all of the given settings to the device
+    pub fn set_options(&self, options: &SensorOptions) {
+        for (sensor, option, value) in options.to_device_options() {
+            self.set_option(sensor, option, value);
+        }
+    }
+
+    /// Whether the capture thread is still running
+    pub fn is_running(&self) -> bool {
+        self.thread.as_ref().is_some_and(|t| !t.is_finished())
+    }
+
+    fn send(&self, command: CaptureCommand) {
+        // The thread only hangs up once it has exited, in which case there is nothing to control
+        let _ = self.tx.send(command);
+    }
+
+    fn shutdown(&mut self) {
+        self.send(CaptureCommand::Stop);
+        if let Some(thread) = self.thread.take() {
+            let _ = thread.join();
+        }
+    }
+}
+
+impl Drop for CaptureHandle {
+    fn drop(&mut self) {
+        self.shutdown();
+    }
+}
+
+impl Default for StreamConfig {
+    fn default() -> Self {
+        Self {
+            color_width: 640,
+            color_height: 0,
+            depth_width: 640,
+            depth_height: 0,
+            fps: 60,
+        }
+    }
+}
+
+/// Block a frame source's thread until it should start streaming again: after "delay" when
+/// retrying after an error, or once resumed when paused (no delay). A pause arriving during the
+/// delay is kept, so the source waits to be resumed once the delay is over. Returns false if it
+/// was told to stop in the meantime.
+pub(crate) fn wait_to_restart(
+    commands: &Receiver<CaptureCommand>,
+    delay: Option<Duration>,
+) -> bool {
+    let mut deadline = delay.map(|delay| Instant::now() + delay);
+    let mut paused = delay.is_none();
+    loop {
+        let command = match deadline {
+            Some(until) => {
+                match commands.recv_timeout(until.saturating_duration_since(Instant::now())) {
+                    Ok(command) => command,
+                    Err(RecvTimeoutError::Timeout) if paused => {
+                        deadline = None;
+                        continue;
+                    }
+                    Err(RecvTimeoutError::Timeout) => return true,
+                    Err(RecvTimeoutError::Disconnected) => return false,
+                }
+            }
+            None => match commands.recv() {
+                Ok(command) => command,
+                Err(_) => return false,
+            },
+        };
+        match command {
+            CaptureCommand::Stop => return false,
+            CaptureCommand::Pause(pause) => {
+                paused = pause;
+                if !paused && deadline.is_none() {
+                    return true;
+                }
+            }
+            _ => (),
+        }
+    }
+}
@@ -0,0 +1,514 @@
+//! Compression of depth and color images, for recordings and network streams. Depth is always
+//! compressed losslessly; color may be lossy.
+//!
+//! A compressed frame is a codec byte each for depth and color, the width and height as u32, then
+//! three payloads each prefixed by its length as a u32: the `FrameMetadata` as JSON, the depth
+//! image and the color image aligned to depth. All integers are little-endian.
+
+use std::fmt;
+use std::str::FromStr;
+
+use anyhow::{bail, ensure, Context, Result};
+use serde::{Deserialize, Serialize};
+
+use crate::{FrameMetadata, ImagePointCloud};
+
+/// zstd level used for depth, favouring speed since frames arrive at up to 90 FPS
+const ZSTD_LEVEL: i32 = 1;
+
+const JPEG_QUALITY: u8 = 90;
+
+/// Largest image decoded, far above any RealSense resolution. Frames come from files and the
+/// network, so sizes are checked before anything is allocated for them.
+const MAX_PIXELS: usize = 8192 * 8192;
+
+/// Encoding of a depth image. Every depth codec is lossless.
+#[derive(Copy, Clone, Debug, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub enum DepthCodec {
+    /// Two bytes per pixel, row-major
+    #[default]
+    Raw,
+    /// Differences between horizontal neighbours, split into low and high byte planes and
+    /// compressed with zstd
+    Zstd,
+    /// 16-bit grayscale PNG
+    Png,
+}
+
+/// Encoding of a color image
+#[derive(Copy, Clone, Debug, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub enum ColorCodec {
+    /// Three bytes (RGB) per pixel, row-major
+    #[default]
+    Raw,
+    /// Lossless QOI
+    Qoi,
+    /// Lossy JPEG
+    Jpeg,
+}
+
+/// How the images of a frame are compressed
+#[derive(Copy, Clone, Debug, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct FrameCodecs {
+    pub depth: DepthCodec,
+    pub color: ColorCodec,
+}
+
+impl DepthCodec {
+    pub const ALL: [Self; 3] = [Self::Raw, Self::Zstd, Self::Png];
+
+    /// Identifier written before the payload
+    pub fn id(&self) -> u8 {
+        match self {
+            Self::Raw => 0,
+            Self::Zstd => 1,
+            Self::Png => 2,
+        }
+    }
+
+    pub fn from_id(id: u8) -> Result<Self> {
+        Self::ALL
+            .into_iter()
+            .find(|c| c.id() == id)
+            .with_context(|| format!("Unknown depth codec {id}"))
+    }
+
+    pub fn encode(&self, depth: &[u16], width: usize) -> Result<Vec<u8>> {
+        match self {
+            Self::Raw => Ok(depth.iter().flat_map(|d| d.to_le_bytes()).collect()),
+            Self::Zstd => {
+                let deltas = delta_planes(depth, width);
+                Ok(zstd::encode_all(deltas.as_slice(), ZSTD_LEVEL)?)
+            }
+            Self::Png => {
+                let mut bytes = vec![];
+                let height = depth.len() / width.max(1);
+                let mut encoder = png::Encoder::new(&mut bytes, width as u32, height as u32);
+                encoder.set_color(png::ColorType::Grayscale);
+                encoder.set_depth(png::BitDepth::Sixteen);
+                encoder.set_compression(png::Compression::Fast);
+                let pixels: Vec<u8> = depth.iter().flat_map(|d| d.to_be_bytes()).collect();
+                encoder.write_header()?.write_image_data(&pixels)?;
+                Ok(bytes)
+            }
+        }
+    }
+
+    /// Decode an image of "width" x "height" pixels
+    pub fn decode(&self, bytes: &[u8], width: usize, height: usize) -> Result<Vec<u16>> {
+        let n_pixels = image_pixels(width, height)?;
+        let depth: Vec<u16> = match self {
+            Self::Raw => {
+                ensure!(bytes.len() == n_pixels * 2, "Wrong depth image size");
+                bytes
+                    .chunks_exact(2)
+                    .map(|b| u16::from_le_bytes([b[0], b[1]]))
+                    .collect()
+            }
+            Self::Zstd => {
+                let planes = zstd::bulk::decompress(bytes, n_pixels * 2)?;
+                ensure!(planes.len() == n_pixels * 2, "Wrong depth image size");
+                undelta_planes(&planes, width)
+            }
+            Self::Png => {
+                let mut reader = png::Decoder::new(bytes).read_info()?;
+                let info = reader.info();
+                ensure!(
+                    (info.width as usize, info.height as usize) == (width, height),
+                    "Wrong depth image size"
+                );
+                let mut pixels = vec![0; reader.output_buffer_size()];
+                let info = reader.next_frame(&mut pixels)?;
+                ensure!(
+                    info.color_type == png::ColorType::Grayscale
+                        && info.bit_depth == png::BitDepth::Sixteen,
+                    "Depth PNG is not 16-bit grayscale"
+                );
+                pixels[..info.buffer_size()]
+                    .chunks_exact(2)
+                    .map(|b| u16::from_be_bytes([b[0], b[1]]))
+                    .collect()
+            }
+        };
+        ensure!(depth.len() == n_pixels, "Wrong depth image size");
+        Ok(depth)
+    }
+}
+
+impl ColorCodec {
+    pub const ALL: [Self; 3] = [Self::Raw, Self::Qoi, Self::Jpeg];
+
+    /// Identifier written before the payload
+    pub fn id(&self) -> u8 {
+        match self {
+            Self::Raw => 0,
+            Self::Qoi => 1,
+            Self::Jpeg => 2,
+        }
+    }
+
+    pub fn from_id(id: u8) -> Result<Self> {
+        Self::ALL
+            .into_iter()
+            .find(|c| c.id() == id)
+            .with_context(|| format!("Unknown color codec {id}"))
+    }
+
+    pub fn is_lossless(&self) -> bool {
+        !matches!(self, Self::Jpeg)
+    }
+
+    pub fn encode(&self, color: &[[u8; 3]], width: usize) -> Result<Vec<u8>> {
+        let pixels: &[u8] = bytemuck::cast_slice(color);
+        let height = color.len() / width.max(1);
+        match self {
+            Self::Raw => Ok(pixels.to_vec()),
+            Self::Qoi => Ok(qoi::encode_to_vec(pixels, width as u32, height as u32)?),
+            Self::Jpeg => {
+                ensure!(
+                    width <= u16::MAX as usize && height <= u16::MAX as usize,
+                    "Image is too large for JPEG"
+                );
+                let mut bytes = vec![];
+                jpeg_encoder::Encoder::new(&mut bytes, JPEG_QUALITY).encode(
+                    pixels,
+                    width as u16,
+                    height as u16,
+                    jpeg_encoder::ColorType::Rgb,
+                )?;
+                Ok(bytes)
+            }
+        }
+    }
+
+    /// Decode an image of "width" x "height" pixels
+    pub fn decode(&self, bytes: &[u8], width: usize, height: usize) -> Result<Vec<[u8; 3]>> {
+        let n_pixels = image_pixels(width, height)?;
+        let pixels = match self {
+            Self::Raw => bytes.to_vec(),
+            Self::Qoi => {
+                let header = qoi::decode_header(bytes)?;
+                ensure!(
+                    (header.width as usize, header.height as usize) == (width, height),
+                    "Wrong color image size"
+                );
+                let (header, pixels) = qoi::decode_to_vec(bytes)?;
+                ensure!(
+                    header.channels == qoi::Channels::Rgb,
+                    "Color QOI is not RGB"
+                );
+                pixels
+            }
+            Self::Jpeg => {
+                let mut decoder = jpeg_decoder::Decoder::new(bytes);
+                decoder.read_info()?;
+                let info = decoder.info().context("JPEG has no header")?;
+                ensure!(
+                    (info.width as usize, info.height as usize) == (width, height),
+                    "Wrong color image size"
+                );
+                ensure!(
+                    info.pixel_format == jpeg_decoder::PixelFormat::RGB24,
+                    "Color JPEG is not RGB"
+                );
+                decoder.decode()?
+            }
+        };
+        ensure!(pixels.len() == n_pixels * 3, "Wrong color image size");
+        Ok(bytemuck::cast_slice(&pixels).to_vec())
+    }
+}
+
+/// Number of pixels in an image of "width" x "height", failing if it is implausibly large
+fn image_pixels(width: usize, height: usize) -> Result<usize> {
+    match width.checked_mul(height) {
+        Some(n_pixels) if n_pixels <= MAX_PIXELS => Ok(n_pixels),
+        _ => bail!("Image of {width}x{height} pixels is too large"),
+    }
+}
+
+/// Row-wise differences, zigzag encoded so that small changes either way are small numbers, with
+/// the low bytes of every pixel followed by the high bytes. Depth changes slowly across surfaces,
+/// so most high bytes are zero and compress to almost nothing.
+fn delta_planes(depth: &[u16], width: usize) -> Vec<u8> {
+    let mut planes = vec![0; depth.len() * 2];
+    let (low, high) = planes.split_at_mut(depth.len());
+    for (row_idx, row) in depth.chunks(width.max(1)).enumerate() {
+        let mut prev = 0_u16;
+        for (x, d) in row.iter().enumerate() {
+            let delta = d.wrapping_sub(prev) as i16;
+            let zigzag = ((delta << 1) ^ (delta >> 15)) as u16;
+            let [l, h] = zigzag.to_le_bytes();
+            low[row_idx * width + x] = l;
+            high[row_idx * width + x] = h;
+            prev = *d;
+        }
+    }
+    planes
+}
+
+/// Inverse of `delta_planes`
+fn undelta_planes(planes: &[u8], width: usize) -> Vec<u16> {
+    let (low, high) = planes.split_at(planes.len() / 2);
+    let mut depth = Vec::with_capacity(low.len());
+    let mut prev = 0_u16;
+    for (idx, (l, h)) in low.iter().zip(high).enumerate() {
+        if idx % width.max(1) == 0 {
+            prev = 0;
+        }
+        let zigzag = u16::from_le_bytes([*l, *h]);
+        let delta = ((zigzag >> 1) as i16) ^ -((zigzag & 1) as i16);
+        prev = prev.wrapping_add(delta as u16);
+        depth.push(prev);
+    }
+    depth
+}
+
+/// Serializes a frame's depth and color images, compressed, along with its metadata
+pub fn encode_frame(frame: &ImagePointCloud, codecs: FrameCodecs) -> Result<Vec<u8>> {
+    let metadata = serde_json::to_vec(frame.metadata())?;
+    let depth = codecs.depth.encode(&frame.depth_image(), frame.width())?;
+    let color = codecs.color.encode(frame.color(), frame.width())?;
+
+    let mut bytes = vec![codecs.depth.id(), codecs.color.id()];
+    bytes.extend((frame.width() as u32).to_le_bytes());
+    bytes.extend((frame.height() as u32).to_le_bytes());
+    for payload in [&metadata, &depth, &color] {
+        bytes.extend((payload.len() as u32).to_le_bytes());
+        bytes.extend(payload);
+    }
+    Ok(bytes)
+}
+
+/// Deserializes a frame written by `encode_frame`, deprojecting its depth image
+pub fn decode_frame(mut bytes: &[u8]) -> Result<ImagePointCloud> {
+    let codecs = take(&mut bytes, 2)?;
+    let depth_codec = DepthCodec::from_id(codecs[0])?;
+    let color_codec = ColorCodec::from_id(codecs[1])?;
+    let width = take_u32(&mut bytes)? as usize;
+    let height = take_u32(&mut bytes)? as usize;
+    ensure!(width > 0 && height > 0, "Empty frame");
+    image_pixels(width, height)?;
+
+    let len = take_u32(&mut bytes)? as usize;
+    let metadata: FrameMetadata = serde_json::from_slice(take(&mut bytes, len)?)?;
+    let intrinsics = &metadata.depth.intrinsics;
+    ensure!(
+        (intrinsics.width as usize, intrinsics.height as usize) == (width, height),
+        "Frame size doesn't match its depth intrinsics"
+    );
+    let len = take_u32(&mut bytes)? as usize;
+    let depth = depth_codec.decode(take(&mut bytes, len)?, width, height)?;
+    let len = take_u32(&mut bytes)? as usize;
+    let color = color_codec.decode(take(&mut bytes, len)?, width, height)?;
+
+    ImagePointCloud::from_depth(depth, color, width, metadata)
+}
+
+pub(crate) fn take<'a>(bytes: &mut &'a [u8], len: usize) -> Result<&'a [u8]> {
+    ensure!(bytes.len() >= len, "Frame is truncated");
+    let (head, rest) = bytes.split_at(len);
+    *bytes = rest;
+    Ok(head)
+}
+
+pub(crate) fn take_u32(bytes: &mut &[u8]) -> Result<u32> {
+    Ok(u32::from_le_bytes(take(bytes, 4)?.try_into().unwrap()))
+}
+
+impl fmt::Display for DepthCodec {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let name = match self {
+            Self::Raw => "raw",
+            Self::Zstd => "zstd",
+            Self::Png => "png",
+        };
+        f.write_str(name)
+    }
+}
+
+impl fmt::Display for ColorCodec {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let name = match self {
+            Self::Raw => "raw",
+            Self::Qoi => "qoi",
+            Self::Jpeg => "jpeg",
+        };
+        f.write_str(name)
+    }
+}
+
+impl FromStr for DepthCodec {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        match Self::ALL.into_iter().find(|c| c.to_string() == s) {
+            Some(codec) => Ok(codec),
+            None => bail!("Unknown depth codec {s:?}, expected raw, zstd or png"),
+        }
+    }
+}
+
+impl FromStr for ColorCodec {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        match Self::ALL.into_iter().find(|c| c.to_string() == s) {
+            Some(codec) => Ok(codec),
+            None => bail!("Unknown color codec {s:?}, expected raw, qoi or jpeg"),
+        }
+    }
+}
+
+#[cfg(test)]
+pub(crate) mod tests {
+    use super::*;
+    use crate::Rs2IntrinsicsSerde;
+
+    /// Odd, so rows don't line up with any power of two
+    const WIDTH: usize = 7;
+    const HEIGHT: usize = 3;
+
+    /// Depth jumping between the extremes of its range, the worst case for `delta_planes`
+    fn test_depth() -> Vec<u16> {
+        (0..WIDTH * HEIGHT)
+            .map(|i| match i % 4 {
+                0 | 2 => 0,
+                1 => u16::MAX,
+                _ => i as u16 * 97,
+            })
+            .collect()
+    }
+
+    fn test_color() -> Vec<[u8; 3]> {
+        (0..WIDTH * HEIGHT)
+            .map(|i| [i as u8 * 12, 255 - i as u8, 128])
+            .collect()
+    }
+
+    /// Frame of "width" x "height" pixels with deprojectable intrinsics
+    pub(crate) fn test_frame(width: usize, height: usize) -> ImagePointCloud {
+        let depth = (0..width * height)
+            .map(|i| (i % 60) as u16 * 1000)
+            .collect();
+        let color = (0..width * height)
+            .map(|i| [i as u8, 255 - i as u8, 7])
+            .collect();
+        let mut metadata = FrameMetadata {
+            serial: "test".into(),
+            depth_scale: 0.001,
+            ..Default::default()
+        };
+        metadata.depth.frame_number = 42;
+        metadata.depth.intrinsics = Rs2IntrinsicsSerde {
+            width: width as i32,
+            height: height as i32,
+            ppx: width as f32 / 2.,
+            ppy: height as f32 / 2.,
+            fx: 4.,
+            fy: 4.,
+            ..Default::default()
+        };
+        ImagePointCloud::from_depth(depth, color, width, metadata).unwrap()
+    }
+
+    #[test]
+    fn delta_planes_round_trip() {
+        let depth = test_depth();
+        assert_eq!(undelta_planes(&delta_planes(&depth, WIDTH), WIDTH), depth);
+    }
+
+    #[test]
+    fn depth_codecs_round_trip() {
+        let depth = test_depth();
+        for codec in DepthCodec::ALL {
+            let bytes = codec.encode(&depth, WIDTH).unwrap();
+            assert_eq!(
+                codec.decode(&bytes, WIDTH, HEIGHT).unwrap(),
+                depth,
+                "{codec}"
+            );
+        }
+    }
+
+    #[test]
+    fn color_codecs_round_trip() {
+        let color = test_color();
+        for codec in ColorCodec::ALL {
+            let bytes = codec.encode(&color, WIDTH).unwrap();
+            let decoded = codec.decode(&bytes, WIDTH, HEIGHT).unwrap();
+            assert_eq!(decoded.len(), color.len(), "{codec}");
+            if codec.is_lossless() {
+                assert_eq!(decoded, color, "{codec}");
+            }
+        }
+    }
+
+    #[test]
+    fn codecs_reject_bad_payloads() {
+        for codec in DepthCodec::ALL {
+            let bytes = codec.encode(&test_depth(), WIDTH).unwrap();
+            assert!(
+                codec
+                    .decode(&bytes[..bytes.len() / 2], WIDTH, HEIGHT)
+                    .is_err(),
+                "{codec}"
+            );
+            assert!(codec.decode(&bytes, WIDTH + 1, HEIGHT).is_err(), "{codec}");
+            assert!(codec.decode(&bytes, usize::MAX, 2).is_err(), "{codec}");
+        }
+        for codec in ColorCodec::ALL {
+            let bytes = codec.encode(&test_color(), WIDTH).unwrap();
+            assert!(
+                codec
+                    .decode(&bytes[..bytes.len() / 2], WIDTH, HEIGHT)
+                    .is_err(),
+                "{codec}"
+            );
+            assert!(codec.decode(&bytes, WIDTH + 1, HEIGHT).is_err(), "{codec}");
+            assert!(codec.decode(&bytes, usize::MAX, 2).is_err(), "{codec}");
+        }
+    }
+
+    #[test]
+    fn frame_round_trip() {
+        let frame = test_frame(WIDTH, HEIGHT);
+        for depth in DepthCodec::ALL {
+            for color in ColorCodec::ALL {
+                let codecs = FrameCodecs { depth, color };
+                let decoded = decode_frame(&encode_frame(&frame, codecs).unwrap()).unwrap();
+                assert_eq!(decoded.width(), frame.width());
+                assert_eq!(decoded.depth(), frame.depth(), "{codecs:?}");
+                assert_eq!(decoded.position(), frame.position(), "{codecs:?}");
+                assert_eq!(decoded.metadata().depth.frame_number, 42);
+                if color.is_lossless() {
+                    assert_eq!(decoded.color(), frame.color(), "{codecs:?}");
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn decode_frame_rejects_bad_frames() {
+        let codecs = FrameCodecs {
+            depth: DepthCodec::Zstd,
+            color: ColorCodec::Qoi,
+        };
+        let bytes = encode_frame(&test_frame(WIDTH, HEIGHT), codecs).unwrap();
+        for len in 0..bytes.len() {
+            assert!(decode_frame(&bytes[..len]).is_err(), "Truncated to {len}");
+        }
+
+        let corrupt = |offset: usize, value: &[u8]| {
+            let mut bytes = bytes.clone();
+            bytes[offset..offset + value.len()].copy_from_slice(value);
+            decode_frame(&bytes)
+        };
+        assert!(corrupt(0, &[9]).is_err(), "Unknown codec");
+        assert!(corrupt(2, &u32::MAX.to_le_bytes()).is_err(), "Huge width");
+        assert!(
+            corrupt(6, &(HEIGHT as u32 + 1).to_le_bytes()).is_err(),
+            "Wrong height"
+        );
+    }
+}
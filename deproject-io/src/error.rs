@@ -0,0 +1,90 @@
+use std::fmt;
+use std::time::Duration;
+
+use crate::ImagePointCloud;
+
+/// Errors which can occur while capturing from a device
+#[derive(Clone, Debug)]
+pub enum CaptureError {
+    /// No device with the given serial number is connected
+    DeviceNotFound(String),
+    /// The device or librealsense reported an error (e.g. it was unplugged)
+    Device(String),
+    /// No frames arrived before the timeout
+    Timeout,
+    /// A stream required for deprojection was not present
+    MissingStream(&'static str),
+    /// A frameset did not contain the expected frame
+    MissingFrame(&'static str),
+    /// A frame contained pixels in a format other than the one requested
+    UnexpectedPixelFormat(String),
+    /// A network stream could not be reached or sent an invalid message
+    Stream(String),
+    /// A recording being played back could not be read
+    Recording(String),
+}
+
+/// Connection state of a capture thread
+#[derive(Clone, Debug)]
+pub enum CaptureStatus {
+    /// Opening the device and starting the pipeline
+    Connecting,
+    /// Frames are arriving
+    Streaming,
+    /// Capture failed, and will be retried after the given delay
+    Reconnecting {
+        error: CaptureError,
+        retry_in: Duration,
+    },
+    /// The device is closed until capture is resumed
+    Paused,
+    /// The capture thread has exited
+    Stopped,
+}
+
+/// Message sent from a capture thread to its consumer
+pub enum CaptureEvent {
+    Frame(ImagePointCloud),
+    Status {
+        serial: String,
+        status: CaptureStatus,
+    },
+}
+
+impl CaptureError {
+    /// Wrap an error reported by librealsense
+    pub fn device(err: impl fmt::Display) -> Self {
+        Self::Device(err.to_string())
+    }
+}
+
+impl fmt::Display for CaptureError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::DeviceNotFound(serial) => write!(f, "Device {serial} not found"),
+            Self::Device(msg) => write!(f, "Device error: {msg}"),
+            Self::Timeout => write!(f, "Timed out waiting for frames"),
+            Self::MissingStream(kind) => write!(f, "No {kind} stream"),
+            Self::MissingFrame(kind) => write!(f, "Frameset has no {kind} frame"),
+            Self::UnexpectedPixelFormat(pixel) => write!(f, "Unexpected pixel format {pixel}"),
+            Self::Stream(msg) => write!(f, "Stream error: {msg}"),
+            Self::Recording(msg) => write!(f, "Recording error: {msg}"),
+        }
+    }
+}
+
+impl std::error::Error for CaptureError {}
+
+impl fmt::Display for CaptureStatus {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Connecting => write!(f, "Connecting"),
+            Self::Streaming => write!(f, "Streaming"),
+            Self::Reconnecting { error, retry_in } => {
+                write!(f, "{error} (retrying in {:.1}s)", retry_in.as_secs_f32())
+            }
+            Self::Paused => write!(f, "Paused"),
+            Self::Stopped => write!(f, "Stopped"),
+        }
+    }
+}
@@ -0,0 +1,518 @@
+use std::collections::{BTreeMap, HashMap};
+
+use glam::{Affine3A, Mat4, Quat, Vec3};
+use serde::{Deserialize, Serialize};
+
+use crate::graycode::ProjectorMap;
+use crate::ImagePointCloud;
+
+/// Rigid transform from a camera's frame into the world frame, in depth units
+#[derive(Clone, Copy, Debug, PartialEq, Serialize, Deserialize)]
+pub struct Extrinsics {
+    pub rotation: Quat,
+    pub translation: Vec3,
+}
+
+/// A pair of points which should coincide: (source, destination)
+pub type Correspondence = (Vec3, Vec3);
+
+/// Summary of the distances between corresponding points after alignment
+#[derive(Copy, Clone, Debug, Default)]
+pub struct Residuals {
+    /// Number of correspondences
+    pub count: usize,
+    /// Number of correspondences used in the final solve
+    pub inliers: usize,
+    /// Root mean square distance over inliers
+    pub rms: f32,
+    /// Median distance over all correspondences
+    pub median: f32,
+    /// Largest distance over all correspondences
+    pub max: f32,
+}
+
+/// Result of aligning one camera to another
+#[derive(Copy, Clone, Debug)]
+pub struct PairCalibration {
+    /// Maps points in the other camera's frame into the reference camera's frame
+    pub transform: Affine3A,
+    /// Residuals of the closed-form solve from structured light correspondences alone
+    pub initial: Residuals,
+    /// Residuals after outlier rejection and ICP refinement
+    pub refined: Residuals,
+}
+
+/// A decoded structured light capture from one camera
+pub struct DecodedCapture {
+    pub map: ProjectorMap,
+    /// Frame giving the 3D position of each pixel
+    pub cloud: ImagePointCloud,
+}
+
+/// Parameters for `calibrate_pair`
+#[derive(Copy, Clone, Debug, Serialize, Deserialize)]
+#[serde(default)]
+pub struct PairCalibrationParams {
+    /// Correspondences further than this multiple of the median residual are rejected
+    pub outlier_factor: f32,
+    /// Number of ICP iterations run against the full point clouds
+    pub icp_iterations: usize,
+    /// Use every n-th point of the other camera's cloud for ICP
+    pub icp_stride: usize,
+}
+
+/// Pairs up 3D points from two cameras which observed the same projector pixel. Points from the
+/// `source` camera come first in each pair.
+pub fn correspondences(
+    source: (&ProjectorMap, &ImagePointCloud),
+    dest: (&ProjectorMap, &ImagePointCloud),
+) -> Vec<Correspondence> {
+    let source = mean_point_per_projector_pixel(source.0, source.1);
+    let dest = mean_point_per_projector_pixel(dest.0, dest.1);
+
+    source
+        .iter()
+        .filter_map(|(coord, src)| Some((*src, *dest.get(coord)?)))
+        .collect()
+}
+
+/// Several camera pixels may see the same projector pixel; average their positions
+fn mean_point_per_projector_pixel(
+    map: &ProjectorMap,
+    cloud: &ImagePointCloud,
+) -> HashMap<[u32; 2], Vec3> {
+    assert_eq!(map.coords().len(), cloud.valid().len());
+
+    let mut sums: HashMap<[u32; 2], (Vec3, f32)> = HashMap::new();
+    for (coord, sample) in map.coords().iter().zip(cloud.iter_pixels()) {
+        if let (Some(coord), Some((pos, _))) = (coord, sample) {
+            let entry = sums.entry(*coord).or_insert((Vec3::ZERO, 0.));
+            entry.0 += pos;
+            entry.1 += 1.;
+        }
+    }
+
+    sums.into_iter()
+        .map(|(coord, (sum, n))| (coord, sum / n))
+        .collect()
+}
+
+/// Least-squares rigid transform mapping the source points onto the destination points
+/// (Kabsch problem, solved with Horn's quaternion method). Returns `None` if there are fewer
+/// than three correspondences.
+pub fn kabsch(pairs: &[Correspondence]) -> Option<Affine3A> {
+    if pairs.len() < 3 {
+        return None;
+    }
+
+    let n = pairs.len() as f64;
+    let centroid = |f: fn(&Correspondence) -> Vec3| {
+        pairs
+            .iter()
+            .fold(glam::DVec3::ZERO, |acc, p| acc + f(p).as_dvec3())
+            / n
+    };
+    let src_mean = centroid(|p| p.0);
+    let dst_mean = centroid(|p| p.1);
+
+    // Cross-covariance s[i][j] = sum(src_i * dst_j)
+    let mut s = [[0_f64; 3]; 3];
+    for (src, dst) in pairs {
+        let a = src.as_dvec3() - src_mean;
+        let b = dst.as_dvec3() - dst_mean;
+        for i in 0..3 {
+            for j in 0..3 {
+                s[i][j] += a[i] * b[j];
+            }
+        }
+    }
+
+    let [[sxx, sxy, sxz], [syx, syy, syz], [szx, szy, szz]] = s;
+    let horn = [
+        [sxx + syy + szz, syz - szy, szx - sxz, sxy - syx],
+        [syz - szy, sxx - syy - szz, sxy + syx, szx + sxz],
+        [szx - sxz, sxy + syx, -sxx + syy - szz, syz + szy],
+        [sxy - syx, szx + sxz, syz + szy, -sxx - syy + szz],
+    ];
+
+    // The optimal rotation is the eigenvector with the largest eigenvalue
+    let (values, vectors) = symmetric_eigen4(horn);
+    let best = (0..4)
+        .max_by(|a, b| values[*a].total_cmp(&values[*b]))
+        .unwrap();
+    let [w, x, y, z] = [0, 1, 2, 3].map(|row| vectors[row][best] as f32);
+    let rotation = Quat::from_xyzw(x, y, z, w).normalize();
+
+    let translation = dst_mean.as_vec3() - rotation * src_mean.as_vec3();
+
+    Some(Affine3A::from_rotation_translation(rotation, translation))
+}
+
+/// Eigen-decomposition of a symmetric 4x4 matrix by cyclic Jacobi rotations. Returns the
+/// eigenvalues and a matrix with the corresponding eigenvectors in its columns.
+fn symmetric_eigen4(mut a: [[f64; 4]; 4]) -> ([f64; 4], [[f64; 4]; 4]) {
+    let mut v = [[0_f64; 4]; 4];
+    for (i, row) in v.iter_mut().enumerate() {
+        row[i] = 1.;
+    }
+
+    for _ in 0..50 {
+        let off_diagonal: f64 = (0..4)
+            .flat_map(|p| (p + 1..4).map(move |q| (p, q)))
+            .map(|(p, q)| a[p][q] * a[p][q])
+            .sum();
+        if off_diagonal < 1e-30 {
+            break;
+        }
+
+        for p in 0..4 {
+            for q in p + 1..4 {
+                if a[p][q] == 0. {
+                    continue;
+                }
+
+                let theta = (a[q][q] - a[p][p]) / (2. * a[p][q]);
+                let t = theta.signum() / (theta.abs() + (theta * theta + 1.).sqrt());
+                let c = 1. / (t * t + 1.).sqrt();
+                let s = t * c;
+
+                for row in a.iter_mut() {
+                    let (kp, kq) = (row[p], row[q]);
+                    row[p] = c * kp - s * kq;
+                    row[q] = s * kp + c * kq;
+                }
+                let (row_p, row_q) = (a[p], a[q]);
+                for k in 0..4 {
+                    a[p][k] = c * row_p[k] - s * row_q[k];
+                    a[q][k] = s * row_p[k] + c * row_q[k];
+                }
+                for row in v.iter_mut() {
+                    let (kp, kq) = (row[p], row[q]);
+                    row[p] = c * kp - s * kq;
+                    row[q] = s * kp + c * kq;
+                }
+            }
+        }
+    }
+
+    ([a[0][0], a[1][1], a[2][2], a[3][3]], v)
+}
+
+/// Distance between each destination point and its transformed source point
+pub fn distances(pairs: &[Correspondence], transform: &Affine3A) -> Vec<f32> {
+    pairs
+        .iter()
+        .map(|(src, dst)| transform.transform_point3(*src).distance(*dst))
+        .collect()
+}
+
+/// Computes residual statistics, counting only distances up to `inlier_threshold` towards RMS
+pub fn residuals(
+    pairs: &[Correspondence],
+    transform: &Affine3A,
+    inlier_threshold: f32,
+) -> Residuals {
+    let mut dists = distances(pairs, transform);
+    if dists.is_empty() {
+        return Residuals::default();
+    }
+    dists.sort_by(f32::total_cmp);
+
+    let inliers: Vec<f32> = dists
+        .iter()
+        .copied()
+        .filter(|d| *d <= inlier_threshold)
+        .collect();
+    let rms = (inliers.iter().map(|d| d * d).sum::<f32>() / inliers.len().max(1) as f32).sqrt();
+
+    Residuals {
+        count: dists.len(),
+        inliers: inliers.len(),
+        rms,
+        median: dists[dists.len() / 2],
+        max: dists[dists.len() - 1],
+    }
+}
+
+/// Repeatedly solves, discarding correspondences further than `outlier_factor` times the median
+/// residual. Returns the transform and the inlier threshold used for the last solve. Trimming
+/// stops early, keeping the last solve, if too few correspondences would be left to solve again.
+/// None if "pairs" can't be solved at all.
+pub fn kabsch_trimmed(
+    pairs: &[Correspondence],
+    outlier_factor: f32,
+    iterations: usize,
+) -> Option<(Affine3A, f32)> {
+    let mut transform = kabsch(pairs)?;
+    // Every correspondence is an inlier of the untrimmed solve
+    let mut threshold = distances(pairs, &transform)
+        .into_iter()
+        .fold(f32::EPSILON, f32::max);
+    for _ in 0..iterations {
+        let median = median(distances(pairs, &transform));
+        let trim = (median * outlier_factor).max(f32::EPSILON);
+        let inliers: Vec<Correspondence> = pairs
+            .iter()
+            .copied()
+            .filter(|(src, dst)| transform.transform_point3(*src).distance(*dst) <= trim)
+            .collect();
+        let Some(trimmed) = kabsch(&inliers) else {
+            break;
+        };
+        transform = trimmed;
+        threshold = trim;
+    }
+    Some((transform, threshold))
+}
+
+fn median(mut values: Vec<f32>) -> f32 {
+    if values.is_empty() {
+        return 0.;
+    }
+    values.sort_by(f32::total_cmp);
+    values[values.len() / 2]
+}
+
+/// Point-to-point ICP, refining `initial` so that `source` lands on `target`. Only pairs of
+/// nearest neighbours closer than `max_dist` are used.
+pub fn icp(
+    source: &[Vec3],
+    target: &[Vec3],
+    initial: Affine3A,
+    max_dist: f32,
+    iterations: usize,
+) -> Affine3A {
+    let grid = VoxelGrid::new(target, max_dist);
+    let mut transform = initial;
+    for _ in 0..iterations {
+        let pairs: Vec<Correspondence> = source
+            .iter()
+            .filter_map(|src| {
+                let nearest = grid.nearest(transform.transform_point3(*src), max_dist)?;
+                Some((*src, nearest))
+            })
+            .collect();
+
+        match kabsch(&pairs) {
+            Some(t) => transform = t,
+            None => break,
+        }
+    }
+    transform
+}
+
+/// Estimates the transform from the `other` camera into the `reference` camera's frame using
+/// shared projector pixels, then refines it with ICP over the valid points of both clouds.
+pub fn calibrate_pair(
+    reference: (&ProjectorMap, &ImagePointCloud),
+    other: (&ProjectorMap, &ImagePointCloud),
+    params: &PairCalibrationParams,
+) -> Option<PairCalibration> {
+    let pairs = correspondences(other, reference);
+
+    let closed_form = kabsch(&pairs)?;
+    let initial = residuals(&pairs, &closed_form, f32::INFINITY);
+
+    let (trimmed, threshold) = kabsch_trimmed(&pairs, params.outlier_factor, 3)?;
+
+    let valid_points = |cloud: &ImagePointCloud| -> Vec<Vec3> {
+        cloud.iter_pixels().flatten().map(|(pos, _)| pos).collect()
+    };
+    let source: Vec<Vec3> = valid_points(other.1)
+        .into_iter()
+        .step_by(params.icp_stride.max(1))
+        .collect();
+    let target = valid_points(reference.1);
+
+    let transform = icp(&source, &target, trimmed, threshold, params.icp_iterations);
+    let refined = residuals(&pairs, &transform, threshold);
+
+    Some(PairCalibration {
+        transform,
+        initial,
+        refined,
+    })
+}
+
+/// Aligns every capture to the capture of the `reference` camera, keyed by serial number. Cameras
+/// which share too few projector pixels with the reference are left out.
+pub fn calibrate_rig(
+    captures: &BTreeMap<String, DecodedCapture>,
+    reference: &str,
+    params: &PairCalibrationParams,
+) -> BTreeMap<String, PairCalibration> {
+    let Some(refr) = captures.get(reference) else {
+        return BTreeMap::new();
+    };
+
+    captures
+        .iter()
+        .filter(|(serial, _)| serial.as_str() != reference)
+        .filter_map(|(serial, other)| {
+            let result =
+                calibrate_pair((&refr.map, &refr.cloud), (&other.map, &other.cloud), params)?;
+            Some((serial.clone(), result))
+        })
+        .collect()
+}
+
+/// Uniform grid for nearest neighbour queries within a fixed radius
+struct VoxelGrid<'a> {
+    cell_size: f32,
+    cells: HashMap<[i32; 3], Vec<usize>>,
+    points: &'a [Vec3],
+}
+
+impl<'a> VoxelGrid<'a> {
+    fn new(points: &'a [Vec3], cell_size: f32) -> Self {
+        let mut cells: HashMap<[i32; 3], Vec<usize>> = HashMap::new();
+        for (idx, pt) in points.iter().enumerate() {
+            cells.entry(cell_key(*pt, cell_size)).or_default().push(idx);
+        }
+        Self {
+            cell_size,
+            cells,
+            points,
+        }
+    }
+
+    /// Closest point within `max_dist` (which must not exceed the cell size)
+    fn nearest(&self, query: Vec3, max_dist: f32) -> Option<Vec3> {
+        let [x, y, z] = cell_key(query, self.cell_size);
+        let mut best: Option<(f32, Vec3)> = None;
+        for dz in -1..=1 {
+            for dy in -1..=1 {
+                for dx in -1..=1 {
+                    let Some(cell) = self.cells.get(&[x + dx, y + dy, z + dz]) else {
+                        continue;
+                    };
+                    for &idx in cell {
+                        let pt = self.points[idx];
+                        let dist = pt.distance_squared(query);
+                        if best.is_none_or(|(d, _)| dist < d) {
+                            best = Some((dist, pt));
+                        }
+                    }
+                }
+            }
+        }
+        best.filter(|(d, _)| *d <= max_dist * max_dist)
+            .map(|(_, pt)| pt)
+    }
+}
+
+fn cell_key(pt: Vec3, cell_size: f32) -> [i32; 3] {
+    (pt / cell_size).floor().as_ivec3().to_array()
+}
+
+impl Extrinsics {
+    /// Camera-to-world matrix
+    pub fn matrix(&self) -> Mat4 {
+        Mat4::from_rotation_translation(self.rotation, self.translation)
+    }
+
+    /// Extract the rigid part of a camera-to-world matrix
+    pub fn from_matrix(matrix: Mat4) -> Self {
+        let (_, rotation, translation) = matrix.to_scale_rotation_translation();
+        Self {
+            rotation,
+            translation,
+        }
+    }
+}
+
+impl Default for Extrinsics {
+    fn default() -> Self {
+        Self {
+            rotation: Quat::IDENTITY,
+            translation: Vec3::ZERO,
+        }
+    }
+}
+
+impl Default for PairCalibrationParams {
+    fn default() -> Self {
+        Self {
+            outlier_factor: 3.,
+            icp_iterations: 10,
+            icp_stride: 16,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Corners of an uneven box, so no three are collinear and not all are coplanar
+    fn points() -> Vec<Vec3> {
+        (0..8)
+            .map(|i| {
+                Vec3::new(
+                    (i & 1) as f32 * 300.,
+                    (i >> 1 & 1) as f32 * 200.,
+                    500. + (i >> 2) as f32 * 100.,
+                )
+            })
+            .collect()
+    }
+
+    fn moved(transform: Affine3A) -> Vec<Correspondence> {
+        points()
+            .into_iter()
+            .map(|p| (p, transform.transform_point3(p)))
+            .collect()
+    }
+
+    fn assert_recovers(expected: Affine3A, solved: Affine3A) {
+        for p in points() {
+            let (a, b) = (expected.transform_point3(p), solved.transform_point3(p));
+            assert!(a.abs_diff_eq(b, 1e-2), "{a} != {b}");
+        }
+    }
+
+    #[test]
+    fn kabsch_recovers_transform() {
+        let rotations = [
+            Quat::IDENTITY,
+            Quat::from_euler(glam::EulerRot::XYZ, 0.3, -1.2, 2.5),
+            Quat::from_rotation_y(std::f32::consts::PI),
+            Quat::from_axis_angle(Vec3::new(1., 1., 0.).normalize(), std::f32::consts::PI),
+        ];
+        for rotation in rotations {
+            let expected =
+                Affine3A::from_rotation_translation(rotation, Vec3::new(120., -40., 800.));
+            let solved = kabsch(&moved(expected)).unwrap();
+            assert_recovers(expected, solved);
+        }
+    }
+
+    #[test]
+    fn kabsch_needs_three_pairs() {
+        let pairs = moved(Affine3A::IDENTITY);
+        assert!(kabsch(&pairs[..2]).is_none());
+    }
+
+    #[test]
+    fn kabsch_trimmed_rejects_outlier() {
+        let expected =
+            Affine3A::from_rotation_translation(Quat::from_rotation_z(0.5), Vec3::X * 50.);
+        let mut pairs = moved(expected);
+        pairs[3].1 += Vec3::splat(400.);
+        let (solved, _) = kabsch_trimmed(&pairs, 3., 3).unwrap();
+        assert_recovers(expected, solved);
+    }
+
+    #[test]
+    fn kabsch_trimmed_keeps_untrimmed_solve() {
+        // Every pair is off by a little, so a zero threshold would leave no inliers
+        let expected = Affine3A::from_translation(Vec3::Y * 30.);
+        let mut pairs = moved(expected);
+        for (i, (_, dst)) in pairs.iter_mut().enumerate() {
+            *dst += Vec3::splat(if i % 2 == 0 { 0.5 } else { -0.5 });
+        }
+        let (solved, threshold) = kabsch_trimmed(&pairs, 0., 3).unwrap();
+        assert_recovers(kabsch(&pairs).unwrap(), solved);
+        assert!(threshold.is_finite());
+    }
+}
@@ -0,0 +1,343 @@
+use anyhow::{ensure, Context, Result};
+
+/// Axis of the projector image which a pattern encodes
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum Axis {
+    /// Patterns are vertical stripes, encoding the projector column
+    Columns,
+    /// Patterns are horizontal stripes, encoding the projector row
+    Rows,
+}
+
+/// A single Gray code pattern displayed by the projector
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub struct Pattern {
+    pub axis: Axis,
+    /// Bit index, most significant first
+    pub bit: usize,
+    /// Total number of bits encoded along this axis
+    pub n_bits: usize,
+    /// Whether this is the inverse of the pattern, used as a per-pixel threshold
+    pub inverted: bool,
+}
+
+/// Mean luminance of the frames captured while each pattern was displayed
+#[derive(Clone, Default)]
+pub struct PatternImages {
+    /// Sum of luminance over the frames of the current pattern
+    sum: Vec<f32>,
+    count: usize,
+    /// Mean luminance for each completed pattern
+    images: Vec<Vec<f32>>,
+}
+
+/// Projector coordinates decoded for each camera pixel
+#[derive(Clone, Default)]
+pub struct ProjectorMap {
+    /// Projector (column, row) for each pixel, if it could be decoded
+    coords: Vec<Option<[u32; 2]>>,
+    /// Smallest contrast between a pattern and its inverse over all bits for each pixel
+    confidence: Vec<f32>,
+    width: usize,
+    /// Number of projector columns and rows which were encoded
+    resolution: [u32; 2],
+}
+
+pub fn gray_encode(n: u32) -> u32 {
+    n ^ (n >> 1)
+}
+
+pub fn gray_decode(mut g: u32) -> u32 {
+    let mut n = g;
+    while g > 0 {
+        g >>= 1;
+        n ^= g;
+    }
+    n
+}
+
+/// Full sequence of patterns (each followed by its inverse) for the given subdivisions
+pub fn pattern_sequence(horiz_subdivs: usize, vert_subdivs: usize) -> Vec<Pattern> {
+    let mut patterns = vec![];
+    for (axis, n_bits) in [(Axis::Columns, horiz_subdivs), (Axis::Rows, vert_subdivs)] {
+        for bit in 0..n_bits {
+            for inverted in [false, true] {
+                patterns.push(Pattern {
+                    axis,
+                    bit,
+                    n_bits,
+                    inverted,
+                });
+            }
+        }
+    }
+    patterns
+}
+
+impl Pattern {
+    /// Whether the given projector column (or row, depending on the axis) is lit
+    pub fn is_lit(&self, coord: u32) -> bool {
+        let shift = self.n_bits - 1 - self.bit;
+        let lit = (gray_encode(coord) >> shift) & 1 == 1;
+        lit != self.inverted
+    }
+
+    /// Number of projector columns (or rows) this pattern subdivides its axis into
+    pub fn resolution(&self) -> u32 {
+        1 << self.n_bits
+    }
+}
+
+/// Converts RGB pixels to luminance in the range 0 to 1
+pub fn luminance(color: &[[u8; 3]]) -> Vec<f32> {
+    color
+        .iter()
+        .map(|[r, g, b]| (0.2126 * *r as f32 + 0.7152 * *g as f32 + 0.0722 * *b as f32) / 255.)
+        .collect()
+}
+
+impl PatternImages {
+    /// Add a frame captured during the current pattern
+    pub fn add(&mut self, color: &[[u8; 3]]) {
+        let lum = luminance(color);
+        if self.sum.len() != lum.len() {
+            self.sum = vec![0.; lum.len()];
+        }
+        self.sum.iter_mut().zip(&lum).for_each(|(s, l)| *s += l);
+        self.count += 1;
+    }
+
+    /// Number of frames added for the current pattern
+    pub fn count(&self) -> usize {
+        self.count
+    }
+
+    /// Store the mean of the current pattern's frames and move on to the next pattern. A pattern
+    /// without frames gets an empty image, which decoding rejects.
+    pub fn finish_pattern(&mut self) {
+        let n = self.count.max(1) as f32;
+        let mean = std::mem::take(&mut self.sum)
+            .into_iter()
+            .map(|s| s / n)
+            .collect();
+        self.images.push(mean);
+        self.count = 0;
+    }
+
+    /// Number of completed patterns
+    pub fn len(&self) -> usize {
+        self.images.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.images.is_empty()
+    }
+
+    /// Mean luminance image of each completed pattern, for `ProjectorMap::decode_sequence`
+    pub fn into_images(self) -> Vec<Vec<f32>> {
+        self.images
+    }
+}
+
+/// Decodes one axis from (pattern, inverse) luminance image pairs ordered by bit, most
+/// significant first. Returns the decoded coordinate and contrast of each pixel. Fails if there
+/// are no images, too many bits, or images of different sizes, e.g. if the stream was
+/// reconfigured during the capture.
+pub fn decode_axis(
+    bits: &[(Vec<f32>, Vec<f32>)],
+    min_contrast: f32,
+) -> Result<(Vec<Option<u32>>, Vec<f32>)> {
+    let n_pixels = bits.first().map(|(pos, _)| pos.len()).unwrap_or(0);
+    ensure!(n_pixels > 0, "No pattern images to decode");
+    ensure!(bits.len() < 32, "Too many bits to decode");
+
+    let mut gray = vec![0_u32; n_pixels];
+    let mut contrast = vec![f32::INFINITY; n_pixels];
+
+    for (positive, negative) in bits {
+        ensure!(
+            positive.len() == n_pixels && negative.len() == n_pixels,
+            "Pattern images have different sizes"
+        );
+        for i in 0..n_pixels {
+            let diff = positive[i] - negative[i];
+            gray[i] = (gray[i] << 1) | (diff > 0.) as u32;
+            contrast[i] = contrast[i].min(diff.abs());
+        }
+    }
+
+    let coords = gray
+        .iter()
+        .zip(&contrast)
+        .map(|(g, c)| (*c >= min_contrast).then(|| gray_decode(*g)))
+        .collect();
+
+    Ok((coords, contrast))
+}
+
+impl ProjectorMap {
+    /// Decodes a full capture from column and row (pattern, inverse) luminance image pairs. Fails
+    /// like `decode_axis`, or if the images aren't "width" pixels wide.
+    pub fn decode(
+        columns: &[(Vec<f32>, Vec<f32>)],
+        rows: &[(Vec<f32>, Vec<f32>)],
+        width: usize,
+        min_contrast: f32,
+    ) -> Result<Self> {
+        let resolution = [1 << columns.len(), 1 << rows.len()];
+        let (cols, col_contrast) = decode_axis(columns, min_contrast).context("Columns")?;
+        let (rows, row_contrast) = decode_axis(rows, min_contrast).context("Rows")?;
+        ensure!(
+            cols.len() == rows.len(),
+            "Column and row images have different sizes"
+        );
+        ensure!(
+            width > 0 && cols.len() % width == 0,
+            "Images don't have a width of {width}"
+        );
+
+        let coords = cols
+            .iter()
+            .zip(&rows)
+            .map(|(c, r)| Some([(*c)?, (*r)?]))
+            .collect();
+
+        let confidence = col_contrast
+            .iter()
+            .zip(&row_contrast)
+            .map(|(c, r)| c.min(*r))
+            .collect();
+
+        Ok(Self {
+            coords,
+            confidence,
+            width,
+            resolution,
+        })
+    }
+
+    /// Decodes a capture of "patterns" from the mean luminance image of each pattern, in the same
+    /// order. Fails like `decode`, or if there are fewer images than patterns.
+    pub fn decode_sequence(
+        patterns: &[Pattern],
+        images: Vec<Vec<f32>>,
+        width: usize,
+        min_contrast: f32,
+    ) -> Result<Self> {
+        let mut columns = vec![];
+        let mut rows = vec![];
+        let mut images = images.into_iter();
+        for pair in patterns.chunks_exact(2) {
+            let (pos, neg) = images
+                .next()
+                .zip(images.next())
+                .context("Missing pattern images")?;
+            match pair[0].axis {
+                Axis::Columns => columns.push((pos, neg)),
+                Axis::Rows => rows.push((pos, neg)),
+            }
+        }
+
+        Self::decode(&columns, &rows, width, min_contrast)
+    }
+
+    /// Decoded projector (column, row) for each pixel
+    pub fn coords(&self) -> &[Option<[u32; 2]>] {
+        &self.coords
+    }
+
+    /// Decoding confidence (contrast) for each pixel
+    pub fn confidence(&self) -> &[f32] {
+        &self.confidence
+    }
+
+    /// Number of projector (columns, rows) the decoded coordinates range over
+    pub fn resolution(&self) -> [u32; 2] {
+        self.resolution
+    }
+
+    /// Pixel dimension width
+    pub fn width(&self) -> usize {
+        self.width
+    }
+
+    /// Pixel dimension height
+    pub fn height(&self) -> usize {
+        self.coords.len() / self.width
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Mean images of each pattern seen by a camera whose pixels each look at one projector
+    /// pixel, in order, with the projector's columns along the camera's rows
+    fn synthetic_images(patterns: &[Pattern], width: u32, height: u32) -> Vec<Vec<f32>> {
+        patterns
+            .iter()
+            .map(|pattern| {
+                (0..width * height)
+                    .map(|i| {
+                        let coord = match pattern.axis {
+                            Axis::Columns => i % width,
+                            Axis::Rows => i / width,
+                        };
+                        match pattern.is_lit(coord) {
+                            true => 0.9,
+                            false => 0.1,
+                        }
+                    })
+                    .collect()
+            })
+            .collect()
+    }
+
+    #[test]
+    fn gray_code_round_trip() {
+        for n in 0..1024 {
+            assert_eq!(gray_decode(gray_encode(n)), n);
+        }
+    }
+
+    #[test]
+    fn decodes_synthetic_capture() {
+        let patterns = pattern_sequence(5, 3);
+        let (width, height) = (32, 8);
+        let images = synthetic_images(&patterns, width, height);
+        let map = ProjectorMap::decode_sequence(&patterns, images, width as usize, 0.5).unwrap();
+
+        assert_eq!(map.resolution(), [32, 8]);
+        assert_eq!(map.height(), height as usize);
+        for (i, coord) in map.coords().iter().enumerate() {
+            let i = i as u32;
+            assert_eq!(*coord, Some([i % width, i / width]));
+        }
+    }
+
+    #[test]
+    fn rejects_low_contrast() {
+        let patterns = pattern_sequence(2, 2);
+        let images = synthetic_images(&patterns, 4, 4);
+        let map = ProjectorMap::decode_sequence(&patterns, images, 4, 0.9).unwrap();
+        assert!(map.coords().iter().all(Option::is_none));
+    }
+
+    #[test]
+    fn rejects_bad_captures() {
+        let patterns = pattern_sequence(2, 2);
+        let mut images = synthetic_images(&patterns, 4, 4);
+        let decode =
+            |images: Vec<Vec<f32>>| ProjectorMap::decode_sequence(&patterns, images, 4, 0.5);
+
+        assert!(
+            decode(images[..images.len() - 1].to_vec()).is_err(),
+            "Missing pattern"
+        );
+        images[3].pop();
+        assert!(decode(images).is_err(), "Mismatched sizes");
+        let no_columns = pattern_sequence(0, 2);
+        let images = synthetic_images(&no_columns, 4, 4);
+        assert!(ProjectorMap::decode_sequence(&no_columns, images, 4, 0.5).is_err());
+    }
+}
@@ -1,16 +1,54 @@
+use anyhow::{ensure, Result};
 use glam::Vec3;
 
+pub mod accumulate;
+pub mod bag;
+mod capture;
+pub mod codec;
+mod error;
+pub mod extrinsics;
+pub mod graycode;
+mod metadata;
+pub mod metrics;
+mod options;
+pub mod playback;
+pub mod ply;
+pub mod pool;
+pub mod profile;
+pub mod queue;
 mod realsense;
 mod realsense_utils;
+pub mod recording;
+pub mod sequence;
+pub mod stream;
 
-pub use realsense::realsense_mainloop;
+pub use capture::{CaptureCommand, CaptureHandle, Rs2Option, StreamConfig};
+pub use error::{CaptureError, CaptureEvent, CaptureStatus};
+pub use metadata::{FrameMetadata, StreamMetadata, TimestampDomain};
+pub use options::{SensorKind, SensorOptions, VisualPreset};
+pub use realsense::{list_devices, start_realsense};
+pub use realsense_utils::{Rs2ExtrinsicsSerde, Rs2IntrinsicsSerde};
 
-#[derive(Default)]
+use pool::{PointBuffers, Pool};
+
+/// Meters per unit of depth of every frame handed out. Extrinsics, calibration and the viewer all
+/// work in millimeters, so frames from sources with other depth units are converted as they're
+/// read.
+pub const DEPTH_SCALE: f32 = 0.001;
+
+#[derive(Default, Clone)]
 pub struct ImagePointCloud {
     valid: Vec<bool>,
     position: Vec<Vec3>,
     color: Vec<[u8; 3]>,
     width: usize,
+    /// Raw depth image, in depth units (empty if unavailable)
+    depth: Vec<u16>,
+    /// Unaligned color image, at the color stream's resolution (empty if unavailable)
+    raw_color: Vec<[u8; 3]>,
+    metadata: Box<FrameMetadata>,
+    /// Pool the buffers are returned to when dropped
+    pool: Option<Pool<PointBuffers>>,
 }
 
 /// 3D position relative to camera, RGB color
@@ -27,9 +65,110 @@ impl ImagePointCloud {
             position,
             color,
             width,
+            depth: vec![],
+            raw_color: vec![],
+            metadata: Box::default(),
+            pool: None,
         }
     }
 
+    /// Build a point cloud from pooled buffers, which are returned to "pool" when it is dropped
+    pub fn from_pool(buffers: PointBuffers, width: usize, pool: Pool<PointBuffers>) -> Self {
+        let PointBuffers {
+            valid,
+            position,
+            color,
+            depth,
+            raw_color,
+        } = buffers;
+        let mut cloud = Self::new(valid, position, color, width).with_raw(depth, raw_color);
+        cloud.pool = Some(pool);
+        cloud
+    }
+
+    /// Build a point cloud from a raw depth image and the color image aligned to it, deprojecting
+    /// with the depth intrinsics in "metadata". Fails if the images don't match "width" or the
+    /// intrinsics use a distortion model which can't be deprojected, since frames may come from
+    /// recordings or the network. Depth is converted to units of `DEPTH_SCALE`.
+    pub fn from_depth(
+        mut depth: Vec<u16>,
+        color: Vec<[u8; 3]>,
+        width: usize,
+        mut metadata: FrameMetadata,
+    ) -> Result<Self> {
+        to_depth_scale(&mut depth, &mut metadata.depth_scale);
+        ensure!(
+            width > 0 && depth.len().is_multiple_of(width) && color.len() == depth.len(),
+            "Depth and color images don't match a width of {width}"
+        );
+        ensure!(
+            metadata.depth.intrinsics.can_deproject(),
+            "Depth distortion model {} can't be deprojected",
+            metadata.depth.intrinsics.model
+        );
+        let intrinsics = realsense_rust::base::Rs2Intrinsics(metadata.depth.intrinsics.into());
+        let mut position = Vec::with_capacity(depth.len());
+        realsense_utils::deproject_image(&intrinsics, &depth, width, &mut position);
+        let valid = depth.iter().map(|d| *d != 0).collect();
+        let cloud = Self::new(valid, position, color, width)
+            .with_raw(depth, vec![])
+            .with_metadata(metadata);
+        Ok(cloud)
+    }
+
+    /// Build a point cloud from a raw depth image and an unaligned color image, aligning color to
+    /// depth and deprojecting with the intrinsics and extrinsics in "metadata". Fails like
+    /// `from_depth`, or if the images don't match the sizes in the intrinsics.
+    pub fn from_images(
+        mut depth: Vec<u16>,
+        raw_color: Vec<[u8; 3]>,
+        mut metadata: FrameMetadata,
+    ) -> Result<Self> {
+        to_depth_scale(&mut depth, &mut metadata.depth_scale);
+        let (depth_size, color_size) = (&metadata.depth.intrinsics, &metadata.color.intrinsics);
+        let pixels =
+            |i: &Rs2IntrinsicsSerde| (i.width.max(0) as usize) * (i.height.max(0) as usize);
+        ensure!(
+            depth.len() == pixels(depth_size) && raw_color.len() == pixels(color_size),
+            "Images don't match the sizes of their intrinsics"
+        );
+        ensure!(
+            metadata.depth.intrinsics.can_deproject() && color_size.model_is_known(),
+            "Unsupported distortion model"
+        );
+        let depth_intrinsics =
+            realsense_rust::base::Rs2Intrinsics(metadata.depth.intrinsics.into());
+        let color_intrinsics =
+            realsense_rust::base::Rs2Intrinsics(metadata.color.intrinsics.into());
+        let depth_to_color = realsense_rust::base::Rs2Extrinsics(metadata.depth_to_color.into());
+        let mut color = vec![[0; 3]; depth.len()];
+        realsense_utils::align_images(
+            &depth_intrinsics,
+            &depth_to_color,
+            &color_intrinsics,
+            &depth,
+            &raw_color,
+            &mut color,
+        );
+        let width = depth_intrinsics.width();
+        let raw_depth = depth.clone();
+        Ok(Self::from_depth(depth, color, width, metadata)?.with_raw(raw_depth, raw_color))
+    }
+
+    /// Attach the raw depth and unaligned color images this point cloud was computed from
+    pub fn with_raw(mut self, depth: Vec<u16>, raw_color: Vec<[u8; 3]>) -> Self {
+        assert!(depth.is_empty() || depth.len() == self.valid.len());
+        self.depth = depth;
+        self.raw_color = raw_color;
+        self
+    }
+
+    /// Attach capture metadata (device serial, timestamps, intrinsics) to this point cloud
+    pub fn with_metadata(mut self, metadata: FrameMetadata) -> Self {
+        self.metadata = Box::new(metadata);
+        self
+    }
+
     /// Returns a sample for each pixel
     pub fn iter_pixels(&self) -> impl Iterator<Item = Option<Sample>> + '_ {
         self.position
@@ -60,8 +199,74 @@ impl ImagePointCloud {
         &self.color
     }
 
+    /// Raw depth image, in units of `metadata().depth_scale` meters. Empty if unavailable.
+    pub fn depth(&self) -> &[u16] {
+        &self.depth
+    }
+
+    /// Raw depth image, or one recovered from the positions if the frame has none (e.g. frames
+    /// read from a recording). Invalid pixels are zero.
+    pub fn depth_image(&self) -> Vec<u16> {
+        if !self.depth.is_empty() {
+            return self.depth.clone();
+        }
+        self.iter_pixels()
+            .map(|sample| sample.map_or(0, |(pos, _)| pos.z.round() as u16))
+            .collect()
+    }
+
+    /// Color image before alignment to depth, sized according to `metadata().color.intrinsics`.
+    /// Empty if unavailable.
+    pub fn raw_color(&self) -> &[[u8; 3]] {
+        &self.raw_color
+    }
+
+    /// Serial number of the capturing device (empty if unknown)
+    pub fn serial(&self) -> &str {
+        &self.metadata.serial
+    }
+
+    /// How and when this frame was captured
+    pub fn metadata(&self) -> &FrameMetadata {
+        &self.metadata
+    }
+
     /// Whether each pixel is valid data
     pub fn valid(&self) -> &[bool] {
         &self.valid
     }
 }
+
+/// Convert a depth image in units of "depth_scale" meters to units of `DEPTH_SCALE`, updating
+/// "depth_scale" to match. Depths too far to represent become invalid. Images without a depth
+/// scale are left as they are.
+pub(crate) fn to_depth_scale(depth: &mut [u16], depth_scale: &mut f32) {
+    let scale = *depth_scale / DEPTH_SCALE;
+    if !scale.is_finite() || scale <= 0. || scale == 1. {
+        return;
+    }
+    for d in depth {
+        let scaled = (*d as f32 * scale).round();
+        *d = match scaled <= u16::MAX as f32 {
+            true => scaled as u16,
+            false => 0,
+        };
+    }
+    *depth_scale = DEPTH_SCALE;
+}
+
+impl Drop for ImagePointCloud {
+    fn drop(&mut self) {
+        if let Some(pool) = self.pool.take() {
+            let mut buffers = PointBuffers {
+                valid: std::mem::take(&mut self.valid),
+                position: std::mem::take(&mut self.position),
+                color: std::mem::take(&mut self.color),
+                depth: std::mem::take(&mut self.depth),
+                raw_color: std::mem::take(&mut self.raw_color),
+            };
+            buffers.clear();
+            pool.put(buffers);
+        }
+    }
+}
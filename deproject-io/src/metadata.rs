@@ -0,0 +1,43 @@
+use serde::{Deserialize, Serialize};
+
+use crate::realsense_utils::{Rs2ExtrinsicsSerde, Rs2IntrinsicsSerde};
+
+/// Describes how and when a frame was captured
+#[derive(Clone, Debug, Default, Serialize, Deserialize)]
+pub struct FrameMetadata {
+    /// Serial number of the capturing device (empty if unknown)
+    pub serial: String,
+    pub depth: StreamMetadata,
+    pub color: StreamMetadata,
+    /// Transform from the depth camera's frame to the color camera's frame
+    pub depth_to_color: Rs2ExtrinsicsSerde,
+    /// Meters per unit of raw depth
+    pub depth_scale: f32,
+}
+
+/// Per-stream part of `FrameMetadata`
+#[derive(Copy, Clone, Debug, Default, Serialize, Deserialize)]
+pub struct StreamMetadata {
+    /// Frame counter reported by the device
+    pub frame_number: u64,
+    /// Capture time in milliseconds, relative to `timestamp_domain`
+    pub timestamp: f64,
+    pub timestamp_domain: TimestampDomain,
+    /// Host time at which the frame arrived, in milliseconds since the Unix epoch, if reported
+    pub time_of_arrival: Option<i64>,
+    /// Exposure time in microseconds, if reported
+    pub exposure: Option<i64>,
+    pub intrinsics: Rs2IntrinsicsSerde,
+}
+
+/// Clock which a frame timestamp is measured against
+#[derive(Copy, Clone, Debug, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub enum TimestampDomain {
+    /// The device's internal clock
+    #[default]
+    HardwareClock,
+    /// The host's system clock
+    SystemTime,
+    /// The device's clock converted to the host's system clock
+    GlobalTime,
+}
@@ -0,0 +1,236 @@
+//! Per-stage timing of the capture and render pipeline. Each source (a device serial number, or
+//! the viewport) keeps a rolling window of recent durations for each stage.
+
+use std::collections::{BTreeMap, VecDeque};
+use std::io::{self, Write};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+/// Number of samples kept for each stage
+pub const HISTORY_LEN: usize = 512;
+
+/// A step of the pipeline which is timed
+#[derive(Copy, Clone, Debug, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub enum Stage {
+    /// Time between consecutive frames
+    Interval,
+    /// Waiting for the device to deliver a frameset
+    Wait,
+    /// Copying pixels out of the device's frames
+    Unpack,
+    /// Reprojecting color onto the depth image
+    Align,
+    /// Converting depth pixels into 3D points
+    Deproject,
+    /// Handing the frame to the consumer
+    Send,
+    /// Transforming and merging the clouds of every device
+    Fuse,
+    /// Copying geometry to the GPU
+    Upload,
+    /// GPU time spent drawing the scene
+    Draw,
+}
+
+/// The most recent durations recorded for a stage, in milliseconds
+#[derive(Clone, Debug)]
+pub struct RollingHistogram {
+    samples: VecDeque<f32>,
+    capacity: usize,
+}
+
+/// Summary of a `RollingHistogram`, in milliseconds
+#[derive(Copy, Clone, Debug, Default)]
+pub struct StageStats {
+    pub count: usize,
+    pub mean: f32,
+    pub min: f32,
+    pub p50: f32,
+    pub p95: f32,
+    pub max: f32,
+}
+
+/// Shared collection of stage timings. Clones refer to the same collection, so one can be handed
+/// to each capture thread and the renderer.
+#[derive(Clone, Default)]
+pub struct Metrics {
+    /// Histograms by source, then stage. Keyed by source first so recording can look the source
+    /// up by reference, and only allocate its name the first time it is seen.
+    stages: Arc<Mutex<BTreeMap<String, BTreeMap<Stage, RollingHistogram>>>>,
+}
+
+/// Records the time between successive laps as the duration of a stage
+pub struct StageTimer {
+    metrics: Metrics,
+    source: String,
+    last: Instant,
+}
+
+impl Stage {
+    pub const ALL: [Self; 9] = [
+        Self::Interval,
+        Self::Wait,
+        Self::Unpack,
+        Self::Align,
+        Self::Deproject,
+        Self::Send,
+        Self::Fuse,
+        Self::Upload,
+        Self::Draw,
+    ];
+
+    pub fn name(&self) -> &'static str {
+        match self {
+            Self::Interval => "interval",
+            Self::Wait => "wait",
+            Self::Unpack => "unpack",
+            Self::Align => "align",
+            Self::Deproject => "deproject",
+            Self::Send => "send",
+            Self::Fuse => "fuse",
+            Self::Upload => "upload",
+            Self::Draw => "draw",
+        }
+    }
+}
+
+impl RollingHistogram {
+    pub fn new(capacity: usize) -> Self {
+        Self {
+            samples: VecDeque::with_capacity(capacity),
+            capacity,
+        }
+    }
+
+    pub fn push(&mut self, duration: Duration) {
+        if self.samples.len() == self.capacity {
+            self.samples.pop_front();
+        }
+        self.samples.push_back(duration.as_secs_f32() * 1e3);
+    }
+
+    /// Samples in milliseconds, oldest first
+    pub fn samples(&self) -> impl Iterator<Item = f32> + '_ {
+        self.samples.iter().copied()
+    }
+
+    /// Most recent sample in milliseconds
+    pub fn last(&self) -> Option<f32> {
+        self.samples.back().copied()
+    }
+
+    pub fn stats(&self) -> StageStats {
+        if self.samples.is_empty() {
+            return StageStats::default();
+        }
+
+        let mut sorted: Vec<f32> = self.samples().collect();
+        sorted.sort_by(|a, b| a.total_cmp(b));
+        let percentile = |p: f32| sorted[((sorted.len() - 1) as f32 * p).round() as usize];
+
+        StageStats {
+            count: sorted.len(),
+            mean: sorted.iter().sum::<f32>() / sorted.len() as f32,
+            min: sorted[0],
+            p50: percentile(0.5),
+            p95: percentile(0.95),
+            max: sorted[sorted.len() - 1],
+        }
+    }
+
+    /// Counts samples in "n_bins" equal bins spanning 0 to "max" milliseconds. Samples above
+    /// "max" are counted in the last bin.
+    pub fn bins(&self, n_bins: usize, max: f32) -> Vec<usize> {
+        let mut bins = vec![0; n_bins];
+        if n_bins == 0 || max <= 0. {
+            return bins;
+        }
+        for sample in self.samples() {
+            let idx = ((sample / max) * n_bins as f32) as usize;
+            bins[idx.min(n_bins - 1)] += 1;
+        }
+        bins
+    }
+}
+
+impl Metrics {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Record one duration of a stage
+    pub fn record(&self, source: &str, stage: Stage, duration: Duration) {
+        let mut stages = self.stages.lock().unwrap();
+        if !stages.contains_key(source) {
+            stages.insert(source.to_string(), BTreeMap::new());
+        }
+        stages
+            .get_mut(source)
+            .unwrap()
+            .entry(stage)
+            .or_insert_with(|| RollingHistogram::new(HISTORY_LEN))
+            .push(duration);
+    }
+
+    /// Start timing stages of the given source
+    pub fn timer(&self, source: &str) -> StageTimer {
+        StageTimer {
+            metrics: self.clone(),
+            source: source.to_string(),
+            last: Instant::now(),
+        }
+    }
+
+    /// Copy of every histogram, ordered by source then stage
+    pub fn snapshot(&self) -> Vec<(String, Stage, RollingHistogram)> {
+        let stages = self.stages.lock().unwrap();
+        stages
+            .iter()
+            .flat_map(|(source, hists)| {
+                hists
+                    .iter()
+                    .map(move |(stage, hist)| (source.clone(), *stage, hist.clone()))
+            })
+            .collect()
+    }
+
+    /// Forget all recorded samples
+    pub fn clear(&self) {
+        self.stages.lock().unwrap().clear();
+    }
+
+    /// Writes a summary row for each source and stage, with times in milliseconds
+    pub fn write_csv(&self, w: &mut impl Write) -> io::Result<()> {
+        writeln!(w, "source,stage,count,mean_ms,min_ms,p50_ms,p95_ms,max_ms")?;
+        for (source, stage, hist) in self.snapshot() {
+            let s = hist.stats();
+            writeln!(
+                w,
+                "{},{},{},{},{},{},{},{}",
+                source,
+                stage.name(),
+                s.count,
+                s.mean,
+                s.min,
+                s.p50,
+                s.p95,
+                s.max
+            )?;
+        }
+        Ok(())
+    }
+}
+
+impl StageTimer {
+    /// Record the time since the last lap (or since the timer was created) as "stage"
+    pub fn lap(&mut self, stage: Stage) {
+        let now = Instant::now();
+        self.metrics.record(&self.source, stage, now - self.last);
+        self.last = now;
+    }
+
+    /// Start the next lap now, without recording anything
+    pub fn reset(&mut self) {
+        self.last = Instant::now();
+    }
+}
@@ -0,0 +1,127 @@
+use serde::{Deserialize, Serialize};
+
+use crate::capture::Rs2Option;
+
+/// Which of a device's sensors an option is set on
+#[derive(Copy, Clone, Debug, PartialEq, Eq, Hash, Serialize, Deserialize)]
+pub enum SensorKind {
+    Color,
+    Depth,
+}
+
+/// Depth sensor presets of the D400 series
+#[derive(Copy, Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub enum VisualPreset {
+    Custom,
+    Default,
+    Hand,
+    HighAccuracy,
+    HighDensity,
+    MediumDensity,
+}
+
+/// Sensor settings relevant to structured light capture. For consistent decoding the emitter
+/// should be disabled and color exposure/white balance locked.
+#[derive(Copy, Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub struct SensorOptions {
+    /// Color sensor automatic exposure and gain
+    pub auto_exposure: bool,
+    /// Color exposure time in microseconds, used when auto exposure is off
+    pub exposure: f32,
+    /// Color gain, used when auto exposure is off
+    pub gain: f32,
+    pub auto_white_balance: bool,
+    /// Color temperature in Kelvin, used when auto white balance is off
+    pub white_balance: f32,
+    /// Depth visual preset, applied before the emitter settings
+    pub visual_preset: VisualPreset,
+    /// Whether the IR pattern projector is on
+    pub emitter_enabled: bool,
+    /// IR pattern projector power in milliwatts
+    pub laser_power: f32,
+}
+
+impl VisualPreset {
+    pub const ALL: [Self; 6] = [
+        Self::Custom,
+        Self::Default,
+        Self::Hand,
+        Self::HighAccuracy,
+        Self::HighDensity,
+        Self::MediumDensity,
+    ];
+
+    /// Value of `Rs2Option::VisualPreset` for this preset
+    pub fn value(&self) -> f32 {
+        match self {
+            Self::Custom => 0.,
+            Self::Default => 1.,
+            Self::Hand => 2.,
+            Self::HighAccuracy => 3.,
+            Self::HighDensity => 4.,
+            Self::MediumDensity => 5.,
+        }
+    }
+}
+
+impl SensorOptions {
+    /// Options to set on the device, in the order they must be applied
+    pub fn to_device_options(&self) -> Vec<(SensorKind, Rs2Option, f32)> {
+        let flag = |b: bool| if b { 1. } else { 0. };
+
+        let mut options = vec![(
+            SensorKind::Color,
+            Rs2Option::EnableAutoExposure,
+            flag(self.auto_exposure),
+        )];
+        if !self.auto_exposure {
+            options.push((SensorKind::Color, Rs2Option::Exposure, self.exposure));
+            options.push((SensorKind::Color, Rs2Option::Gain, self.gain));
+        }
+
+        options.push((
+            SensorKind::Color,
+            Rs2Option::EnableAutoWhiteBalance,
+            flag(self.auto_white_balance),
+        ));
+        if !self.auto_white_balance {
+            options.push((
+                SensorKind::Color,
+                Rs2Option::WhiteBalance,
+                self.white_balance,
+            ));
+        }
+
+        options.push((
+            SensorKind::Depth,
+            Rs2Option::VisualPreset,
+            self.visual_preset.value(),
+        ));
+        options.push((
+            SensorKind::Depth,
+            Rs2Option::EmitterEnabled,
+            flag(self.emitter_enabled),
+        ));
+        if self.emitter_enabled {
+            options.push((SensorKind::Depth, Rs2Option::LaserPower, self.laser_power));
+        }
+
+        options
+    }
+}
+
+/// Factory defaults of the D415
+impl Default for SensorOptions {
+    fn default() -> Self {
+        Self {
+            auto_exposure: true,
+            exposure: 166.,
+            gain: 64.,
+            auto_white_balance: true,
+            white_balance: 4600.,
+            visual_preset: VisualPreset::Default,
+            emitter_enabled: true,
+            laser_power: 150.,
+        }
+    }
+}
@@ -0,0 +1,150 @@
+//! Playing recorded frames in real time and in a loop, as if they were streamed from a device
+
+use std::sync::mpsc::{self, Receiver, RecvTimeoutError};
+use std::thread;
+use std::time::{Duration, Instant};
+
+use anyhow::{ensure, Result};
+
+use crate::capture::{wait_to_restart, CaptureCommand, CaptureHandle};
+use crate::error::{CaptureError, CaptureEvent, CaptureStatus};
+use crate::ImagePointCloud;
+
+/// Delay before reopening a recording which failed to play
+const RETRY_DELAY: Duration = Duration::from_secs(2);
+
+/// Frames read from a file in the order they were recorded
+pub trait RecordedFrames {
+    /// The next frame and the time it was recorded, relative to any fixed origin, or None at the
+    /// end of the recording
+    fn next_frame(&mut self) -> Result<Option<(ImagePointCloud, Duration)>>;
+}
+
+/// Starts playing "frames" on a new thread, calling "reopen" to start over at the end or after an
+/// error. Like `start_realsense`, the thread calls "callback" with each frame and any change in
+/// status, and reports status under "serial".
+pub(crate) fn start_playback<F: RecordedFrames + Send + 'static>(
+    serial: String,
+    frames: F,
+    reopen: impl FnMut() -> Result<F> + Send + 'static,
+    callback: impl FnMut(CaptureEvent) -> bool + Send + 'static,
+) -> CaptureHandle {
+    let (tx, rx) = mpsc::channel();
+    let thread_serial = serial.clone();
+    let thread =
+        thread::spawn(move || playback_mainloop(callback, &thread_serial, frames, reopen, rx));
+    CaptureHandle::new(serial, tx, thread)
+}
+
+/// Why playback stopped without error
+enum PlaybackExit {
+    Stop,
+    Pause,
+}
+
+fn playback_mainloop<F: RecordedFrames>(
+    mut callback: impl FnMut(CaptureEvent) -> bool,
+    serial: &str,
+    frames: F,
+    mut reopen: impl FnMut() -> Result<F>,
+    commands: Receiver<CaptureCommand>,
+) {
+    let status = |callback: &mut dyn FnMut(CaptureEvent) -> bool, status| {
+        callback(CaptureEvent::Status {
+            serial: serial.to_string(),
+            status,
+        })
+    };
+
+    // Kept while paused so that playback resumes where it left off
+    let mut frames = Some(frames);
+    loop {
+        let mut streaming = false;
+        let result = play(&mut frames, &mut reopen, &commands, |frame| {
+            if !streaming {
+                streaming = true;
+                if !status(&mut callback, CaptureStatus::Streaming) {
+                    return false;
+                }
+            }
+            callback(CaptureEvent::Frame(frame))
+        });
+
+        let wait = match result {
+            Ok(PlaybackExit::Stop) => {
+                status(&mut callback, CaptureStatus::Stopped);
+                return;
+            }
+            Ok(PlaybackExit::Pause) => {
+                if !status(&mut callback, CaptureStatus::Paused) {
+                    return;
+                }
+                None
+            }
+            Err(e) => {
+                frames = None;
+                let reconnecting = CaptureStatus::Reconnecting {
+                    error: CaptureError::Recording(format!("{e:#}")),
+                    retry_in: RETRY_DELAY,
+                };
+                if !status(&mut callback, reconnecting) {
+                    return;
+                }
+                Some(RETRY_DELAY)
+            }
+        };
+
+        if !wait_to_restart(&commands, wait) {
+            status(&mut callback, CaptureStatus::Stopped);
+            return;
+        }
+    }
+}
+
+/// Pass frames to "on_frame" at the pace they were recorded, starting over at the end, until a
+/// command ends playback, "on_frame" returns false or the recording can't be read
+fn play<F: RecordedFrames>(
+    frames: &mut Option<F>,
+    reopen: &mut impl FnMut() -> Result<F>,
+    commands: &Receiver<CaptureCommand>,
+    mut on_frame: impl FnMut(ImagePointCloud) -> bool,
+) -> Result<PlaybackExit> {
+    // Recording time of the first frame played since (re)starting, and when it was played
+    let mut start: Option<(Duration, Instant)> = None;
+    let mut any_frames = false;
+    loop {
+        let reader = match frames {
+            Some(reader) => reader,
+            None => frames.insert(reopen()?),
+        };
+        let Some((frame, time)) = reader.next_frame()? else {
+            ensure!(any_frames, "Recording has no frames");
+            *frames = None;
+            start = None;
+            any_frames = false;
+            continue;
+        };
+        any_frames = true;
+
+        let (start_time, start_instant) = *start.get_or_insert((time, Instant::now()));
+        let due = start_instant + time.saturating_sub(start_time);
+        loop {
+            let command = match commands.recv_timeout(due.saturating_duration_since(Instant::now()))
+            {
+                Ok(command) => command,
+                Err(RecvTimeoutError::Timeout) => break,
+                Err(RecvTimeoutError::Disconnected) => return Ok(PlaybackExit::Stop),
+            };
+            match command {
+                CaptureCommand::Stop => return Ok(PlaybackExit::Stop),
+                CaptureCommand::Pause(true) => return Ok(PlaybackExit::Pause),
+                // The recording's settings can't be changed
+                _ => (),
+            }
+        }
+
+        if !on_frame(frame) {
+            return Ok(PlaybackExit::Stop);
+        }
+    }
+}
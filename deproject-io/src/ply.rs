@@ -0,0 +1,40 @@
+//! Writing point clouds as binary PLY files, readable by most point cloud tools
+
+use std::io::Write;
+
+use anyhow::Result;
+use glam::Vec3;
+
+use crate::ImagePointCloud;
+
+/// Writes points with colors as a little-endian binary PLY file
+pub fn write_ply(w: &mut impl Write, points: &[(Vec3, [u8; 3])]) -> Result<()> {
+    writeln!(w, "ply")?;
+    writeln!(w, "format binary_little_endian 1.0")?;
+    writeln!(w, "element vertex {}", points.len())?;
+    for axis in ["x", "y", "z"] {
+        writeln!(w, "property float {axis}")?;
+    }
+    for channel in ["red", "green", "blue"] {
+        writeln!(w, "property uchar {channel}")?;
+    }
+    writeln!(w, "end_header")?;
+
+    for (pos, color) in points {
+        for v in pos.to_array() {
+            w.write_all(&v.to_le_bytes())?;
+        }
+        w.write_all(color)?;
+    }
+    Ok(())
+}
+
+/// Valid points of a frame, in meters in the depth camera's frame
+pub fn frame_points(frame: &ImagePointCloud) -> Vec<(Vec3, [u8; 3])> {
+    let depth_scale = frame.metadata().depth_scale;
+    frame
+        .iter_pixels()
+        .flatten()
+        .map(|(pos, color)| (pos * depth_scale, color))
+        .collect()
+}
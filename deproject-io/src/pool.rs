@@ -0,0 +1,73 @@
+//! Recycling of large per-frame allocations. A capture thread takes buffers from a pool to build
+//! each frame, and the buffers return to the pool when the consumer drops the frame.
+
+use std::sync::{Arc, Mutex};
+
+use glam::Vec3;
+
+/// Number of spare buffers a capture thread keeps around
+pub const FRAME_POOL_LEN: usize = 8;
+
+/// A shared stack of reusable values. Clones refer to the same pool.
+pub struct Pool<T> {
+    free: Arc<Mutex<Vec<T>>>,
+    max_len: usize,
+}
+
+/// Storage backing an `ImagePointCloud`
+#[derive(Default)]
+pub struct PointBuffers {
+    pub valid: Vec<bool>,
+    pub position: Vec<Vec3>,
+    pub color: Vec<[u8; 3]>,
+    pub depth: Vec<u16>,
+    pub raw_color: Vec<[u8; 3]>,
+}
+
+impl<T: Default> Pool<T> {
+    /// Create a pool which keeps at most "max_len" spare values
+    pub fn new(max_len: usize) -> Self {
+        Self {
+            free: Default::default(),
+            max_len,
+        }
+    }
+
+    /// Take a previously returned value, or a default one if none are spare
+    pub fn take(&self) -> T {
+        self.free.lock().unwrap().pop().unwrap_or_default()
+    }
+
+    /// Return a value for reuse. It is dropped if the pool is full.
+    pub fn put(&self, value: T) {
+        let mut free = self.free.lock().unwrap();
+        if free.len() < self.max_len {
+            free.push(value);
+        }
+    }
+
+    /// Number of spare values
+    pub fn spare(&self) -> usize {
+        self.free.lock().unwrap().len()
+    }
+}
+
+impl<T> Clone for Pool<T> {
+    fn clone(&self) -> Self {
+        Self {
+            free: self.free.clone(),
+            max_len: self.max_len,
+        }
+    }
+}
+
+impl PointBuffers {
+    /// Empty every buffer, keeping their allocations
+    pub fn clear(&mut self) {
+        self.valid.clear();
+        self.position.clear();
+        self.color.clear();
+        self.depth.clear();
+        self.raw_color.clear();
+    }
+}
@@ -0,0 +1,62 @@
+//! Rig profiles: where each device of a rig is placed and how it streams, in a file shared
+//! between machines and between the viewer and the command line tools
+
+use std::collections::BTreeMap;
+use std::fs::File;
+use std::io::{BufReader, BufWriter};
+use std::path::Path;
+
+use anyhow::{Context, Result};
+use serde::de::DeserializeOwned;
+use serde::{Deserialize, Serialize};
+
+use crate::extrinsics::Extrinsics;
+use crate::{SensorOptions, StreamConfig};
+
+/// Placement of one device of a profile
+#[derive(Copy, Clone, Debug, Serialize, Deserialize)]
+#[serde(default)]
+pub struct DeviceProfile {
+    pub extrinsics: Extrinsics,
+    pub visible: bool,
+}
+
+/// Devices, stream and sensor settings of a rig, keyed by serial number. "V" holds settings only
+/// the viewer has, such as the projector and capture parameters. Tools without them leave them
+/// out, so importing their profiles keeps the viewer's own.
+#[derive(Default, Serialize, Deserialize)]
+#[serde(default)]
+pub struct RigProfile<V = ()> {
+    pub devices: BTreeMap<String, DeviceProfile>,
+    pub stream: StreamConfig,
+    pub options: SensorOptions,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub viewer: Option<V>,
+}
+
+impl<V: DeserializeOwned + Default> RigProfile<V> {
+    pub fn load(path: impl AsRef<Path>) -> Result<Self> {
+        let path = path.as_ref();
+        let file = File::open(path).with_context(|| format!("Opening {}", path.display()))?;
+        serde_json::from_reader(BufReader::new(file))
+            .with_context(|| format!("Reading {}", path.display()))
+    }
+}
+
+impl<V: Serialize> RigProfile<V> {
+    pub fn save(&self, path: impl AsRef<Path>) -> Result<()> {
+        let path = path.as_ref();
+        let file = File::create(path).with_context(|| format!("Creating {}", path.display()))?;
+        serde_json::to_writer_pretty(BufWriter::new(file), self)?;
+        Ok(())
+    }
+}
+
+impl Default for DeviceProfile {
+    fn default() -> Self {
+        Self {
+            extrinsics: Extrinsics::default(),
+            visible: true,
+        }
+    }
+}
@@ -0,0 +1,87 @@
+//! Channel carrying capture events from capture threads to a consumer. The number of queued
+//! frames is bounded; when a slow consumer falls behind, the oldest frames are discarded. Status
+//! events are never discarded.
+
+use std::collections::VecDeque;
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use std::sync::{Arc, Mutex};
+
+use crate::CaptureEvent;
+
+struct Shared {
+    events: Mutex<VecDeque<CaptureEvent>>,
+    max_frames: usize,
+    dropped: AtomicU64,
+    receiver_alive: AtomicBool,
+}
+
+/// Sending half of an `event_channel`
+#[derive(Clone)]
+pub struct EventSender {
+    shared: Arc<Shared>,
+}
+
+/// Receiving half of an `event_channel`
+pub struct EventReceiver {
+    shared: Arc<Shared>,
+}
+
+/// Creates a channel which holds at most "max_frames" frames at a time
+pub fn event_channel(max_frames: usize) -> (EventSender, EventReceiver) {
+    let shared = Arc::new(Shared {
+        events: Mutex::new(VecDeque::new()),
+        max_frames: max_frames.max(1),
+        dropped: AtomicU64::new(0),
+        receiver_alive: AtomicBool::new(true),
+    });
+    (
+        EventSender {
+            shared: shared.clone(),
+        },
+        EventReceiver { shared },
+    )
+}
+
+impl EventSender {
+    /// Queue an event, discarding the oldest queued frame if the queue is full. Returns false once
+    /// the receiver has been dropped.
+    pub fn send(&self, event: CaptureEvent) -> bool {
+        if !self.shared.receiver_alive.load(Ordering::Relaxed) {
+            return false;
+        }
+
+        let mut events = self.shared.events.lock().unwrap();
+        if matches!(event, CaptureEvent::Frame(_)) {
+            let is_frame = |e: &CaptureEvent| matches!(e, CaptureEvent::Frame(_));
+            if events.iter().filter(|e| is_frame(e)).count() >= self.shared.max_frames {
+                if let Some(oldest) = events.iter().position(is_frame) {
+                    // Dropping the frame returns its buffers to their pool
+                    events.remove(oldest);
+                    self.shared.dropped.fetch_add(1, Ordering::Relaxed);
+                }
+            }
+        }
+        events.push_back(event);
+
+        true
+    }
+}
+
+impl EventReceiver {
+    /// Takes every queued event, oldest first, without blocking
+    pub fn try_iter(&self) -> impl Iterator<Item = CaptureEvent> {
+        let events = std::mem::take(&mut *self.shared.events.lock().unwrap());
+        events.into_iter()
+    }
+
+    /// Number of frames discarded because the queue was full
+    pub fn dropped_frames(&self) -> u64 {
+        self.shared.dropped.load(Ordering::Relaxed)
+    }
+}
+
+impl Drop for EventReceiver {
+    fn drop(&mut self) {
+        self.shared.receiver_alive.store(false, Ordering::Relaxed);
+    }
+}
@@ -1,130 +1,406 @@
-use anyhow::{ensure, Ok, Result};
 use std::collections::HashSet;
-use std::fs::File;
-use std::io::BufWriter;
-use std::path::PathBuf;
-
-use std::sync::mpsc;
-use std::sync::mpsc::{Receiver, Sender};
+use std::ffi::CString;
+use std::sync::mpsc::{self, Receiver, RecvTimeoutError, TryRecvError};
+use std::thread;
+use std::time::Duration;
 use std::time::Instant;
-use std::{path::Path, time::Duration};
 
 use realsense_rust::{
+    base::Rs2Intrinsics,
     config::Config,
     context::Context,
     frame::PixelKind,
-    frame::{ColorFrame, DepthFrame},
-    kind::{Rs2CameraInfo, Rs2Format, Rs2StreamKind},
-    pipeline::InactivePipeline,
+    frame::{ColorFrame, DepthFrame, FrameEx},
+    kind::{
+        Rs2CameraInfo, Rs2Extension, Rs2Format, Rs2FrameMetadata, Rs2StreamKind,
+        Rs2TimestampDomain,
+    },
+    pipeline::{ActivePipeline, FrameWaitError, InactivePipeline},
 };
 
-use realsense_rust::{base::*, kind::Rs2DistortionModel};
-use serde::{Deserialize, Serialize};
-
-use crate::ImagePointCloud;
+use crate::capture::{CaptureCommand, CaptureHandle, Rs2Option, StreamConfig};
+use crate::error::{CaptureError, CaptureEvent, CaptureStatus};
+use crate::metrics::{Metrics, Stage};
+use crate::options::SensorKind;
+use crate::pool::{PointBuffers, Pool, FRAME_POOL_LEN};
+use crate::{to_depth_scale, FrameMetadata, ImagePointCloud, StreamMetadata, TimestampDomain};
 
 use crate::realsense_utils::*;
 
-/// Gets frames from the realsense, processes them, and then calls "callback". Intended to be
-/// embedded in an external thread, since this method never returns
-pub fn realsense_mainloop(mut callback: impl FnMut(ImagePointCloud), color_width: usize, color_height: usize, depth_width: usize, depth_height: usize, fps: usize) -> Result<()> {
-    // Check for depth or color-compatible devices.
+/// Shortest delay before reconnecting after an error
+const MIN_BACKOFF: Duration = Duration::from_millis(250);
+
+/// Longest delay before reconnecting after repeated errors
+const MAX_BACKOFF: Duration = Duration::from_secs(8);
+
+/// Meters per depth unit, if the device doesn't report it
+const DEFAULT_DEPTH_SCALE: f32 = 1e-3;
+
+/// Returns the serial numbers of all connected devices
+pub fn list_devices() -> Result<Vec<String>, CaptureError> {
     let queried_devices = HashSet::new(); // Query any devices
-    let context = Context::new()?;
+    let context = Context::new().map_err(CaptureError::device)?;
     let devices = context.query_devices(queried_devices);
-    ensure!(!devices.is_empty(), "No devices found");
+    Ok(devices
+        .iter()
+        .filter_map(|device| device.info(Rs2CameraInfo::SerialNumber))
+        .map(|serial| serial.to_string_lossy().into_owned())
+        .collect())
+}
 
-    let device = &devices[0];
+/// Starts capturing from the device with the given serial number on a new thread. The thread
+/// calls "callback" with each frame and with any change in connection status, and exits once
+/// stopped through the returned handle or once "callback" returns false. The time taken by each
+/// processing stage is recorded in "metrics" under the device's serial number.
+pub fn start_realsense(serial: String, config: StreamConfig, metrics: Metrics, callback: impl FnMut(CaptureEvent) -> bool + Send + 'static) -> CaptureHandle {
+    let (tx, rx) = mpsc::channel();
+    let thread_serial = serial.clone();
+    let thread = thread::spawn(move || realsense_mainloop(callback, &thread_serial, config, metrics, rx));
+    CaptureHandle::new(serial, tx, thread)
+}
+
+/// Settings which persist across reconnects
+struct CaptureState {
+    config: StreamConfig,
+    options: Vec<(SensorKind, Rs2Option, f32)>,
+    /// Options have changed since they were last applied to the device
+    options_dirty: bool,
+    paused: bool,
+    metrics: Metrics,
+}
+
+/// Why streaming from a device ended without error
+enum StreamExit {
+    Stop,
+    /// Close and reopen the device, e.g. to apply a new configuration or pause
+    Restart,
+}
+
+/// Gets frames from the realsense with the given serial number, processes them, and then calls
+/// "callback". Errors are reported through the callback and the device is reconnected with
+/// exponential backoff. Returns once a stop command arrives or "callback" returns false.
+fn realsense_mainloop(mut callback: impl FnMut(CaptureEvent) -> bool, serial: &str, config: StreamConfig, metrics: Metrics, commands: Receiver<CaptureCommand>) {
+    let mut state = CaptureState {
+        config,
+        options: vec![],
+        options_dirty: false,
+        paused: false,
+        metrics,
+    };
+
+    let mut backoff = MIN_BACKOFF;
+    loop {
+        if state.paused {
+            if !send_status(&mut callback, serial, CaptureStatus::Paused) {
+                return;
+            }
+            while state.paused {
+                let command = commands.recv().unwrap_or(CaptureCommand::Stop);
+                if let Some(StreamExit::Stop) = state.apply(command) {
+                    send_status(&mut callback, serial, CaptureStatus::Stopped);
+                    return;
+                }
+            }
+        }
+
+        if !send_status(&mut callback, serial, CaptureStatus::Connecting) {
+            return;
+        }
+
+        let mut streaming = false;
+        let result = stream_device(
+            |event| {
+                if !streaming {
+                    streaming = true;
+                    if !send_status(&mut callback, serial, CaptureStatus::Streaming) {
+                        return false;
+                    }
+                }
+                callback(event)
+            },
+            serial,
+            &mut state,
+            &commands,
+        );
+
+        let error = match result {
+            Ok(StreamExit::Stop) => {
+                send_status(&mut callback, serial, CaptureStatus::Stopped);
+                return;
+            }
+            Ok(StreamExit::Restart) => {
+                backoff = MIN_BACKOFF;
+                continue;
+            }
+            Err(e) => e,
+        };
+
+        // Only back off further if we never managed to stream
+        if streaming {
+            backoff = MIN_BACKOFF;
+        }
+        let delay = backoff;
+        backoff = (backoff * 2).min(MAX_BACKOFF);
+
+        let reconnecting = CaptureStatus::Reconnecting {
+            error,
+            retry_in: delay,
+        };
+        if !send_status(&mut callback, serial, reconnecting) {
+            return;
+        }
+
+        // Wait out the backoff, staying responsive to commands
+        let deadline = Instant::now() + delay;
+        while let Some(remaining) = deadline.checked_duration_since(Instant::now()) {
+            match commands.recv_timeout(remaining) {
+                Ok(command) => {
+                    if let Some(StreamExit::Stop) = state.apply(command) {
+                        send_status(&mut callback, serial, CaptureStatus::Stopped);
+                        return;
+                    }
+                }
+                Err(RecvTimeoutError::Timeout) => break,
+                Err(RecvTimeoutError::Disconnected) => return,
+            }
+        }
+    }
+}
+
+impl CaptureState {
+    /// Update settings from a command, returning whether streaming needs to end
+    fn apply(&mut self, command: CaptureCommand) -> Option<StreamExit> {
+        match command {
+            CaptureCommand::Stop => Some(StreamExit::Stop),
+            CaptureCommand::Pause(paused) => {
+                let changed = self.paused != paused;
+                self.paused = paused;
+                changed.then_some(StreamExit::Restart)
+            }
+            CaptureCommand::Reconfigure(config) => {
+                let changed = self.config != config;
+                self.config = config;
+                changed.then_some(StreamExit::Restart)
+            }
+            CaptureCommand::SetOption(sensor, option, value) => {
+                self.options.retain(|(s, o, _)| (*s, *o) != (sensor, option));
+                self.options.push((sensor, option, value));
+                self.options_dirty = true;
+                None
+            }
+        }
+    }
+}
+
+fn send_status(callback: &mut impl FnMut(CaptureEvent) -> bool, serial: &str, status: CaptureStatus) -> bool {
+    callback(CaptureEvent::Status {
+        serial: serial.to_string(),
+        status,
+    })
+}
+
+/// Set options on the matching sensors of the device, in order
+fn apply_options(pipeline: &ActivePipeline, options: &[(SensorKind, Rs2Option, f32)]) {
+    for mut sensor in pipeline.profile().device().sensors() {
+        let kind = match sensor.extension() {
+            Rs2Extension::ColorSensor => SensorKind::Color,
+            Rs2Extension::DepthSensor | Rs2Extension::DepthStereoSensor => SensorKind::Depth,
+            _ => continue,
+        };
+
+        for (_, option, value) in options.iter().filter(|(s, _, _)| *s == kind) {
+            if !sensor.supports_option(*option) {
+                continue;
+            }
+            if let Err(e) = sensor.set_option(*option, *value) {
+                eprintln!("Failed to set {option:?} to {value}: {e}");
+            }
+        }
+    }
+}
+
+/// Meters per unit of raw depth reported by the device's depth sensor
+fn depth_scale(pipeline: &ActivePipeline) -> f32 {
+    pipeline
+        .profile()
+        .device()
+        .sensors()
+        .iter()
+        .filter(|s| {
+            matches!(
+                s.extension(),
+                Rs2Extension::DepthSensor | Rs2Extension::DepthStereoSensor
+            )
+        })
+        .find_map(|s| s.get_option(Rs2Option::DepthUnits))
+        .unwrap_or(DEFAULT_DEPTH_SCALE)
+}
+
+fn stream_metadata(frame: &impl FrameEx, intrinsics: &Rs2Intrinsics) -> StreamMetadata {
+    StreamMetadata {
+        frame_number: frame.frame_number(),
+        timestamp: frame.timestamp(),
+        timestamp_domain: match frame.timestamp_domain() {
+            Rs2TimestampDomain::HardwareClock => TimestampDomain::HardwareClock,
+            Rs2TimestampDomain::SystemTime => TimestampDomain::SystemTime,
+            Rs2TimestampDomain::GlobalTime => TimestampDomain::GlobalTime,
+        },
+        time_of_arrival: frame.metadata(Rs2FrameMetadata::TimeOfArrival),
+        exposure: frame.metadata(Rs2FrameMetadata::ActualExposure),
+        intrinsics: intrinsics.0.into(),
+    }
+}
+
+/// Streams frames from the device until an error occurs, a command requires the device to be
+/// closed, or "callback" returns false
+fn stream_device(mut callback: impl FnMut(CaptureEvent) -> bool, serial: &str, state: &mut CaptureState, commands: &Receiver<CaptureCommand>) -> Result<StreamExit, CaptureError> {
+    let StreamConfig {
+        color_width,
+        color_height,
+        depth_width,
+        depth_height,
+        fps,
+    } = state.config;
+
+    let context = Context::new().map_err(CaptureError::device)?;
+    if !list_devices()?.iter().any(|s| s == serial) {
+        return Err(CaptureError::DeviceNotFound(serial.to_string()));
+    }
 
     // TODO: Support devices other than the D415!
     // Create pipeline
-    let pipeline = InactivePipeline::try_from(&context)?;
+    let pipeline = InactivePipeline::try_from(&context).map_err(CaptureError::device)?;
+    let serial_cstr = CString::new(serial).map_err(CaptureError::device)?;
     let mut config = Config::new();
     config
-        .enable_device_from_serial(device.info(Rs2CameraInfo::SerialNumber).unwrap())?
-        .disable_all_streams()?
-        .enable_stream(Rs2StreamKind::Color, None, color_width, color_height, Rs2Format::Bgr8, fps)?
-        .enable_stream(Rs2StreamKind::Depth, None, depth_width, depth_height, Rs2Format::Z16, fps)
-        .unwrap();
+        .enable_device_from_serial(&serial_cstr)
+        .and_then(|c| c.disable_all_streams())
+        .and_then(|c| c.enable_stream(Rs2StreamKind::Color, None, color_width, color_height, Rs2Format::Bgr8, fps))
+        .and_then(|c| c.enable_stream(Rs2StreamKind::Depth, None, depth_width, depth_height, Rs2Format::Z16, fps))
+        .map_err(CaptureError::device)?;
 
     // Change pipeline's type from InactivePipeline -> ActivePipeline
-    let mut pipeline = pipeline.start(Some(config))?;
+    let mut pipeline = pipeline.start(Some(config)).map_err(CaptureError::device)?;
+    apply_options(&pipeline, &state.options);
+    state.options_dirty = false;
+    let depth_scale = depth_scale(&pipeline);
 
     let streams = pipeline.profile().streams();
 
     let depth_stream = streams
         .iter()
         .find(|p| p.kind() == Rs2StreamKind::Depth)
-        .unwrap();
+        .ok_or(CaptureError::MissingStream("depth"))?;
     let color_stream = streams
         .iter()
         .find(|p| p.kind() == Rs2StreamKind::Color)
-        .unwrap();
+        .ok_or(CaptureError::MissingStream("color"))?;
 
-    let depth_intrinsics = depth_stream.intrinsics()?;
-    let depth_to_color_extrinsics = depth_stream.extrinsics(color_stream)?;
-    let color_intrinsics = color_stream.intrinsics()?;
+    let depth_intrinsics = depth_stream.intrinsics().map_err(CaptureError::device)?;
+    let depth_to_color_extrinsics = depth_stream
+        .extrinsics(color_stream)
+        .map_err(CaptureError::device)?;
+    let color_intrinsics = color_stream.intrinsics().map_err(CaptureError::device)?;
 
-    let mut in_color_buf: Vec<[u8; 3]> = vec![];
-    let mut in_depth_buf: Vec<u16> = vec![];
-    let mut out_color_buf: Vec<[u8; 3]> = vec![];
+    let pool: Pool<PointBuffers> = Pool::new(FRAME_POOL_LEN);
 
-    let mut last_elap = Instant::now();
+    let mut timer = state.metrics.timer(serial);
+    let mut last_frame: Option<Instant> = None;
 
     let timeout = Duration::from_millis(2000);
     loop {
-        let fps = 1. / last_elap.elapsed().as_secs_f32();
-        println!("FPS: {fps}");
+        loop {
+            match commands.try_recv() {
+                Ok(command) => {
+                    if let Some(exit) = state.apply(command) {
+                        return Ok(exit);
+                    }
+                }
+                Err(TryRecvError::Empty) => break,
+                Err(TryRecvError::Disconnected) => return Ok(StreamExit::Stop),
+            }
+        }
+
+        if state.options_dirty {
+            apply_options(&pipeline, &state.options);
+            state.options_dirty = false;
+        }
+
+        timer.reset();
+        let frames = pipeline.wait(Some(timeout)).map_err(|e| match e {
+            FrameWaitError::DidTimeoutBeforeFrameArrival => CaptureError::Timeout,
+            e => CaptureError::device(e),
+        })?;
+        timer.lap(Stage::Wait);
 
-        let frames = pipeline.wait(Some(timeout)).unwrap();
+        let now = Instant::now();
+        if let Some(last) = last_frame.replace(now) {
+            state.metrics.record(serial, Stage::Interval, now - last);
+        }
 
-        let color_frame: &ColorFrame = &frames.frames_of_type()[0];
-        let depth_frame: &DepthFrame = &frames.frames_of_type()[0];
+        let color_frames: Vec<ColorFrame> = frames.frames_of_type();
+        let depth_frames: Vec<DepthFrame> = frames.frames_of_type();
+        let color_frame = color_frames
+            .first()
+            .ok_or(CaptureError::MissingFrame("color"))?;
+        let depth_frame = depth_frames
+            .first()
+            .ok_or(CaptureError::MissingFrame("depth"))?;
 
-        in_depth_buf.clear();
-        in_color_buf.clear();
-        out_color_buf.clear();
+        // Buffers come back cleared once the consumer drops a frame
+        let mut buffers = pool.take();
 
-        in_depth_buf.extend(depth_frame.iter().map(|p| match p {
-            PixelKind::Z16 { depth } => depth,
-            _ => panic!("{:?}", p),
-        }));
+        for p in depth_frame.iter() {
+            match p {
+                PixelKind::Z16 { depth } => buffers.depth.push(*depth),
+                _ => return Err(CaptureError::UnexpectedPixelFormat(format!("{:?}", p))),
+            }
+        }
 
-        in_color_buf.extend(color_frame.iter().map(|p| match p {
-            PixelKind::Bgr8 { b, g, r } => [*r, *g, *b],
-            _ => panic!("{:?}", p),
-        }));
+        for p in color_frame.iter() {
+            match p {
+                PixelKind::Bgr8 { b, g, r } => buffers.raw_color.push([*r, *g, *b]),
+                _ => return Err(CaptureError::UnexpectedPixelFormat(format!("{:?}", p))),
+            }
+        }
 
-        out_color_buf.resize(in_depth_buf.len(), [0; 3]);
+        let mut frame_depth_scale = depth_scale;
+        to_depth_scale(&mut buffers.depth, &mut frame_depth_scale);
+        buffers.color.resize(buffers.depth.len(), [0; 3]);
+        timer.lap(Stage::Unpack);
 
         align_images(
             &depth_intrinsics,
             &depth_to_color_extrinsics,
             &color_intrinsics,
-            &in_depth_buf,
-            &in_color_buf,
-            &mut out_color_buf,
+            &buffers.depth,
+            &buffers.raw_color,
+            &mut buffers.color,
         );
+        timer.lap(Stage::Align);
 
         // Convert for use elsewhere
-        let valid = in_depth_buf.iter().map(|depth| *depth != 0).collect();
-        let mut position = vec![];
-        let (width, height) = (color_frame.width(), color_frame.height());
-        for y in 0..height {
-            for x in 0..width {
-                let pixel_idx = y * width + x;
-                let pt = rs2_deproject_pixel_to_point(
-                    &depth_intrinsics,
-                    [x as f32 - 0.5, y as f32 - 0.5],
-                    in_depth_buf[pixel_idx] as f32,
-                );
-                //let pt = pt.map(|v| v / 1e1);
-                position.push(pt.into());
-            }
-        }
+        buffers
+            .valid
+            .extend(buffers.depth.iter().map(|depth| *depth != 0));
+        let width = depth_frame.width();
+        deproject_image(&depth_intrinsics, &buffers.depth, width, &mut buffers.position);
 
-        let pcld_data = ImagePointCloud::new(valid, position, out_color_buf.clone(), width);
+        let metadata = FrameMetadata {
+            serial: serial.to_string(),
+            depth: stream_metadata(depth_frame, &depth_intrinsics),
+            color: stream_metadata(color_frame, &color_intrinsics),
+            depth_to_color: depth_to_color_extrinsics.0.into(),
+            depth_scale: frame_depth_scale,
+        };
 
-        callback(pcld_data);
+        let pcld_data =
+            ImagePointCloud::from_pool(buffers, width, pool.clone()).with_metadata(metadata);
+        timer.lap(Stage::Deproject);
+
+        if !callback(CaptureEvent::Frame(pcld_data)) {
+            return Ok(StreamExit::Stop);
+        }
+        timer.lap(Stage::Send);
     }
 }
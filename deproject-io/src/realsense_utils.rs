@@ -215,6 +215,22 @@ pub fn rs2_transform_point_to_point(extrin: &Rs2Extrinsics, from_point: [f32; 3]
     ]
 }
 
+/// Deprojects every pixel of a depth image, appending the points (in depth units) to "position"
+pub fn deproject_image(intrin: &Rs2Intrinsics, depth: &[u16], width: usize, position: &mut Vec<glam::Vec3>) {
+    let height = depth.len() / width;
+    for y in 0..height {
+        for x in 0..width {
+            let pixel_idx = y * width + x;
+            let pt = rs2_deproject_pixel_to_point(
+                intrin,
+                [x as f32 - 0.5, y as f32 - 0.5],
+                depth[pixel_idx] as f32,
+            );
+            position.push(pt.into());
+        }
+    }
+}
+
 pub fn align_images(
     depth_intrin: &Rs2Intrinsics,
     depth_to_other: &Rs2Extrinsics,
@@ -277,7 +293,7 @@ pub fn align_images(
     }
 }
 
-#[derive(Copy, Clone, Debug, Serialize, Deserialize)]
+#[derive(Copy, Clone, Debug, Default, Serialize, Deserialize)]
 pub struct Rs2IntrinsicsSerde {
     /// Width of the image in pixels"]
     pub width: i32,
@@ -297,6 +313,21 @@ pub struct Rs2IntrinsicsSerde {
     pub coeffs: [f32; 5usize],
 }
 
+impl Rs2IntrinsicsSerde {
+    /// Whether pixels of this image can be deprojected. librealsense can't invert the modified
+    /// Brown-Conrady model, so neither `rs2_deproject_pixel_to_point` nor the GPU deprojection
+    /// shader accept it, and unknown models can't be converted to `Rs2DistortionModel`.
+    pub fn can_deproject(&self) -> bool {
+        self.model_is_known()
+            && self.model != realsense_sys::rs2_distortion_RS2_DISTORTION_MODIFIED_BROWN_CONRADY
+    }
+
+    /// Whether the distortion model is one librealsense defines, so points can be projected
+    pub fn model_is_known(&self) -> bool {
+        self.model < realsense_sys::rs2_distortion_RS2_DISTORTION_COUNT
+    }
+}
+
 impl Into<realsense_sys::rs2_intrinsics> for Rs2IntrinsicsSerde {
     fn into(self) -> realsense_sys::rs2_intrinsics {
         realsense_sys::rs2_intrinsics {
@@ -327,3 +358,37 @@ impl From<realsense_sys::rs2_intrinsics> for Rs2IntrinsicsSerde {
     }
 }
 
+#[derive(Copy, Clone, Debug, Serialize, Deserialize)]
+pub struct Rs2ExtrinsicsSerde {
+    /// Column-major 3x3 rotation matrix
+    pub rotation: [f32; 9usize],
+    /// Three-element translation vector, in meters
+    pub translation: [f32; 3usize],
+}
+
+impl From<Rs2ExtrinsicsSerde> for realsense_sys::rs2_extrinsics {
+    fn from(e: Rs2ExtrinsicsSerde) -> Self {
+        Self {
+            rotation: e.rotation,
+            translation: e.translation,
+        }
+    }
+}
+
+impl From<realsense_sys::rs2_extrinsics> for Rs2ExtrinsicsSerde {
+    fn from(e: realsense_sys::rs2_extrinsics) -> Self {
+        Self {
+            rotation: e.rotation,
+            translation: e.translation,
+        }
+    }
+}
+
+impl Default for Rs2ExtrinsicsSerde {
+    fn default() -> Self {
+        Self {
+            rotation: [1., 0., 0., 0., 1., 0., 0., 0., 1.],
+            translation: [0.; 3],
+        }
+    }
+}
@@ -0,0 +1,432 @@
+//! Recordings are directories containing a JSON header describing how they were captured, and one
+//! binary file per frame. Frames hold either positions, or depth and color images compressed with
+//! the header's codecs.
+
+use std::collections::BTreeMap;
+use std::fs::File;
+use std::io::{BufReader, BufWriter, Write};
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicU64, AtomicUsize, Ordering};
+use std::sync::mpsc::{self, SyncSender, TrySendError};
+use std::sync::{Arc, Mutex};
+use std::thread::{self, JoinHandle};
+
+use anyhow::{anyhow, ensure, Context, Result};
+use glam::Vec3;
+use serde::{Deserialize, Serialize};
+
+use crate::accumulate::{AccumulateParams, FrameAccumulator};
+use crate::codec::{self, take, take_u32, FrameCodecs};
+use crate::extrinsics::DecodedCapture;
+use crate::graycode::{pattern_sequence, PatternImages, ProjectorMap};
+use crate::{FrameMetadata, ImagePointCloud, SensorOptions, StreamConfig};
+
+/// Incremented whenever the header or frame format changes
+pub const RECORDING_VERSION: u32 = 3;
+
+/// Oldest version which can still be read
+const MIN_RECORDING_VERSION: u32 = 2;
+
+const HEADER_FILE: &str = "recording.json";
+const FRAME_MAGIC: &[u8; 4] = b"DPJF";
+const COMPRESSED_FRAME_MAGIC: &[u8; 4] = b"DPJZ";
+
+/// Frames waiting to be written by a `RecordingThread`
+const WRITE_QUEUE_LEN: usize = 16;
+
+/// Capture settings stored alongside the frames of a recording
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct RecordingHeader {
+    pub version: u32,
+    /// Serial numbers of the devices recorded
+    pub devices: Vec<String>,
+    pub stream: StreamConfig,
+    pub options: SensorOptions,
+    /// Set if this recording is a structured light capture
+    #[serde(default)]
+    pub capture: Option<CaptureParams>,
+    /// Set if frames are stored as compressed depth and color images rather than positions
+    #[serde(default)]
+    pub codecs: Option<FrameCodecs>,
+}
+
+/// How a structured light capture was recorded. After settling on each pattern of
+/// `pattern_sequence(horiz_subdivs, vert_subdivs)`, `frames_per_pattern` frames were written from
+/// every device.
+#[derive(Copy, Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub struct CaptureParams {
+    pub horiz_subdivs: usize,
+    pub vert_subdivs: usize,
+    pub frames_per_pattern: usize,
+    /// How the first frames of each device were fused for 3D positions, so that decoding the
+    /// capture later gives the same result. Captures from before it was recorded use the defaults.
+    #[serde(default)]
+    pub averaging: AccumulateParams,
+}
+
+/// Writes frames into a recording directory
+pub struct RecordingWriter {
+    dir: PathBuf,
+    frame_count: usize,
+    codecs: Option<FrameCodecs>,
+}
+
+/// Writes frames into a recording on a background thread, so that compressing and writing them
+/// doesn't hold up the caller. Dropping it waits for the queued frames to be written.
+pub struct RecordingThread {
+    dir: PathBuf,
+    tx: Option<SyncSender<Arc<ImagePointCloud>>>,
+    thread: Option<JoinHandle<()>>,
+    written: Arc<AtomicUsize>,
+    dropped: Arc<AtomicU64>,
+    /// Why the thread stopped writing, if it failed
+    error: Arc<Mutex<Option<String>>>,
+}
+
+/// Reads frames from a recording directory
+pub struct RecordingReader {
+    header: RecordingHeader,
+    frames: Vec<PathBuf>,
+}
+
+impl RecordingHeader {
+    pub fn new(devices: Vec<String>, stream: StreamConfig, options: SensorOptions) -> Self {
+        Self {
+            version: RECORDING_VERSION,
+            devices,
+            stream,
+            options,
+            capture: None,
+            codecs: None,
+        }
+    }
+
+    /// Mark the recording as a structured light capture
+    pub fn with_capture(mut self, params: CaptureParams) -> Self {
+        self.capture = Some(params);
+        self
+    }
+
+    /// Store frames as depth and color images compressed with "codecs"
+    pub fn with_codecs(mut self, codecs: FrameCodecs) -> Self {
+        self.codecs = Some(codecs);
+        self
+    }
+}
+
+impl RecordingWriter {
+    /// Creates the directory (if needed) and writes the header. Frames of an earlier recording in
+    /// the directory are deleted, since they would otherwise be read back as part of this one.
+    pub fn create(dir: impl AsRef<Path>, header: &RecordingHeader) -> Result<Self> {
+        let dir = dir.as_ref().to_path_buf();
+        std::fs::create_dir_all(&dir)
+            .with_context(|| format!("Creating recording directory {}", dir.display()))?;
+        for entry in std::fs::read_dir(&dir)? {
+            let path = entry?.path();
+            let name = path.file_name().unwrap_or_default().to_string_lossy();
+            if name.starts_with("frame_") && name.ends_with(".bin") {
+                std::fs::remove_file(&path)
+                    .with_context(|| format!("Removing {}", path.display()))?;
+            }
+        }
+
+        let file = File::create(dir.join(HEADER_FILE))?;
+        serde_json::to_writer_pretty(BufWriter::new(file), header)?;
+
+        Ok(Self {
+            dir,
+            frame_count: 0,
+            codecs: header.codecs,
+        })
+    }
+
+    pub fn write(&mut self, frame: &ImagePointCloud) -> Result<()> {
+        let path = self.dir.join(frame_file_name(self.frame_count));
+        let mut writer = BufWriter::new(File::create(path)?);
+        match self.codecs {
+            Some(codecs) => write_compressed_frame(&mut writer, frame, codecs)?,
+            None => write_frame(&mut writer, frame)?,
+        }
+        writer.flush()?;
+        self.frame_count += 1;
+        Ok(())
+    }
+
+    /// Number of frames written so far
+    pub fn frame_count(&self) -> usize {
+        self.frame_count
+    }
+
+    pub fn dir(&self) -> &Path {
+        &self.dir
+    }
+}
+
+impl RecordingThread {
+    /// Start writing frames with "writer" on a new thread
+    pub fn spawn(writer: RecordingWriter) -> Self {
+        Self::spawn_with_queue(writer, WRITE_QUEUE_LEN)
+    }
+
+    /// Start writing frames with "writer" on a new thread, queueing up to "queue_len" frames
+    /// before `try_send` drops them
+    pub fn spawn_with_queue(mut writer: RecordingWriter, queue_len: usize) -> Self {
+        let (tx, rx) = mpsc::sync_channel::<Arc<ImagePointCloud>>(queue_len);
+        let written = Arc::new(AtomicUsize::new(0));
+        let error = Arc::new(Mutex::new(None));
+        let dir = writer.dir().to_path_buf();
+
+        let (thread_written, thread_error) = (written.clone(), error.clone());
+        let thread = thread::spawn(move || {
+            for frame in rx {
+                if let Err(e) = writer.write(&frame) {
+                    *thread_error.lock().unwrap() = Some(format!("{e:#}"));
+                    return;
+                }
+                thread_written.store(writer.frame_count(), Ordering::Relaxed);
+            }
+        });
+
+        Self {
+            dir,
+            tx: Some(tx),
+            thread: Some(thread),
+            written,
+            dropped: Arc::new(AtomicU64::new(0)),
+            error,
+        }
+    }
+
+    /// Queue a frame, dropping it if the writer has fallen behind. Fails once writing has failed.
+    pub fn try_send(&self, frame: Arc<ImagePointCloud>) -> Result<()> {
+        match self.tx.as_ref().unwrap().try_send(frame) {
+            Ok(()) => Ok(()),
+            Err(TrySendError::Full(_)) => {
+                self.dropped.fetch_add(1, Ordering::Relaxed);
+                Ok(())
+            }
+            Err(TrySendError::Disconnected(_)) => Err(self.error()),
+        }
+    }
+
+    fn error(&self) -> anyhow::Error {
+        let error = self.error.lock().unwrap().clone();
+        anyhow!(error.unwrap_or_else(|| "Recording thread stopped".to_string()))
+    }
+
+    /// Number of frames written so far
+    pub fn frame_count(&self) -> usize {
+        self.written.load(Ordering::Relaxed)
+    }
+
+    /// Number of frames dropped by `try_send` because the writer was behind
+    pub fn dropped_frames(&self) -> u64 {
+        self.dropped.load(Ordering::Relaxed)
+    }
+
+    pub fn dir(&self) -> &Path {
+        &self.dir
+    }
+}
+
+impl Drop for RecordingThread {
+    fn drop(&mut self) {
+        // Closing the channel lets the thread finish the queued frames and exit
+        self.tx = None;
+        if let Some(thread) = self.thread.take() {
+            let _ = thread.join();
+        }
+    }
+}
+
+impl RecordingReader {
+    pub fn open(dir: impl AsRef<Path>) -> Result<Self> {
+        let dir = dir.as_ref();
+        let header_path = dir.join(HEADER_FILE);
+        let file = File::open(&header_path)
+            .with_context(|| format!("Opening {}", header_path.display()))?;
+        let header: RecordingHeader = serde_json::from_reader(BufReader::new(file))?;
+        ensure!(
+            (MIN_RECORDING_VERSION..=RECORDING_VERSION).contains(&header.version),
+            "Unsupported recording version {}",
+            header.version
+        );
+
+        let mut frames = vec![];
+        while dir.join(frame_file_name(frames.len())).exists() {
+            frames.push(dir.join(frame_file_name(frames.len())));
+        }
+
+        Ok(Self { header, frames })
+    }
+
+    pub fn header(&self) -> &RecordingHeader {
+        &self.header
+    }
+
+    /// Number of frames in the recording
+    pub fn len(&self) -> usize {
+        self.frames.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.frames.is_empty()
+    }
+
+    pub fn read(&self, index: usize) -> Result<ImagePointCloud> {
+        let path = self.frames.get(index).context("Frame index out of range")?;
+        let bytes = std::fs::read(path).with_context(|| format!("Reading {}", path.display()))?;
+        read_frame(&bytes)
+    }
+
+    /// Reads every frame in order
+    pub fn frames(&self) -> impl Iterator<Item = Result<ImagePointCloud>> + '_ {
+        (0..self.len()).map(|i| self.read(i))
+    }
+}
+
+/// Decodes a structured light capture, keyed by serial number. The first frames of each device are
+/// fused as they were while capturing to give the 3D position of its pixels.
+pub fn decode_capture(
+    reader: &RecordingReader,
+    min_contrast: f32,
+) -> Result<BTreeMap<String, DecodedCapture>> {
+    let params = reader
+        .header()
+        .capture
+        .context("Recording is not a structured light capture")?;
+    let patterns = pattern_sequence(params.horiz_subdivs, params.vert_subdivs);
+    let averaging = params.averaging;
+
+    let mut devices: BTreeMap<String, (PatternImages, FrameAccumulator)> = BTreeMap::new();
+    for frame in reader.frames() {
+        let frame = frame?;
+        let (images, depth) = devices
+            .entry(frame.serial().to_string())
+            .or_insert_with(|| (PatternImages::default(), FrameAccumulator::new(averaging)));
+        images.add(frame.color());
+        if images.count() >= params.frames_per_pattern.max(1) {
+            images.finish_pattern();
+        }
+        depth.add(&frame);
+    }
+
+    devices
+        .into_iter()
+        .map(|(serial, (images, depth))| {
+            let cloud = depth
+                .fuse()
+                .with_context(|| format!("Capture from {serial} has no frames"))?;
+            let map = ProjectorMap::decode_sequence(
+                &patterns,
+                images.into_images(),
+                cloud.width(),
+                min_contrast,
+            )
+            .with_context(|| format!("Decoding the capture from {serial}"))?;
+            ensure!(
+                map.coords().len() == cloud.valid().len(),
+                "Pattern images from {serial} don't match its depth frames"
+            );
+            Ok((serial, DecodedCapture { map, cloud }))
+        })
+        .collect()
+}
+
+fn frame_file_name(index: usize) -> String {
+    format!("frame_{index:06}.bin")
+}
+
+/// Serializes a frame: magic, width, height, length-prefixed JSON metadata, then per-pixel valid
+/// flags, positions and colors. All integers and floats are little-endian.
+pub fn write_frame(w: &mut impl Write, frame: &ImagePointCloud) -> Result<()> {
+    w.write_all(FRAME_MAGIC)?;
+    w.write_all(&(frame.width() as u32).to_le_bytes())?;
+    w.write_all(&(frame.height() as u32).to_le_bytes())?;
+    let metadata = serde_json::to_vec(frame.metadata())?;
+    w.write_all(&(metadata.len() as u32).to_le_bytes())?;
+    w.write_all(&metadata)?;
+
+    let valid: Vec<u8> = frame.valid().iter().map(|v| *v as u8).collect();
+    w.write_all(&valid)?;
+    for pos in frame.position() {
+        for v in pos.to_array() {
+            w.write_all(&v.to_le_bytes())?;
+        }
+    }
+    w.write_all(bytemuck::cast_slice(frame.color()))?;
+
+    Ok(())
+}
+
+/// Serializes a frame as magic then its images compressed by `codec::encode_frame`
+pub fn write_compressed_frame(
+    w: &mut impl Write,
+    frame: &ImagePointCloud,
+    codecs: FrameCodecs,
+) -> Result<()> {
+    w.write_all(COMPRESSED_FRAME_MAGIC)?;
+    w.write_all(&codec::encode_frame(frame, codecs)?)?;
+    Ok(())
+}
+
+/// Deserializes a frame written by `write_frame` or `write_compressed_frame`, checking every
+/// length against the bytes available before allocating
+pub fn read_frame(mut bytes: &[u8]) -> Result<ImagePointCloud> {
+    let magic = take(&mut bytes, 4)?;
+    if magic == COMPRESSED_FRAME_MAGIC {
+        return codec::decode_frame(bytes);
+    }
+    ensure!(magic == FRAME_MAGIC, "Not a frame");
+
+    let width = take_u32(&mut bytes)? as usize;
+    let height = take_u32(&mut bytes)? as usize;
+    ensure!(width > 0, "Empty frame");
+    let len = take_u32(&mut bytes)? as usize;
+    let metadata: FrameMetadata = serde_json::from_slice(take(&mut bytes, len)?)?;
+
+    // One valid flag, three f32 coordinates and three color bytes per pixel
+    let n_pixels = width.checked_mul(height).context("Frame is too large")?;
+    let expected = n_pixels.checked_mul(1 + 12 + 3);
+    ensure!(
+        expected == Some(bytes.len()),
+        "Frame is truncated or corrupt"
+    );
+
+    let valid = take(&mut bytes, n_pixels)?
+        .iter()
+        .map(|v| *v != 0)
+        .collect();
+    let position = take(&mut bytes, n_pixels * 12)?
+        .chunks_exact(12)
+        .map(|c| {
+            let f = |i: usize| f32::from_le_bytes(c[i * 4..i * 4 + 4].try_into().unwrap());
+            Vec3::new(f(0), f(1), f(2))
+        })
+        .collect();
+    let color = bytemuck::cast_slice(take(&mut bytes, n_pixels * 3)?).to_vec();
+
+    Ok(ImagePointCloud::new(valid, position, color, width).with_metadata(metadata))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn rerecording_replaces_frames() {
+        let dir = std::env::temp_dir().join(format!("deproject-rerecord-{}", std::process::id()));
+        let header = RecordingHeader::new(vec![], StreamConfig::default(), Default::default());
+        let frame = ImagePointCloud::new(vec![true; 4], vec![Vec3::ONE; 4], vec![[1; 3]; 4], 2);
+
+        for n_frames in [5, 2] {
+            let mut writer = RecordingWriter::create(&dir, &header).unwrap();
+            for _ in 0..n_frames {
+                writer.write(&frame).unwrap();
+            }
+        }
+        let reader = RecordingReader::open(&dir).unwrap();
+        std::fs::remove_dir_all(&dir).unwrap();
+        assert_eq!(reader.len(), 2);
+    }
+}
@@ -0,0 +1,341 @@
+//! Folders of depth and color images, as published by RGB-D datasets such as TUM and Redwood.
+//!
+//! Depth images are 16-bit grayscale PNGs and color images are PNGs or JPEGs. Frames are listed
+//! by an association file if there is one: each line holds a timestamp and path for the color
+//! image and for the depth image, in either order, with paths relative to the folder. Otherwise
+//! the sorted images of the `depth` folder are paired with those of the first of `color`, `rgb`
+//! or `image`.
+//!
+//! Intrinsics are JSON, either a single `Rs2IntrinsicsSerde` when color is registered to depth,
+//! or an object with `depth` and `color` intrinsics and an optional `depth_to_color`
+//! `Rs2ExtrinsicsSerde` when color must be aligned.
+
+use std::fs::File;
+use std::io::{BufRead, BufReader};
+use std::path::{Path, PathBuf};
+use std::time::Duration;
+
+use anyhow::{bail, ensure, Context, Result};
+use serde::{Deserialize, Serialize};
+
+use crate::capture::CaptureHandle;
+use crate::error::CaptureEvent;
+use crate::playback::{start_playback, RecordedFrames};
+use crate::{
+    FrameMetadata, ImagePointCloud, Rs2ExtrinsicsSerde, Rs2IntrinsicsSerde, StreamMetadata,
+};
+
+/// Folders searched for color images when there is no association file
+const COLOR_DIRS: [&str; 3] = ["color", "rgb", "image"];
+
+/// How to read an image sequence. Paths are relative to its folder.
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+#[serde(default)]
+pub struct SequenceConfig {
+    /// JSON file of intrinsics
+    pub intrinsics: String,
+    /// File pairing depth and color images, or empty to pair the images of each folder in order
+    pub association: String,
+    /// Meters per unit of depth image value, e.g. 0.0002 for TUM and 0.001 for Redwood
+    pub depth_scale: f32,
+    /// Framerate to play at when there's no association file to give timestamps
+    pub fps: f32,
+}
+
+/// Contents of the intrinsics file
+#[derive(Deserialize)]
+#[serde(untagged)]
+enum IntrinsicsFile {
+    /// Color is registered to depth
+    Registered(Rs2IntrinsicsSerde),
+    Separate {
+        depth: Rs2IntrinsicsSerde,
+        color: Rs2IntrinsicsSerde,
+        #[serde(default)]
+        depth_to_color: Rs2ExtrinsicsSerde,
+    },
+}
+
+struct SequenceFrame {
+    depth: PathBuf,
+    color: PathBuf,
+    /// Timestamp of the depth image
+    time: Duration,
+}
+
+/// The frames of an image sequence, read on demand
+pub struct ImageSequence {
+    frames: Vec<SequenceFrame>,
+    /// Metadata shared by every frame
+    metadata: FrameMetadata,
+    /// Whether color images must be aligned to depth
+    align: bool,
+    /// Index of the frame `next_frame` returns
+    next: usize,
+}
+
+impl ImageSequence {
+    /// List the frames of the sequence in "dir", reporting them under the folder's name
+    pub fn open(dir: impl AsRef<Path>, config: &SequenceConfig) -> Result<Self> {
+        let dir = dir.as_ref();
+        let path = dir.join(&config.intrinsics);
+        let file = File::open(&path).with_context(|| format!("Opening {}", path.display()))?;
+        let intrinsics: IntrinsicsFile = serde_json::from_reader(BufReader::new(file))
+            .with_context(|| format!("Reading {}", path.display()))?;
+        let (depth, color, depth_to_color, align) = match intrinsics {
+            IntrinsicsFile::Registered(intrinsics) => {
+                (intrinsics, intrinsics, Rs2ExtrinsicsSerde::default(), false)
+            }
+            IntrinsicsFile::Separate {
+                depth,
+                color,
+                depth_to_color,
+            } => (depth, color, depth_to_color, true),
+        };
+
+        let frames = match config.association.is_empty() {
+            true => pair_folders(dir, config.fps)?,
+            false => read_association(dir, &dir.join(&config.association))?,
+        };
+        ensure!(!frames.is_empty(), "{} has no frames", dir.display());
+
+        let stream = |intrinsics| StreamMetadata {
+            intrinsics,
+            ..StreamMetadata::default()
+        };
+        let serial = dir.file_name().unwrap_or_default().to_string_lossy();
+        let metadata = FrameMetadata {
+            serial: serial.into_owned(),
+            depth: stream(depth),
+            color: stream(color),
+            depth_to_color,
+            depth_scale: config.depth_scale,
+        };
+        Ok(Self {
+            frames,
+            metadata,
+            align,
+            next: 0,
+        })
+    }
+
+    /// Name the frames are reported under
+    pub fn serial(&self) -> &str {
+        &self.metadata.serial
+    }
+
+    pub fn len(&self) -> usize {
+        self.frames.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.frames.is_empty()
+    }
+
+    /// Read and deproject one frame
+    pub fn read(&self, index: usize) -> Result<ImagePointCloud> {
+        let frame = self.frames.get(index).context("Frame index out of range")?;
+        let (depth, depth_width, depth_height) = read_depth(&frame.depth)
+            .with_context(|| format!("Reading {}", frame.depth.display()))?;
+        let (color, color_width, color_height) = read_color(&frame.color)
+            .with_context(|| format!("Reading {}", frame.color.display()))?;
+
+        let mut metadata = self.metadata.clone();
+        let intrinsics = &metadata.depth.intrinsics;
+        ensure!(
+            depth_width == intrinsics.width as usize && depth_height == intrinsics.height as usize,
+            "{} doesn't match the depth intrinsics",
+            frame.depth.display()
+        );
+        let intrinsics = &metadata.color.intrinsics;
+        ensure!(
+            color_width == intrinsics.width as usize && color_height == intrinsics.height as usize,
+            "{} doesn't match the color intrinsics",
+            frame.color.display()
+        );
+
+        let timestamp = frame.time.as_secs_f64() * 1e3;
+        for stream in [&mut metadata.depth, &mut metadata.color] {
+            stream.frame_number = index as u64;
+            stream.timestamp = timestamp;
+        }
+        match self.align {
+            true => ImagePointCloud::from_images(depth, color, metadata),
+            false => ImagePointCloud::from_depth(depth, color, depth_width, metadata),
+        }
+    }
+}
+
+impl RecordedFrames for ImageSequence {
+    fn next_frame(&mut self) -> Result<Option<(ImagePointCloud, Duration)>> {
+        if self.next >= self.frames.len() {
+            return Ok(None);
+        }
+        let frame = self.read(self.next)?;
+        let time = self.frames[self.next].time;
+        self.next += 1;
+        Ok(Some((frame, time)))
+    }
+}
+
+/// Pair the sorted images of the depth folder and the color folder, at "fps"
+fn pair_folders(dir: &Path, fps: f32) -> Result<Vec<SequenceFrame>> {
+    let depth = sorted_images(&dir.join("depth"))?;
+    let color_dir = COLOR_DIRS
+        .iter()
+        .map(|name| dir.join(name))
+        .find(|path| path.is_dir())
+        .with_context(|| format!("{} has no color folder", dir.display()))?;
+    let color = sorted_images(&color_dir)?;
+    ensure!(
+        depth.len() == color.len(),
+        "{} depth images but {} color images",
+        depth.len(),
+        color.len()
+    );
+
+    let interval = Duration::from_secs_f32(1. / fps.max(1e-3));
+    Ok(depth
+        .into_iter()
+        .zip(color)
+        .enumerate()
+        .map(|(index, (depth, color))| SequenceFrame {
+            depth,
+            color,
+            time: interval * index as u32,
+        })
+        .collect())
+}
+
+fn sorted_images(dir: &Path) -> Result<Vec<PathBuf>> {
+    let entries = std::fs::read_dir(dir).with_context(|| format!("Listing {}", dir.display()))?;
+    let mut paths = vec![];
+    for entry in entries {
+        let path = entry?.path();
+        let extension = path.extension().unwrap_or_default().to_ascii_lowercase();
+        if ["png", "jpg", "jpeg"].iter().any(|e| extension == *e) {
+            paths.push(path);
+        }
+    }
+    paths.sort();
+    Ok(paths)
+}
+
+/// Read an association file of "timestamp path timestamp path" lines. The path containing "depth"
+/// is the depth image; if neither does, color comes first as in TUM's `associate.py`.
+fn read_association(dir: &Path, path: &Path) -> Result<Vec<SequenceFrame>> {
+    let file = File::open(path).with_context(|| format!("Opening {}", path.display()))?;
+    let mut frames = vec![];
+    let mut first_time = None;
+    for (number, line) in BufReader::new(file).lines().enumerate() {
+        let line = line?;
+        let line = line.trim();
+        if line.is_empty() || line.starts_with('#') {
+            continue;
+        }
+
+        let fields: Vec<&str> = line.split_whitespace().collect();
+        let [time_a, path_a, time_b, path_b, ..] = fields.as_slice() else {
+            bail!(
+                "Line {} of {} has too few fields",
+                number + 1,
+                path.display()
+            );
+        };
+        let a_is_depth = path_a.contains("depth") && !path_b.contains("depth");
+        let (depth_time, depth, color) = match a_is_depth {
+            true => (time_a, path_a, path_b),
+            false => (time_b, path_b, path_a),
+        };
+
+        let seconds: f64 = depth_time
+            .parse()
+            .with_context(|| format!("Line {} of {}", number + 1, path.display()))?;
+        let first = *first_time.get_or_insert(seconds);
+        frames.push(SequenceFrame {
+            depth: dir.join(depth),
+            color: dir.join(color),
+            time: Duration::from_secs_f64((seconds - first).max(0.)),
+        });
+    }
+    Ok(frames)
+}
+
+/// A 16-bit grayscale PNG, and its width and height
+fn read_depth(path: &Path) -> Result<(Vec<u16>, usize, usize)> {
+    let mut reader = png::Decoder::new(BufReader::new(File::open(path)?)).read_info()?;
+    let mut pixels = vec![0; reader.output_buffer_size()];
+    let info = reader.next_frame(&mut pixels)?;
+    ensure!(
+        info.color_type == png::ColorType::Grayscale && info.bit_depth == png::BitDepth::Sixteen,
+        "Depth image is not 16-bit grayscale"
+    );
+    let depth = pixels[..info.buffer_size()]
+        .chunks_exact(2)
+        .map(|b| u16::from_be_bytes([b[0], b[1]]))
+        .collect();
+    Ok((depth, info.width as usize, info.height as usize))
+}
+
+/// An 8-bit PNG or JPEG as RGB, and its width and height
+fn read_color(path: &Path) -> Result<(Vec<[u8; 3]>, usize, usize)> {
+    let extension = path.extension().unwrap_or_default().to_ascii_lowercase();
+    if extension == "png" {
+        let mut decoder = png::Decoder::new(BufReader::new(File::open(path)?));
+        decoder.set_transformations(png::Transformations::EXPAND | png::Transformations::STRIP_16);
+        let mut reader = decoder.read_info()?;
+        let mut pixels = vec![0; reader.output_buffer_size()];
+        let info = reader.next_frame(&mut pixels)?;
+        let pixels = &pixels[..info.buffer_size()];
+        let color = match info.color_type {
+            png::ColorType::Rgb => pixels.chunks_exact(3).map(|p| [p[0], p[1], p[2]]).collect(),
+            png::ColorType::Rgba => pixels.chunks_exact(4).map(|p| [p[0], p[1], p[2]]).collect(),
+            png::ColorType::Grayscale => pixels.iter().map(|l| [*l; 3]).collect(),
+            png::ColorType::GrayscaleAlpha => pixels.chunks_exact(2).map(|p| [p[0]; 3]).collect(),
+            other => bail!("Unsupported PNG color type {other:?}"),
+        };
+        return Ok((color, info.width as usize, info.height as usize));
+    }
+
+    let mut decoder = jpeg_decoder::Decoder::new(BufReader::new(File::open(path)?));
+    let pixels = decoder.decode()?;
+    let info = decoder.info().context("JPEG has no header")?;
+    let color = match info.pixel_format {
+        jpeg_decoder::PixelFormat::RGB24 => {
+            pixels.chunks_exact(3).map(|p| [p[0], p[1], p[2]]).collect()
+        }
+        jpeg_decoder::PixelFormat::L8 => pixels.iter().map(|l| [*l; 3]).collect(),
+        other => bail!("Unsupported JPEG pixel format {other:?}"),
+    };
+    Ok((color, info.width as usize, info.height as usize))
+}
+
+/// Starts playing an image sequence on a new thread, at the pace of its timestamps and in a loop.
+/// Like `start_realsense`, the thread calls "callback" with each frame and any change in status,
+/// under the name of the folder.
+pub fn start_sequence_playback(
+    dir: impl AsRef<Path>,
+    config: SequenceConfig,
+    callback: impl FnMut(CaptureEvent) -> bool + Send + 'static,
+) -> Result<CaptureHandle> {
+    let dir = dir.as_ref().to_path_buf();
+    let frames = ImageSequence::open(&dir, &config)?;
+    let serial = frames.serial().to_string();
+    Ok(start_playback(
+        serial,
+        frames,
+        move || ImageSequence::open(&dir, &config),
+        callback,
+    ))
+}
+
+impl Default for SequenceConfig {
+    fn default() -> Self {
+        Self {
+            intrinsics: "intrinsics.json".to_string(),
+            association: String::new(),
+            depth_scale: 0.001,
+            fps: 30.,
+        }
+    }
+}
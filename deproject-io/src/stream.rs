@@ -0,0 +1,361 @@
+//! Streaming frames over TCP to viewers on other machines.
+//!
+//! # Protocol
+//!
+//! Only the server writes. On accepting a connection it sends a greeting of `STREAM_MAGIC`
+//! followed by `STREAM_VERSION` as a u32, then one message per frame. All integers are
+//! little-endian.
+//!
+//! | Field           | Bytes | Contents                                                 |
+//! |-----------------|-------|----------------------------------------------------------|
+//! | length          | 4     | Size of the rest of the message                          |
+//! | depth codec     | 1     | `DepthCodec::id` of the depth payload                    |
+//! | color codec     | 1     | `ColorCodec::id` of the color payload                    |
+//! | width           | 4     | Depth image width in pixels                              |
+//! | height          | 4     | Depth image height in pixels                             |
+//! | metadata length | 4     |                                                          |
+//! | metadata        | n     | `FrameMetadata` as JSON                                  |
+//! | depth length    | 4     |                                                          |
+//! | depth           | n     | Depth image, in units of `depth_scale` meters            |
+//! | color length    | 4     |                                                          |
+//! | color           | n     | Color image aligned to depth                             |
+//!
+//! After the length, a message is a frame as written by `codec::encode_frame`, compressed with the
+//! server's `FrameCodecs`. Positions are not sent: clients deproject the depth image with the
+//! intrinsics in the metadata. Clients which fall behind have frames dropped, rather than holding
+//! up the server or each other.
+
+use std::io::{ErrorKind, Read, Write};
+use std::net::{SocketAddr, TcpListener, TcpStream, ToSocketAddrs};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::mpsc::{self, Receiver, SyncSender, TryRecvError, TrySendError};
+use std::sync::{Arc, Mutex};
+use std::thread;
+use std::time::Duration;
+
+use anyhow::{bail, ensure, Context, Result};
+
+use crate::capture::{wait_to_restart, CaptureCommand, CaptureHandle};
+use crate::codec::{self, FrameCodecs};
+use crate::error::{CaptureError, CaptureEvent, CaptureStatus};
+use crate::ImagePointCloud;
+
+pub const STREAM_MAGIC: &[u8; 4] = b"DPJS";
+/// Incremented whenever the message format changes
+pub const STREAM_VERSION: u32 = 1;
+
+/// Port servers listen on unless told otherwise
+pub const DEFAULT_PORT: u16 = 5730;
+
+/// Messages waiting to be sent to each client before frames are dropped
+const CLIENT_QUEUE_LEN: usize = 2;
+
+/// How often blocked threads check whether they should stop
+const POLL_INTERVAL: Duration = Duration::from_millis(100);
+
+/// Delay before a client reconnects after losing its connection
+const RECONNECT_DELAY: Duration = Duration::from_secs(1);
+
+/// Largest message a client accepts, to fail fast on a corrupt stream
+const MAX_MESSAGE_LEN: usize = 256 << 20;
+
+/// Queues of encoded messages for each connected client
+type ClientQueues = Arc<Mutex<Vec<SyncSender<Arc<Vec<u8>>>>>>;
+
+/// Accepts viewer connections and sends every frame to each of them
+pub struct StreamServer {
+    local_addr: SocketAddr,
+    codecs: FrameCodecs,
+    clients: ClientQueues,
+    stop: Arc<AtomicBool>,
+}
+
+impl StreamServer {
+    /// Listen for viewers on the given address, accepting them on a background thread. Frames are
+    /// compressed with "codecs".
+    pub fn bind(addr: impl ToSocketAddrs, codecs: FrameCodecs) -> Result<Self> {
+        let listener = TcpListener::bind(addr).context("Binding stream server")?;
+        listener.set_nonblocking(true)?;
+        let local_addr = listener.local_addr()?;
+
+        let clients = Arc::new(Mutex::new(vec![]));
+        let stop = Arc::new(AtomicBool::new(false));
+        let (thread_clients, thread_stop) = (clients.clone(), stop.clone());
+        thread::spawn(move || accept_clients(listener, thread_clients, thread_stop));
+
+        Ok(Self {
+            local_addr,
+            codecs,
+            clients,
+            stop,
+        })
+    }
+
+    pub fn local_addr(&self) -> SocketAddr {
+        self.local_addr
+    }
+
+    /// Number of viewers currently connected
+    pub fn client_count(&self) -> usize {
+        self.clients.lock().unwrap().len()
+    }
+
+    /// Queue a frame for every connected viewer, dropping it for any which are behind
+    pub fn send(&self, frame: &ImagePointCloud) -> Result<()> {
+        let mut clients = self.clients.lock().unwrap();
+        if clients.is_empty() {
+            return Ok(());
+        }
+
+        let message = Arc::new(encode_message(frame, self.codecs)?);
+        clients.retain(|client| match client.try_send(message.clone()) {
+            Ok(()) | Err(TrySendError::Full(_)) => true,
+            Err(TrySendError::Disconnected(_)) => false,
+        });
+        Ok(())
+    }
+}
+
+impl Drop for StreamServer {
+    fn drop(&mut self) {
+        self.stop.store(true, Ordering::Relaxed);
+    }
+}
+
+fn accept_clients(listener: TcpListener, clients: ClientQueues, stop: Arc<AtomicBool>) {
+    while !stop.load(Ordering::Relaxed) {
+        let (stream, addr) = match listener.accept() {
+            Ok(client) => client,
+            Err(e) if e.kind() == ErrorKind::WouldBlock => {
+                thread::sleep(POLL_INTERVAL);
+                continue;
+            }
+            Err(e) => {
+                eprintln!("Failed to accept stream client: {e}");
+                continue;
+            }
+        };
+
+        let (tx, rx) = mpsc::sync_channel(CLIENT_QUEUE_LEN);
+        thread::spawn(move || {
+            if let Err(e) = send_to_client(stream, rx) {
+                eprintln!("Stream client {addr} disconnected: {e:#}");
+            }
+        });
+        clients.lock().unwrap().push(tx);
+    }
+}
+
+/// Write the greeting then each queued message, until the server is dropped or the client leaves
+fn send_to_client(mut stream: TcpStream, messages: Receiver<Arc<Vec<u8>>>) -> Result<()> {
+    stream.set_nonblocking(false)?;
+    stream.set_nodelay(true)?;
+    stream.write_all(STREAM_MAGIC)?;
+    stream.write_all(&STREAM_VERSION.to_le_bytes())?;
+    for message in messages {
+        stream.write_all(&message)?;
+    }
+    Ok(())
+}
+
+/// Serializes a frame as a length-prefixed message
+fn encode_message(frame: &ImagePointCloud, codecs: FrameCodecs) -> Result<Vec<u8>> {
+    let body = codec::encode_frame(frame, codecs)?;
+    let mut message = Vec::with_capacity(body.len() + 4);
+    message.extend((body.len() as u32).to_le_bytes());
+    message.extend(body);
+    Ok(message)
+}
+
+/// Starts receiving frames from a `StreamServer` on a new thread, reconnecting whenever the
+/// connection is lost. Like `start_realsense`, the thread calls "callback" with each frame and any
+/// change in connection status, which is reported under the server's address.
+pub fn start_stream_client(
+    address: String,
+    callback: impl FnMut(CaptureEvent) -> bool + Send + 'static,
+) -> CaptureHandle {
+    let (tx, rx) = mpsc::channel();
+    let thread_address = address.clone();
+    let thread = thread::spawn(move || client_mainloop(callback, &thread_address, rx));
+    CaptureHandle::new(address, tx, thread)
+}
+
+/// Why a client stopped reading from its connection
+enum ClientExit {
+    Stop,
+    Pause,
+}
+
+fn client_mainloop(
+    mut callback: impl FnMut(CaptureEvent) -> bool,
+    address: &str,
+    commands: Receiver<CaptureCommand>,
+) {
+    let status = |callback: &mut dyn FnMut(CaptureEvent) -> bool, status| {
+        callback(CaptureEvent::Status {
+            serial: address.to_string(),
+            status,
+        })
+    };
+
+    loop {
+        if !status(&mut callback, CaptureStatus::Connecting) {
+            return;
+        }
+
+        let mut streaming = false;
+        let result = receive_frames(address, &commands, |frame| {
+            if !streaming {
+                streaming = true;
+                if !status(&mut callback, CaptureStatus::Streaming) {
+                    return false;
+                }
+            }
+            callback(CaptureEvent::Frame(frame))
+        });
+
+        let wait = match result {
+            Ok(ClientExit::Stop) => {
+                status(&mut callback, CaptureStatus::Stopped);
+                return;
+            }
+            Ok(ClientExit::Pause) => {
+                if !status(&mut callback, CaptureStatus::Paused) {
+                    return;
+                }
+                None
+            }
+            Err(e) => {
+                let reconnecting = CaptureStatus::Reconnecting {
+                    error: CaptureError::Stream(format!("{e:#}")),
+                    retry_in: RECONNECT_DELAY,
+                };
+                if !status(&mut callback, reconnecting) {
+                    return;
+                }
+                Some(RECONNECT_DELAY)
+            }
+        };
+
+        if !wait_to_restart(&commands, wait) {
+            status(&mut callback, CaptureStatus::Stopped);
+            return;
+        }
+    }
+}
+
+/// Connect and pass each frame to "on_frame" until a command ends streaming, "on_frame" returns
+/// false or the connection fails
+fn receive_frames(
+    address: &str,
+    commands: &Receiver<CaptureCommand>,
+    mut on_frame: impl FnMut(ImagePointCloud) -> bool,
+) -> Result<ClientExit> {
+    let mut stream =
+        TcpStream::connect(address).with_context(|| format!("Connecting to {address}"))?;
+    stream.set_read_timeout(Some(POLL_INTERVAL))?;
+
+    // Check for commands whenever a read times out
+    let mut poll = || loop {
+        match commands.try_recv() {
+            Ok(CaptureCommand::Stop) | Err(TryRecvError::Disconnected) => {
+                return Some(ClientExit::Stop)
+            }
+            Ok(CaptureCommand::Pause(true)) => return Some(ClientExit::Pause),
+            // Stream settings belong to the server
+            Ok(_) => (),
+            Err(TryRecvError::Empty) => return None,
+        }
+    };
+
+    let mut greeting = [0; 8];
+    if let Some(exit) = read_polling(&mut stream, &mut greeting, &mut poll)? {
+        return Ok(exit);
+    }
+    ensure!(&greeting[..4] == STREAM_MAGIC, "Not a frame stream");
+    let version = u32::from_le_bytes(greeting[4..].try_into().unwrap());
+    ensure!(
+        version == STREAM_VERSION,
+        "Unsupported stream version {version}"
+    );
+
+    let mut body = vec![];
+    loop {
+        let mut len = [0; 4];
+        if let Some(exit) = read_polling(&mut stream, &mut len, &mut poll)? {
+            return Ok(exit);
+        }
+        let len = u32::from_le_bytes(len) as usize;
+        ensure!(len <= MAX_MESSAGE_LEN, "Message of {len} bytes is too long");
+
+        body.resize(len, 0);
+        if let Some(exit) = read_polling(&mut stream, &mut body, &mut poll)? {
+            return Ok(exit);
+        }
+        if !on_frame(codec::decode_frame(&body)?) {
+            return Ok(ClientExit::Stop);
+        }
+    }
+}
+
+/// Fill "buf" from a stream with a read timeout, calling "poll" each time the timeout expires.
+/// Returns early with the result of "poll" if it returns Some.
+fn read_polling<T>(
+    stream: &mut TcpStream,
+    buf: &mut [u8],
+    poll: &mut impl FnMut() -> Option<T>,
+) -> Result<Option<T>> {
+    let mut filled = 0;
+    while filled < buf.len() {
+        match stream.read(&mut buf[filled..]) {
+            Ok(0) => bail!("Server closed the connection"),
+            Ok(n) => filled += n,
+            Err(e) if matches!(e.kind(), ErrorKind::WouldBlock | ErrorKind::TimedOut) => {
+                if let Some(exit) = poll() {
+                    return Ok(Some(exit));
+                }
+            }
+            Err(e) if e.kind() == ErrorKind::Interrupted => (),
+            Err(e) => return Err(e.into()),
+        }
+    }
+    Ok(None)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Instant;
+
+    use super::*;
+    use crate::codec::tests::test_frame;
+
+    #[test]
+    fn loopback() {
+        let server = StreamServer::bind("127.0.0.1:0", FrameCodecs::default()).unwrap();
+        let (tx, rx) = mpsc::channel();
+        let client = start_stream_client(server.local_addr().to_string(), move |event| {
+            if let CaptureEvent::Frame(frame) = event {
+                let _ = tx.send(frame);
+            }
+            true
+        });
+
+        let deadline = Instant::now() + Duration::from_secs(10);
+        while server.client_count() == 0 {
+            assert!(Instant::now() < deadline, "Client didn't connect");
+            thread::sleep(Duration::from_millis(10));
+        }
+        let sent = test_frame(5, 3);
+        server.send(&sent).unwrap();
+        let received = rx.recv_timeout(Duration::from_secs(10)).unwrap();
+        client.stop();
+
+        assert_eq!(received.width(), sent.width());
+        assert_eq!(received.depth(), sent.depth());
+        assert_eq!(received.color(), sent.color());
+        assert_eq!(received.valid(), sent.valid());
+        assert_eq!(received.position(), sent.position());
+        assert_eq!(received.serial(), "test");
+        assert_eq!(received.metadata().depth.frame_number, 42);
+    }
+}
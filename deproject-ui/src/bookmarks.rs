@@ -0,0 +1,119 @@
+//! Named camera views, kept in eframe's storage
+
+use anyhow::Result;
+use eframe::egui::{Button, Grid, Ui};
+use eframe::Storage;
+use glam::Vec3;
+use serde::{Deserialize, Serialize};
+
+use crate::camera::{ArcBall, Camera};
+
+/// Storage key of the bookmarks
+const BOOKMARKS_KEY: &str = "camera_views";
+
+/// Where the camera is and how it projects, without its controller settings
+#[derive(Copy, Clone, Debug, Serialize, Deserialize)]
+pub struct CameraView {
+    pub pivot: [f32; 3],
+    pub yaw: f32,
+    pub pitch: f32,
+    pub distance: f32,
+    pub orthographic: bool,
+}
+
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct Bookmark {
+    pub name: String,
+    pub view: CameraView,
+}
+
+/// Contents of `BOOKMARKS_KEY`
+#[derive(Clone, Debug, Default, Serialize, Deserialize)]
+pub struct CameraBookmarks {
+    pub bookmarks: Vec<Bookmark>,
+    /// Name for the next bookmark
+    #[serde(skip)]
+    new_name: String,
+    /// Set by the UI to fit the camera to the live cloud on the next frame
+    #[serde(skip)]
+    pub frame_all: bool,
+}
+
+impl CameraView {
+    pub fn of(camera: &Camera) -> Self {
+        Self {
+            pivot: camera.view.pivot.into(),
+            yaw: camera.view.yaw,
+            pitch: camera.view.pitch,
+            distance: camera.view.distance,
+            orthographic: camera.orthographic,
+        }
+    }
+
+    pub fn arcball(&self) -> ArcBall {
+        ArcBall {
+            pivot: Vec3::from(self.pivot),
+            distance: self.distance,
+            yaw: self.yaw,
+            pitch: self.pitch,
+        }
+    }
+}
+
+impl CameraBookmarks {
+    /// Load from "storage", or start empty if nothing has been saved yet
+    pub fn load(storage: Option<&dyn Storage>) -> Result<Self> {
+        match storage.and_then(|s| s.get_string(BOOKMARKS_KEY)) {
+            Some(json) => Ok(serde_json::from_str(&json)?),
+            None => Ok(Self::default()),
+        }
+    }
+
+    pub fn save(&self, storage: &mut dyn Storage) -> Result<()> {
+        storage.set_string(BOOKMARKS_KEY, serde_json::to_string(self)?);
+        Ok(())
+    }
+}
+
+/// Saved views, which animate the camera to them when clicked
+pub fn bookmarks_ui(ui: &mut Ui, bookmarks: &mut CameraBookmarks, camera: &mut Camera) {
+    ui.strong("Camera views");
+    if ui.button("Frame all").clicked() {
+        bookmarks.frame_all = true;
+    }
+
+    let mut delete = None;
+    Grid::new("camera_bookmarks").show(ui, |ui| {
+        for (idx, bookmark) in bookmarks.bookmarks.iter_mut().enumerate() {
+            if ui.button(&bookmark.name).clicked() {
+                let view = bookmark.view;
+                camera.animate_to(view.arcball(), view.orthographic);
+            }
+            if ui.button("Update").clicked() {
+                bookmark.view = CameraView::of(camera);
+            }
+            if ui.button("Delete").clicked() {
+                delete = Some(idx);
+            }
+            ui.end_row();
+        }
+    });
+    if let Some(idx) = delete {
+        bookmarks.bookmarks.remove(idx);
+    }
+
+    ui.horizontal(|ui| {
+        ui.text_edit_singleline(&mut bookmarks.new_name);
+        let name = bookmarks.new_name.trim();
+        if ui
+            .add_enabled(!name.is_empty(), Button::new("Save view"))
+            .clicked()
+        {
+            bookmarks.bookmarks.push(Bookmark {
+                name: name.to_string(),
+                view: CameraView::of(camera),
+            });
+            bookmarks.new_name.clear();
+        }
+    });
+}
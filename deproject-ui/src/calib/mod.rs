@@ -0,0 +1,418 @@
+use std::collections::BTreeMap;
+use std::sync::mpsc::{self, Receiver, TryRecvError};
+use std::sync::Arc;
+use std::thread;
+
+use anyhow::{bail, ensure, Result};
+
+use deproject_io::accumulate::FrameAccumulator;
+use deproject_io::extrinsics::{
+    calibrate_rig, DecodedCapture, PairCalibration, PairCalibrationParams,
+};
+use deproject_io::graycode::{pattern_sequence, Axis, Pattern, PatternImages, ProjectorMap};
+use deproject_io::recording::{CaptureParams, RecordingHeader, RecordingThread, RecordingWriter};
+use deproject_io::ImagePointCloud;
+use eframe::egui::{self, Color32, ComboBox, DragValue, Grid, Rect, Ui};
+
+use crate::rig::{Extrinsics, Rig};
+use crate::{CalibratorConfig, RecorderConfig};
+
+/// Runtime state of structured light capture and calibration
+#[derive(Default)]
+pub struct Calibrator {
+    sequence: Option<CaptureSequence>,
+    /// Decoded captures from the last completed sequence, keyed by serial number. Shared with the
+    /// solver thread.
+    captures: Arc<BTreeMap<String, DecodedCapture>>,
+    /// Transforms into the reference camera's frame, keyed by serial number
+    results: BTreeMap<String, PairCalibration>,
+    /// Results of the solve running on a worker thread, if solving
+    solving: Option<Receiver<BTreeMap<String, PairCalibration>>>,
+    /// Why the last capture was cancelled, if it failed
+    error: Option<String>,
+}
+
+/// Steps through the Gray code patterns, collecting images from every device for each one
+struct CaptureSequence {
+    patterns: Vec<Pattern>,
+    current: usize,
+    settle_frames: usize,
+    pics_per_pattern: usize,
+    devices: BTreeMap<String, DeviceCapture>,
+    /// Recording the collected frames are saved to, if saving captures
+    writer: Option<RecordingThread>,
+}
+
+struct DeviceCapture {
+    /// Frames received since the current pattern was shown
+    frames_seen: usize,
+    images: PatternImages,
+    /// The first captured frames, fused for 3D positions
+    depth: FrameAccumulator,
+}
+
+impl Calibrator {
+    /// Begin displaying patterns and capturing from every device of the rig
+    pub fn start(&mut self, cfg: &RecorderConfig, rig: &Rig) {
+        let serials: Vec<String> = rig.devices.keys().cloned().collect();
+        // Room for every frame of a pattern, so the writer only has to keep up between patterns
+        let queue_len = (cfg.pics_per_pattern * serials.len()).max(1);
+        let writer = cfg.save_captures.then(|| {
+            let header = RecordingHeader::new(serials.clone(), rig.stream, rig.options)
+                .with_capture(CaptureParams {
+                    horiz_subdivs: cfg.horiz_subdivs,
+                    vert_subdivs: cfg.vert_subdivs,
+                    frames_per_pattern: cfg.pics_per_pattern,
+                    averaging: cfg.averaging,
+                });
+            RecordingWriter::create(&cfg.capture_path, &header)
+                .map(|writer| RecordingThread::spawn_with_queue(writer, queue_len))
+                .map_err(|e| eprintln!("Failed to save capture: {e:#}"))
+                .ok()
+        });
+
+        self.error = None;
+        self.sequence = Some(CaptureSequence {
+            patterns: pattern_sequence(cfg.horiz_subdivs, cfg.vert_subdivs),
+            current: 0,
+            settle_frames: cfg.settle_frames,
+            pics_per_pattern: cfg.pics_per_pattern,
+            devices: serials
+                .into_iter()
+                .map(|s| {
+                    let device = DeviceCapture {
+                        frames_seen: 0,
+                        images: PatternImages::default(),
+                        depth: FrameAccumulator::new(cfg.averaging),
+                    };
+                    (s, device)
+                })
+                .collect(),
+            writer: writer.flatten(),
+        });
+    }
+
+    pub fn cancel(&mut self) {
+        self.sequence = None;
+    }
+
+    /// Pattern the projector should currently display, if capturing
+    pub fn current_pattern(&self) -> Option<&Pattern> {
+        let seq = self.sequence.as_ref()?;
+        seq.patterns.get(seq.current)
+    }
+
+    /// Projector coordinates decoded by the last completed sequence, keyed by serial number
+    pub fn projector_maps(&self) -> BTreeMap<&str, &ProjectorMap> {
+        self.captures
+            .iter()
+            .map(|(serial, capture)| (serial.as_str(), &capture.map))
+            .collect()
+    }
+
+    /// Feed a camera frame to the capture sequence, decoding once all patterns are captured
+    pub fn push_frame(&mut self, frame: &Arc<ImagePointCloud>, min_contrast: f32) {
+        let Some(seq) = &mut self.sequence else {
+            return;
+        };
+
+        match seq.push_frame(frame) {
+            Ok(true) => {
+                let seq = self.sequence.take().unwrap();
+                self.captures = Arc::new(seq.decode(min_contrast));
+                self.results.clear();
+                // Results for the previous captures would no longer apply
+                self.solving = None;
+            }
+            Ok(false) => (),
+            Err(e) => {
+                self.error = Some(format!("Capture cancelled: {e:#}"));
+                self.sequence = None;
+            }
+        }
+    }
+
+    /// Solve for each camera's transform into the reference camera's frame on a worker thread,
+    /// since ICP can take several seconds. `poll_solve` collects the results.
+    pub fn solve(&mut self, reference: &str, params: PairCalibrationParams) {
+        let (tx, rx) = mpsc::channel();
+        let captures = self.captures.clone();
+        let reference = reference.to_string();
+        thread::spawn(move || {
+            // The receiver is gone if the solve was superseded, so the result can be dropped
+            let _ = tx.send(calibrate_rig(&captures, &reference, &params));
+        });
+        self.solving = Some(rx);
+    }
+
+    /// Store the results of the solve once it finishes
+    pub fn poll_solve(&mut self) {
+        let Some(rx) = &self.solving else {
+            return;
+        };
+        match rx.try_recv() {
+            Ok(results) => {
+                self.results = results;
+                self.solving = None;
+            }
+            Err(TryRecvError::Empty) => (),
+            Err(TryRecvError::Disconnected) => {
+                eprintln!("Calibration solver stopped without a result");
+                self.solving = None;
+            }
+        }
+    }
+}
+
+impl CaptureSequence {
+    /// Returns true once every pattern has been captured. Fails if the saved capture would be
+    /// missing frames.
+    fn push_frame(&mut self, frame: &Arc<ImagePointCloud>) -> Result<bool> {
+        let Some(device) = self.devices.get_mut(frame.serial()) else {
+            return Ok(false);
+        };
+
+        device.frames_seen += 1;
+        if device.frames_seen > self.settle_frames && device.images.count() < self.pics_per_pattern
+        {
+            device.images.add(frame.color());
+            device.depth.add(frame);
+            // Queued without blocking, so the pattern on the projector stays current
+            if let Some(writer) = &self.writer {
+                if let Err(e) = writer.try_send(frame.clone()) {
+                    eprintln!("Failed to save capture: {e:#}");
+                    self.writer = None;
+                }
+            }
+        }
+
+        let all_done = self
+            .devices
+            .values()
+            .all(|d| d.images.count() >= self.pics_per_pattern);
+        if all_done {
+            // Every captured frame is needed to decode the saved capture
+            if self.writer.as_ref().is_some_and(|w| w.dropped_frames() > 0) {
+                bail!("Saving the capture fell behind, try a faster disk or fewer frames");
+            }
+            for device in self.devices.values_mut() {
+                device.images.finish_pattern();
+                device.frames_seen = 0;
+            }
+            self.current += 1;
+        }
+
+        Ok(self.current >= self.patterns.len())
+    }
+
+    fn decode(self, min_contrast: f32) -> BTreeMap<String, DecodedCapture> {
+        let patterns = self.patterns;
+        self.devices
+            .into_iter()
+            .filter_map(|(serial, device)| {
+                let cloud = device.depth.fuse()?;
+                let map = ProjectorMap::decode_sequence(
+                    &patterns,
+                    device.images.into_images(),
+                    cloud.width(),
+                    min_contrast,
+                )
+                .and_then(|map| {
+                    ensure!(
+                        map.coords().len() == cloud.valid().len(),
+                        "Pattern images don't match the depth frames"
+                    );
+                    Ok(map)
+                });
+                match map {
+                    Ok(map) => Some((serial, DecodedCapture { map, cloud })),
+                    Err(e) => {
+                        eprintln!("Failed to decode the capture from {serial}: {e:#}");
+                        None
+                    }
+                }
+            })
+            .collect()
+    }
+}
+
+/// Fill the available space with the given pattern
+pub fn draw_pattern(ui: &mut Ui, pattern: &Pattern) {
+    let rect = ui.max_rect();
+    let painter = ui.painter();
+    painter.rect_filled(rect, 0., Color32::BLACK);
+
+    let resolution = pattern.resolution();
+    let extent = match pattern.axis {
+        Axis::Columns => rect.width(),
+        Axis::Rows => rect.height(),
+    };
+    let step = extent / resolution as f32;
+
+    // Draw runs of lit stripes as single rectangles
+    let mut run_start = None;
+    for coord in 0..=resolution {
+        let lit = coord < resolution && pattern.is_lit(coord);
+        match (run_start, lit) {
+            (None, true) => run_start = Some(coord),
+            (Some(start), false) => {
+                let (a, b) = (start as f32 * step, coord as f32 * step);
+                let stripe = match pattern.axis {
+                    Axis::Columns => {
+                        Rect::from_x_y_ranges(rect.left() + a..=rect.left() + b, rect.y_range())
+                    }
+                    Axis::Rows => {
+                        Rect::from_x_y_ranges(rect.x_range(), rect.top() + a..=rect.top() + b)
+                    }
+                };
+                painter.rect_filled(stripe, 0., Color32::WHITE);
+                run_start = None;
+            }
+            _ => (),
+        }
+    }
+}
+
+/// Capture progress and controls, shown in the Record tab
+pub fn capture_ui(ui: &mut Ui, calibrator: &mut Calibrator, cfg: &RecorderConfig, rig: &Rig) {
+    match &calibrator.sequence {
+        Some(seq) => {
+            ui.add(
+                egui::ProgressBar::new(seq.current as f32 / seq.patterns.len() as f32).text(
+                    format!("Pattern {}/{}", seq.current + 1, seq.patterns.len()),
+                ),
+            );
+            if ui.button("Cancel").clicked() {
+                calibrator.cancel();
+            }
+        }
+        None => {
+            if let Some(error) = &calibrator.error {
+                ui.colored_label(Color32::RED, error);
+            }
+            ui.centered_and_justified(|ui| {
+                if ui.button("Start").clicked() {
+                    calibrator.start(cfg, rig);
+                }
+            });
+        }
+    }
+}
+
+pub fn calib_ui(
+    ui: &mut Ui,
+    cfg: &mut CalibratorConfig,
+    calibrator: &mut Calibrator,
+    rig: &mut Rig,
+) {
+    ui.strong("Decoding");
+    ui.add(
+        DragValue::new(&mut cfg.min_contrast)
+            .prefix("Minimum contrast: ")
+            .speed(1e-3)
+            .clamp_range(0.0..=1.0),
+    );
+
+    ui.separator();
+
+    ui.strong("Captures");
+    if calibrator.captures.is_empty() {
+        ui.label("No captures yet, start one from the Record tab");
+        return;
+    }
+    for (serial, capture) in calibrator.captures.iter() {
+        let decoded = capture.map.coords().iter().filter(|c| c.is_some()).count();
+        ui.label(format!("{serial}: {decoded} pixels decoded"));
+    }
+
+    ui.separator();
+
+    ui.strong("Camera to camera extrinsics");
+    let reference = cfg.reference.get_or_insert_with(|| {
+        calibrator
+            .captures
+            .keys()
+            .next()
+            .cloned()
+            .unwrap_or_default()
+    });
+    ComboBox::from_label("Reference camera")
+        .selected_text(reference.as_str())
+        .show_ui(ui, |ui| {
+            for serial in calibrator.captures.keys() {
+                ui.selectable_value(reference, serial.clone(), serial);
+            }
+        });
+
+    ui.add(
+        DragValue::new(&mut cfg.params.outlier_factor)
+            .prefix("Outlier rejection (x median): ")
+            .speed(1e-2)
+            .clamp_range(1.0..=20.0),
+    );
+    ui.add(
+        DragValue::new(&mut cfg.params.icp_iterations)
+            .prefix("ICP iterations: ")
+            .clamp_range(0..=100),
+    );
+    ui.add(
+        DragValue::new(&mut cfg.params.icp_stride)
+            .prefix("ICP point stride: ")
+            .clamp_range(1..=256),
+    );
+
+    match calibrator.solving {
+        Some(_) => {
+            ui.horizontal(|ui| {
+                ui.spinner();
+                ui.label("Solving");
+            });
+        }
+        None => {
+            if ui.button("Solve").clicked() {
+                calibrator.solve(reference, cfg.params);
+            }
+        }
+    }
+
+    if calibrator.results.is_empty() {
+        return;
+    }
+
+    Grid::new("Residuals").striped(true).show(ui, |ui| {
+        ui.strong("Camera");
+        ui.strong("Pairs");
+        ui.strong("Inliers");
+        ui.strong("Initial RMS");
+        ui.strong("RMS");
+        ui.strong("Median");
+        ui.strong("Max");
+        ui.end_row();
+
+        for (serial, result) in &calibrator.results {
+            let r = &result.refined;
+            ui.label(serial);
+            ui.label(r.count.to_string());
+            ui.label(r.inliers.to_string());
+            ui.label(format!("{:.2}", result.initial.rms));
+            ui.label(format!("{:.2}", r.rms));
+            ui.label(format!("{:.2}", r.median));
+            ui.label(format!("{:.2}", r.max));
+            ui.end_row();
+        }
+    });
+
+    if ui.button("Apply to rig").clicked() {
+        let world_from_ref = rig
+            .devices
+            .get(reference.as_str())
+            .map(|d| d.extrinsics.matrix())
+            .unwrap_or_default();
+        for (serial, result) in &calibrator.results {
+            if let Some(device) = rig.devices.get_mut(serial) {
+                let world_from_other = world_from_ref * glam::Mat4::from(result.transform);
+                device.extrinsics = Extrinsics::from_matrix(world_from_other);
+            }
+        }
+    }
+}
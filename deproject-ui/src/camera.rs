@@ -1,19 +1,173 @@
-use std::f32::consts::FRAC_PI_2;
+use std::f32::consts::{FRAC_PI_2, PI, TAU};
+use std::time::{Duration, Instant};
 
+use eframe::egui::{ComboBox, DragValue, Ui};
 use glam::{Mat4, Vec3, Vec4, Vec4Swizzles};
+use serde::{Deserialize, Serialize};
+
+/// Length of animated transitions between views
+const TRANSITION_TIME: Duration = Duration::from_millis(400);
+
+/// Pitch of the top view, just short of straight down so the view's up vector stays defined
+const TOP_PITCH: f32 = FRAC_PI_2 - 1e-3;
 
 /// Camera controller and parameters
-#[derive(Default, Copy, Clone)]
+#[derive(Default, Copy, Clone, Serialize, Deserialize)]
+#[serde(default)]
 pub struct Camera {
     pub proj: Perspective,
     pub view: ArcBall,
     pub control: ArcBallController,
+    pub mode: ControlMode,
+    pub orthographic: bool,
+    /// 0 for a perspective projection and 1 for orthographic, in between while animating
+    #[serde(skip)]
+    ortho_blend: f32,
+    #[serde(skip)]
+    transition: Option<Transition>,
+}
+
+/// How mouse and keyboard input moves the camera
+#[derive(Default, Copy, Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub enum ControlMode {
+    /// Drag to orbit around the pivot
+    #[default]
+    Orbit,
+    /// Drag to look around from the eye, WASD to move, Q and E to descend and ascend
+    Fly,
+}
+
+/// Fixed views along the world axes
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum ViewPreset {
+    Top,
+    Front,
+    Side,
+}
+
+/// An animation from one view to another
+#[derive(Copy, Clone)]
+struct Transition {
+    from: ArcBall,
+    to: ArcBall,
+    from_ortho: f32,
+    start: Instant,
 }
 
 impl Camera {
     /// Return the projection matrix of this camera
     pub fn projection(&self, width: f32, height: f32) -> Mat4 {
-        self.proj.matrix(width, height)
+        let perspective = self.proj.matrix(width, height);
+        if self.ortho_blend == 0. {
+            return perspective;
+        }
+
+        // Match the perspective projection's extent at the pivot, so switching keeps the scale
+        let half_height = self.view.distance * (self.proj.fov / 2.).tan();
+        let half_width = half_height * width / height;
+        let ortho = Mat4::orthographic_rh(
+            -half_width,
+            half_width,
+            -half_height,
+            half_height,
+            -self.proj.clip_far,
+            self.proj.clip_far,
+        );
+
+        let t = self.ortho_blend;
+        Mat4::from_cols_array(&std::array::from_fn(|i| {
+            let (p, o) = (perspective.to_cols_array()[i], ortho.to_cols_array()[i]);
+            p + (o - p) * t
+        }))
+    }
+
+    /// Advance any running transition. Returns true while animating.
+    pub fn update(&mut self, now: Instant) -> bool {
+        let target_ortho = self.orthographic as u8 as f32;
+        let Some(transition) = self.transition else {
+            self.ortho_blend = target_ortho;
+            return false;
+        };
+
+        let t = (now - transition.start).as_secs_f32() / TRANSITION_TIME.as_secs_f32();
+        if t >= 1. {
+            self.view = transition.to;
+            self.ortho_blend = target_ortho;
+            self.transition = None;
+            return false;
+        }
+
+        // Ease in and out
+        let t = t * t * (3. - 2. * t);
+        self.view = transition.from.lerp(&transition.to, t);
+        self.ortho_blend = transition.from_ortho + (target_ortho - transition.from_ortho) * t;
+        true
+    }
+
+    /// Animate to the given view and projection
+    pub fn animate_to(&mut self, view: ArcBall, orthographic: bool) {
+        self.transition = Some(Transition {
+            from: self.view,
+            to: view,
+            from_ortho: self.ortho_blend,
+            start: Instant::now(),
+        });
+        self.orthographic = orthographic;
+    }
+
+    /// Animate to orbiting around "point", keeping the eye where it is
+    pub fn orbit_about(&mut self, point: Vec3) {
+        let offset = self.view.pivot + self.view.eye() - point;
+        let distance = offset.length().max(self.control.closest_zoom);
+        let view = ArcBall {
+            pivot: point,
+            distance,
+            yaw: offset.z.atan2(offset.x),
+            pitch: (offset.y / distance).clamp(-1., 1.).asin(),
+        };
+        self.animate_to(view, self.orthographic);
+    }
+
+    /// Animate to looking at the box from "min" to "max" from the current direction, close
+    /// enough that it fills the view
+    pub fn frame(&mut self, min: Vec3, max: Vec3) {
+        let radius = (max - min).length() / 2.;
+        let view = ArcBall {
+            pivot: (min + max) / 2.,
+            distance: (radius / (self.proj.fov / 2.).sin()).max(self.control.closest_zoom),
+            ..self.view
+        };
+        self.animate_to(view, self.orthographic);
+    }
+
+    /// Animate to looking along a world axis, with an orthographic projection
+    pub fn preset(&mut self, preset: ViewPreset) {
+        let (yaw, pitch) = match preset {
+            ViewPreset::Top => (-FRAC_PI_2, TOP_PITCH),
+            ViewPreset::Front => (FRAC_PI_2, 0.),
+            ViewPreset::Side => (0., 0.),
+        };
+        let view = ArcBall {
+            yaw,
+            pitch,
+            ..self.view
+        };
+        self.animate_to(view, true);
+    }
+
+    /// Look around by the given mouse pointer delta, keeping the eye in place
+    pub fn look(&mut self, delta_x: f32, delta_y: f32) {
+        let eye = self.view.pivot + self.view.eye();
+        self.control.pivot(&mut self.view, delta_x, delta_y);
+        self.view.pivot = eye - self.view.eye();
+    }
+
+    /// Move the eye and pivot together, by the given amounts along the view's forward, right and
+    /// world up directions
+    pub fn fly(&mut self, forward: f32, right: f32, up: f32) {
+        let forward_dir = -self.view.eye().normalize_or_zero();
+        let right_dir = forward_dir.cross(Vec3::Y).normalize_or_zero();
+        self.view.pivot += forward_dir * forward + right_dir * right + Vec3::Y * up;
     }
 
     /// Return the view matrix of this camera
@@ -38,7 +192,7 @@ impl Camera {
 }
 
 /// Perspective projection parameters
-#[derive(Copy, Clone)]
+#[derive(Copy, Clone, Serialize, Deserialize)]
 pub struct Perspective {
     pub fov: f32,
     pub clip_near: f32,
@@ -46,7 +200,7 @@ pub struct Perspective {
 }
 
 /// Arcball camera parameters
-#[derive(Copy, Clone)]
+#[derive(Copy, Clone, Serialize, Deserialize)]
 pub struct ArcBall {
     pub pivot: Vec3,
     pub distance: f32,
@@ -55,12 +209,15 @@ pub struct ArcBall {
 }
 
 /// Arcball camera controller parameters
-#[derive(Copy, Clone)]
+#[derive(Copy, Clone, Serialize, Deserialize)]
+#[serde(default)]
 pub struct ArcBallController {
     pub pan_sensitivity: f32,
     pub swivel_sensitivity: f32,
     pub zoom_sensitivity: f32,
     pub closest_zoom: f32,
+    /// Speed of the fly controller, in units per second
+    pub fly_speed: f32,
 }
 
 impl Perspective {
@@ -85,6 +242,17 @@ impl ArcBall {
             self.yaw.sin() * self.pitch.cos().abs(),
         ) * self.distance
     }
+
+    /// Interpolate towards "other", turning the shorter way around and zooming geometrically
+    fn lerp(&self, other: &Self, t: f32) -> Self {
+        let yaw_delta = (other.yaw - self.yaw + PI).rem_euclid(TAU) - PI;
+        Self {
+            pivot: self.pivot.lerp(other.pivot, t),
+            distance: self.distance * (other.distance / self.distance).powf(t),
+            yaw: self.yaw + yaw_delta * t,
+            pitch: self.pitch + (other.pitch - self.pitch) * t,
+        }
+    }
 }
 
 impl ArcBallController {
@@ -144,6 +312,53 @@ impl Default for ArcBallController {
             swivel_sensitivity: 0.005,
             zoom_sensitivity: 0.04,
             closest_zoom: 0.01,
+            fly_speed: 100.,
+        }
+    }
+}
+
+/// Controller, projection and view presets
+pub fn camera_ui(ui: &mut Ui, camera: &mut Camera) {
+    ComboBox::from_label("Camera control")
+        .selected_text(format!("{:?}", camera.mode))
+        .show_ui(ui, |ui| {
+            for mode in [ControlMode::Orbit, ControlMode::Fly] {
+                ui.selectable_value(&mut camera.mode, mode, format!("{mode:?}"));
+            }
+        });
+    match camera.mode {
+        ControlMode::Orbit => {
+            ui.label(
+                "Drag to orbit, shift-drag or right-drag to pan, middle-click to orbit a point",
+            );
+        }
+        ControlMode::Fly => {
+            ui.label("Drag to look, WASD to move, Q/E for down/up, hold shift to go faster");
+            ui.add(
+                DragValue::new(&mut camera.control.fly_speed)
+                    .prefix("Fly speed: ")
+                    .clamp_range(1.0..=10000.0),
+            );
         }
     }
+
+    ui.horizontal(|ui| {
+        let mut orthographic = camera.orthographic;
+        ui.selectable_value(&mut orthographic, false, "Perspective");
+        ui.selectable_value(&mut orthographic, true, "Orthographic");
+        if orthographic != camera.orthographic {
+            camera.animate_to(camera.view, orthographic);
+        }
+    });
+
+    ui.horizontal(|ui| {
+        for preset in [ViewPreset::Top, ViewPreset::Front, ViewPreset::Side] {
+            if ui.button(format!("{preset:?}")).clicked() {
+                camera.preset(preset);
+            }
+        }
+        if ui.button("Reset").clicked() {
+            camera.animate_to(ArcBall::default(), false);
+        }
+    });
 }
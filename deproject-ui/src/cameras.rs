@@ -0,0 +1,127 @@
+//! Frustums of the rig's cameras and projector, kept up to date in the scene's Cameras layer
+
+use std::collections::HashMap;
+use std::sync::Arc;
+
+use deproject_io::graycode::{Axis, Pattern};
+use deproject_io::{ImagePointCloud, Rs2IntrinsicsSerde};
+use glam::{Affine3A, Mat3, Mat4, Vec3};
+
+use crate::imageplane::ImagePlane;
+use crate::rig::{Extrinsics, Rig, ToViewport, DEPTH_TO_VIEWPORT};
+use crate::scene::{Geometry, Scene, CAMERAS_LAYER};
+use crate::{shapes, Vertex};
+
+/// Distance to the far end of camera frustums, in depth units
+const CAMERA_FRUSTUM_DEPTH: f32 = 300.;
+/// Distance to the projector's image plane, in depth units
+const PROJECTOR_FRUSTUM_DEPTH: f32 = 1000.;
+/// Length of the axes drawn at each camera's origin, in depth units
+const AXES_LENGTH: f32 = 50.;
+
+const DEPTH_COLOR: [f32; 3] = [0.2, 0.6, 1.0];
+const COLOR_COLOR: [f32; 3] = [1.0, 0.6, 0.2];
+const PROJECTOR_COLOR: [f32; 3] = [1.0, 1.0, 0.3];
+
+/// Brightness of the projector's image plane when no pattern is being displayed
+const IDLE_LUMINANCE: u8 = 40;
+
+/// Add or update the depth and color camera frustums of every device with a frame, and the
+/// projector's frustum and, if shown, its image plane showing "pattern"
+pub fn update_cameras(
+    scene: &mut Scene,
+    rig: &Rig,
+    frames: &HashMap<String, Arc<ImagePointCloud>>,
+    pattern: Option<&Pattern>,
+) {
+    for (serial, frame) in frames {
+        let Some(device) = rig.devices.get(serial) else {
+            continue;
+        };
+        let metadata = frame.metadata();
+
+        scene.set_generated(
+            CAMERAS_LAYER,
+            &format!("{serial} depth"),
+            Geometry::Lines(camera_lines(&metadata.depth.intrinsics, DEPTH_COLOR)),
+            device.extrinsics.to_viewport(),
+        );
+
+        // The extrinsics map depth camera points into the color camera's frame, in meters
+        let to_color = &metadata.depth_to_color;
+        let depth_to_color = Affine3A::from_mat3_translation(
+            Mat3::from_cols_array(&to_color.rotation),
+            Vec3::from(to_color.translation) / metadata.depth_scale,
+        );
+        let world_from_color = device.extrinsics.matrix() * Mat4::from(depth_to_color.inverse());
+        scene.set_generated(
+            CAMERAS_LAYER,
+            &format!("{serial} color"),
+            Geometry::Lines(camera_lines(&metadata.color.intrinsics, COLOR_COLOR)),
+            Extrinsics::from_matrix(world_from_color).to_viewport(),
+        );
+    }
+
+    let projector = &rig.projector;
+    let size = projector.resolution.map(|r| r as f32);
+    let focal = [projector.focal_length(); 2];
+    let principal = size.map(|s| s / 2.);
+    let depth = PROJECTOR_FRUSTUM_DEPTH * DEPTH_TO_VIEWPORT;
+    let transform = projector.extrinsics.to_viewport();
+
+    let mut lines = shapes::frustum(size, focal, principal, depth, PROJECTOR_COLOR);
+    lines.extend(shapes::axes(AXES_LENGTH * DEPTH_TO_VIEWPORT));
+    scene.set_generated(
+        CAMERAS_LAYER,
+        "Projector",
+        Geometry::Lines(lines),
+        transform,
+    );
+
+    if !projector.show_image {
+        scene.remove_generated("Projector image");
+        return;
+    }
+    let (width, height, pixels) = pattern_image(pattern);
+    let plane = ImagePlane {
+        corners: shapes::frustum_corners(size, focal, principal, depth),
+        width,
+        height,
+        pixels,
+    };
+    scene.set_generated(
+        CAMERAS_LAYER,
+        "Projector image",
+        Geometry::Image(plane),
+        transform,
+    );
+}
+
+/// Frustum and axes of a camera, in its own frame
+fn camera_lines(intrinsics: &Rs2IntrinsicsSerde, color: [f32; 3]) -> Vec<Vertex> {
+    let mut lines = shapes::frustum(
+        [intrinsics.width as f32, intrinsics.height as f32],
+        [intrinsics.fx, intrinsics.fy],
+        [intrinsics.ppx, intrinsics.ppy],
+        CAMERA_FRUSTUM_DEPTH * DEPTH_TO_VIEWPORT,
+        color,
+    );
+    lines.extend(shapes::axes(AXES_LENGTH * DEPTH_TO_VIEWPORT));
+    lines
+}
+
+/// A pattern as a strip one pixel wide across its axis, or a dim pixel if there is none
+fn pattern_image(pattern: Option<&Pattern>) -> (i32, i32, Vec<u8>) {
+    let Some(pattern) = pattern else {
+        return (1, 1, vec![IDLE_LUMINANCE]);
+    };
+
+    let resolution = pattern.resolution();
+    let pixels = (0..resolution)
+        .map(|coord| if pattern.is_lit(coord) { 255 } else { 0 })
+        .collect();
+    match pattern.axis {
+        Axis::Columns => (resolution as i32, 1, pixels),
+        Axis::Rows => (1, resolution as i32, pixels),
+    }
+}
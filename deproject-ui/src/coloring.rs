@@ -0,0 +1,235 @@
+//! Ways of coloring points other than by their camera color, for inspecting depth and decoding
+
+use deproject_io::{graycode::ProjectorMap, ImagePointCloud};
+use eframe::egui::{ComboBox, DragValue, Ui};
+use glam::{Quat, Vec3};
+use serde::{Deserialize, Serialize};
+
+/// Color of points without the data a coloring needs, e.g. pixels which failed to decode
+const MISSING_COLOR: [f32; 3] = [0.3; 3];
+
+/// Decode contrast shown at the top of the colormap in `PointColoring::Confidence`
+const CONFIDENCE_FULL_SCALE: f32 = 0.5;
+
+/// Polynomial fits of the colormaps for each channel, constant term first
+#[rustfmt::skip]
+const TURBO: [[f32; 6]; 3] = [
+    [0.1357214, 4.615393, -42.66032, 132.1311, -152.9424, 59.28638],
+    [0.09140261, 2.194188, 4.842967, -14.18503, 4.277299, 2.829566],
+    [0.1066733, 12.64195, -60.58205, 110.3628, -89.90311, 27.34825],
+];
+#[rustfmt::skip]
+const VIRIDIS: [[f32; 7]; 3] = [
+    [0.2777273, 0.105093, -0.3308618, -4.63423, 6.22827, 4.776385, -5.435456],
+    [0.005407344, 1.404614, 0.2148476, -5.799101, 14.17993, -13.74515, 4.645853],
+    [0.3340998, 1.38459, 0.09509516, -19.33244, 56.69055, -65.35303, 26.31244],
+];
+
+/// How the points of live clouds are colored
+#[derive(Copy, Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub enum PointColoring {
+    /// Color camera image aligned to depth
+    Rgb,
+    /// Distance along the depth camera's axis, mapped over a range in meters
+    Depth {
+        colormap: Colormap,
+        min: f32,
+        max: f32,
+    },
+    /// Surface normals in the world frame, with each axis mapped to a channel
+    Normals,
+    /// Contrast of the last structured light capture of each device
+    Confidence,
+    /// Projector column decoded by the last structured light capture, as a hue
+    ProjectorColumn,
+    /// Projector row decoded by the last structured light capture, as a hue
+    ProjectorRow,
+}
+
+#[derive(Copy, Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub enum Colormap {
+    Turbo,
+    Viridis,
+}
+
+impl PointColoring {
+    pub const ALL: [Self; 6] = [
+        Self::Rgb,
+        Self::Depth {
+            colormap: Colormap::Turbo,
+            min: 0.2,
+            max: 4.0,
+        },
+        Self::Normals,
+        Self::Confidence,
+        Self::ProjectorColumn,
+        Self::ProjectorRow,
+    ];
+
+    pub fn name(&self) -> &'static str {
+        match self {
+            Self::Rgb => "RGB",
+            Self::Depth { .. } => "Depth",
+            Self::Normals => "Normals",
+            Self::Confidence => "Decode confidence",
+            Self::ProjectorColumn => "Projector column",
+            Self::ProjectorRow => "Projector row",
+        }
+    }
+
+    /// Whether the depth cloud shader can apply this coloring itself. Other colorings need
+    /// neighbouring pixels or decoded captures, so are computed on the CPU.
+    pub fn gpu_supported(&self) -> bool {
+        matches!(self, Self::Rgb | Self::Depth { .. })
+    }
+
+    /// Colors for each pixel of a frame, in the same order as `ImagePointCloud::iter_pixels`.
+    /// "map" is the last decoded capture of the frame's device, and "rotation" the device's
+    /// orientation in the world. Colors are computed as they are iterated, so only colorings
+    /// which need neighbouring pixels allocate.
+    pub fn frame_colors<'a>(
+        &self,
+        frame: &'a ImagePointCloud,
+        map: Option<&'a ProjectorMap>,
+        rotation: Quat,
+    ) -> Box<dyn Iterator<Item = [f32; 3]> + 'a> {
+        let n_pixels = frame.position().len();
+        // Captures are decoded at depth resolution, so index the same way as frames
+        let map = map.filter(|m| m.coords().len() == n_pixels);
+
+        let missing = || Box::new(std::iter::repeat_n(MISSING_COLOR, n_pixels));
+        match *self {
+            Self::Rgb => Box::new(frame.color().iter().map(|c| c.map(|c| c as f32 / 256.0))),
+            Self::Depth { colormap, min, max } => {
+                let scale = frame.metadata().depth_scale;
+                Box::new(
+                    frame
+                        .position()
+                        .iter()
+                        .map(move |p| colormap.sample((p.z * scale - min) / (max - min).max(1e-3))),
+                )
+            }
+            Self::Normals => Box::new(normals(frame).into_iter().map(move |n| match n {
+                Some(n) => ((rotation * n) * 0.5 + 0.5).into(),
+                None => MISSING_COLOR,
+            })),
+            Self::Confidence => match map {
+                Some(map) => Box::new(
+                    map.confidence()
+                        .iter()
+                        .map(|c| Colormap::Viridis.sample(c / CONFIDENCE_FULL_SCALE)),
+                ),
+                None => missing(),
+            },
+            Self::ProjectorColumn | Self::ProjectorRow => match map {
+                Some(map) => {
+                    let axis = (*self == Self::ProjectorRow) as usize;
+                    let resolution = map.resolution()[axis] as f32;
+                    Box::new(map.coords().iter().map(move |c| match c {
+                        Some(c) => hue(c[axis] as f32 / resolution),
+                        None => MISSING_COLOR,
+                    }))
+                }
+                None => missing(),
+            },
+        }
+    }
+}
+
+impl Colormap {
+    /// Color at "t", clamped to 0 to 1. Uses polynomial fits which match depthcloud.vert.
+    pub fn sample(&self, t: f32) -> [f32; 3] {
+        let t = t.clamp(0., 1.);
+        let poly = |c: &[f32]| c.iter().rev().fold(0., |acc, c| acc * t + c);
+        match self {
+            Self::Turbo => TURBO.map(|c| poly(&c)),
+            Self::Viridis => VIRIDIS.map(|c| poly(&c)),
+        }
+        .map(|c| c.clamp(0., 1.))
+    }
+
+    /// Value passed to depthcloud.vert's u_colormap
+    pub fn shader_index(&self) -> i32 {
+        match self {
+            Self::Turbo => 1,
+            Self::Viridis => 2,
+        }
+    }
+}
+
+/// Fully saturated hue for "t" from 0 to 1, stopping short of wrapping back to red
+fn hue(t: f32) -> [f32; 3] {
+    let h = t.clamp(0., 1.) * 5.;
+    [5., 3., 1.].map(|offset| {
+        let k = (h + offset) % 6.;
+        1. - k.min(4. - k).clamp(0., 1.)
+    })
+}
+
+/// Camera-frame normal of each pixel, from the positions of its neighbours
+fn normals(frame: &ImagePointCloud) -> Vec<Option<Vec3>> {
+    let (width, height) = (frame.width(), frame.height());
+    let position = frame.position();
+    let valid = frame.valid();
+    let at = |x: usize, y: usize| valid[y * width + x].then(|| position[y * width + x]);
+
+    (0..height)
+        .flat_map(|y| (0..width).map(move |x| (x, y)))
+        .map(|(x, y)| {
+            if x == 0 || y == 0 || x + 1 == width || y + 1 == height {
+                return None;
+            }
+            let dx = at(x + 1, y)? - at(x - 1, y)?;
+            let dy = at(x, y + 1)? - at(x, y - 1)?;
+            // Face the camera, which looks along +Z
+            let normal = dy.cross(dx).try_normalize()?;
+            Some(normal)
+        })
+        .collect()
+}
+
+pub fn coloring_ui(ui: &mut Ui, coloring: &mut PointColoring) {
+    ComboBox::from_label("Point color")
+        .selected_text(coloring.name())
+        .show_ui(ui, |ui| {
+            for option in PointColoring::ALL {
+                let selected = std::mem::discriminant(coloring) == std::mem::discriminant(&option);
+                if ui.selectable_label(selected, option.name()).clicked() && !selected {
+                    *coloring = option;
+                }
+            }
+        });
+
+    match coloring {
+        PointColoring::Depth { colormap, min, max } => {
+            ComboBox::from_label("Colormap")
+                .selected_text(format!("{colormap:?}"))
+                .show_ui(ui, |ui| {
+                    for option in [Colormap::Turbo, Colormap::Viridis] {
+                        ui.selectable_value(colormap, option, format!("{option:?}"));
+                    }
+                });
+            ui.horizontal(|ui| {
+                ui.label("Range");
+                ui.add(
+                    DragValue::new(min)
+                        .speed(1e-2)
+                        .suffix(" m")
+                        .clamp_range(0.0..=*max),
+                );
+                ui.add(
+                    DragValue::new(max)
+                        .speed(1e-2)
+                        .suffix(" m")
+                        .clamp_range(*min..=65.0),
+                );
+            });
+        }
+        PointColoring::Confidence
+        | PointColoring::ProjectorColumn
+        | PointColoring::ProjectorRow => {
+            ui.label("Uses the last structured light capture of each device");
+        }
+        PointColoring::Rgb | PointColoring::Normals => (),
+    }
+}
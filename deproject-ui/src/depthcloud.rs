@@ -0,0 +1,210 @@
+//! Point clouds deprojected on the GPU from raw depth and color textures
+
+use std::sync::Arc;
+
+use deproject_io::{ImagePointCloud, Rs2IntrinsicsSerde};
+use glam::Mat4;
+use glow::HasContext;
+
+/// A frame to be deprojected by the GPU
+#[derive(Clone)]
+pub struct DepthCloud {
+    pub frame: Arc<ImagePointCloud>,
+    /// Depth camera to viewport transform
+    pub transform: Mat4,
+}
+
+/// Textures and parameters of a `DepthCloud` resident on the GPU
+pub struct GpuDepthCloud {
+    depth_tex: glow::Texture,
+    color_tex: glow::Texture,
+    depth_size: (i32, i32),
+    color_size: (i32, i32),
+    depth_intrinsics: Rs2IntrinsicsSerde,
+    color_intrinsics: Rs2IntrinsicsSerde,
+    depth_to_color_rotation: [f32; 9],
+    depth_to_color_translation: [f32; 3],
+    /// Meters per depth unit
+    depth_scale: f32,
+    transform: Mat4,
+}
+
+impl GpuDepthCloud {
+    pub fn new(gl: &glow::Context) -> Self {
+        unsafe {
+            let create = || {
+                let tex = gl.create_texture().expect("Cannot create texture");
+                gl.bind_texture(glow::TEXTURE_2D, Some(tex));
+                for param in [glow::TEXTURE_MIN_FILTER, glow::TEXTURE_MAG_FILTER] {
+                    gl.tex_parameter_i32(glow::TEXTURE_2D, param, glow::NEAREST as i32);
+                }
+                gl.bind_texture(glow::TEXTURE_2D, None);
+                tex
+            };
+
+            Self {
+                depth_tex: create(),
+                color_tex: create(),
+                depth_size: (0, 0),
+                color_size: (0, 0),
+                depth_intrinsics: Default::default(),
+                color_intrinsics: Default::default(),
+                depth_to_color_rotation: [0.; 9],
+                depth_to_color_translation: [0.; 3],
+                depth_scale: 0.,
+                transform: Mat4::IDENTITY,
+            }
+        }
+    }
+
+    /// Upload the raw images of a frame. Returns false if the frame has no raw images, or its depth
+    /// distortion model can't be deprojected.
+    pub fn upload(&mut self, gl: &glow::Context, cloud: &DepthCloud) -> bool {
+        let frame = &cloud.frame;
+        let metadata = frame.metadata();
+        let depth_size = (frame.width() as i32, frame.height() as i32);
+        let color_size = (
+            metadata.color.intrinsics.width,
+            metadata.color.intrinsics.height,
+        );
+
+        let color_pixels = (color_size.0 * color_size.1) as usize;
+        if frame.depth().is_empty()
+            || frame.raw_color().len() != color_pixels
+            || !metadata.depth.intrinsics.can_deproject()
+        {
+            return false;
+        }
+
+        unsafe {
+            // Rows of RGB8 and R16 images aren't necessarily 4-byte aligned
+            gl.pixel_store_i32(glow::UNPACK_ALIGNMENT, 1);
+            upload_texture(
+                gl,
+                self.depth_tex,
+                &mut self.depth_size,
+                depth_size,
+                (glow::R16UI, glow::RED_INTEGER, glow::UNSIGNED_SHORT),
+                bytemuck::cast_slice(frame.depth()),
+            );
+            upload_texture(
+                gl,
+                self.color_tex,
+                &mut self.color_size,
+                color_size,
+                (glow::RGB8, glow::RGB, glow::UNSIGNED_BYTE),
+                bytemuck::cast_slice(frame.raw_color()),
+            );
+            gl.pixel_store_i32(glow::UNPACK_ALIGNMENT, 4);
+        }
+
+        self.depth_intrinsics = metadata.depth.intrinsics;
+        self.color_intrinsics = metadata.color.intrinsics;
+        self.depth_to_color_rotation = metadata.depth_to_color.rotation;
+        self.depth_to_color_translation = metadata.depth_to_color.translation;
+        self.depth_scale = metadata.depth_scale;
+        self.transform = cloud.transform;
+
+        true
+    }
+
+    /// Draw one point per depth pixel using the depth cloud program, whose camera uniforms must
+    /// already be set. An empty vertex array must be bound. The frame is placed in the world by
+    /// "model" after its own transform.
+    pub fn draw(&self, gl: &glow::Context, program: glow::Program, model: Mat4) {
+        unsafe {
+            let loc = |name: &str| gl.get_uniform_location(program, name);
+
+            gl.active_texture(glow::TEXTURE0);
+            gl.bind_texture(glow::TEXTURE_2D, Some(self.depth_tex));
+            gl.uniform_1_i32(loc("u_depth").as_ref(), 0);
+            gl.active_texture(glow::TEXTURE1);
+            gl.bind_texture(glow::TEXTURE_2D, Some(self.color_tex));
+            gl.uniform_1_i32(loc("u_color").as_ref(), 1);
+
+            gl.uniform_matrix_4_f32_slice(
+                loc("u_model").as_ref(),
+                false,
+                &(model * self.transform).to_cols_array(),
+            );
+
+            for (prefix, intrin) in [
+                ("u_depth", &self.depth_intrinsics),
+                ("u_color", &self.color_intrinsics),
+            ] {
+                gl.uniform_2_f32(
+                    loc(&format!("{prefix}_pp")).as_ref(),
+                    intrin.ppx,
+                    intrin.ppy,
+                );
+                gl.uniform_2_f32(loc(&format!("{prefix}_f")).as_ref(), intrin.fx, intrin.fy);
+                gl.uniform_1_i32(
+                    loc(&format!("{prefix}_model")).as_ref(),
+                    intrin.model as i32,
+                );
+                gl.uniform_1_f32_slice(loc(&format!("{prefix}_coeffs")).as_ref(), &intrin.coeffs);
+            }
+
+            gl.uniform_matrix_3_f32_slice(
+                loc("u_depth_to_color_rot").as_ref(),
+                false,
+                &self.depth_to_color_rotation,
+            );
+            let [x, y, z] = self.depth_to_color_translation;
+            gl.uniform_3_f32(loc("u_depth_to_color_tl").as_ref(), x, y, z);
+            gl.uniform_1_f32(loc("u_depth_scale").as_ref(), self.depth_scale);
+
+            gl.draw_arrays(glow::POINTS, 0, self.depth_size.0 * self.depth_size.1);
+
+            gl.bind_texture(glow::TEXTURE_2D, None);
+            gl.active_texture(glow::TEXTURE0);
+            gl.bind_texture(glow::TEXTURE_2D, None);
+        }
+    }
+
+    pub fn destroy(&self, gl: &glow::Context) {
+        unsafe {
+            gl.delete_texture(self.depth_tex);
+            gl.delete_texture(self.color_tex);
+        }
+    }
+}
+
+/// Replace the contents of a texture, only reallocating its storage if the size changed
+unsafe fn upload_texture(
+    gl: &glow::Context,
+    tex: glow::Texture,
+    current_size: &mut (i32, i32),
+    size: (i32, i32),
+    (internal_format, format, ty): (u32, u32, u32),
+    data: &[u8],
+) {
+    gl.bind_texture(glow::TEXTURE_2D, Some(tex));
+    if *current_size == size {
+        gl.tex_sub_image_2d(
+            glow::TEXTURE_2D,
+            0,
+            0,
+            0,
+            size.0,
+            size.1,
+            format,
+            ty,
+            glow::PixelUnpackData::Slice(data),
+        );
+    } else {
+        gl.tex_image_2d(
+            glow::TEXTURE_2D,
+            0,
+            internal_format as i32,
+            size.0,
+            size.1,
+            0,
+            format,
+            ty,
+            Some(data),
+        );
+        *current_size = size;
+    }
+    gl.bind_texture(glow::TEXTURE_2D, None);
+}
@@ -0,0 +1,142 @@
+//! A textured quad showing a single-channel image in the 3D view, e.g. the projector's pattern
+
+use glam::Mat4;
+use glow::HasContext;
+
+/// An image to be drawn across a quad
+#[derive(Clone, PartialEq)]
+pub struct ImagePlane {
+    /// Corners of the quad in object space, clockwise from the top left of the image
+    pub corners: [[f32; 3]; 4],
+    pub width: i32,
+    pub height: i32,
+    /// Luminance, row-major from the top left
+    pub pixels: Vec<u8>,
+}
+
+/// Geometry and texture of an `ImagePlane` resident on the GPU
+pub struct GpuImagePlane {
+    array: glow::VertexArray,
+    buf: glow::Buffer,
+    tex: glow::Texture,
+    size: (i32, i32),
+    /// Last uploaded image, to skip uploads when it hasn't changed
+    pixels: Vec<u8>,
+}
+
+impl GpuImagePlane {
+    pub fn new(gl: &glow::Context) -> Self {
+        unsafe {
+            let array = gl.create_vertex_array().unwrap();
+            let buf = gl.create_buffer().expect("Cannot create vertex buffer");
+            gl.bind_vertex_array(Some(array));
+            gl.bind_buffer(glow::ARRAY_BUFFER, Some(buf));
+
+            // Position followed by texture coordinate
+            let stride = 5 * std::mem::size_of::<f32>() as i32;
+            gl.enable_vertex_attrib_array(0);
+            gl.vertex_attrib_pointer_f32(0, 3, glow::FLOAT, false, stride, 0);
+            gl.enable_vertex_attrib_array(1);
+            gl.vertex_attrib_pointer_f32(
+                1,
+                2,
+                glow::FLOAT,
+                false,
+                stride,
+                3 * std::mem::size_of::<f32>() as i32,
+            );
+
+            gl.bind_vertex_array(None);
+            gl.bind_buffer(glow::ARRAY_BUFFER, None);
+
+            let tex = gl.create_texture().expect("Cannot create texture");
+            gl.bind_texture(glow::TEXTURE_2D, Some(tex));
+            for param in [glow::TEXTURE_MIN_FILTER, glow::TEXTURE_MAG_FILTER] {
+                gl.tex_parameter_i32(glow::TEXTURE_2D, param, glow::NEAREST as i32);
+            }
+            for param in [glow::TEXTURE_WRAP_S, glow::TEXTURE_WRAP_T] {
+                gl.tex_parameter_i32(glow::TEXTURE_2D, param, glow::CLAMP_TO_EDGE as i32);
+            }
+            gl.bind_texture(glow::TEXTURE_2D, None);
+
+            Self {
+                array,
+                buf,
+                tex,
+                size: (0, 0),
+                pixels: vec![],
+            }
+        }
+    }
+
+    pub fn upload(&mut self, gl: &glow::Context, plane: &ImagePlane) {
+        let uvs = [[0., 0.], [1., 0.], [1., 1.], [0., 1.]];
+        let vertices: Vec<f32> = plane
+            .corners
+            .iter()
+            .zip(uvs)
+            .flat_map(|([x, y, z], [u, v])| [*x, *y, *z, u, v])
+            .collect();
+
+        unsafe {
+            gl.bind_buffer(glow::ARRAY_BUFFER, Some(self.buf));
+            gl.buffer_data_u8_slice(
+                glow::ARRAY_BUFFER,
+                bytemuck::cast_slice(&vertices),
+                glow::DYNAMIC_DRAW,
+            );
+            gl.bind_buffer(glow::ARRAY_BUFFER, None);
+
+            let size = (plane.width, plane.height);
+            if size == self.size && plane.pixels == self.pixels {
+                return;
+            }
+
+            gl.bind_texture(glow::TEXTURE_2D, Some(self.tex));
+            gl.pixel_store_i32(glow::UNPACK_ALIGNMENT, 1);
+            gl.tex_image_2d(
+                glow::TEXTURE_2D,
+                0,
+                glow::R8 as i32,
+                plane.width,
+                plane.height,
+                0,
+                glow::RED,
+                glow::UNSIGNED_BYTE,
+                Some(&plane.pixels),
+            );
+            gl.pixel_store_i32(glow::UNPACK_ALIGNMENT, 4);
+            gl.bind_texture(glow::TEXTURE_2D, None);
+        }
+
+        self.size = (plane.width, plane.height);
+        self.pixels.clone_from(&plane.pixels);
+    }
+
+    /// Draw using the image plane program, whose camera uniforms must already be set
+    pub fn draw(&self, gl: &glow::Context, program: glow::Program, model: Mat4) {
+        unsafe {
+            gl.uniform_matrix_4_f32_slice(
+                gl.get_uniform_location(program, "u_model").as_ref(),
+                false,
+                &model.to_cols_array(),
+            );
+            gl.active_texture(glow::TEXTURE0);
+            gl.bind_texture(glow::TEXTURE_2D, Some(self.tex));
+            gl.uniform_1_i32(gl.get_uniform_location(program, "u_image").as_ref(), 0);
+
+            gl.bind_vertex_array(Some(self.array));
+            gl.draw_arrays(glow::TRIANGLE_FAN, 0, 4);
+            gl.bind_vertex_array(None);
+            gl.bind_texture(glow::TEXTURE_2D, None);
+        }
+    }
+
+    pub fn destroy(&self, gl: &glow::Context) {
+        unsafe {
+            gl.delete_vertex_array(self.array);
+            gl.delete_buffer(self.buf);
+            gl.delete_texture(self.tex);
+        }
+    }
+}
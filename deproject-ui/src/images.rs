@@ -0,0 +1,349 @@
+//! 2D views of the images behind the live cloud, with readouts of the pixel under the cursor
+
+use std::collections::HashMap;
+use std::sync::Arc;
+
+use deproject_io::ImagePointCloud;
+use eframe::egui::{
+    self, Color32, ColorImage, ComboBox, Context, DragValue, Grid, Rect, Sense, Stroke,
+    TextureHandle, TextureOptions, Ui, Window,
+};
+use serde::{Deserialize, Serialize};
+
+use crate::coloring::Colormap;
+use crate::measure::{Pick, CURSOR_COLOR};
+
+/// Images of a frame which can be shown
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum ImageKind {
+    /// Color camera image at its own resolution
+    Color,
+    /// Raw depth image, colormapped
+    Depth,
+    /// Color image aligned to depth
+    AlignedColor,
+    /// Pixels with valid depth in white
+    Valid,
+}
+
+/// Settings of the image views, saved between sessions
+#[derive(Serialize, Deserialize)]
+#[serde(default)]
+pub struct ImagesConfig {
+    pub open: bool,
+    /// Serial number of the device whose images are shown, or None for the first
+    pub serial: Option<String>,
+    pub colormap: Colormap,
+    /// Depth mapped to the bottom of the colormap, in meters
+    pub min: f32,
+    /// Depth mapped to the top of the colormap, in meters
+    pub max: f32,
+}
+
+/// Textures of the images on show
+#[derive(Default)]
+pub struct ImageViews {
+    /// Frame the textures were made from
+    source: Option<Arc<ImagePointCloud>>,
+    /// Colormap and range the depth texture was made with
+    depth_coloring: Option<(Colormap, f32, f32)>,
+    /// Texture of each of `ImageKind::ALL`, if the frame has that image
+    textures: [Option<TextureHandle>; 4],
+}
+
+impl ImageKind {
+    pub const ALL: [Self; 4] = [Self::Color, Self::Depth, Self::AlignedColor, Self::Valid];
+
+    pub fn name(&self) -> &'static str {
+        match self {
+            Self::Color => "Color",
+            Self::Depth => "Depth",
+            Self::AlignedColor => "Aligned color",
+            Self::Valid => "Valid",
+        }
+    }
+
+    /// Whether pixels of this image are pixels of the depth image
+    pub fn is_aligned(&self) -> bool {
+        !matches!(self, Self::Color)
+    }
+
+    /// This image of "frame", or None if the frame doesn't have it
+    fn image(&self, frame: &ImagePointCloud, config: &ImagesConfig) -> Option<ColorImage> {
+        let size = [frame.width(), frame.height()];
+        let pixels: Vec<Color32> = match self {
+            Self::Color => {
+                let intrinsics = &frame.metadata().color.intrinsics;
+                let raw = frame.raw_color();
+                let color_size = [intrinsics.width as usize, intrinsics.height as usize];
+                if raw.is_empty() || raw.len() != color_size[0] * color_size[1] {
+                    return None;
+                }
+                let pixels = raw.iter().map(|[r, g, b]| Color32::from_rgb(*r, *g, *b));
+                return Some(ColorImage {
+                    size: color_size,
+                    pixels: pixels.collect(),
+                });
+            }
+            Self::Depth => {
+                let scale = frame.metadata().depth_scale;
+                let range = (config.max - config.min).max(1e-3);
+                frame
+                    .depth_image()
+                    .iter()
+                    .map(|d| match d {
+                        0 => Color32::BLACK,
+                        d => {
+                            let t = (*d as f32 * scale - config.min) / range;
+                            let [r, g, b] = config.colormap.sample(t).map(|c| (c * 255.) as u8);
+                            Color32::from_rgb(r, g, b)
+                        }
+                    })
+                    .collect()
+            }
+            Self::AlignedColor => frame
+                .color()
+                .iter()
+                .map(|[r, g, b]| Color32::from_rgb(*r, *g, *b))
+                .collect(),
+            Self::Valid => frame
+                .valid()
+                .iter()
+                .map(|v| if *v { Color32::WHITE } else { Color32::BLACK })
+                .collect(),
+        };
+        Some(ColorImage { size, pixels })
+    }
+}
+
+impl ImageViews {
+    /// Remake the textures if the frame or depth coloring has changed
+    fn update(&mut self, ctx: &Context, frame: &Arc<ImagePointCloud>, config: &ImagesConfig) {
+        let coloring = (config.colormap, config.min, config.max);
+        let same_frame = self.source.as_ref().is_some_and(|s| Arc::ptr_eq(s, frame));
+        if same_frame && self.depth_coloring == Some(coloring) {
+            return;
+        }
+        self.source = Some(frame.clone());
+        self.depth_coloring = Some(coloring);
+
+        for (texture, kind) in self.textures.iter_mut().zip(ImageKind::ALL) {
+            let Some(image) = kind.image(frame, config) else {
+                *texture = None;
+                continue;
+            };
+            match texture {
+                Some(texture) => texture.set(image, TextureOptions::NEAREST),
+                None => {
+                    *texture = Some(ctx.load_texture(kind.name(), image, TextureOptions::NEAREST))
+                }
+            }
+        }
+    }
+
+    /// Drop the textures and the frame they were made from
+    fn clear(&mut self) {
+        *self = Self::default();
+    }
+}
+
+/// Show the images of one device in a window, marking "cursor" if it is a pixel of that device.
+/// Returns the device and depth pixel index under the mouse, if it is over a depth-aligned image.
+pub fn images_window(
+    ctx: &Context,
+    config: &mut ImagesConfig,
+    views: &mut ImageViews,
+    frames: &HashMap<String, Arc<ImagePointCloud>>,
+    cursor: Option<&Pick>,
+) -> Option<(String, usize)> {
+    if !config.open {
+        views.clear();
+        return None;
+    }
+
+    let mut open = config.open;
+    let mut hovered = None;
+    Window::new("Images")
+        .open(&mut open)
+        .default_width(640.)
+        .show(ctx, |ui| {
+            hovered = images_ui(ui, config, views, frames, cursor)
+        });
+    config.open = open;
+    hovered
+}
+
+fn images_ui(
+    ui: &mut Ui,
+    config: &mut ImagesConfig,
+    views: &mut ImageViews,
+    frames: &HashMap<String, Arc<ImagePointCloud>>,
+    cursor: Option<&Pick>,
+) -> Option<(String, usize)> {
+    let mut serials: Vec<&String> = frames.keys().collect();
+    serials.sort();
+    let shown = config
+        .serial
+        .as_ref()
+        .filter(|s| frames.contains_key(*s))
+        .or(serials.first().copied())
+        .cloned();
+
+    ui.horizontal(|ui| {
+        ComboBox::from_label("Camera")
+            .selected_text(shown.as_deref().unwrap_or("None"))
+            .show_ui(ui, |ui| {
+                for serial in &serials {
+                    let selected = shown.as_ref() == Some(*serial);
+                    if ui.selectable_label(selected, serial.as_str()).clicked() {
+                        config.serial = Some(serial.to_string());
+                    }
+                }
+            });
+        ComboBox::from_label("Colormap")
+            .selected_text(format!("{:?}", config.colormap))
+            .show_ui(ui, |ui| {
+                for option in [Colormap::Turbo, Colormap::Viridis] {
+                    ui.selectable_value(&mut config.colormap, option, format!("{option:?}"));
+                }
+            });
+        ui.add(
+            DragValue::new(&mut config.min)
+                .speed(1e-2)
+                .suffix(" m")
+                .clamp_range(0.0..=config.max),
+        );
+        ui.add(
+            DragValue::new(&mut config.max)
+                .speed(1e-2)
+                .suffix(" m")
+                .clamp_range(config.min..=65.0),
+        );
+    });
+
+    let Some((serial, frame)) = shown.and_then(|s| frames.get(&s).map(|f| (s, f))) else {
+        ui.label("No frames");
+        return None;
+    };
+    views.update(ui.ctx(), frame, config);
+
+    let marker = cursor.filter(|c| c.serial == serial).map(|c| c.pixel);
+    let width = (ui.available_width() - ui.spacing().item_spacing.x) / 2.;
+    let mut hovered = None;
+    let mut hovered_color = None;
+    Grid::new("images").show(ui, |ui| {
+        for (i, (kind, texture)) in ImageKind::ALL.iter().zip(&views.textures).enumerate() {
+            ui.vertical(|ui| {
+                ui.label(kind.name());
+                let marker = marker.filter(|_| kind.is_aligned());
+                let pixel = image_ui(ui, texture.as_ref(), width, marker);
+                match pixel {
+                    Some([x, y]) if kind.is_aligned() => hovered = Some(y * frame.width() + x),
+                    Some(pixel) => hovered_color = Some(pixel),
+                    None => (),
+                }
+            });
+            if i % 2 == 1 {
+                ui.end_row();
+            }
+        }
+    });
+
+    ui.separator();
+    readout_ui(ui, frame, hovered_color, marker.and(cursor));
+    hovered.map(|idx| (serial, idx))
+}
+
+/// Draw a texture "width" points wide, with a crosshair at "marker". Returns the pixel under the
+/// mouse, if any.
+fn image_ui(
+    ui: &mut Ui,
+    texture: Option<&TextureHandle>,
+    width: f32,
+    marker: Option<[usize; 2]>,
+) -> Option<[usize; 2]> {
+    let Some(texture) = texture else {
+        ui.label("Unavailable");
+        return None;
+    };
+    let [w, h] = texture.size();
+    let size = egui::vec2(width, width * h as f32 / w.max(1) as f32);
+    let (rect, response) = ui.allocate_exact_size(size, Sense::hover());
+    let painter = ui.painter_at(rect);
+    let uv = Rect::from_min_max(egui::pos2(0., 0.), egui::pos2(1., 1.));
+    painter.image(texture.id(), rect, uv, Color32::WHITE);
+
+    if let Some([x, y]) = marker {
+        let pos = rect.min
+            + egui::vec2(
+                (x as f32 + 0.5) / w as f32 * rect.width(),
+                (y as f32 + 0.5) / h as f32 * rect.height(),
+            );
+        let stroke = Stroke::new(1.5, CURSOR_COLOR);
+        painter.hline(rect.x_range(), pos.y, stroke);
+        painter.vline(pos.x, rect.y_range(), stroke);
+    }
+
+    let pos = response.hover_pos()?;
+    let x = (pos.x - rect.left()) / rect.width() * w as f32;
+    let y = (pos.y - rect.top()) / rect.height() * h as f32;
+    Some([(x as usize).min(w - 1), (y as usize).min(h - 1)])
+}
+
+/// Values of the pixel under the cursor: the color image pixel if hovering that, and the depth
+/// pixel shared with the viewport
+fn readout_ui(
+    ui: &mut Ui,
+    frame: &ImagePointCloud,
+    color_pixel: Option<[usize; 2]>,
+    cursor: Option<&Pick>,
+) {
+    if let Some([x, y]) = color_pixel {
+        let intrinsics = &frame.metadata().color.intrinsics;
+        let [r, g, b] = frame.raw_color()[y * intrinsics.width as usize + x];
+        ui.label(format!("Color pixel {x}, {y}: RGB {r}, {g}, {b}"));
+    }
+
+    let Some(cursor) = cursor else {
+        ui.label("Hover over an image or the viewport to inspect a pixel");
+        return;
+    };
+    let [x, y] = cursor.pixel;
+    let idx = y * frame.width() + x;
+    // The cursor may have been picked from an earlier frame of another size
+    let (Some(pos), Some([r, g, b])) = (frame.position().get(idx), frame.color().get(idx)) else {
+        return;
+    };
+    let camera = *pos * frame.metadata().depth_scale;
+    let world = cursor.position;
+
+    Grid::new("pixel readout").show(ui, |ui| {
+        ui.label("Pixel");
+        ui.label(format!("{x}, {y}"));
+        ui.end_row();
+        ui.label("Depth");
+        ui.label(format!("{} ({:.3} m)", pos.z.round(), cursor.depth));
+        ui.end_row();
+        ui.label("Camera point (m)");
+        ui.label(format!("{:.3}, {:.3}, {:.3}", camera.x, camera.y, camera.z));
+        ui.end_row();
+        ui.label("World point (m)");
+        ui.label(format!("{:.3}, {:.3}, {:.3}", world.x, world.y, world.z));
+        ui.end_row();
+        ui.label("Aligned color");
+        ui.label(format!("RGB {r}, {g}, {b}"));
+        ui.end_row();
+    });
+}
+
+impl Default for ImagesConfig {
+    fn default() -> Self {
+        Self {
+            open: false,
+            serial: None,
+            colormap: Colormap::Turbo,
+            min: 0.2,
+            max: 4.0,
+        }
+    }
+}
@@ -0,0 +1,126 @@
+//! Vertex buffers holding independently updatable geometry
+
+use glow::HasContext;
+
+use crate::Vertex;
+
+/// How the vertices of a layer are assembled
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum Primitive {
+    Points,
+    /// Each pair of vertices is a line segment
+    Lines,
+    /// Each three vertices are a triangle
+    Triangles,
+}
+
+/// A named set of vertices resident on the GPU
+pub struct GeometryLayer {
+    array: glow::VertexArray,
+    buf: glow::Buffer,
+    primitive: Primitive,
+    /// Size of the buffer's storage in bytes
+    capacity: usize,
+    count: i32,
+    /// Number of times the contents have been replaced
+    updates: usize,
+}
+
+impl GeometryLayer {
+    pub fn new(gl: &glow::Context, primitive: Primitive) -> Self {
+        unsafe {
+            let array = gl.create_vertex_array().unwrap();
+            let buf = gl.create_buffer().expect("Cannot create vertex buffer");
+            gl.bind_vertex_array(Some(array));
+            gl.bind_buffer(glow::ARRAY_BUFFER, Some(buf));
+
+            // Set vertex attributes
+            gl.enable_vertex_attrib_array(0);
+            gl.vertex_attrib_pointer_f32(
+                0,
+                3,
+                glow::FLOAT,
+                false,
+                std::mem::size_of::<Vertex>() as i32,
+                0,
+            );
+
+            gl.enable_vertex_attrib_array(1);
+            gl.vertex_attrib_pointer_f32(
+                1,
+                3,
+                glow::FLOAT,
+                false,
+                std::mem::size_of::<Vertex>() as i32,
+                3 * std::mem::size_of::<f32>() as i32,
+            );
+
+            gl.bind_vertex_array(None);
+            gl.bind_buffer(glow::ARRAY_BUFFER, None);
+
+            Self {
+                array,
+                buf,
+                primitive,
+                capacity: 0,
+                count: 0,
+                updates: 0,
+            }
+        }
+    }
+
+    /// Replace the layer's vertices. Storage is only reallocated when it must grow; otherwise only
+    /// the range holding the new vertices is written.
+    pub fn upload(&mut self, gl: &glow::Context, primitive: Primitive, vertices: &[Vertex]) {
+        let data: &[u8] = bytemuck::cast_slice(vertices);
+
+        unsafe {
+            gl.bind_buffer(glow::ARRAY_BUFFER, Some(self.buf));
+            if data.len() > self.capacity {
+                // Layers written once (e.g. the grid) are hinted as static, everything else as
+                // streamed
+                let (usage, capacity) = if self.updates == 0 {
+                    (glow::STATIC_DRAW, data.len())
+                } else {
+                    // Leave headroom so a live cloud fluctuating in size doesn't reallocate every
+                    // frame
+                    (glow::STREAM_DRAW, data.len() + data.len() / 2)
+                };
+                self.capacity = capacity;
+                gl.buffer_data_size(glow::ARRAY_BUFFER, self.capacity as i32, usage);
+            }
+            gl.buffer_sub_data_u8_slice(glow::ARRAY_BUFFER, 0, data);
+            gl.bind_buffer(glow::ARRAY_BUFFER, None);
+        }
+
+        self.primitive = primitive;
+        self.count = vertices.len() as i32;
+        self.updates += 1;
+    }
+
+    /// Draw with whatever program is bound
+    pub fn draw(&self, gl: &glow::Context) {
+        if self.count == 0 {
+            return;
+        }
+
+        let mode = match self.primitive {
+            Primitive::Points => glow::POINTS,
+            Primitive::Lines => glow::LINES,
+            Primitive::Triangles => glow::TRIANGLES,
+        };
+
+        unsafe {
+            gl.bind_vertex_array(Some(self.array));
+            gl.draw_arrays(mode, 0, self.count);
+            gl.bind_vertex_array(None);
+        }
+    }
+
+    pub fn destroy(&self, gl: &glow::Context) {
+        unsafe {
+            gl.delete_vertex_array(self.array);
+            gl.delete_buffer(self.buf);
+        }
+    }
+}
@@ -1,25 +1,69 @@
-use deproject_io::{realsense_mainloop, ImagePointCloud};
+use bookmarks::CameraBookmarks;
+use calib::Calibrator;
+use coloring::PointColoring;
+use deproject_io::{
+    accumulate::{AccumulateParams, FrameAccumulator, Reduction},
+    codec::{ColorCodec, DepthCodec, FrameCodecs},
+    extrinsics::PairCalibrationParams,
+    list_devices,
+    metrics::{Metrics, Stage},
+    pool::Pool,
+    queue::{event_channel, EventReceiver, EventSender},
+    recording::{RecordingHeader, RecordingReader, RecordingThread, RecordingWriter},
+    CaptureEvent, ImagePointCloud,
+};
 use eframe::{
-    egui::{self, Context, DragValue, SidePanel, Ui, ViewportBuilder, ViewportId},
+    egui::{self, ComboBox, Context, DragValue, SidePanel, Ui, ViewportBuilder, ViewportId},
     epaint::Vec2,
 };
 use egui::mutex::Mutex;
+use glam::{BVec3, Mat4, Vec3};
+use images::{ImageViews, ImagesConfig};
+use layer::Primitive;
+use measure::{MeasureTool, Pick};
+use rig::{Rig, ToViewport};
+use scene::{Geometry, OutlinerAction, Scene, SceneObject};
+use serde::{Deserialize, Serialize};
+use settings::{ProfileConfig, Settings, SettingsRef};
+use stats::StatsConfig;
+use std::collections::HashMap;
 use std::sync::{
-    mpsc::{channel, Receiver, Sender},
+    mpsc::{channel, Receiver, Sender, TryRecvError},
     Arc,
 };
+use std::time::Instant;
 use view3d::{RenderMsg, Viewport3d, ViewportState};
 
+mod bookmarks;
+mod calib;
 mod camera;
+mod cameras;
+mod coloring;
+mod depthcloud;
+mod imageplane;
+mod images;
+mod layer;
+mod measure;
+mod rig;
+mod scene;
+mod screenshot;
+mod settings;
 mod shapes;
+mod stats;
 mod vertex;
 mod view3d;
 use vertex::Vertex;
 
-#[derive(PartialEq)]
+/// Most frames which may be waiting for the UI before the oldest are dropped
+const FRAME_QUEUE_LEN: usize = 4;
+
+#[derive(PartialEq, Serialize, Deserialize)]
 enum Tabs {
     Record,
     Calibrate,
+    Devices,
+    View,
+    Stats,
 }
 
 struct MyApp {
@@ -27,18 +71,68 @@ struct MyApp {
     viewport_state: ViewportState,
     cfg: AppConfig,
     render_tx: Sender<RenderMsg>,
-    camera_rx: Receiver<ImagePointCloud>,
+    camera_rx: EventReceiver,
+    /// Sender cloned into capture threads started after launch
+    camera_tx: EventSender,
+    /// Vertex buffers returned by the viewport after uploading them
+    vertex_pool: Pool<Vec<Vertex>>,
+    /// Most recent frame from each device, keyed by serial number
+    latest_frames: HashMap<String, Arc<ImagePointCloud>>,
+    calibrator: Calibrator,
+    /// Open recording, if recording
+    recording: Option<RecordingThread>,
+    /// Timings of the capture and render pipeline
+    metrics: Metrics,
+    scene: Scene,
+    /// Coloring of the last live cloud sent to the viewport
+    sent_coloring: Option<PointColoring>,
+    measure: MeasureTool,
+    image_views: ImageViews,
+    /// Point under the mouse in the viewport or an image view, marked in both
+    cursor: Option<Pick>,
+    /// Mouse position "cursor" was last picked at in the viewport. Picking tests every point, so
+    /// it's only done again once the mouse moves.
+    cursor_hover: Option<egui::Pos2>,
+    /// Frames being fused for a high quality snapshot, keyed by serial number
+    snapshot: Option<HashMap<String, FrameAccumulator>>,
+    /// Number of snapshots taken, for naming them
+    snapshot_count: usize,
+    /// Path and result of the recording being read on a worker thread, while importing one
+    importing: Option<(String, Receiver<ImportResult>)>,
 }
 
-#[derive(Default)]
+/// Vertices of the first frame of each device in a recording, keyed by serial number
+type ImportResult = anyhow::Result<Vec<(String, Vec<Vertex>)>>;
+
+/// Settings of the side panel, saved between sessions
+#[derive(Default, Serialize, Deserialize)]
+#[serde(default)]
 struct AppConfig {
+    /// Kept under their own storage key
+    #[serde(skip)]
+    bookmarks: CameraBookmarks,
     calib: CalibratorConfig,
+    images: ImagesConfig,
     record: RecorderConfig,
+    rig: Rig,
+    #[serde(skip)]
+    stats: StatsConfig,
     tab: Tabs,
+    profile: ProfileConfig,
 }
 
-struct CalibratorConfig {}
+#[derive(Serialize, Deserialize)]
+#[serde(default)]
+struct CalibratorConfig {
+    /// Minimum difference in luminance between a pattern and its inverse for a pixel to decode
+    min_contrast: f32,
+    /// Serial number of the camera which other cameras are aligned to
+    reference: Option<String>,
+    params: PairCalibrationParams,
+}
 
+#[derive(Serialize, Deserialize)]
+#[serde(default)]
 struct RecorderConfig {
     /// Number of horizontal subdivisions, pixel resolution is 2**n
     horiz_subdivs: usize,
@@ -46,6 +140,19 @@ struct RecorderConfig {
     vert_subdivs: usize,
     /// Number of frames to capture for each pattern
     pics_per_pattern: usize,
+    /// Number of frames to discard after changing patterns, while the projector catches up
+    settle_frames: usize,
+    /// How frames are fused for the 3D positions of captures and for high quality snapshots
+    averaging: AccumulateParams,
+    /// Directory to write recordings into
+    recording_path: String,
+    /// Save the frames of structured light captures, for decoding and calibrating offline
+    save_captures: bool,
+    /// Directory to save structured light captures into
+    capture_path: String,
+    /// Store recorded frames as compressed depth and color images rather than positions
+    compress: bool,
+    codecs: FrameCodecs,
 }
 
 fn main() -> Result<(), eframe::Error> {
@@ -63,18 +170,52 @@ fn main() -> Result<(), eframe::Error> {
     )
 }
 
-fn app_ui(ui: &mut Ui, state: &mut AppConfig) {
+fn app_ui(
+    ui: &mut Ui,
+    state: &mut AppConfig,
+    viewport: &mut ViewportState,
+    calibrator: &mut Calibrator,
+    recording: &mut Option<RecordingThread>,
+    metrics: &Metrics,
+    dropped_frames: u64,
+) {
+    rig::status_ui(ui, &state.rig);
+    ui.separator();
+
     ui.horizontal(|ui| {
         ui.selectable_value(&mut state.tab, Tabs::Record, "Record");
         ui.selectable_value(&mut state.tab, Tabs::Calibrate, "Calibrate");
+        ui.selectable_value(&mut state.tab, Tabs::Devices, "Devices");
+        ui.selectable_value(&mut state.tab, Tabs::View, "View");
+        ui.selectable_value(&mut state.tab, Tabs::Stats, "Stats");
     });
 
     if state.tab == Tabs::Record {
         record_ui(ui, &mut state.record);
+        calib::capture_ui(ui, calibrator, &state.record, &state.rig);
+        ui.separator();
+        recording_ui(ui, recording, &mut state.record, &state.rig);
     }
 
     if state.tab == Tabs::Calibrate {
-        calib_ui(ui, &mut state.calib);
+        calib::calib_ui(ui, &mut state.calib, calibrator, &mut state.rig);
+    }
+
+    if state.tab == Tabs::Devices {
+        rig::rig_ui(ui, &mut state.rig);
+        ui.separator();
+        settings::profile_ui(ui, state);
+    }
+
+    if state.tab == Tabs::View {
+        view3d::viewport_settings_ui(ui, viewport);
+        ui.checkbox(&mut state.images.open, "Show images");
+        ui.separator();
+        bookmarks::bookmarks_ui(ui, &mut state.bookmarks, &mut viewport.camera);
+    }
+
+    if state.tab == Tabs::Stats {
+        stats::stats_ui(ui, &mut state.stats, metrics, dropped_frames);
     }
 }
 
@@ -112,17 +253,113 @@ fn record_ui(ui: &mut Ui, state: &mut RecorderConfig) {
             .prefix("Frames per pattern: ")
             .clamp_range(1..=15),
     );
+    ui.add(
+        DragValue::new(&mut state.settle_frames)
+            .prefix("Settling frames: ")
+            .clamp_range(0..=60),
+    );
+    averaging_ui(ui, &mut state.averaging);
+    ui.checkbox(&mut state.save_captures, "Save captures");
+    if state.save_captures {
+        ui.horizontal(|ui| {
+            ui.label("Directory: ");
+            ui.text_edit_singleline(&mut state.capture_path);
+        });
+    }
+}
 
-    ui.centered_and_justified(|ui| {
-        if ui.button("Start").clicked() {
-            todo!()
-        }
-    });
+fn averaging_ui(ui: &mut Ui, params: &mut AccumulateParams) {
+    ui.label("Frames fused for the 3D positions of captures and high quality snapshots");
+    ui.add(
+        DragValue::new(&mut params.frames)
+            .prefix("Frames averaged: ")
+            .clamp_range(1..=100),
+    );
+    ComboBox::from_label("Depth per pixel")
+        .selected_text(format!("{:?}", params.reduction))
+        .show_ui(ui, |ui| {
+            for option in [Reduction::Median, Reduction::Mean] {
+                ui.selectable_value(&mut params.reduction, option, format!("{option:?}"));
+            }
+        });
+    ui.add(
+        DragValue::new(&mut params.outlier_factor)
+            .prefix("Outlier rejection (x std dev): ")
+            .speed(1e-2)
+            .clamp_range(1.0..=10.0),
+    );
+    ui.add(
+        DragValue::new(&mut params.min_inlier_fraction)
+            .prefix("Minimum valid fraction: ")
+            .speed(1e-2)
+            .clamp_range(0.0..=1.0),
+    );
 }
 
-fn calib_ui(ui: &mut Ui, state: &mut CalibratorConfig) {
-    let c = ui.ctx().clone();
-    c.inspection_ui(ui);
+fn codecs_ui(ui: &mut Ui, codecs: &mut FrameCodecs) {
+    ComboBox::from_label("Depth codec")
+        .selected_text(codecs.depth.to_string())
+        .show_ui(ui, |ui| {
+            for codec in DepthCodec::ALL {
+                ui.selectable_value(&mut codecs.depth, codec, codec.to_string());
+            }
+        });
+    ComboBox::from_label("Color codec")
+        .selected_text(codecs.color.to_string())
+        .show_ui(ui, |ui| {
+            for codec in ColorCodec::ALL {
+                ui.selectable_value(&mut codecs.color, codec, codec.to_string());
+            }
+        });
+}
+
+fn recording_ui(
+    ui: &mut Ui,
+    recording: &mut Option<RecordingThread>,
+    state: &mut RecorderConfig,
+    rig: &Rig,
+) {
+    ui.strong("Recording");
+    match recording {
+        Some(writer) => {
+            ui.label(format!(
+                "Recorded {} frames to {}",
+                writer.frame_count(),
+                writer.dir().display()
+            ));
+            if writer.dropped_frames() > 0 {
+                ui.label(format!(
+                    "Dropped {} frames while writing",
+                    writer.dropped_frames()
+                ));
+            }
+            if ui.button("Stop recording").clicked() {
+                *recording = None;
+            }
+        }
+        None => {
+            ui.horizontal(|ui| {
+                ui.label("Directory: ");
+                ui.text_edit_singleline(&mut state.recording_path);
+            });
+            ui.checkbox(&mut state.compress, "Compress frames");
+            ui.add_enabled_ui(state.compress, |ui| codecs_ui(ui, &mut state.codecs));
+            if ui.button("Start recording").clicked() {
+                let mut header = RecordingHeader::new(
+                    rig.devices.keys().cloned().collect(),
+                    rig.stream,
+                    rig.options,
+                );
+                if state.compress {
+                    header = header.with_codecs(state.codecs);
+                }
+                match RecordingWriter::create(&state.recording_path, &header) {
+                    Ok(writer) => *recording = Some(RecordingThread::spawn(writer)),
+                    Err(e) => eprintln!("Failed to start recording: {e:#}"),
+                }
+            }
+        }
+    }
 }
 
 /// Returns the number of horizontal and vertical subdivisions to use for this window
@@ -137,7 +374,11 @@ fn window_size_in_pixels(ctx: &Context) -> Vec2 {
 
 impl Default for CalibratorConfig {
     fn default() -> Self {
-        Self {}
+        Self {
+            min_contrast: 0.05,
+            reference: None,
+            params: PairCalibrationParams::default(),
+        }
     }
 }
 
@@ -147,6 +388,16 @@ impl Default for RecorderConfig {
             horiz_subdivs: 11,
             vert_subdivs: 10,
             pics_per_pattern: 1,
+            settle_frames: 5,
+            averaging: AccumulateParams::default(),
+            recording_path: "recording".to_string(),
+            save_captures: false,
+            capture_path: "capture".to_string(),
+            compress: true,
+            codecs: FrameCodecs {
+                depth: DepthCodec::Zstd,
+                color: ColorCodec::Raw,
+            },
         }
     }
 }
@@ -165,27 +416,266 @@ impl MyApp {
             .expect("You need to run eframe with the glow backend");
         let (render_tx, rx) = channel();
 
-        render_tx
-            .send(RenderMsg {
-                lines: shapes::default_grid(),
-                points: vec![],
-            })
-            .unwrap();
+        let metrics = Metrics::new();
+        let vertex_pool = Pool::new(2);
+        let view3d = Viewport3d::new(gl, rx, metrics.clone(), vertex_pool.clone());
 
-        let view3d = Viewport3d::new(&gl, rx);
+        let serials = list_devices().unwrap_or_else(|e| {
+            eprintln!("Failed to query devices: {e:#}");
+            vec![]
+        });
 
-        let camera_rx = spawn_realsense_thread();
+        let Settings {
+            app: mut cfg,
+            viewport: viewport_state,
+        } = Settings::load(cc.storage).unwrap_or_else(|e| {
+            eprintln!("Failed to load settings: {e:#}");
+            Settings::default()
+        });
+        match CameraBookmarks::load(cc.storage) {
+            Ok(bookmarks) => cfg.bookmarks = bookmarks,
+            Err(e) => eprintln!("Failed to load camera bookmarks: {e:#}"),
+        }
+
+        let (camera_tx, camera_rx) = event_channel(FRAME_QUEUE_LEN);
+        for serial in &serials {
+            cfg.rig.start_capture(serial, camera_tx.clone(), &metrics);
+        }
 
         Self {
             camera_rx,
-            viewport_state: ViewportState::default(),
+            camera_tx,
+            vertex_pool,
+            latest_frames: HashMap::new(),
+            viewport_state,
             view3d: Arc::new(Mutex::new(view3d)),
             render_tx,
-            cfg: AppConfig::default(),
+            cfg,
+            calibrator: Calibrator::default(),
+            recording: None,
+            metrics,
+            scene: Scene::default(),
+            sent_coloring: None,
+            measure: MeasureTool::default(),
+            image_views: ImageViews::default(),
+            cursor: None,
+            cursor_hover: None,
+            snapshot: None,
+            snapshot_count: 0,
+            importing: None,
+        }
+    }
+}
+
+impl MyApp {
+    fn handle_outliner_action(&mut self, action: OutlinerAction) {
+        match action {
+            OutlinerAction::SnapshotLiveCloud => {
+                self.add_snapshot("Snapshot", self.latest_frames.clone());
+            }
+            OutlinerAction::HighQualitySnapshot => {
+                let accumulators: HashMap<_, _> = self
+                    .latest_frames
+                    .keys()
+                    .map(|serial| {
+                        let accumulator = FrameAccumulator::new(self.cfg.record.averaging);
+                        (serial.clone(), accumulator)
+                    })
+                    .collect();
+                if !accumulators.is_empty() {
+                    self.snapshot = Some(accumulators);
+                }
+            }
+            OutlinerAction::CancelSnapshot => self.snapshot = None,
+            OutlinerAction::ImportRecording(path) => self.import_recording(path),
+        }
+    }
+
+    /// Add the fused cloud of "frames" to the scene as a new object
+    fn add_snapshot(&mut self, name: &str, frames: HashMap<String, Arc<ImagePointCloud>>) {
+        let points = self.cfg.rig.fuse(
+            &frames,
+            &self.viewport_state.point_coloring,
+            &self.calibrator.projector_maps(),
+            vec![],
+        );
+        self.snapshot_count += 1;
+        let name = format!("{name} {}", self.snapshot_count);
+        self.scene.add(
+            scene::IMPORTED_LAYER,
+            SceneObject::new(name, Geometry::Points(points)),
+        );
+    }
+
+    /// Add the high quality snapshot to the scene once every device has given enough frames
+    fn finish_snapshot(&mut self) {
+        let Some(accumulators) = &self.snapshot else {
+            self.scene.snapshot_progress = None;
+            return;
+        };
+        if !accumulators.values().all(|a| a.is_full()) {
+            let frames = self.cfg.record.averaging.frames.max(1) * accumulators.len();
+            let added: usize = accumulators.values().map(|a| a.len()).sum();
+            self.scene.snapshot_progress = Some(added as f32 / frames as f32);
+            return;
+        }
+
+        let fused = accumulators
+            .iter()
+            .filter_map(|(serial, a)| Some((serial.clone(), Arc::new(a.fuse()?))))
+            .collect();
+        self.snapshot = None;
+        self.scene.snapshot_progress = None;
+        self.add_snapshot("High quality snapshot", fused);
+    }
+
+    /// Transform placing the live cloud in the viewport
+    fn live_cloud_model(&self) -> Mat4 {
+        self.scene
+            .live_cloud()
+            .map(|o| o.matrix())
+            .unwrap_or_default()
+    }
+
+    /// Find the point under the mouse, from the image views if it's over one and otherwise from
+    /// the viewport while the image views are open, so that each can mark it
+    fn update_cursor(&mut self, response: &egui::Response, image_hover: Option<(String, usize)>) {
+        let model = self.live_cloud_model();
+        let hover = match image_hover {
+            // Points of a hidden live cloud can't be under the mouse
+            None if self.cfg.images.open && self.scene.live_cloud_visible() => response.hover_pos(),
+            _ => None,
+        };
+        if hover.is_some() && hover == self.cursor_hover {
+            return;
+        }
+        self.cursor_hover = hover;
+
+        self.cursor = match image_hover {
+            Some((serial, idx)) => self
+                .latest_frames
+                .get(&serial)
+                .and_then(|frame| measure::pick_pixel(frame, &self.cfg.rig, model, idx)),
+            None => hover.and_then(|cursor| {
+                measure::pick(
+                    &self.latest_frames,
+                    &self.cfg.rig,
+                    model,
+                    &self.viewport_state,
+                    response.rect,
+                    cursor,
+                )
+            }),
+        };
+    }
+
+    /// Pick the point under a click in the viewport. Left clicks add it to the measurement, and
+    /// middle clicks orbit the camera around it.
+    fn pick(&mut self, response: &egui::Response) {
+        let orbit = response.middle_clicked();
+        if !response.clicked() && !orbit {
+            return;
+        }
+        let Some(cursor) = response.interact_pointer_pos() else {
+            return;
+        };
+        if !self.scene.live_cloud_visible() {
+            return;
+        }
+        let model = self.live_cloud_model();
+        let pick = measure::pick(
+            &self.latest_frames,
+            &self.cfg.rig,
+            model,
+            &self.viewport_state,
+            response.rect,
+            cursor,
+        );
+        match pick {
+            Some(pick) if orbit => self.viewport_state.camera.orbit_about(pick.viewport_pos),
+            Some(pick) => self.measure.picks.push(pick),
+            None => (),
+        }
+    }
+
+    /// Fit the camera to the box around the live cloud, as placed in the viewport
+    fn frame_all(&mut self) {
+        let Some((min, max)) = self.cfg.rig.bounds(&self.latest_frames) else {
+            return;
+        };
+        let model = self.live_cloud_model();
+        let corners = (0..8).map(|i| {
+            let corner = Vec3::select(BVec3::new(i & 1 != 0, i & 2 != 0, i & 4 != 0), max, min);
+            model.transform_point3(corner)
+        });
+        let (min, max) = corners.fold((Vec3::INFINITY, Vec3::NEG_INFINITY), |(lo, hi), c| {
+            (lo.min(c), hi.max(c))
+        });
+        self.viewport_state.camera.frame(min, max);
+    }
+
+    /// Read the first frame of each device in a recording on a worker thread, since decoding
+    /// can take a while. `finish_import` adds them to the scene.
+    fn import_recording(&mut self, path: String) {
+        let (tx, rx) = channel();
+        let thread_path = path.clone();
+        std::thread::spawn(move || {
+            // The receiver is gone if the app closed, so the result can be dropped
+            let _ = tx.send(read_first_frames(&thread_path));
+        });
+        self.importing = Some((path, rx));
+        self.scene.importing = true;
+    }
+
+    /// Add the frames of an imported recording to the scene once read, placed by the rig
+    fn finish_import(&mut self) {
+        let Some((path, rx)) = &self.importing else {
+            return;
+        };
+        let result = match rx.try_recv() {
+            Ok(result) => result,
+            Err(TryRecvError::Empty) => return,
+            Err(TryRecvError::Disconnected) => Err(anyhow::anyhow!("Import stopped")),
+        };
+        let path = path.clone();
+        self.importing = None;
+        self.scene.importing = false;
+
+        let clouds = match result {
+            Ok(clouds) => clouds,
+            Err(e) => {
+                eprintln!("Failed to import {path}: {e:#}");
+                return;
+            }
+        };
+        for (serial, vertices) in clouds {
+            let mut object =
+                SceneObject::new(format!("{path} ({serial})"), Geometry::Points(vertices));
+            if let Some(device) = self.cfg.rig.devices.get(&serial) {
+                object.transform = device.extrinsics.to_viewport();
+            }
+            self.scene.add(scene::IMPORTED_LAYER, object);
         }
     }
 }
 
+/// Vertices of the first frame of each device in the recording at "path"
+fn read_first_frames(path: &str) -> ImportResult {
+    let reader = RecordingReader::open(path)?;
+    let mut clouds: Vec<(String, Vec<Vertex>)> = vec![];
+    for frame in reader.frames() {
+        let frame = frame?;
+        if clouds.iter().any(|(serial, _)| serial == frame.serial()) {
+            continue;
+        }
+        clouds.push((frame.serial().to_string(), scene::cloud_vertices(&frame)));
+
+        if clouds.len() == reader.header().devices.len() {
+            break;
+        }
+    }
+    Ok(clouds)
+}
 
 impl eframe::App for MyApp {
     fn update(&mut self, ctx: &egui::Context, _frame: &mut eframe::Frame) {
@@ -193,60 +683,172 @@ impl eframe::App for MyApp {
             ViewportId::from_hash_of("Projector display"),
             ViewportBuilder::default().with_title("Projector display"),
             |ctx, _vp_class| {
-
-                /*
-                egui::CentralPanel::default().show(ctx, |ui| {
-                    //ui.label("wassup bitches");
-                    egui::Frame::canvas(ui.style()).show(ui, |ui| {
-                        //self.show_calibration_pattern(ui);
-                        view3d::viewport_widget(&mut self.viewport_state, self.view3d.clone(), ui);
-                    })
-                });
-                */
+                egui::CentralPanel::default()
+                    .frame(egui::Frame::none().fill(egui::Color32::BLACK))
+                    .show(ctx, |ui| {
+                        if let Some(pattern) = self.calibrator.current_pattern() {
+                            calib::draw_pattern(ui, pattern);
+                        }
+                    });
             },
         );
 
         egui::SidePanel::left("Left").show(ctx, |ui| {
-            app_ui(ui, &mut self.cfg);
+            app_ui(
+                ui,
+                &mut self.cfg,
+                &mut self.viewport_state,
+                &mut self.calibrator,
+                &mut self.recording,
+                &self.metrics,
+                self.camera_rx.dropped_frames(),
+            );
+        });
+
+        if std::mem::take(&mut self.cfg.bookmarks.frame_all) {
+            self.frame_all();
+        }
+        if std::mem::take(&mut self.cfg.rig.remote.connect) {
+            self.cfg.rig.connect_remote(self.camera_tx.clone());
+        }
+        if std::mem::take(&mut self.cfg.rig.playback.open) {
+            self.cfg.rig.start_playback(self.camera_tx.clone());
+        }
+
+        egui::SidePanel::right("Outliner").show(ctx, |ui| {
+            if let Some(action) = scene::outliner_ui(ui, &mut self.scene) {
+                self.handle_outliner_action(action);
+            }
+            ui.separator();
+            measure::measure_ui(ui, &mut self.measure);
         });
 
+        let image_hover = images::images_window(
+            ctx,
+            &mut self.cfg.images,
+            &mut self.image_views,
+            &self.latest_frames,
+            self.cursor.as_ref(),
+        );
+
         // Always repaint!
         ctx.request_repaint();
 
-        if let Some(latest_frame) = self.camera_rx.try_iter().last() {
-            let pointcloud = latest_frame
-                .iter_pixels()
-                .filter_map(|x| x)
-                .map(|(pos, color)| Vertex::new((pos / 3.).into(), color.map(|c| c as f32 / 256.0)))
-                .collect();
-            self.render_tx
-                .send(RenderMsg {
-                    points: pointcloud,
-                    lines: vec![],
-                })
-                .unwrap();
+        let mut any_new_frames = false;
+        for event in self.camera_rx.try_iter() {
+            match event {
+                CaptureEvent::Frame(frame) => {
+                    let frame = Arc::new(frame);
+                    self.cfg.rig.add_device(frame.serial());
+                    self.calibrator
+                        .push_frame(&frame, self.cfg.calib.min_contrast);
+                    if let Some(accumulator) = self
+                        .snapshot
+                        .as_mut()
+                        .and_then(|s| s.get_mut(frame.serial()))
+                    {
+                        accumulator.add(&frame);
+                    }
+                    if let Some(writer) = &self.recording {
+                        if let Err(e) = writer.try_send(frame.clone()) {
+                            eprintln!("Recording failed: {e:#}");
+                            self.recording = None;
+                        }
+                    }
+                    self.latest_frames.insert(frame.serial().to_string(), frame);
+                    any_new_frames = true;
+                }
+                CaptureEvent::Status { serial, status } => {
+                    self.cfg.rig.set_status(&serial, status);
+                }
+            }
+        }
+
+        self.finish_snapshot();
+        self.finish_import();
+        self.calibrator.poll_solve();
+
+        // Colorings and distortion models the shader can't apply need the CPU path, and recoloring
+        // needs a new cloud
+        let coloring = self.viewport_state.point_coloring;
+        let unsupported_distortion = self
+            .latest_frames
+            .values()
+            .any(|f| !f.metadata().depth.intrinsics.can_deproject());
+        self.viewport_state.gpu_unsupported_distortion = unsupported_distortion;
+        let gpu_deprojection = self.viewport_state.gpu_deprojection
+            && coloring.gpu_supported()
+            && !unsupported_distortion;
+        if any_new_frames || self.sent_coloring != Some(coloring) {
+            self.sent_coloring = Some(coloring);
+            let (clouds, points) = if gpu_deprojection {
+                let clouds = self.cfg.rig.depth_clouds(&self.latest_frames);
+                let points = RenderMsg::RemoveLayer(view3d::CLOUD_LAYER.to_string());
+                (clouds, points)
+            } else {
+                let start = Instant::now();
+                let pointcloud = self.cfg.rig.fuse(
+                    &self.latest_frames,
+                    &coloring,
+                    &self.calibrator.projector_maps(),
+                    self.vertex_pool.take(),
+                );
+                self.metrics
+                    .record(view3d::METRICS_SOURCE, Stage::Fuse, start.elapsed());
+                let points = RenderMsg::SetLayer {
+                    name: view3d::CLOUD_LAYER.to_string(),
+                    primitive: Primitive::Points,
+                    vertices: pointcloud,
+                };
+                (vec![], points)
+            };
+            self.render_tx.send(RenderMsg::DepthClouds(clouds)).unwrap();
+            self.render_tx.send(points).unwrap();
         }
+        cameras::update_cameras(
+            &mut self.scene,
+            &self.cfg.rig,
+            &self.latest_frames,
+            self.calibrator.current_pattern(),
+        );
+        self.scene.sync(&self.render_tx);
 
         egui::CentralPanel::default().show(ctx, |ui| {
             egui::Frame::canvas(ui.style()).show(ui, |ui| {
                 //self.show_calibration_pattern(ui);
-                view3d::viewport_widget(&mut self.viewport_state, self.view3d.clone(), ui);
+                let response =
+                    view3d::viewport_widget(&mut self.viewport_state, self.view3d.clone(), ui);
+                self.pick(&response);
+                self.update_cursor(&response, image_hover);
+                let painter = ui.painter_at(response.rect);
+                measure::overlay(&painter, &self.measure, &self.viewport_state, response.rect);
+                if let Some(cursor) = &self.cursor {
+                    measure::cursor_overlay(&painter, cursor, &self.viewport_state, response.rect);
+                }
             });
         });
     }
 
+    fn save(&mut self, storage: &mut dyn eframe::Storage) {
+        let settings = SettingsRef {
+            app: &self.cfg,
+            viewport: &self.viewport_state,
+        };
+        if let Err(e) = settings.save(storage) {
+            eprintln!("Failed to save settings: {e:#}");
+        }
+        if let Err(e) = self.cfg.bookmarks.save(storage) {
+            eprintln!("Failed to save camera bookmarks: {e:#}");
+        }
+    }
+
     fn on_exit(&mut self, gl: Option<&glow::Context>) {
-        if let Some(_gl) = gl {
-            // TODO: Destroy viewport3d here?
+        // Settings were already saved by `save`
+        self.cfg.rig.stop_all();
+
+        if let Some(gl) = gl {
+            self.view3d.lock().destroy(gl);
         }
     }
 }
 
-fn spawn_realsense_thread() -> Receiver<ImagePointCloud> {
-    let (tx, rx) = std::sync::mpsc::channel();
-    std::thread::spawn(move || {
-        let callback = |x| tx.send(x).unwrap();
-        realsense_mainloop(callback, 640, 0, 640, 0, 60).unwrap();
-    });
-    rx
-}
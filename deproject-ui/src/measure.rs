@@ -0,0 +1,213 @@
+//! Picking points of the live cloud in the viewport, and measuring between them
+
+use std::collections::HashMap;
+use std::sync::Arc;
+
+use deproject_io::ImagePointCloud;
+use eframe::egui::{self, Align2, Color32, FontId, Grid, Painter, Pos2, Rect, Stroke, Ui};
+use glam::{Mat4, Vec3};
+
+use crate::rig::{Rig, DEPTH_TO_VIEWPORT};
+use crate::view3d::ViewportState;
+
+/// Furthest a point may be drawn from the cursor to be picked, in points
+const PICK_RADIUS: f32 = 8.;
+
+const OVERLAY_COLOR: Color32 = Color32::YELLOW;
+
+/// Color of the cursor shared between the viewport and the image views
+pub const CURSOR_COLOR: Color32 = Color32::from_rgb(0, 255, 255);
+
+/// A point of the live cloud, selected in the viewport or an image view
+pub struct Pick {
+    pub serial: String,
+    /// Column and row in the depth image
+    pub pixel: [usize; 2],
+    /// Depth along the camera's axis, in meters
+    pub depth: f32,
+    /// Position in the rig's world frame, in meters
+    pub position: Vec3,
+    /// Position as drawn in the viewport
+    pub viewport_pos: Vec3,
+}
+
+/// Points picked so far. Consecutive picks are measured against each other.
+#[derive(Default)]
+pub struct MeasureTool {
+    pub picks: Vec<Pick>,
+}
+
+/// Find the point nearest the camera among those drawn within `PICK_RADIUS` of "cursor". "model"
+/// places the live cloud in the viewport, and "rect" is the viewport's area on screen.
+pub fn pick(
+    frames: &HashMap<String, Arc<ImagePointCloud>>,
+    rig: &Rig,
+    model: Mat4,
+    state: &ViewportState,
+    rect: Rect,
+    cursor: Pos2,
+) -> Option<Pick> {
+    let mut best: Option<(f32, &ImagePointCloud, usize)> = None;
+    for (serial, frame) in frames {
+        let Some(device) = rig.devices.get(serial).filter(|d| d.visible) else {
+            continue;
+        };
+        let world_from_camera = device.extrinsics.matrix();
+        let viewport_from_world = model * Mat4::from_scale(Vec3::splat(DEPTH_TO_VIEWPORT));
+
+        for (idx, sample) in frame.iter_pixels().enumerate() {
+            let Some((pos, _)) = sample else {
+                continue;
+            };
+            let world = world_from_camera.transform_point3(pos);
+            let viewport_pos = viewport_from_world.transform_point3(world);
+            let Some((screen, ndc_depth)) = state.project(viewport_pos, rect) else {
+                continue;
+            };
+            if screen.distance(cursor) > PICK_RADIUS {
+                continue;
+            }
+            if best.is_some_and(|(d, _, _)| d <= ndc_depth) {
+                continue;
+            }
+            best = Some((ndc_depth, frame, idx));
+        }
+    }
+    best.and_then(|(_, frame, idx)| pick_pixel(frame, rig, model, idx))
+}
+
+/// The point at pixel "idx" of a frame, placed by the rig, if its depth is valid. "model" places
+/// the live cloud in the viewport.
+pub fn pick_pixel(frame: &ImagePointCloud, rig: &Rig, model: Mat4, idx: usize) -> Option<Pick> {
+    let pos = *frame.position().get(idx).filter(|_| frame.valid()[idx])?;
+    let world_from_camera = rig
+        .devices
+        .get(frame.serial())
+        .map(|d| d.extrinsics.matrix())
+        .unwrap_or_default();
+    let viewport_from_world = model * Mat4::from_scale(Vec3::splat(DEPTH_TO_VIEWPORT));
+    let depth_scale = frame.metadata().depth_scale;
+    let world = world_from_camera.transform_point3(pos);
+    Some(Pick {
+        serial: frame.serial().to_string(),
+        pixel: [idx % frame.width(), idx / frame.width()],
+        depth: pos.z * depth_scale,
+        position: world * depth_scale,
+        viewport_pos: viewport_from_world.transform_point3(world),
+    })
+}
+
+impl MeasureTool {
+    /// Distance in meters from each pick to the next
+    pub fn distances(&self) -> impl Iterator<Item = f32> + '_ {
+        self.picks
+            .windows(2)
+            .map(|w| w[0].position.distance(w[1].position))
+    }
+
+    /// Angle in degrees at each pick between its neighbours
+    pub fn angles(&self) -> impl Iterator<Item = f32> + '_ {
+        self.picks.windows(3).map(|w| {
+            let a = w[0].position - w[1].position;
+            let b = w[2].position - w[1].position;
+            a.angle_between(b).to_degrees()
+        })
+    }
+}
+
+/// Draw the picked points, the lines between them and their measurements over the viewport
+pub fn overlay(painter: &Painter, tool: &MeasureTool, state: &ViewportState, rect: Rect) {
+    let screen: Vec<Option<Pos2>> = tool
+        .picks
+        .iter()
+        .map(|p| state.project(p.viewport_pos, rect).map(|(s, _)| s))
+        .collect();
+    let stroke = Stroke::new(1.5, OVERLAY_COLOR);
+    let font = FontId::proportional(14.);
+
+    for (i, pos) in screen.iter().enumerate() {
+        if let Some(pos) = pos {
+            painter.circle_stroke(*pos, 4., stroke);
+            painter.text(
+                *pos + egui::vec2(6., -6.),
+                Align2::LEFT_BOTTOM,
+                (i + 1).to_string(),
+                font.clone(),
+                OVERLAY_COLOR,
+            );
+        }
+    }
+
+    for (w, distance) in screen.windows(2).zip(tool.distances()) {
+        if let [Some(a), Some(b)] = w {
+            painter.line_segment([*a, *b], stroke);
+            painter.text(
+                a.lerp(*b, 0.5),
+                Align2::CENTER_BOTTOM,
+                format!("{:.1} mm", distance * 1e3),
+                font.clone(),
+                OVERLAY_COLOR,
+            );
+        }
+    }
+
+    for (pos, angle) in screen[1..].iter().zip(tool.angles()) {
+        if let Some(pos) = pos {
+            painter.text(
+                *pos + egui::vec2(6., 6.),
+                Align2::LEFT_TOP,
+                format!("{angle:.1}°"),
+                font.clone(),
+                OVERLAY_COLOR,
+            );
+        }
+    }
+}
+
+/// Mark the point under the cursor of an image view, so it can be found in the viewport
+pub fn cursor_overlay(painter: &Painter, cursor: &Pick, state: &ViewportState, rect: Rect) {
+    let Some((pos, _)) = state.project(cursor.viewport_pos, rect) else {
+        return;
+    };
+    let stroke = Stroke::new(1.5, CURSOR_COLOR);
+    painter.line_segment([pos - egui::vec2(8., 0.), pos + egui::vec2(8., 0.)], stroke);
+    painter.line_segment([pos - egui::vec2(0., 8.), pos + egui::vec2(0., 8.)], stroke);
+}
+
+/// Readouts of the picked points and measurements, shown beside the viewport
+pub fn measure_ui(ui: &mut Ui, tool: &mut MeasureTool) {
+    ui.strong("Measure");
+    if tool.picks.is_empty() {
+        ui.label("Click a point in the viewport to pick it");
+        return;
+    }
+
+    Grid::new("picks").striped(true).show(ui, |ui| {
+        for heading in ["#", "Camera", "Pixel", "Depth (m)", "Position (m)"] {
+            ui.label(heading);
+        }
+        ui.end_row();
+
+        for (i, pick) in tool.picks.iter().enumerate() {
+            let [x, y] = pick.pixel;
+            let p = pick.position;
+            ui.label((i + 1).to_string());
+            ui.label(&pick.serial);
+            ui.label(format!("{x}, {y}"));
+            ui.label(format!("{:.3}", pick.depth));
+            ui.label(format!("{:.3}, {:.3}, {:.3}", p.x, p.y, p.z));
+            ui.end_row();
+        }
+    });
+
+    for (i, distance) in tool.distances().enumerate() {
+        ui.label(format!("{} → {}: {:.1} mm", i + 1, i + 2, distance * 1e3));
+    }
+    for (i, angle) in tool.angles().enumerate() {
+        ui.label(format!("Angle at {}: {angle:.1}°", i + 2));
+    }
+
+    if ui.button("Clear").clicked() {
+        tool.picks.clear();
+    }
+}
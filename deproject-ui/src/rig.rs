@@ -0,0 +1,624 @@
+use std::collections::{BTreeMap, HashMap};
+use std::sync::Arc;
+
+use deproject_io::bag::start_bag_playback;
+pub use deproject_io::extrinsics::Extrinsics;
+use deproject_io::profile::{DeviceProfile, RigProfile};
+use deproject_io::sequence::{start_sequence_playback, SequenceConfig};
+use deproject_io::stream::{start_stream_client, DEFAULT_PORT};
+use deproject_io::{
+    graycode::ProjectorMap, metrics::Metrics, queue::EventSender, start_realsense, CaptureHandle,
+    CaptureStatus, ImagePointCloud, SensorOptions, StreamConfig, VisualPreset,
+};
+use eframe::egui::{Color32, ComboBox, DragValue, TextEdit, Ui};
+use glam::{EulerRot, Mat4, Quat, Vec3};
+use serde::{Deserialize, Serialize};
+
+use crate::coloring::PointColoring;
+use crate::depthcloud::DepthCloud;
+use crate::Vertex;
+
+/// Viewport units per depth unit. Frames are always in units of `deproject_io::DEPTH_SCALE`.
+pub const DEPTH_TO_VIEWPORT: f32 = 1. / 3.;
+
+/// Largest width or height offered for a stream, beyond any RealSense camera's
+const MAX_RESOLUTION: usize = 4096;
+
+/// All of the depth cameras in the rig, keyed by serial number
+#[derive(Default, Serialize, Deserialize)]
+#[serde(default)]
+pub struct Rig {
+    pub devices: BTreeMap<String, RigDevice>,
+    /// Resolution and framerate used by every device
+    pub stream: StreamConfig,
+    /// Sensor settings applied to every device
+    pub options: SensorOptions,
+    pub projector: Projector,
+    pub remote: RemoteStream,
+    pub playback: Playback,
+}
+
+/// A single depth camera and its placement in the shared world frame
+#[derive(Serialize, Deserialize)]
+#[serde(default)]
+pub struct RigDevice {
+    pub extrinsics: Extrinsics,
+    pub visible: bool,
+    /// Last status reported by the capture thread
+    #[serde(skip)]
+    pub status: Option<CaptureStatus>,
+    /// Running capture thread, if any
+    #[serde(skip)]
+    pub capture: Option<CaptureHandle>,
+    #[serde(skip)]
+    pub paused: bool,
+}
+
+/// The projector displaying structured light patterns, modelled as a pinhole camera
+#[derive(Serialize, Deserialize)]
+#[serde(default)]
+pub struct Projector {
+    pub extrinsics: Extrinsics,
+    /// Image size in pixels
+    pub resolution: [u32; 2],
+    /// Horizontal field of view in degrees
+    pub fov: f32,
+    /// Show the image plane textured with the current pattern in the viewport
+    pub show_image: bool,
+}
+
+/// Connection to frames streamed from another machine. Its devices join the rig as their frames
+/// arrive.
+#[derive(Serialize, Deserialize)]
+#[serde(default)]
+pub struct RemoteStream {
+    /// Address of the stream server, as host:port
+    pub address: String,
+    /// Last status reported by the client thread
+    #[serde(skip)]
+    pub status: Option<CaptureStatus>,
+    #[serde(skip)]
+    pub client: Option<CaptureHandle>,
+    /// Set by the UI to connect on the next frame
+    #[serde(skip)]
+    pub connect: bool,
+}
+
+/// A RealSense Viewer recording or image sequence played as if it were a connected device
+#[derive(Serialize, Deserialize)]
+#[serde(default)]
+pub struct Playback {
+    /// Path of the .bag file, or of a folder of depth and color images
+    pub path: String,
+    /// How to read a folder of images
+    pub sequence: SequenceConfig,
+    /// Set by the UI to start playing on the next frame
+    #[serde(skip)]
+    pub open: bool,
+    /// Why the last recording failed to open
+    #[serde(skip)]
+    pub error: String,
+}
+
+impl Rig {
+    /// Register a device by serial number, if it isn't already known
+    pub fn add_device(&mut self, serial: &str) {
+        self.devices.entry(serial.to_string()).or_default();
+    }
+
+    /// Start a capture thread for the given device, sending its events to "tx"
+    pub fn start_capture(&mut self, serial: &str, tx: EventSender, metrics: &Metrics) {
+        self.add_device(serial);
+        let handle = start_realsense(
+            serial.to_string(),
+            self.stream,
+            metrics.clone(),
+            move |event| tx.send(event),
+        );
+        handle.set_options(&self.options);
+        if let Some(device) = self.devices.get_mut(serial) {
+            device.capture = Some(handle);
+            device.paused = false;
+        }
+    }
+
+    /// Start receiving frames from the remote stream's address, sending its events to "tx"
+    pub fn connect_remote(&mut self, tx: EventSender) {
+        let address = self.remote.address.trim().to_string();
+        self.remote.status = None;
+        self.remote.client = Some(start_stream_client(address, move |event| tx.send(event)));
+    }
+
+    /// Start playing the recording or image sequence at the playback path, as the device which
+    /// recorded it
+    pub fn start_playback(&mut self, tx: EventSender) {
+        let path = self.playback.path.trim();
+        let callback = move |event| tx.send(event);
+        let handle = match std::path::Path::new(path).is_dir() {
+            true => start_sequence_playback(path, self.playback.sequence.clone(), callback),
+            false => start_bag_playback(path, callback),
+        };
+        match handle {
+            Ok(handle) => {
+                self.playback.error.clear();
+                let serial = handle.serial().to_string();
+                self.add_device(&serial);
+                if let Some(device) = self.devices.get_mut(&serial) {
+                    device.capture = Some(handle);
+                    device.paused = false;
+                }
+            }
+            Err(e) => {
+                eprintln!("Failed to play {path}: {e:#}");
+                self.playback.error = format!("{e:#}");
+            }
+        }
+    }
+
+    /// Stop every capture thread, waiting for them to close their devices
+    pub fn stop_all(&mut self) {
+        for device in self.devices.values_mut() {
+            if let Some(capture) = device.capture.take() {
+                capture.stop();
+            }
+        }
+        if let Some(client) = self.remote.client.take() {
+            client.stop();
+        }
+    }
+
+    /// Take the placements and settings of a loaded profile, keeping devices which are running and
+    /// applying the new settings to their captures. Returns the profile's viewer settings.
+    pub fn apply_profile<V>(&mut self, profile: RigProfile<V>) -> Option<V> {
+        for (serial, settings) in profile.devices {
+            let device = self.devices.entry(serial).or_default();
+            device.extrinsics = settings.extrinsics;
+            device.visible = settings.visible;
+        }
+        self.stream = profile.stream;
+        self.options = profile.options;
+
+        for capture in self.devices.values().filter_map(|d| d.capture.as_ref()) {
+            capture.reconfigure(self.stream);
+            capture.set_options(&self.options);
+        }
+        profile.viewer
+    }
+
+    /// Placements and settings of the rig for a profile, with "viewer" settings
+    pub fn profile<V>(&self, viewer: V) -> RigProfile<V> {
+        let devices = self
+            .devices
+            .iter()
+            .map(|(serial, device)| {
+                let settings = DeviceProfile {
+                    extrinsics: device.extrinsics,
+                    visible: device.visible,
+                };
+                (serial.clone(), settings)
+            })
+            .collect();
+        RigProfile {
+            devices,
+            stream: self.stream,
+            options: self.options,
+            viewer: Some(viewer),
+        }
+    }
+
+    /// Record the latest status reported by a device's capture thread
+    pub fn set_status(&mut self, serial: &str, status: CaptureStatus) {
+        // The stream client reports its status under the server's address
+        if serial == self.remote.address.trim() {
+            self.remote.status = Some(status);
+            return;
+        }
+
+        self.add_device(serial);
+        if let Some(device) = self.devices.get_mut(serial) {
+            device.status = Some(status);
+        }
+    }
+
+    /// Fuse the latest frame from each visible device into one world-space point cloud, reusing
+    /// the allocation of "points". "maps" holds each device's last decoded capture, for colorings
+    /// which show decoding.
+    pub fn fuse(
+        &self,
+        frames: &HashMap<String, Arc<ImagePointCloud>>,
+        coloring: &PointColoring,
+        maps: &BTreeMap<&str, &ProjectorMap>,
+        mut points: Vec<Vertex>,
+    ) -> Vec<Vertex> {
+        points.clear();
+        for (serial, frame) in frames {
+            let Some(device) = self.devices.get(serial) else {
+                continue;
+            };
+            if !device.visible {
+                continue;
+            }
+
+            let matrix = device.extrinsics.matrix();
+            let map = maps.get(serial.as_str()).copied();
+            let colors = coloring.frame_colors(frame, map, device.extrinsics.rotation);
+            points.extend(
+                frame
+                    .iter_pixels()
+                    .zip(colors)
+                    .filter_map(|(sample, color)| {
+                        let pos = matrix.transform_point3(sample?.0);
+                        Some(Vertex::new((pos * DEPTH_TO_VIEWPORT).into(), color))
+                    }),
+            );
+        }
+        points
+    }
+
+    /// The latest frame from each visible device, placed in the world, for deprojection on the GPU
+    pub fn depth_clouds(&self, frames: &HashMap<String, Arc<ImagePointCloud>>) -> Vec<DepthCloud> {
+        frames
+            .iter()
+            .filter_map(|(serial, frame)| {
+                let device = self.devices.get(serial).filter(|d| d.visible)?;
+                Some(DepthCloud {
+                    frame: frame.clone(),
+                    transform: Mat4::from_scale(Vec3::splat(DEPTH_TO_VIEWPORT))
+                        * device.extrinsics.matrix(),
+                })
+            })
+            .collect()
+    }
+
+    /// Smallest and largest corners of the box around the visible points of "frames", in
+    /// viewport units
+    pub fn bounds(&self, frames: &HashMap<String, Arc<ImagePointCloud>>) -> Option<(Vec3, Vec3)> {
+        let mut bounds: Option<(Vec3, Vec3)> = None;
+        for (serial, frame) in frames {
+            let Some(device) = self.devices.get(serial).filter(|d| d.visible) else {
+                continue;
+            };
+            let matrix = device.extrinsics.matrix();
+            for (pos, _) in frame.iter_pixels().flatten() {
+                let pos = matrix.transform_point3(pos) * DEPTH_TO_VIEWPORT;
+                bounds = Some(match bounds {
+                    Some((min, max)) => (min.min(pos), max.max(pos)),
+                    None => (pos, pos),
+                });
+            }
+        }
+        bounds
+    }
+}
+
+impl Projector {
+    /// Focal length in pixels, the same along both axes
+    pub fn focal_length(&self) -> f32 {
+        self.resolution[0] as f32 / 2. / (self.fov.to_radians() / 2.).tan()
+    }
+}
+
+/// Conversion of transforms placing things in the world into viewport units
+pub trait ToViewport {
+    /// The same transform with its translation converted from depth units to viewport units
+    fn to_viewport(self) -> Self;
+}
+
+impl ToViewport for Extrinsics {
+    fn to_viewport(self) -> Self {
+        Self {
+            rotation: self.rotation,
+            translation: self.translation * DEPTH_TO_VIEWPORT,
+        }
+    }
+}
+
+/// One line per device showing its connection status
+pub fn status_ui(ui: &mut Ui, rig: &Rig) {
+    for (serial, device) in &rig.devices {
+        status_line(ui, serial, &device.status);
+    }
+    if let Some(client) = &rig.remote.client {
+        status_line(ui, client.serial(), &rig.remote.status);
+    }
+}
+
+fn status_line(ui: &mut Ui, name: &str, status: &Option<CaptureStatus>) {
+    ui.horizontal(|ui| {
+        let (color, text) = match status {
+            None => (Color32::GRAY, "Not started".to_string()),
+            Some(status @ CaptureStatus::Connecting) => (Color32::YELLOW, status.to_string()),
+            Some(status @ CaptureStatus::Streaming) => (Color32::GREEN, status.to_string()),
+            Some(status @ CaptureStatus::Reconnecting { .. }) => (Color32::RED, status.to_string()),
+            Some(status @ (CaptureStatus::Paused | CaptureStatus::Stopped)) => {
+                (Color32::GRAY, status.to_string())
+            }
+        };
+        ui.colored_label(color, "⏺");
+        ui.label(format!("{name}: {text}"));
+    });
+}
+
+pub fn rig_ui(ui: &mut Ui, rig: &mut Rig) {
+    ui.strong("Streams");
+    stream_config_ui(ui, &mut rig.stream);
+    if ui.button("Apply").clicked() {
+        for capture in rig.devices.values().filter_map(|d| d.capture.as_ref()) {
+            capture.reconfigure(rig.stream);
+        }
+    }
+
+    ui.separator();
+
+    ui.strong("Sensor options");
+    sensor_options_ui(ui, &mut rig.options);
+    if ui.button("Apply").clicked() {
+        for capture in rig.devices.values().filter_map(|d| d.capture.as_ref()) {
+            capture.set_options(&rig.options);
+        }
+    }
+
+    ui.separator();
+
+    ui.strong("Projector");
+    projector_ui(ui, &mut rig.projector);
+
+    ui.separator();
+
+    remote_ui(ui, &mut rig.remote);
+
+    ui.separator();
+
+    playback_ui(ui, &mut rig.playback);
+
+    ui.separator();
+
+    ui.strong("Devices");
+    if rig.devices.is_empty() {
+        ui.label("No devices connected");
+    }
+
+    for (serial, device) in &mut rig.devices {
+        ui.separator();
+        ui.horizontal(|ui| {
+            ui.checkbox(&mut device.visible, serial.as_str());
+            if let Some(capture) = &device.capture {
+                let text = if device.paused { "Resume" } else { "Pause" };
+                if ui.button(text).clicked() {
+                    device.paused = !device.paused;
+                    capture.pause(device.paused);
+                }
+            }
+        });
+        extrinsics_ui(ui, &mut device.extrinsics);
+    }
+}
+
+fn remote_ui(ui: &mut Ui, remote: &mut RemoteStream) {
+    ui.strong("Remote stream");
+    ui.horizontal(|ui| {
+        ui.label("Server: ");
+        ui.add_enabled(
+            remote.client.is_none(),
+            TextEdit::singleline(&mut remote.address),
+        );
+    });
+    match &remote.client {
+        None => remote.connect = ui.button("Connect").clicked(),
+        Some(_) => {
+            if ui.button("Disconnect").clicked() {
+                if let Some(client) = remote.client.take() {
+                    client.stop();
+                }
+                remote.status = None;
+            }
+        }
+    }
+}
+
+fn playback_ui(ui: &mut Ui, playback: &mut Playback) {
+    ui.strong("Playback");
+    ui.horizontal(|ui| {
+        ui.label(".bag file or folder: ");
+        ui.text_edit_singleline(&mut playback.path);
+    });
+    if std::path::Path::new(playback.path.trim()).is_dir() {
+        sequence_ui(ui, &mut playback.sequence);
+    }
+    playback.open = ui.button("Play").clicked();
+    if !playback.error.is_empty() {
+        ui.colored_label(Color32::RED, &playback.error);
+    }
+}
+
+fn sequence_ui(ui: &mut Ui, config: &mut SequenceConfig) {
+    ui.horizontal(|ui| {
+        ui.label("Intrinsics: ");
+        ui.text_edit_singleline(&mut config.intrinsics);
+    });
+    ui.horizontal(|ui| {
+        ui.label("Association: ");
+        ui.add(TextEdit::singleline(&mut config.association).hint_text("Pair folders in order"));
+    });
+    ui.horizontal(|ui| {
+        ui.label("Depth scale: ");
+        ui.add(
+            DragValue::new(&mut config.depth_scale)
+                .speed(1e-5)
+                .clamp_range(1e-6..=1.)
+                .suffix(" m"),
+        );
+        ui.label("FPS: ");
+        ui.add(DragValue::new(&mut config.fps).clamp_range(1..=120));
+    });
+}
+
+fn stream_config_ui(ui: &mut Ui, config: &mut StreamConfig) {
+    ui.horizontal(|ui| {
+        ui.label("Color");
+        ui.add(
+            DragValue::new(&mut config.color_width)
+                .prefix("w: ")
+                .clamp_range(1..=MAX_RESOLUTION),
+        );
+        ui.add(
+            DragValue::new(&mut config.color_height)
+                .prefix("h: ")
+                .clamp_range(1..=MAX_RESOLUTION),
+        );
+    });
+    ui.horizontal(|ui| {
+        ui.label("Depth");
+        ui.add(
+            DragValue::new(&mut config.depth_width)
+                .prefix("w: ")
+                .clamp_range(1..=MAX_RESOLUTION),
+        );
+        ui.add(
+            DragValue::new(&mut config.depth_height)
+                .prefix("h: ")
+                .clamp_range(1..=MAX_RESOLUTION),
+        );
+    });
+    ui.add(
+        DragValue::new(&mut config.fps)
+            .prefix("FPS: ")
+            .clamp_range(1..=300),
+    );
+}
+
+fn sensor_options_ui(ui: &mut Ui, options: &mut SensorOptions) {
+    ui.label("For structured light, disable the emitter and lock exposure and white balance.");
+
+    ui.checkbox(&mut options.auto_exposure, "Auto exposure");
+    ui.add_enabled_ui(!options.auto_exposure, |ui| {
+        ui.add(
+            DragValue::new(&mut options.exposure)
+                .prefix("Exposure: ")
+                .suffix(" µs")
+                .clamp_range(1.0..=10000.0),
+        );
+        ui.add(
+            DragValue::new(&mut options.gain)
+                .prefix("Gain: ")
+                .clamp_range(0.0..=128.0),
+        );
+    });
+
+    ui.checkbox(&mut options.auto_white_balance, "Auto white balance");
+    ui.add_enabled_ui(!options.auto_white_balance, |ui| {
+        ui.add(
+            DragValue::new(&mut options.white_balance)
+                .prefix("White balance: ")
+                .suffix(" K")
+                .speed(10.)
+                .clamp_range(2800.0..=6500.0),
+        );
+    });
+
+    ComboBox::from_label("Depth preset")
+        .selected_text(format!("{:?}", options.visual_preset))
+        .show_ui(ui, |ui| {
+            for preset in VisualPreset::ALL {
+                ui.selectable_value(&mut options.visual_preset, preset, format!("{preset:?}"));
+            }
+        });
+
+    ui.checkbox(&mut options.emitter_enabled, "IR emitter");
+    ui.add_enabled_ui(options.emitter_enabled, |ui| {
+        ui.add(
+            DragValue::new(&mut options.laser_power)
+                .prefix("Laser power: ")
+                .suffix(" mW")
+                .clamp_range(0.0..=360.0),
+        );
+    });
+}
+
+fn projector_ui(ui: &mut Ui, projector: &mut Projector) {
+    ui.horizontal(|ui| {
+        ui.label("Resolution");
+        ui.add(DragValue::new(&mut projector.resolution[0]).prefix("w: "));
+        ui.add(DragValue::new(&mut projector.resolution[1]).prefix("h: "));
+    });
+    ui.add(
+        DragValue::new(&mut projector.fov)
+            .prefix("Horizontal FOV: ")
+            .suffix("°")
+            .speed(0.1)
+            .clamp_range(1.0..=170.0),
+    );
+    extrinsics_ui(ui, &mut projector.extrinsics);
+    ui.checkbox(&mut projector.show_image, "Show image plane");
+}
+
+pub fn extrinsics_ui(ui: &mut Ui, extrinsics: &mut Extrinsics) {
+    ui.horizontal(|ui| {
+        ui.label("Translation");
+        ui.add(DragValue::new(&mut extrinsics.translation.x).prefix("x: "));
+        ui.add(DragValue::new(&mut extrinsics.translation.y).prefix("y: "));
+        ui.add(DragValue::new(&mut extrinsics.translation.z).prefix("z: "));
+    });
+
+    let (yaw, pitch, roll) = extrinsics.rotation.to_euler(EulerRot::YXZ);
+    let mut angles = [yaw, pitch, roll].map(f32::to_degrees);
+    let mut changed = false;
+    ui.horizontal(|ui| {
+        ui.label("Rotation");
+        for (angle, prefix) in angles.iter_mut().zip(["yaw: ", "pitch: ", "roll: "]) {
+            changed |= ui
+                .add(DragValue::new(angle).prefix(prefix).suffix("°").speed(0.5))
+                .changed();
+        }
+    });
+
+    if changed {
+        let [yaw, pitch, roll] = angles.map(f32::to_radians);
+        extrinsics.rotation = Quat::from_euler(EulerRot::YXZ, yaw, pitch, roll);
+    }
+}
+
+impl Default for RigDevice {
+    fn default() -> Self {
+        Self {
+            extrinsics: Extrinsics::default(),
+            visible: true,
+            status: None,
+            capture: None,
+            paused: false,
+        }
+    }
+}
+
+impl Default for RemoteStream {
+    fn default() -> Self {
+        Self {
+            address: format!("127.0.0.1:{DEFAULT_PORT}"),
+            status: None,
+            client: None,
+            connect: false,
+        }
+    }
+}
+
+impl Default for Playback {
+    fn default() -> Self {
+        Self {
+            path: "recording.bag".to_string(),
+            sequence: SequenceConfig::default(),
+            open: false,
+            error: String::new(),
+        }
+    }
+}
+
+impl Default for Projector {
+    fn default() -> Self {
+        Self {
+            extrinsics: Extrinsics::default(),
+            resolution: [1920, 1080],
+            fov: 30.,
+            show_image: true,
+        }
+    }
+}
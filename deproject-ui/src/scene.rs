@@ -0,0 +1,462 @@
+//! Objects shown in the 3D viewport, grouped into layers, and the outliner used to manage them
+
+use std::sync::mpsc::Sender;
+
+use deproject_io::ImagePointCloud;
+use eframe::egui::{self, ComboBox, DragValue, Ui};
+use glam::{Mat4, Vec3};
+
+use crate::imageplane::ImagePlane;
+use crate::layer::Primitive;
+use crate::rig::{self, Extrinsics, DEPTH_TO_VIEWPORT};
+use crate::view3d::{self, DrawParams, RenderMsg};
+use crate::{shapes, Vertex};
+
+/// How an object's vertices are colored
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub enum ColorMode {
+    /// The colors stored with each vertex
+    Vertex,
+    /// A single color for the whole object
+    Solid([f32; 3]),
+}
+
+/// What an object draws
+pub enum Geometry {
+    /// The fused clouds of the rig's devices, updated as frames arrive
+    LiveCloud,
+    Points(Vec<Vertex>),
+    /// Line segments, e.g. grids and camera frustums
+    Lines(Vec<Vertex>),
+    /// Triangles, e.g. planes and meshes
+    Triangles(Vec<Vertex>),
+    /// A textured quad, e.g. the projector's image plane
+    Image(ImagePlane),
+}
+
+pub struct SceneObject {
+    pub name: String,
+    pub geometry: Geometry,
+    pub visible: bool,
+    /// Object to world transform
+    pub transform: Extrinsics,
+    pub scale: f32,
+    pub point_size: f32,
+    pub color_mode: ColorMode,
+    /// Name of the viewport layer holding this object's geometry
+    key: String,
+    /// The geometry has changed since it was last sent to the viewport
+    dirty: bool,
+}
+
+/// A named group of objects which can be hidden together
+pub struct SceneLayer {
+    pub name: String,
+    pub visible: bool,
+    pub objects: Vec<SceneObject>,
+}
+
+pub struct Scene {
+    pub layers: Vec<SceneLayer>,
+    /// Key of the object shown in the properties panel
+    selected: Option<String>,
+    next_id: usize,
+    /// Keys of deleted objects whose geometry is still held by the viewport
+    removed: Vec<String>,
+    /// Recording to import from
+    import_path: String,
+    /// Fraction of the frames collected for a high quality snapshot, while taking one
+    pub snapshot_progress: Option<f32>,
+    /// Set while a recording is being read for import
+    pub importing: bool,
+}
+
+/// Requests from the outliner which need state outside of the scene
+pub enum OutlinerAction {
+    /// Copy the current live cloud into a new object
+    SnapshotLiveCloud,
+    /// Fuse the next frames of each device into a new object, for less noise than one frame
+    HighQualitySnapshot,
+    /// Stop collecting frames for a high quality snapshot
+    CancelSnapshot,
+    /// Import the first frame of each device in a recording as new objects
+    ImportRecording(String),
+}
+
+/// Prefix of the keys of objects generated from other state, which are replaced as it changes
+const GENERATED_PREFIX: &str = "generated:";
+
+/// Names of the layers every scene starts with
+pub const REFERENCE_LAYER: &str = "Reference";
+pub const LIVE_LAYER: &str = "Live";
+pub const IMPORTED_LAYER: &str = "Imported";
+pub const CAMERAS_LAYER: &str = "Cameras";
+pub const GEOMETRY_LAYER: &str = "Geometry";
+
+impl SceneObject {
+    pub fn new(name: impl Into<String>, geometry: Geometry) -> Self {
+        Self {
+            name: name.into(),
+            geometry,
+            visible: true,
+            transform: Extrinsics::default(),
+            scale: 1.,
+            point_size: 8.,
+            color_mode: ColorMode::Vertex,
+            key: String::new(),
+            dirty: true,
+        }
+    }
+
+    /// Object to world matrix, including scale
+    pub fn matrix(&self) -> Mat4 {
+        self.transform.matrix() * Mat4::from_scale(Vec3::splat(self.scale))
+    }
+
+    /// Message uploading the object's geometry to the viewport. The live cloud is sent separately.
+    fn geometry_msg(&self) -> Option<RenderMsg> {
+        let name = self.key.clone();
+        let (primitive, vertices) = match &self.geometry {
+            Geometry::LiveCloud => return None,
+            Geometry::Points(v) => (Primitive::Points, v),
+            Geometry::Lines(v) => (Primitive::Lines, v),
+            Geometry::Triangles(v) => (Primitive::Triangles, v),
+            Geometry::Image(plane) => {
+                let plane = plane.clone();
+                return Some(RenderMsg::SetImage { name, plane });
+            }
+        };
+        Some(RenderMsg::SetLayer {
+            name,
+            primitive,
+            vertices: vertices.clone(),
+        })
+    }
+
+    /// Whether the object is regenerated by `Scene::set_generated`, overriding edits
+    fn is_generated(&self) -> bool {
+        self.key.starts_with(GENERATED_PREFIX)
+    }
+}
+
+/// Name of the viewport layer holding the geometry of the generated object "id"
+pub fn generated_key(id: &str) -> String {
+    format!("{GENERATED_PREFIX}{id}")
+}
+
+impl Scene {
+    /// Add an object to the named layer, creating the layer if needed
+    pub fn add(&mut self, layer: &str, mut object: SceneObject) {
+        object.key = match object.geometry {
+            Geometry::LiveCloud => view3d::CLOUD_LAYER.to_string(),
+            _ => {
+                self.next_id += 1;
+                format!("object{}", self.next_id)
+            }
+        };
+        self.insert(layer, object);
+    }
+
+    /// Add or update an object derived from other state, e.g. a camera frustum, identified by
+    /// "id". Its geometry is only sent to the viewport again if it changed.
+    pub fn set_generated(
+        &mut self,
+        layer: &str,
+        id: &str,
+        geometry: Geometry,
+        transform: Extrinsics,
+    ) {
+        let key = generated_key(id);
+        let existing = self
+            .layers
+            .iter_mut()
+            .flat_map(|l| l.objects.iter_mut())
+            .find(|o| o.key == key);
+
+        match existing {
+            Some(object) => {
+                let changed = match (&object.geometry, &geometry) {
+                    (Geometry::Lines(a), Geometry::Lines(b))
+                    | (Geometry::Triangles(a), Geometry::Triangles(b)) => {
+                        bytemuck::cast_slice::<_, u8>(a) != bytemuck::cast_slice::<_, u8>(b)
+                    }
+                    (Geometry::Image(a), Geometry::Image(b)) => a != b,
+                    _ => true,
+                };
+                if changed {
+                    object.geometry = geometry;
+                    object.dirty = true;
+                }
+                object.transform = transform;
+            }
+            None => {
+                let mut object = SceneObject::new(id, geometry);
+                object.transform = transform;
+                object.key = key;
+                self.insert(layer, object);
+            }
+        }
+    }
+
+    /// Remove the object generated as "id", if there is one
+    pub fn remove_generated(&mut self, id: &str) {
+        let key = generated_key(id);
+        let mut found = false;
+        for layer in &mut self.layers {
+            layer.objects.retain(|o| {
+                found |= o.key == key;
+                o.key != key
+            });
+        }
+        if found {
+            if self.selected.as_ref() == Some(&key) {
+                self.selected = None;
+            }
+            self.removed.push(key);
+        }
+    }
+
+    fn insert(&mut self, layer: &str, object: SceneObject) {
+        let idx = match self.layers.iter().position(|l| l.name == layer) {
+            Some(idx) => idx,
+            None => {
+                self.layers.push(SceneLayer {
+                    name: layer.to_string(),
+                    visible: true,
+                    objects: vec![],
+                });
+                self.layers.len() - 1
+            }
+        };
+        self.layers[idx].objects.push(object);
+    }
+
+    /// The object drawing the live cloud, if any
+    pub fn live_cloud(&self) -> Option<&SceneObject> {
+        self.layers
+            .iter()
+            .flat_map(|l| &l.objects)
+            .find(|o| matches!(o.geometry, Geometry::LiveCloud))
+    }
+
+    /// Whether the live cloud object and its layer are both shown
+    pub fn live_cloud_visible(&self) -> bool {
+        self.layers.iter().any(|l| {
+            l.visible
+                && l.objects
+                    .iter()
+                    .any(|o| o.visible && matches!(o.geometry, Geometry::LiveCloud))
+        })
+    }
+
+    /// Send changed geometry, deletions and the draw settings of every object to the viewport
+    pub fn sync(&mut self, tx: &Sender<RenderMsg>) {
+        for key in self.removed.drain(..) {
+            let _ = tx.send(RenderMsg::RemoveLayer(key));
+        }
+
+        for layer in &mut self.layers {
+            for object in &mut layer.objects {
+                if object.dirty {
+                    object.dirty = false;
+                    if let Some(msg) = object.geometry_msg() {
+                        let _ = tx.send(msg);
+                    }
+                }
+
+                let params = DrawParams {
+                    visible: layer.visible && object.visible,
+                    transform: object.matrix(),
+                    point_size: object.point_size,
+                    color_mode: object.color_mode,
+                };
+                let _ = tx.send(RenderMsg::SetParams {
+                    name: object.key.clone(),
+                    params,
+                });
+            }
+        }
+    }
+
+    fn selected_mut(&mut self) -> Option<&mut SceneObject> {
+        let key = self.selected.as_ref()?;
+        self.layers
+            .iter_mut()
+            .flat_map(|l| l.objects.iter_mut())
+            .find(|o| &o.key == key)
+    }
+
+    fn remove_selected(&mut self) {
+        let Some(key) = self.selected.take() else {
+            return;
+        };
+        for layer in &mut self.layers {
+            layer.objects.retain(|o| o.key != key);
+        }
+        self.removed.push(key);
+    }
+}
+
+/// Vertices of a single frame in the device's frame, in viewport units
+pub fn cloud_vertices(frame: &ImagePointCloud) -> Vec<Vertex> {
+    frame
+        .iter_pixels()
+        .flatten()
+        .map(|(pos, color)| {
+            Vertex::new(
+                (pos * DEPTH_TO_VIEWPORT).into(),
+                color.map(|c| c as f32 / 256.0),
+            )
+        })
+        .collect()
+}
+
+/// Lists layers and objects, with the properties of the selected object below
+pub fn outliner_ui(ui: &mut Ui, scene: &mut Scene) -> Option<OutlinerAction> {
+    let mut action = None;
+
+    ui.strong("Outliner");
+    for layer in &mut scene.layers {
+        ui.horizontal(|ui| {
+            ui.checkbox(&mut layer.visible, "");
+            ui.label(&layer.name);
+        });
+        ui.indent(&layer.name, |ui| {
+            for object in &mut layer.objects {
+                ui.horizontal(|ui| {
+                    ui.checkbox(&mut object.visible, "");
+                    let selected = scene.selected.as_ref() == Some(&object.key);
+                    if ui.selectable_label(selected, &object.name).clicked() {
+                        scene.selected = Some(object.key.clone());
+                    }
+                });
+            }
+        });
+    }
+
+    ui.separator();
+    if ui.button("Snapshot live cloud").clicked() {
+        action = Some(OutlinerAction::SnapshotLiveCloud);
+    }
+    match scene.snapshot_progress {
+        Some(progress) => {
+            ui.add(egui::ProgressBar::new(progress).text("Averaging frames"));
+            if ui.button("Cancel snapshot").clicked() {
+                action = Some(OutlinerAction::CancelSnapshot);
+            }
+        }
+        None => {
+            if ui.button("High quality snapshot").clicked() {
+                action = Some(OutlinerAction::HighQualitySnapshot);
+            }
+        }
+    }
+    if ui.button("Add plane").clicked() {
+        scene.add(
+            GEOMETRY_LAYER,
+            SceneObject::new("Plane", Geometry::Triangles(shapes::plane([0.3; 3]))),
+        );
+    }
+    ui.horizontal(|ui| {
+        ui.label("Recording: ");
+        ui.text_edit_singleline(&mut scene.import_path);
+    });
+    if scene.importing {
+        ui.horizontal(|ui| {
+            ui.spinner();
+            ui.label("Importing");
+        });
+    } else if ui.button("Import recording").clicked() {
+        action = Some(OutlinerAction::ImportRecording(scene.import_path.clone()));
+    }
+
+    let mut delete = false;
+    if let Some(object) = scene.selected_mut() {
+        ui.separator();
+        ui.strong("Properties");
+        ui.horizontal(|ui| {
+            ui.label("Name: ");
+            ui.text_edit_singleline(&mut object.name);
+        });
+        ui.add(
+            DragValue::new(&mut object.point_size)
+                .prefix("Point size: ")
+                .speed(0.1)
+                .clamp_range(1.0..=64.0),
+        );
+        color_mode_ui(ui, &mut object.color_mode);
+        if object.is_generated() {
+            ui.label("Placed by the rig, edit it from the Devices tab");
+        } else {
+            rig::extrinsics_ui(ui, &mut object.transform);
+        }
+        ui.add(
+            DragValue::new(&mut object.scale)
+                .prefix("Scale: ")
+                .speed(1e-2)
+                .clamp_range(1e-3..=1e3),
+        );
+        if !matches!(object.geometry, Geometry::LiveCloud) && !object.is_generated() {
+            delete = ui.button("Delete").clicked();
+        }
+    }
+    if delete {
+        scene.remove_selected();
+    }
+
+    action
+}
+
+fn color_mode_ui(ui: &mut Ui, mode: &mut ColorMode) {
+    let name = |mode: &ColorMode| match mode {
+        ColorMode::Vertex => "Vertex colors",
+        ColorMode::Solid(_) => "Solid",
+    };
+
+    ComboBox::from_label("Color")
+        .selected_text(name(mode))
+        .show_ui(ui, |ui| {
+            for option in [ColorMode::Vertex, ColorMode::Solid([1.; 3])] {
+                let selected = std::mem::discriminant(mode) == std::mem::discriminant(&option);
+                if ui.selectable_label(selected, name(&option)).clicked() && !selected {
+                    *mode = option;
+                }
+            }
+        });
+
+    if let ColorMode::Solid(color) = mode {
+        egui::color_picker::color_edit_button_rgb(ui, color);
+    }
+}
+
+impl Default for Scene {
+    fn default() -> Self {
+        let mut scene = Self {
+            layers: vec![],
+            selected: None,
+            next_id: 0,
+            removed: vec![],
+            import_path: "recording".to_string(),
+            snapshot_progress: None,
+            importing: false,
+        };
+
+        scene.add(
+            REFERENCE_LAYER,
+            SceneObject::new("Grid", Geometry::Lines(shapes::default_grid())),
+        );
+        scene.add(
+            LIVE_LAYER,
+            SceneObject::new("Live cloud", Geometry::LiveCloud),
+        );
+        for layer in [IMPORTED_LAYER, CAMERAS_LAYER, GEOMETRY_LAYER] {
+            scene.layers.push(SceneLayer {
+                name: layer.to_string(),
+                visible: true,
+                objects: vec![],
+            });
+        }
+
+        scene
+    }
+}
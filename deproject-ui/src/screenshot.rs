@@ -0,0 +1,129 @@
+//! Saving offscreen renders of the viewport as PNG images
+
+use std::fs::File;
+use std::io::BufWriter;
+use std::path::{Path, PathBuf};
+use std::sync::mpsc::{channel, Sender};
+use std::sync::Arc;
+use std::thread;
+
+use anyhow::{Context, Result};
+use eframe::egui::{mutex::Mutex, DragValue, Ui};
+use serde::{Deserialize, Serialize};
+
+/// Largest width or height offered, within the renderbuffer limit of most GPUs.
+/// `Viewport3d::render_offscreen` checks the actual limit.
+const MAX_SIZE: u32 = 8192;
+
+/// An RGBA8 image read back from an offscreen render, with rows from top to bottom
+pub struct Screenshot {
+    pub width: u32,
+    pub height: u32,
+    pub pixels: Vec<u8>,
+}
+
+/// Settings of the screenshot panel
+#[derive(Clone, Serialize, Deserialize)]
+#[serde(default)]
+pub struct ScreenshotConfig {
+    pub width: u32,
+    pub height: u32,
+    pub path: String,
+    /// Outcome of the last screenshot, written by the paint callback which takes it
+    #[serde(skip)]
+    status: Arc<Mutex<String>>,
+}
+
+/// A screenshot to be taken by the paint callback on the next frame
+#[derive(Clone)]
+pub struct ScreenshotRequest {
+    pub width: u32,
+    pub height: u32,
+    pub path: PathBuf,
+    status: Arc<Mutex<String>>,
+}
+
+impl ScreenshotRequest {
+    /// Record the outcome of taking the screenshot, for display in the panel
+    pub fn finish(&self, result: Result<()>) {
+        *self.status.lock() = match result {
+            Ok(()) => format!("Saved {}", self.path.display()),
+            Err(e) => {
+                eprintln!("Screenshot failed: {e:#}");
+                format!("Failed: {e:#}")
+            }
+        };
+    }
+}
+
+/// Start a thread which encodes and writes the screenshots sent to it, so that the paint callback
+/// only has to read the pixels back
+pub fn spawn_writer() -> Sender<(ScreenshotRequest, Screenshot)> {
+    let (tx, rx) = channel::<(ScreenshotRequest, Screenshot)>();
+    thread::spawn(move || {
+        for (request, image) in rx {
+            request.finish(image.save_png(&request.path));
+        }
+    });
+    tx
+}
+
+impl Screenshot {
+    pub fn save_png(&self, path: &Path) -> Result<()> {
+        let file = File::create(path).with_context(|| format!("Creating {}", path.display()))?;
+        let mut encoder = png::Encoder::new(BufWriter::new(file), self.width, self.height);
+        encoder.set_color(png::ColorType::Rgba);
+        encoder.set_depth(png::BitDepth::Eight);
+        encoder
+            .write_header()?
+            .write_image_data(&self.pixels)
+            .context("Writing PNG")?;
+        Ok(())
+    }
+}
+
+/// Resolution and path of screenshots. Returns a request when one should be taken.
+pub fn screenshot_ui(ui: &mut Ui, cfg: &mut ScreenshotConfig) -> Option<ScreenshotRequest> {
+    ui.strong("Screenshot");
+    ui.horizontal(|ui| {
+        ui.label("Size");
+        ui.add(
+            DragValue::new(&mut cfg.width)
+                .prefix("w: ")
+                .clamp_range(1..=MAX_SIZE),
+        );
+        ui.add(
+            DragValue::new(&mut cfg.height)
+                .prefix("h: ")
+                .clamp_range(1..=MAX_SIZE),
+        );
+    });
+    ui.horizontal(|ui| {
+        ui.label("File: ");
+        ui.text_edit_singleline(&mut cfg.path);
+    });
+
+    let clicked = ui.button("Save screenshot").clicked();
+    let status = cfg.status.lock().clone();
+    if !status.is_empty() {
+        ui.label(status);
+    }
+
+    clicked.then(|| ScreenshotRequest {
+        width: cfg.width,
+        height: cfg.height,
+        path: PathBuf::from(&cfg.path),
+        status: cfg.status.clone(),
+    })
+}
+
+impl Default for ScreenshotConfig {
+    fn default() -> Self {
+        Self {
+            width: 1920,
+            height: 1080,
+            path: "screenshot.png".to_string(),
+            status: Default::default(),
+        }
+    }
+}
@@ -0,0 +1,141 @@
+//! Settings saved between sessions, and rig profiles shared between machines
+
+use anyhow::Result;
+use deproject_io::profile::RigProfile;
+use eframe::egui::Ui;
+use eframe::Storage;
+use serde::{Deserialize, Serialize};
+
+use crate::rig::Projector;
+use crate::view3d::ViewportState;
+use crate::{AppConfig, CalibratorConfig, RecorderConfig};
+
+/// Storage key of the settings of the last session
+const SETTINGS_KEY: &str = "deproject_settings";
+
+/// Everything restored on the next launch
+#[derive(Default, Serialize, Deserialize)]
+#[serde(default)]
+pub struct Settings {
+    pub app: AppConfig,
+    pub viewport: ViewportState,
+}
+
+/// `Settings` borrowed from the app for saving
+#[derive(Serialize)]
+pub struct SettingsRef<'a> {
+    pub app: &'a AppConfig,
+    pub viewport: &'a ViewportState,
+}
+
+/// Settings of a rig profile only the viewer has
+#[derive(Default, Deserialize)]
+#[serde(default)]
+struct ViewerProfile {
+    projector: Projector,
+    record: RecorderConfig,
+    calib: CalibratorConfig,
+}
+
+/// `ViewerProfile` borrowed from the app's settings for export
+#[derive(Serialize)]
+struct ViewerProfileRef<'a> {
+    projector: &'a Projector,
+    record: &'a RecorderConfig,
+    calib: &'a CalibratorConfig,
+}
+
+/// Path of the profile to import or export
+#[derive(Serialize, Deserialize)]
+#[serde(default)]
+pub struct ProfileConfig {
+    path: String,
+    /// Outcome of the last import or export
+    #[serde(skip)]
+    status: String,
+}
+
+impl Settings {
+    /// Load the last session's settings from "storage", or defaults if there are none yet
+    pub fn load(storage: Option<&dyn Storage>) -> Result<Self> {
+        match storage.and_then(|s| s.get_string(SETTINGS_KEY)) {
+            Some(json) => Ok(serde_json::from_str(&json)?),
+            None => Ok(Self::default()),
+        }
+    }
+}
+
+impl SettingsRef<'_> {
+    pub fn save(&self, storage: &mut dyn Storage) -> Result<()> {
+        storage.set_string(SETTINGS_KEY, serde_json::to_string(self)?);
+        Ok(())
+    }
+}
+
+/// Write the devices, stream and sensor settings, projector, and recording and calibration
+/// settings of "cfg" to a profile
+fn export_profile(cfg: &AppConfig, path: &str) -> Result<()> {
+    let viewer = ViewerProfileRef {
+        projector: &cfg.rig.projector,
+        record: &cfg.record,
+        calib: &cfg.calib,
+    };
+    cfg.rig.profile(viewer).save(path)
+}
+
+/// Replace the settings of "cfg" with those of a profile. Profiles written by other tools only
+/// hold devices, stream and sensor settings, so the viewer's own settings are kept.
+fn import_profile(cfg: &mut AppConfig, path: &str) -> Result<()> {
+    let profile: RigProfile<ViewerProfile> = RigProfile::load(path)?;
+    if let Some(viewer) = cfg.rig.apply_profile(profile) {
+        cfg.rig.projector = viewer.projector;
+        cfg.record = viewer.record;
+        cfg.calib = viewer.calib;
+    }
+    Ok(())
+}
+
+/// Import and export of rig profiles
+pub fn profile_ui(ui: &mut Ui, cfg: &mut AppConfig) {
+    ui.strong("Rig profile");
+    ui.label(
+        "Device placements, stream and sensor settings, and capture and calibration parameters",
+    );
+    ui.horizontal(|ui| {
+        ui.label("File: ");
+        ui.text_edit_singleline(&mut cfg.profile.path);
+    });
+
+    ui.horizontal(|ui| {
+        let path = cfg.profile.path.clone();
+        let import = ui.button("Import").clicked();
+        let export = ui.button("Export").clicked();
+        let result = if import {
+            Some(import_profile(cfg, &path).map(|()| format!("Imported {path}")))
+        } else if export {
+            Some(export_profile(cfg, &path).map(|()| format!("Exported {path}")))
+        } else {
+            None
+        };
+        match result {
+            Some(Ok(status)) => cfg.profile.status = status,
+            Some(Err(e)) => {
+                eprintln!("Rig profile failed: {e:#}");
+                cfg.profile.status = format!("Failed: {e:#}");
+            }
+            None => (),
+        }
+    });
+    if !cfg.profile.status.is_empty() {
+        ui.label(&cfg.profile.status);
+    }
+}
+
+impl Default for ProfileConfig {
+    fn default() -> Self {
+        Self {
+            path: "rig_profile.json".to_string(),
+            status: String::new(),
+        }
+    }
+}
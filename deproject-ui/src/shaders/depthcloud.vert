@@ -0,0 +1,193 @@
+#version 450
+// Deprojects one point per pixel of a raw depth image, coloring it from the color image.
+// Ported from rs2_deproject_pixel_to_point and rs2_project_point_to_pixel in realsense_utils.rs
+
+// Values of rs2_distortion
+#define DISTORTION_NONE 0
+#define DISTORTION_MODIFIED_BROWN_CONRADY 1
+#define DISTORTION_INVERSE_BROWN_CONRADY 2
+#define DISTORTION_FTHETA 3
+#define DISTORTION_BROWN_CONRADY 4
+#define DISTORTION_KANNALA_BRANDT4 5
+
+out vec4 f_color;
+
+uniform usampler2D u_depth;
+uniform sampler2D u_color;
+
+uniform mat4 u_view;
+uniform mat4 u_projection;
+uniform mat4 u_model;
+// Replaces the sampled color by this color, weighted by alpha
+uniform vec4 u_tint;
+uniform vec2 u_spread;
+uniform float u_ptsize;
+
+// Colormap applied to depth instead of sampling the color image: 0 for none, 1 for turbo and 2 for
+// viridis. Depth in meters is mapped from u_depth_range to the ends of the colormap.
+uniform int u_colormap;
+uniform vec2 u_depth_range;
+// Meters per depth unit
+uniform float u_depth_scale;
+
+// Depth camera intrinsics
+uniform vec2 u_depth_pp;
+uniform vec2 u_depth_f;
+uniform int u_depth_model;
+uniform float u_depth_coeffs[5];
+
+// Color camera intrinsics
+uniform vec2 u_color_pp;
+uniform vec2 u_color_f;
+uniform int u_color_model;
+uniform float u_color_coeffs[5];
+
+// Depth to color extrinsics
+uniform mat3 u_depth_to_color_rot;
+uniform vec3 u_depth_to_color_tl;
+
+const float EPSILON = 1.1920929e-7;
+
+// Polynomial fits of the colormaps, matching Colormap::sample in coloring.rs
+vec3 turbo(float t) {
+    const vec4 r4 = vec4(0.1357214, 4.615393, -42.66032, 132.1311);
+    const vec2 r2 = vec2(-152.9424, 59.28638);
+    const vec4 g4 = vec4(0.09140261, 2.194188, 4.842967, -14.18503);
+    const vec2 g2 = vec2(4.277299, 2.829566);
+    const vec4 b4 = vec4(0.1066733, 12.64195, -60.58205, 110.3628);
+    const vec2 b2 = vec2(-89.90311, 27.34825);
+    vec4 v4 = vec4(1., t, t * t, t * t * t);
+    vec2 v2 = v4.zw * v4.z;
+    return clamp(vec3(dot(v4, r4) + dot(v2, r2), dot(v4, g4) + dot(v2, g2), dot(v4, b4) + dot(v2, b2)), 0., 1.);
+}
+
+vec3 viridis(float t) {
+    const vec3 c0 = vec3(0.2777273, 0.005407345, 0.3340998);
+    const vec3 c1 = vec3(0.1050930, 1.404614, 1.384590);
+    const vec3 c2 = vec3(-0.3308618, 0.2148476, 0.09509516);
+    const vec3 c3 = vec3(-4.634230, -5.799101, -19.33244);
+    const vec3 c4 = vec3(6.228270, 14.17993, 56.69055);
+    const vec3 c5 = vec3(4.776385, -13.74515, -65.35303);
+    const vec3 c6 = vec3(-5.435456, 4.645853, 26.31244);
+    return clamp(c0 + t * (c1 + t * (c2 + t * (c3 + t * (c4 + t * (c5 + t * c6))))), 0., 1.);
+}
+
+// DISTORTION_MODIFIED_BROWN_CONRADY can't be inverted, so frames using it for depth are never
+// uploaded, matching rs2_deproject_pixel_to_point
+vec3 deproject(vec2 pixel, float depth) {
+    float x = (pixel.x - u_depth_pp.x) / u_depth_f.x;
+    float y = (pixel.y - u_depth_pp.y) / u_depth_f.y;
+    float xo = x;
+    float yo = y;
+    float c[5] = u_depth_coeffs;
+
+    if (u_depth_model == DISTORTION_INVERSE_BROWN_CONRADY || u_depth_model == DISTORTION_BROWN_CONRADY) {
+        bool inverse = u_depth_model == DISTORTION_INVERSE_BROWN_CONRADY;
+        for (int i = 0; i < 10; i++) {
+            float r2 = x * x + y * y;
+            float icdist = 1. / (1. + ((c[4] * r2 + c[1]) * r2 + c[0]) * r2);
+            float xq = inverse ? x / icdist : x;
+            float yq = inverse ? y / icdist : y;
+            float delta_x = 2. * c[2] * xq * yq + c[3] * (r2 + 2. * xq * xq);
+            float delta_y = 2. * c[3] * xq * yq + c[2] * (r2 + 2. * yq * yq);
+            x = (xo - delta_x) * icdist;
+            y = (yo - delta_y) * icdist;
+        }
+    } else if (u_depth_model == DISTORTION_KANNALA_BRANDT4) {
+        float rd = max(sqrt(x * x + y * y), EPSILON);
+        float theta = rd;
+        float theta2 = rd * rd;
+        for (int i = 0; i < 4; i++) {
+            float f = theta * (1. + theta2 * (c[0] + theta2 * (c[1] + theta2 * (c[2] + theta2 * c[3])))) - rd;
+            if (abs(f) < EPSILON) {
+                break;
+            }
+            float df = 1. + theta2 * (3. * c[0] + theta2 * (5. * c[1] + theta2 * (7. * c[2] + 9. * theta2 * c[3])));
+            theta -= f / df;
+            theta2 = theta * theta;
+        }
+        float r = tan(theta);
+        x *= r / rd;
+        y *= r / rd;
+    } else if (u_depth_model == DISTORTION_FTHETA) {
+        float rd = max(sqrt(x * x + y * y), EPSILON);
+        float r = tan(c[0] * rd) / atan(2. * tan(c[0] / 2.));
+        x *= r / rd;
+        y *= r / rd;
+    }
+
+    return vec3(depth * x, depth * y, depth);
+}
+
+vec2 project(vec3 point) {
+    float x = point.x / point.z;
+    float y = point.y / point.z;
+    float c[5] = u_color_coeffs;
+
+    if (u_color_model == DISTORTION_MODIFIED_BROWN_CONRADY || u_color_model == DISTORTION_INVERSE_BROWN_CONRADY) {
+        float r2 = x * x + y * y;
+        float f = 1. + c[0] * r2 + c[1] * r2 * r2 + c[4] * r2 * r2 * r2;
+        x *= f;
+        y *= f;
+        float dx = x + 2. * c[2] * x * y + c[3] * (r2 + 2. * x * x);
+        float dy = y + 2. * c[3] * x * y + c[2] * (r2 + 2. * y * y);
+        x = dx;
+        y = dy;
+    } else if (u_color_model == DISTORTION_BROWN_CONRADY) {
+        float r2 = x * x + y * y;
+        float f = 1. + c[0] * r2 + c[1] * r2 * r2 + c[4] * r2 * r2 * r2;
+        float dx = x * f + 2. * c[2] * x * y + c[3] * (r2 + 2. * x * x);
+        float dy = y * f + 2. * c[3] * x * y + c[2] * (r2 + 2. * y * y);
+        x = dx;
+        y = dy;
+    } else if (u_color_model == DISTORTION_FTHETA) {
+        float r = max(sqrt(x * x + y * y), EPSILON);
+        float rd = 1. / c[0] * atan(2. * r * tan(c[0] / 2.));
+        x *= rd / r;
+        y *= rd / r;
+    } else if (u_color_model == DISTORTION_KANNALA_BRANDT4) {
+        float r = max(sqrt(x * x + y * y), EPSILON);
+        float theta = atan(r);
+        float theta2 = theta * theta;
+        float series = 1. + theta2 * (c[0] + theta2 * (c[1] + theta2 * (c[2] + theta2 * c[3])));
+        float rd = theta * series;
+        x *= rd / r;
+        y *= rd / r;
+    }
+
+    return vec2(x, y) * u_color_f + u_color_pp;
+}
+
+void main() {
+    ivec2 size = textureSize(u_depth, 0);
+    ivec2 texel = ivec2(gl_VertexID % size.x, gl_VertexID / size.x);
+    uint depth = texelFetch(u_depth, texel, 0).r;
+
+    // Clip pixels without depth
+    if (depth == 0u) {
+        gl_Position = vec4(0., 0., 2., 1.);
+        gl_PointSize = 0.;
+        f_color = vec4(0.);
+        return;
+    }
+
+    vec3 point = deproject(vec2(texel) - 0.5, float(depth));
+
+    vec3 color;
+    if (u_colormap != 0) {
+        float t = clamp((point.z * u_depth_scale - u_depth_range.x) / max(u_depth_range.y - u_depth_range.x, 1e-3), 0., 1.);
+        color = u_colormap == 1 ? turbo(t) : viridis(t);
+    } else {
+        vec2 color_pixel = project(u_depth_to_color_rot * point + u_depth_to_color_tl);
+        ivec2 color_texel = ivec2(color_pixel + 0.5);
+        ivec2 color_size = textureSize(u_color, 0);
+        bool in_color = all(greaterThanEqual(color_texel, ivec2(0))) && all(lessThan(color_texel, color_size));
+        color = in_color ? texelFetch(u_color, color_texel, 0).rgb : vec3(0.);
+    }
+    f_color = vec4(mix(color, u_tint.rgb, u_tint.a), 1.);
+
+    vec3 pos = (u_model * vec4(point, 1.)).xyz;
+    pos.z = (pos.z - u_spread.x) * u_spread.y + u_spread.x;
+    gl_Position = u_projection * u_view * vec4(pos, 1.);
+    gl_PointSize = u_ptsize;
+}
@@ -0,0 +1,12 @@
+#version 450
+precision mediump float;
+
+in vec2 f_uv;
+
+uniform sampler2D u_image;
+
+out vec4 out_color;
+
+void main() {
+    out_color = vec4(vec3(texture(u_image, f_uv).r), 1.);
+}
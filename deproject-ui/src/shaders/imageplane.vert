@@ -0,0 +1,17 @@
+#version 450
+// Draws a single-channel image across a quad, e.g. the pattern on the projector's image plane
+layout(location = 0) in vec3 v_pos;
+layout(location = 1) in vec2 v_uv;
+out vec2 f_uv;
+
+uniform mat4 u_view;
+uniform mat4 u_projection;
+uniform mat4 u_model;
+uniform vec2 u_spread;
+
+void main() {
+    vec3 pos = (u_model * vec4(v_pos, 1.)).xyz;
+    pos.z = (pos.z - u_spread.x) * u_spread.y + u_spread.x;
+    gl_Position = u_projection * u_view * vec4(pos, 1.);
+    f_uv = v_uv;
+}
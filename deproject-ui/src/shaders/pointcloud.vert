@@ -5,14 +5,17 @@ out vec4 f_color;
 
 uniform mat4 u_view;
 uniform mat4 u_projection;
+uniform mat4 u_model;
+// Replaces the vertex color by this color, weighted by alpha
+uniform vec4 u_tint;
 uniform vec2 u_spread;
 uniform float u_ptsize;
 
 void main() {
-    vec3 pos = v_pos;
+    vec3 pos = (u_model * vec4(v_pos, 1.)).xyz;
     pos.z = (pos.z - u_spread.x) * u_spread.y + u_spread.x;
     gl_Position = u_projection * u_view * vec4(pos, 1.);
     gl_PointSize = u_ptsize;
-    f_color = vec4(v_color, 1.);
+    f_color = vec4(mix(v_color, u_tint.rgb, u_tint.a), 1.);
 }
 
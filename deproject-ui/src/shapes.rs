@@ -17,6 +17,58 @@ pub fn default_grid() -> Vec<Vertex> {
     )
 }
 
+/// Square in the XZ plane, centered on the origin, as two triangles
+pub fn plane(color: [f32; 3]) -> Vec<Vertex> {
+    let h = PLANE_SIZE / 2.;
+    let corners = [[-h, 0., -h], [h, 0., -h], [h, 0., h], [-h, 0., h]];
+    [0, 1, 2, 0, 2, 3]
+        .into_iter()
+        .map(|i| Vertex::new(corners[i], color))
+        .collect()
+}
+
+/// Corners of a pinhole camera's image at the given depth, in the camera's frame (x right, y down,
+/// z forward), clockwise from the top left
+pub fn frustum_corners(
+    size: [f32; 2],
+    focal: [f32; 2],
+    principal: [f32; 2],
+    depth: f32,
+) -> [[f32; 3]; 4] {
+    [[0., 0.], [size[0], 0.], size, [0., size[1]]].map(|[u, v]| {
+        let x = (u - principal[0]) / focal[0];
+        let y = (v - principal[1]) / focal[1];
+        [x * depth, y * depth, depth]
+    })
+}
+
+/// Lines from a pinhole camera's center to the corners of its image at the given depth, and
+/// around the image
+pub fn frustum(
+    size: [f32; 2],
+    focal: [f32; 2],
+    principal: [f32; 2],
+    depth: f32,
+    color: [f32; 3],
+) -> Vec<Vertex> {
+    let corners = frustum_corners(size, focal, principal, depth);
+    (0..4)
+        .flat_map(|i| [[0.; 3], corners[i], corners[i], corners[(i + 1) % 4]])
+        .map(|pos| Vertex::new(pos, color))
+        .collect()
+}
+
+/// X, Y and Z axes of the given length, colored red, green and blue
+pub fn axes(length: f32) -> Vec<Vertex> {
+    [[1., 0., 0.], [0., 1., 0.], [0., 0., 1.]]
+        .into_iter()
+        .flat_map(|axis| {
+            let end = axis.map(|c| c * length);
+            [Vertex::new([0.; 3], axis), Vertex::new(end, axis)]
+        })
+        .collect()
+}
+
 pub fn grid(
     size: i32,
     div: i32,
@@ -0,0 +1,115 @@
+use std::fs::File;
+use std::io::BufWriter;
+
+use deproject_io::metrics::{Metrics, RollingHistogram, Stage};
+use eframe::egui::{self, Color32, Grid, Rect, Sense, Stroke, Ui};
+
+/// Number of bars in the histogram plot
+const HISTOGRAM_BINS: usize = 40;
+
+pub struct StatsConfig {
+    /// Source and stage whose histogram is plotted
+    selected: Option<(String, Stage)>,
+    /// File to export CSV summaries to
+    csv_path: String,
+}
+
+pub fn stats_ui(ui: &mut Ui, state: &mut StatsConfig, metrics: &Metrics, dropped_frames: u64) {
+    let snapshot = metrics.snapshot();
+
+    ui.strong("Framerate");
+    ui.label(format!("Frames dropped by the UI: {dropped_frames}"));
+    for (source, _, hist) in snapshot.iter().filter(|(_, s, _)| *s == Stage::Interval) {
+        let mean = hist.stats().mean;
+        if mean > 0. {
+            ui.label(format!("{source}: {:.1} fps", 1e3 / mean));
+        }
+    }
+
+    ui.separator();
+    ui.strong("Stage timings (ms)");
+    Grid::new("stage_timings").striped(true).show(ui, |ui| {
+        for heading in ["Source", "Stage", "Last", "Mean", "p50", "p95", "Max"] {
+            ui.label(heading);
+        }
+        ui.end_row();
+
+        for (source, stage, hist) in &snapshot {
+            let key = (source.clone(), *stage);
+            let selected = state.selected.as_ref() == Some(&key);
+            if ui.selectable_label(selected, source).clicked() {
+                state.selected = Some(key);
+            }
+            ui.label(stage.name());
+            let s = hist.stats();
+            ui.label(format!("{:.2}", hist.last().unwrap_or(0.)));
+            for v in [s.mean, s.p50, s.p95, s.max] {
+                ui.label(format!("{v:.2}"));
+            }
+            ui.end_row();
+        }
+    });
+
+    let selected = snapshot
+        .iter()
+        .find(|(source, stage, _)| state.selected.as_ref() == Some(&(source.clone(), *stage)));
+    if let Some((source, stage, hist)) = selected {
+        ui.separator();
+        ui.label(format!("{source} {}", stage.name()));
+        histogram_ui(ui, hist);
+    }
+
+    ui.separator();
+    ui.horizontal(|ui| {
+        ui.label("CSV: ");
+        ui.text_edit_singleline(&mut state.csv_path);
+    });
+    ui.horizontal(|ui| {
+        if ui.button("Export").clicked() {
+            let result = File::create(&state.csv_path)
+                .and_then(|f| metrics.write_csv(&mut BufWriter::new(f)));
+            if let Err(e) = result {
+                eprintln!("Failed to export metrics: {e}");
+            }
+        }
+        if ui.button("Clear").clicked() {
+            metrics.clear();
+        }
+    });
+}
+
+/// Bar plot of the distribution of samples, from zero up to a little past the 95th percentile
+fn histogram_ui(ui: &mut Ui, hist: &RollingHistogram) {
+    let max = (hist.stats().p95 * 1.5).max(1e-3);
+    let bins = hist.bins(HISTOGRAM_BINS, max);
+    let tallest = bins.iter().copied().max().unwrap_or(0).max(1);
+
+    let size = egui::vec2(ui.available_width(), 80.);
+    let (rect, _) = ui.allocate_exact_size(size, Sense::hover());
+    let painter = ui.painter_at(rect);
+    painter.rect_stroke(rect, 0., Stroke::new(1., Color32::DARK_GRAY));
+
+    let bar_width = rect.width() / HISTOGRAM_BINS as f32;
+    for (i, count) in bins.into_iter().enumerate() {
+        let height = rect.height() * count as f32 / tallest as f32;
+        let min = egui::pos2(rect.left() + i as f32 * bar_width, rect.bottom() - height);
+        let max = egui::pos2(min.x + bar_width - 1., rect.bottom());
+        painter.rect_filled(Rect::from_min_max(min, max), 0., Color32::LIGHT_BLUE);
+    }
+
+    ui.horizontal(|ui| {
+        ui.label("0 ms");
+        ui.with_layout(egui::Layout::right_to_left(egui::Align::Center), |ui| {
+            ui.label(format!("{max:.2} ms"));
+        });
+    });
+}
+
+impl Default for StatsConfig {
+    fn default() -> Self {
+        Self {
+            selected: None,
+            csv_path: "metrics.csv".to_string(),
+        }
+    }
+}
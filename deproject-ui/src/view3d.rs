@@ -1,61 +1,152 @@
-use crate::{camera::Camera, Vertex};
+use crate::camera::{self, Camera, ControlMode};
+use crate::coloring::{self, PointColoring};
+use crate::depthcloud::{DepthCloud, GpuDepthCloud};
+use crate::imageplane::{GpuImagePlane, ImagePlane};
+use crate::layer::{GeometryLayer, Primitive};
+use crate::scene::ColorMode;
+use crate::screenshot::{self, Screenshot, ScreenshotConfig, ScreenshotRequest};
+use crate::Vertex;
+use deproject_io::{
+    metrics::{Metrics, Stage},
+    pool::Pool,
+};
 use eframe::{egui, emath::Vec2};
 use egui::mutex::Mutex;
+use glam::{Mat4, Vec3};
 use glow::HasContext;
 use glow::VERTEX_PROGRAM_POINT_SIZE;
-use std::sync::{mpsc::Receiver, Arc};
+use serde::{Deserialize, Serialize};
+use std::collections::{BTreeMap, HashMap};
+use std::num::NonZeroU32;
+use std::sync::{
+    mpsc::{Receiver, Sender},
+    Arc,
+};
+use std::time::{Duration, Instant};
 
-#[derive(Default, Clone)]
-pub struct RenderMsg {
-    pub lines: Vec<Vertex>,
-    pub points: Vec<Vertex>,
+/// Source name under which rendering stages are recorded
+pub const METRICS_SOURCE: &str = "viewport";
+
+/// Background of offscreen renders, matching the on-screen canvas
+const OFFSCREEN_BACKGROUND: [f32; 4] = [0.106, 0.106, 0.106, 1.0];
+
+/// Name of the layer holding the fused live point cloud
+pub const CLOUD_LAYER: &str = "cloud";
+
+/// Changes to the geometry held by a `Viewport3d`
+#[derive(Clone)]
+pub enum RenderMsg {
+    /// Replace the vertices of a layer, creating it if needed. Other layers are untouched.
+    SetLayer {
+        name: String,
+        primitive: Primitive,
+        vertices: Vec<Vertex>,
+    },
+    /// Replace the image drawn on a layer's quad, creating it if needed. It is drawn with the
+    /// layer's settings, alongside any vertices of the same name.
+    SetImage { name: String, plane: ImagePlane },
+    /// Remove a layer's vertices and image
+    RemoveLayer(String),
+    /// Set how a layer is drawn. Frames deprojected on the GPU use the settings of `CLOUD_LAYER`.
+    SetParams { name: String, params: DrawParams },
+    /// Replace the frames deprojected on the GPU
+    DepthClouds(Vec<DepthCloud>),
+}
+
+/// Per-layer draw settings
+#[derive(Copy, Clone, Debug)]
+pub struct DrawParams {
+    pub visible: bool,
+    /// Layer to world transform
+    pub transform: Mat4,
+    pub point_size: f32,
+    pub color_mode: ColorMode,
 }
 
 pub struct Viewport3d {
     program: glow::Program,
-
-    point_array: glow::VertexArray,
-    point_buf: glow::NativeBuffer,
-    point_count: i32,
-
-    line_array: glow::VertexArray,
-    line_buf: glow::NativeBuffer,
-    line_count: i32,
+    depth_program: glow::Program,
+    image_program: glow::Program,
+    /// Attribute-less vertex array for drawing depth clouds
+    empty_array: glow::VertexArray,
+    depth_clouds: Vec<GpuDepthCloud>,
+    /// Number of entries of "depth_clouds" holding valid frames
+    depth_cloud_count: usize,
+
+    /// Geometry layers, drawn in name order
+    layers: BTreeMap<String, GeometryLayer>,
+    /// Images drawn on quads, keyed by layer name
+    images: BTreeMap<String, GpuImagePlane>,
+    /// Draw settings of each layer, which may arrive before its geometry
+    params: HashMap<String, DrawParams>,
 
     rx: Receiver<RenderMsg>,
+    /// Rendered screenshots are sent here to be encoded and written
+    screenshot_tx: Sender<(ScreenshotRequest, Screenshot)>,
+
+    metrics: Metrics,
+    /// Timer query measuring GPU draw time, and whether its result is still outstanding
+    draw_query: Option<glow::Query>,
+    draw_query_pending: bool,
+    /// Point buffers are handed back here once uploaded
+    vertex_pool: Pool<Vec<Vertex>>,
 }
 
-#[derive(Clone)]
+#[derive(Clone, Serialize, Deserialize)]
+#[serde(default)]
 pub struct ViewportState {
     pub camera: Camera,
     pub spread: f32,
-    pub point_size: f32,
+    /// Send raw depth and color to the GPU and deproject there, instead of uploading points
+    pub gpu_deprojection: bool,
+    /// How live clouds are colored
+    pub point_coloring: PointColoring,
+    pub screenshot: ScreenshotConfig,
+    /// Screenshot to take when the viewport is next painted
+    #[serde(skip)]
+    pub screenshot_request: Option<ScreenshotRequest>,
+    /// Set while a live frame's depth distortion model can't be deprojected on the GPU
+    #[serde(skip)]
+    pub gpu_unsupported_distortion: bool,
 }
 
+/// Draws the viewport and handles camera movement. Clicks are left to the caller.
 pub fn viewport_widget(
     state: &mut ViewportState,
     view3d: Arc<Mutex<Viewport3d>>,
     ui: &mut egui::Ui,
-) {
+) -> egui::Response {
     let space = ui.available_size(); //Vec2::splat(ui.available_size().min_elem());
 
-    let (rect, response) = ui.allocate_exact_size(space, egui::Sense::drag());
+    let (rect, response) = ui.allocate_exact_size(space, egui::Sense::click_and_drag());
 
     // Camera movement
     if response.dragged_by(egui::PointerButton::Primary) {
+        let delta = response.drag_delta();
         if ui.input(|i| i.raw.modifiers.shift_only()) {
-            state.camera.pan(
-                response.drag_delta().x,
-                response.drag_delta().y,
-                state.spread.powi(-2),
-            );
+            state.camera.pan(delta.x, delta.y, state.spread.powi(-2));
+        } else if state.camera.mode == ControlMode::Fly {
+            state.camera.look(delta.x, delta.y);
         } else {
-            state
-                .camera
-                .pivot(response.drag_delta().x, response.drag_delta().y);
+            state.camera.pivot(delta.x, delta.y);
         }
     }
 
+    if state.camera.mode == ControlMode::Fly && response.hovered() {
+        let (forward, right, up, fast, dt) = ui.input(|i| {
+            let axis = |pos, neg| i.key_down(pos) as i32 as f32 - i.key_down(neg) as i32 as f32;
+            (
+                axis(egui::Key::W, egui::Key::S),
+                axis(egui::Key::D, egui::Key::A),
+                axis(egui::Key::E, egui::Key::Q),
+                i.modifiers.shift,
+                i.stable_dt,
+            )
+        });
+        let speed = state.camera.control.fly_speed * if fast { 4. } else { 1. } * dt;
+        state.camera.fly(forward * speed, right * speed, up * speed);
+    }
+
     if response.dragged_by(egui::PointerButton::Secondary) {
         state.camera.pan(
             response.drag_delta().x,
@@ -68,20 +159,65 @@ pub fn viewport_widget(
         state.camera.zoom(ui.input(|i| i.scroll_delta.y));
     }
 
+    state.camera.update(Instant::now());
+
     // Clone locals so we can move them into the paint callback:
+    let screenshot = state.screenshot_request.take();
     let state = state.clone();
 
     let callback = egui::PaintCallback {
         rect,
-        callback: std::sync::Arc::new(egui_glow::CallbackFn::new(move |_info, painter| {
-            view3d.lock().paint(painter.gl(), state.clone(), space);
+        callback: std::sync::Arc::new(egui_glow::CallbackFn::new(move |info, painter| {
+            let mut view3d = view3d.lock();
+            view3d.paint(painter.gl(), state.clone(), space, 1.);
+            if let Some(request) = screenshot.clone() {
+                // Keep points the same size relative to the image as on screen
+                let on_screen = info.viewport_in_pixels().height_px.max(1) as f32;
+                let point_scale = request.height as f32 / on_screen;
+                let (width, height) = (request.width, request.height);
+                match view3d.render_offscreen(painter.gl(), &state, width, height, point_scale) {
+                    Ok(image) => {
+                        let _ = view3d.screenshot_tx.send((request, image));
+                    }
+                    Err(e) => request.finish(Err(anyhow::Error::msg(e))),
+                }
+            }
         })),
     };
     ui.painter().add(callback);
+
+    response
+}
+
+impl ViewportState {
+    /// Screen position and normalized depth of a point drawn in a viewport occupying "rect",
+    /// including the depth spread applied by the shaders. None if it is behind the camera.
+    pub fn project(&self, pos: Vec3, rect: egui::Rect) -> Option<(egui::Pos2, f32)> {
+        let pivot = self.camera.view.pivot.z;
+        let pos = Vec3::new(pos.x, pos.y, (pos.z - pivot) * self.spread.powi(2) + pivot);
+        let clip = self.camera.projection(rect.width(), rect.height())
+            * self.camera.view()
+            * pos.extend(1.);
+        if clip.w <= 0. {
+            return None;
+        }
+
+        let ndc = clip.truncate() / clip.w;
+        let screen = egui::pos2(
+            rect.left() + (ndc.x + 1.) / 2. * rect.width(),
+            rect.top() + (1. - ndc.y) / 2. * rect.height(),
+        );
+        Some((screen, ndc.z))
+    }
 }
 
 impl Viewport3d {
-    pub fn new(gl: &glow::Context, rx: Receiver<RenderMsg>) -> Self {
+    pub fn new(
+        gl: &glow::Context,
+        rx: Receiver<RenderMsg>,
+        metrics: Metrics,
+        vertex_pool: Pool<Vec<Vertex>>,
+    ) -> Self {
         use glow::HasContext as _;
 
         // Compile shaders
@@ -103,79 +239,44 @@ impl Viewport3d {
 
             let program = compile_glsl_program(gl, &shader_sources).unwrap();
 
-            // Create pointcloud buffer
-            let point_array = gl.create_vertex_array().unwrap();
-            gl.bind_vertex_array(Some(point_array));
-            let point_verts = vec![Vertex::new([0., 0., 0.], [0., 0., 0.])];
-            let point_count = point_verts.len() as i32;
-            let point_buf = gl.create_buffer().expect("Cannot create vertex buffer");
-            gl.bind_buffer(glow::ARRAY_BUFFER, Some(point_buf));
-            gl.buffer_data_u8_slice(
-                glow::ARRAY_BUFFER,
-                bytemuck::cast_slice(&point_verts),
-                glow::STREAM_DRAW,
-            );
-
-            gl.bind_vertex_array(None);
-            gl.bind_buffer(glow::ARRAY_BUFFER, None);
-
-            // Create line buffer
-            let line_array = gl.create_vertex_array().unwrap();
-            gl.bind_vertex_array(Some(line_array));
-            let line_verts = vec![Vertex::new([0.; 3], [0.; 3]); 50];
-            let line_count = line_verts.len() as i32;
-            let line_buf = gl.create_buffer().expect("Cannot create vertex buffer");
-            gl.bind_buffer(glow::ARRAY_BUFFER, Some(line_buf));
-            gl.buffer_data_u8_slice(
-                glow::ARRAY_BUFFER,
-                bytemuck::cast_slice(&line_verts),
-                glow::STREAM_DRAW,
-            );
-
-            gl.bind_vertex_array(None);
-            gl.bind_buffer(glow::ARRAY_BUFFER, None);
-
-            for (array, buf) in [(line_array, line_buf), (point_array, point_buf)] {
-                gl.bind_vertex_array(Some(array));
-                gl.bind_buffer(glow::ARRAY_BUFFER, Some(buf));
-
-                // Set vertex attributes
-                gl.enable_vertex_attrib_array(0);
-                gl.vertex_attrib_pointer_f32(
-                    0,
-                    3,
-                    glow::FLOAT,
-                    false,
-                    std::mem::size_of::<Vertex>() as i32,
-                    0,
-                );
-
-                gl.enable_vertex_attrib_array(1);
-                gl.vertex_attrib_pointer_f32(
-                    1,
-                    3,
-                    glow::FLOAT,
-                    false,
-                    std::mem::size_of::<Vertex>() as i32,
-                    3 * std::mem::size_of::<f32>() as i32,
-                );
+            let depth_shader_sources = [
+                (glow::VERTEX_SHADER, include_str!("shaders/depthcloud.vert")),
+                (
+                    glow::FRAGMENT_SHADER,
+                    include_str!("shaders/pointcloud.frag"),
+                ),
+            ];
+            let depth_program = compile_glsl_program(gl, &depth_shader_sources).unwrap();
+            let empty_array = gl.create_vertex_array().unwrap();
 
-                gl.bind_vertex_array(None);
-                gl.bind_buffer(glow::ARRAY_BUFFER, None);
-            }
+            let image_shader_sources = [
+                (glow::VERTEX_SHADER, include_str!("shaders/imageplane.vert")),
+                (
+                    glow::FRAGMENT_SHADER,
+                    include_str!("shaders/imageplane.frag"),
+                ),
+            ];
+            let image_program = compile_glsl_program(gl, &image_shader_sources).unwrap();
 
             Self {
                 program,
+                depth_program,
+                image_program,
+                empty_array,
+                depth_clouds: vec![],
+                depth_cloud_count: 0,
 
-                point_array,
-                point_buf,
-                point_count,
-
-                line_array,
-                line_buf,
-                line_count,
+                layers: BTreeMap::new(),
+                images: BTreeMap::new(),
+                params: HashMap::new(),
 
                 rx,
+
+                metrics,
+                draw_query: gl.create_query().ok(),
+                draw_query_pending: false,
+                vertex_pool,
+                screenshot_tx: screenshot::spawn_writer(),
             }
         }
     }
@@ -184,35 +285,220 @@ impl Viewport3d {
         use glow::HasContext as _;
         unsafe {
             gl.delete_program(self.program);
-            gl.delete_vertex_array(self.point_array);
-            gl.delete_vertex_array(self.line_array);
+            gl.delete_program(self.depth_program);
+            gl.delete_program(self.image_program);
+            gl.delete_vertex_array(self.empty_array);
+            for layer in self.layers.values() {
+                layer.destroy(gl);
+            }
+            for image in self.images.values() {
+                image.destroy(gl);
+            }
+            for cloud in &self.depth_clouds {
+                cloud.destroy(gl);
+            }
+            if let Some(query) = self.draw_query {
+                gl.delete_query(query);
+            }
         }
     }
 
-    fn paint(&mut self, gl: &glow::Context, state: ViewportState, size: Vec2) {
-        use glow::HasContext as _;
+    /// Apply pending changes to the geometry. Only the newest update of each layer is uploaded.
+    fn apply_messages(&mut self, gl: &glow::Context) {
+        let mut layer_updates = HashMap::new();
+        let mut depth_clouds = None;
+        let mut image_updates = HashMap::new();
+        for msg in self.rx.try_iter() {
+            match msg {
+                RenderMsg::SetLayer {
+                    name,
+                    primitive,
+                    vertices,
+                } => {
+                    if let Some((_, old)) = layer_updates.insert(name, (primitive, vertices)) {
+                        self.vertex_pool.put(old);
+                    }
+                }
+                RenderMsg::SetImage { name, plane } => {
+                    image_updates.insert(name, plane);
+                }
+                RenderMsg::RemoveLayer(name) => {
+                    layer_updates.remove(&name);
+                    image_updates.remove(&name);
+                    self.params.remove(&name);
+                    if let Some(layer) = self.layers.remove(&name) {
+                        layer.destroy(gl);
+                    }
+                    if let Some(image) = self.images.remove(&name) {
+                        image.destroy(gl);
+                    }
+                }
+                RenderMsg::SetParams { name, params } => {
+                    self.params.insert(name, params);
+                }
+                RenderMsg::DepthClouds(clouds) => depth_clouds = Some(clouds),
+            }
+        }
+
+        for (name, plane) in image_updates {
+            self.images
+                .entry(name)
+                .or_insert_with(|| GpuImagePlane::new(gl))
+                .upload(gl, &plane);
+        }
+
+        if layer_updates.is_empty() && depth_clouds.is_none() {
+            return;
+        }
+
+        let start = Instant::now();
+        for (name, (primitive, vertices)) in layer_updates {
+            self.layers
+                .entry(name)
+                .or_insert_with(|| GeometryLayer::new(gl, primitive))
+                .upload(gl, primitive, &vertices);
+            self.vertex_pool.put(vertices);
+        }
+
+        if let Some(clouds) = depth_clouds {
+            self.depth_cloud_count = 0;
+            for cloud in &clouds {
+                if self.depth_cloud_count == self.depth_clouds.len() {
+                    self.depth_clouds.push(GpuDepthCloud::new(gl));
+                }
+                if self.depth_clouds[self.depth_cloud_count].upload(gl, cloud) {
+                    self.depth_cloud_count += 1;
+                }
+            }
+        }
+        self.metrics
+            .record(METRICS_SOURCE, Stage::Upload, start.elapsed());
+    }
 
+    /// Render the view of "state" into an offscreen framebuffer of the given size and read it
+    /// back, scaling point sizes by "point_scale". The framebuffer, viewport and scissor test are
+    /// restored afterwards, so this may be called from a paint callback.
+    pub fn render_offscreen(
+        &mut self,
+        gl: &glow::Context,
+        state: &ViewportState,
+        width: u32,
+        height: u32,
+        point_scale: f32,
+    ) -> Result<Screenshot, String> {
         unsafe {
-            // Upload any new geometry
-            if let Some(msg) = self.rx.try_iter().last() {
-                let RenderMsg { lines, points } = msg;
-                gl.bind_buffer(glow::ARRAY_BUFFER, Some(self.line_buf));
-                gl.buffer_data_u8_slice(
-                    glow::ARRAY_BUFFER,
-                    bytemuck::cast_slice(&lines),
-                    glow::STREAM_DRAW,
+            let max_size = gl.get_parameter_i32(glow::MAX_RENDERBUFFER_SIZE) as u32;
+            if width == 0 || height == 0 || width > max_size || height > max_size {
+                return Err(format!(
+                    "Size {width}x{height} is outside of 1 to {max_size} pixels"
+                ));
+            }
+            let (w, h) = (width as i32, height as i32);
+
+            // Save the state egui expects back
+            let prev_framebuffer = gl.get_parameter_i32(glow::DRAW_FRAMEBUFFER_BINDING);
+            let prev_framebuffer =
+                NonZeroU32::new(prev_framebuffer as u32).map(glow::NativeFramebuffer);
+            let mut prev_viewport = [0; 4];
+            gl.get_parameter_i32_slice(glow::VIEWPORT, &mut prev_viewport);
+            let scissor = gl.is_enabled(glow::SCISSOR_TEST);
+
+            let framebuffer = gl.create_framebuffer()?;
+            let color = gl.create_renderbuffer()?;
+            let depth = gl.create_renderbuffer()?;
+            gl.bind_framebuffer(glow::FRAMEBUFFER, Some(framebuffer));
+            for (renderbuffer, format, attachment) in [
+                (color, glow::RGBA8, glow::COLOR_ATTACHMENT0),
+                (depth, glow::DEPTH_COMPONENT24, glow::DEPTH_ATTACHMENT),
+            ] {
+                gl.bind_renderbuffer(glow::RENDERBUFFER, Some(renderbuffer));
+                gl.renderbuffer_storage(glow::RENDERBUFFER, format, w, h);
+                gl.framebuffer_renderbuffer(
+                    glow::FRAMEBUFFER,
+                    attachment,
+                    glow::RENDERBUFFER,
+                    Some(renderbuffer),
                 );
-                self.line_count = lines.len() as i32;
-
-                gl.bind_buffer(glow::ARRAY_BUFFER, Some(self.point_buf));
-                gl.buffer_data_u8_slice(
-                    glow::ARRAY_BUFFER,
-                    bytemuck::cast_slice(&points),
-                    glow::STREAM_DRAW,
+            }
+            gl.bind_renderbuffer(glow::RENDERBUFFER, None);
+
+            let complete =
+                gl.check_framebuffer_status(glow::FRAMEBUFFER) == glow::FRAMEBUFFER_COMPLETE;
+            let result = if complete {
+                gl.disable(glow::SCISSOR_TEST);
+                gl.viewport(0, 0, w, h);
+                let [r, g, b, a] = OFFSCREEN_BACKGROUND;
+                gl.clear_color(r, g, b, a);
+                gl.clear(glow::COLOR_BUFFER_BIT);
+
+                let size = Vec2::new(width as f32, height as f32);
+                self.paint(gl, state.clone(), size, point_scale);
+
+                let mut pixels = vec![0; width as usize * height as usize * 4];
+                gl.read_pixels(
+                    0,
+                    0,
+                    w,
+                    h,
+                    glow::RGBA,
+                    glow::UNSIGNED_BYTE,
+                    glow::PixelPackData::Slice(&mut pixels),
                 );
-                self.point_count = points.len() as i32;
-                gl.bind_buffer(glow::ARRAY_BUFFER, None);
+
+                // OpenGL rows run from the bottom up
+                let row = width as usize * 4;
+                let pixels = pixels.chunks_exact(row).rev().flatten().copied().collect();
+                Ok(Screenshot {
+                    width,
+                    height,
+                    pixels,
+                })
+            } else {
+                Err("Offscreen framebuffer is incomplete".to_string())
+            };
+
+            gl.bind_framebuffer(glow::FRAMEBUFFER, prev_framebuffer);
+            gl.delete_framebuffer(framebuffer);
+            gl.delete_renderbuffer(color);
+            gl.delete_renderbuffer(depth);
+            let [x, y, vw, vh] = prev_viewport;
+            gl.viewport(x, y, vw, vh);
+            if scissor {
+                gl.enable(glow::SCISSOR_TEST);
+            }
+
+            result
+        }
+    }
+
+    /// Draw everything into the bound framebuffer of the given size, scaling point sizes by
+    /// "point_scale"
+    fn paint(&mut self, gl: &glow::Context, state: ViewportState, size: Vec2, point_scale: f32) {
+        use glow::HasContext as _;
+
+        unsafe {
+            // Upload any new geometry
+            self.apply_messages(gl);
+
+            // Collect the draw time of a previous frame, once the GPU has finished it
+            if let Some(query) = self.draw_query.filter(|_| self.draw_query_pending) {
+                if gl.get_query_parameter_u32(query, glow::QUERY_RESULT_AVAILABLE) != 0 {
+                    let nanos = gl.get_query_parameter_u32(query, glow::QUERY_RESULT);
+                    self.metrics.record(
+                        METRICS_SOURCE,
+                        Stage::Draw,
+                        Duration::from_nanos(nanos.into()),
+                    );
+                    self.draw_query_pending = false;
+                }
             }
+            let timing_draw = match self.draw_query {
+                Some(query) if !self.draw_query_pending => {
+                    gl.begin_query(glow::TIME_ELAPSED, query);
+                    true
+                }
+                _ => false,
+            };
 
             // Enable depth buffer (disabled by egui each frame)
             gl.enable(glow::DEPTH_TEST);
@@ -223,56 +509,172 @@ impl Viewport3d {
             gl.clear_depth_f32(1.0);
             gl.clear(glow::DEPTH_BUFFER_BIT);
 
-            // Draw points
-            gl.use_program(Some(self.program));
+            gl.enable(VERTEX_PROGRAM_POINT_SIZE);
 
-            let view = state.camera.view();
-            gl.uniform_matrix_4_f32_slice(
-                gl.get_uniform_location(self.program, "u_view").as_ref(),
-                false,
-                bytemuck::cast_slice(view.as_ref()),
-            );
-
-            let projection = state.camera.projection(size.x, size.y);
-            gl.uniform_matrix_4_f32_slice(
-                gl.get_uniform_location(self.program, "u_projection")
-                    .as_ref(),
-                false,
-                bytemuck::cast_slice(projection.as_ref()),
-            );
-
-            gl.uniform_2_f32(
-                gl.get_uniform_location(self.program, "u_spread").as_ref(),
-                state.camera.view.pivot.z,
-                state.spread.powi(2),
-            );
-
-            gl.uniform_1_f32(
-                gl.get_uniform_location(self.program, "u_ptsize").as_ref(),
-                state.point_size,
-            );
+            // Draw clouds deprojected on the GPU
+            let params = self.params.get(CLOUD_LAYER).copied().unwrap_or_default();
+            if self.depth_cloud_count > 0 && params.visible {
+                gl.use_program(Some(self.depth_program));
+                set_camera_uniforms(gl, self.depth_program, &state, size);
+                set_draw_uniforms(gl, self.depth_program, &params, point_scale);
+                set_coloring_uniforms(gl, self.depth_program, &state.point_coloring);
+                gl.bind_vertex_array(Some(self.empty_array));
+                for cloud in &self.depth_clouds[..self.depth_cloud_count] {
+                    cloud.draw(gl, self.depth_program, params.transform);
+                }
+            }
 
-            gl.enable(VERTEX_PROGRAM_POINT_SIZE);
+            // Draw geometry layers
+            gl.use_program(Some(self.program));
+            set_camera_uniforms(gl, self.program, &state, size);
+            for (name, layer) in &self.layers {
+                let params = self.params.get(name).copied().unwrap_or_default();
+                if params.visible {
+                    set_draw_uniforms(gl, self.program, &params, point_scale);
+                    layer.draw(gl);
+                }
+            }
 
-            gl.bind_vertex_array(None);
-            gl.bind_vertex_array(Some(self.point_array));
-            gl.draw_arrays(glow::POINTS, 0, self.point_count);
+            // Draw images
+            if !self.images.is_empty() {
+                gl.use_program(Some(self.image_program));
+                set_camera_uniforms(gl, self.image_program, &state, size);
+                for (name, image) in &self.images {
+                    let params = self.params.get(name).copied().unwrap_or_default();
+                    if params.visible {
+                        image.draw(gl, self.image_program, params.transform);
+                    }
+                }
+            }
 
-            gl.bind_vertex_array(None);
-            gl.bind_vertex_array(Some(self.line_array));
-            gl.draw_arrays(glow::LINES, 0, self.line_count);
+            if timing_draw {
+                gl.end_query(glow::TIME_ELAPSED);
+                self.draw_query_pending = true;
+            }
         }
     }
 }
 
-impl RenderMsg {
-    pub fn new() -> Self {
-        Default::default()
+/// Set the view, projection and spread uniforms shared by the point programs
+unsafe fn set_camera_uniforms(
+    gl: &glow::Context,
+    program: glow::Program,
+    state: &ViewportState,
+    size: Vec2,
+) {
+    let view = state.camera.view();
+    gl.uniform_matrix_4_f32_slice(
+        gl.get_uniform_location(program, "u_view").as_ref(),
+        false,
+        bytemuck::cast_slice(view.as_ref()),
+    );
+
+    let projection = state.camera.projection(size.x, size.y);
+    gl.uniform_matrix_4_f32_slice(
+        gl.get_uniform_location(program, "u_projection").as_ref(),
+        false,
+        bytemuck::cast_slice(projection.as_ref()),
+    );
+
+    gl.uniform_2_f32(
+        gl.get_uniform_location(program, "u_spread").as_ref(),
+        state.camera.view.pivot.z,
+        state.spread.powi(2),
+    );
+}
+
+/// Set the model transform, point size and tint uniforms for one layer
+unsafe fn set_draw_uniforms(
+    gl: &glow::Context,
+    program: glow::Program,
+    params: &DrawParams,
+    point_scale: f32,
+) {
+    gl.uniform_matrix_4_f32_slice(
+        gl.get_uniform_location(program, "u_model").as_ref(),
+        false,
+        &params.transform.to_cols_array(),
+    );
+
+    gl.uniform_1_f32(
+        gl.get_uniform_location(program, "u_ptsize").as_ref(),
+        params.point_size * point_scale,
+    );
+
+    let [r, g, b, a] = match params.color_mode {
+        ColorMode::Vertex => [0.; 4],
+        ColorMode::Solid([r, g, b]) => [r, g, b, 1.],
+    };
+    gl.uniform_4_f32(
+        gl.get_uniform_location(program, "u_tint").as_ref(),
+        r,
+        g,
+        b,
+        a,
+    );
+}
+
+/// Set the colormap uniforms of the depth cloud program. Colorings it doesn't support are applied
+/// on the CPU instead, so fall back to the color image.
+unsafe fn set_coloring_uniforms(
+    gl: &glow::Context,
+    program: glow::Program,
+    coloring: &PointColoring,
+) {
+    let (colormap, min, max) = match *coloring {
+        PointColoring::Depth { colormap, min, max } => (colormap.shader_index(), min, max),
+        _ => (0, 0., 0.),
+    };
+    gl.uniform_1_i32(
+        gl.get_uniform_location(program, "u_colormap").as_ref(),
+        colormap,
+    );
+    gl.uniform_2_f32(
+        gl.get_uniform_location(program, "u_depth_range").as_ref(),
+        min,
+        max,
+    );
+}
+
+/// Camera and point rendering settings
+pub fn viewport_settings_ui(ui: &mut egui::Ui, state: &mut ViewportState) {
+    camera::camera_ui(ui, &mut state.camera);
+    ui.separator();
+
+    ui.add(
+        egui::DragValue::new(&mut state.spread)
+            .prefix("Depth spread: ")
+            .speed(1e-2)
+            .clamp_range(0.01..=10.0),
+    );
+    ui.checkbox(&mut state.gpu_deprojection, "Deproject on GPU")
+        .on_hover_text("Upload raw depth and color images and deproject them in the vertex shader");
+    coloring::coloring_ui(ui, &mut state.point_coloring);
+    if state.gpu_deprojection && !state.point_coloring.gpu_supported() {
+        ui.label("This coloring is computed on the CPU, so points are deprojected there too");
+    }
+    if state.gpu_deprojection && state.gpu_unsupported_distortion {
+        ui.colored_label(
+            ui.visuals().error_fg_color,
+            "A camera's depth distortion model can't be deprojected on the GPU, so its points \
+             were deprojected on the CPU",
+        );
     }
 
-    pub fn append(&mut self, other: &RenderMsg) {
-        self.lines.extend_from_slice(&other.lines);
-        self.points.extend_from_slice(&other.points);
+    ui.separator();
+    if let Some(request) = screenshot::screenshot_ui(ui, &mut state.screenshot) {
+        state.screenshot_request = Some(request);
+    }
+}
+
+impl Default for DrawParams {
+    fn default() -> Self {
+        Self {
+            visible: true,
+            transform: Mat4::IDENTITY,
+            point_size: 8.0,
+            color_mode: ColorMode::Vertex,
+        }
     }
 }
 
@@ -281,7 +683,11 @@ impl Default for ViewportState {
         Self {
             camera: Default::default(),
             spread: 1.0,
-            point_size: 8.0,
+            gpu_deprojection: false,
+            point_coloring: PointColoring::Rgb,
+            screenshot: ScreenshotConfig::default(),
+            screenshot_request: None,
+            gpu_unsupported_distortion: false,
         }
     }
 }